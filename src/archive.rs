@@ -0,0 +1,196 @@
+
+pub mod archiving {
+
+    use std::collections::{BTreeMap, HashMap};
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::{Cursor, Read, Write};
+    use flate2::Compression;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use tch::{Device, Tensor};
+    use tch::nn::VarStore;
+    use zip::{ZipArchive, ZipWriter};
+    use zip::write::FileOptions;
+    use crate::{ELMo, JsonELMo};
+
+    // the entries of a model archive
+    pub const WEIGHTS_ENTRY: &str = "model.ot";
+    pub const CONFIG_ENTRY: &str = "config_resolved.json";
+    pub const TOKEN2INT_ENTRY: &str = "token2int.json";
+    pub const CHAR2INT_ENTRY: &str = "char2int.json";
+
+    // gzip streams start with these bytes, so compressed checkpoints are detected on load
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+    // saves the variables to a checkpoint file, gzipped if compress. The file name is kept either way
+    pub fn save_checkpoint(vars: &VarStore, checkpoint_file: &str, compress: bool) -> Result<(), Box<dyn Error>> {
+        if !compress {
+            return Ok(vars.save(checkpoint_file)?)
+        }
+        let mut weights = Vec::new();
+        vars.save_to_stream(&mut weights)?;
+        let mut encoder = GzEncoder::new(File::create(checkpoint_file)?, Compression::default());
+        encoder.write_all(&weights)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    // the content of a checkpoint file, decompressed if it was saved compressed
+    fn read_checkpoint(checkpoint_file: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let content = std::fs::read(checkpoint_file)?;
+        if !content.starts_with(&GZIP_MAGIC) {
+            return Ok(content)
+        }
+        let mut weights = Vec::new();
+        GzDecoder::new(content.as_slice()).read_to_end(&mut weights)?;
+        Ok(weights)
+    }
+
+    // loads a checkpoint saved by save_checkpoint, compressed or not. Checkpoints of older versions, with the
+    // variables at the root instead of named by submodule, are loaded through legacy_names
+    pub fn load_checkpoint(vars: &mut VarStore, checkpoint_file: &str) -> Result<(), Box<dyn Error>> {
+        let weights = read_checkpoint(checkpoint_file)?;
+        let saved: HashMap<String, Tensor> = Tensor::load_multi_from_stream_with_device(Cursor::new(&weights), vars.device())?.into_iter().collect();
+        if saved.contains_key("char_level.embed.weight") || !saved.contains_key("embed.weight") {
+            return Ok(vars.load_from_stream(Cursor::new(weights))?)
+        }
+
+        // the variables of features added since are missing from an old checkpoint, they keep their initialization
+        let legacy_names = legacy_names(vars);
+        for (name, mut var) in vars.variables() {
+            match legacy_names.get(&name).and_then(|legacy_name| saved.get(legacy_name)) {
+                Some(tensor) if tensor.size() == var.size() => tch::no_grad(|| { var.copy_(tensor); }),
+                Some(tensor) => return Err(format!("{} has the shape {:?} in {}, the model has {:?}", name, tensor.size(), checkpoint_file, var.size()).into()),
+                None => println!("{} isn't in the old checkpoint {}, it keeps its initialization", name, checkpoint_file)
+            }
+        }
+        Ok(())
+    }
+
+    // the names of the variables in checkpoints of older versions, by their current names. Older models created
+    // all the variables at the root of the var store, in the order of the model: the char embedding, the conv
+    // blocks, the highways and the projection of the char level net, then the lstm layers and the projection of
+    // each language model and the vocabulary head. tch suffixes a repeated name with __n, n the number of
+    // variables created before it
+    pub fn legacy_names(vars: &VarStore) -> BTreeMap<String, String> {
+
+        let variables = vars.variables();
+        let count = |name: &dyn Fn(usize) -> String| (0..).take_while(|i| variables.contains_key(&name(*i))).count();
+        let n_convs = count(&|i| format!("char_level.conv_{}.conv.weight", i));
+        let n_highways = count(&|i| format!("char_level.highway_{}.w_t.weight", i));
+        let n_lstm_layers = count(&|j| format!("forward_lm.lstm_{}.weight_ih_l0", j));
+
+        let linear = |name: &str, legacy_name: &str| ["weight", "bias"].map(|v| (format!("{}.{}", name, v), format!("{}.{}", legacy_name, v)));
+        let mut names = vec![(String::from("char_level.embed.weight"), String::from("embed.weight"))];
+        for i in 0..n_convs {
+            names.extend(linear(&format!("char_level.conv_{}.conv", i), "conv"));
+        }
+        for i in 0..n_highways {
+            names.extend(linear(&format!("char_level.highway_{}.w_t", i), "w_t"));
+            names.extend(linear(&format!("char_level.highway_{}.w_h", i), "w_h"));
+        }
+        names.extend(linear("char_level.to_dim", "to_dim"));
+        for lm in ["forward_lm", "backward_lm"] {
+            for j in 0..n_lstm_layers {
+                names.extend(["weight_ih_l0", "weight_hh_l0", "bias_ih_l0", "bias_hh_l0"].map(|v| (format!("{}.lstm_{}.{}", lm, j, v), format!("lstm.{}", v))));
+            }
+            names.extend(linear(&format!("{}.to_dim_lstm", lm), "to_dim_lstm"));
+        }
+        names.extend(linear("to_vocab", "to_vocab"));
+
+        let mut created: Vec<String> = Vec::new();
+        names.into_iter().map(|(name, legacy_name)| {
+            let legacy_name = match created.contains(&legacy_name) {
+                true => format!("{}__{}", legacy_name, created.len()),
+                false => legacy_name
+            };
+            created.push(legacy_name.clone());
+            (name, legacy_name)
+        }).collect()
+    }
+
+    // everything needed to run a trained model for inference
+    pub struct ModelArchive {
+        pub params: JsonELMo,
+        pub token2int: HashMap<String, usize>,
+        pub char2int: HashMap<char, usize>,
+        pub vars: VarStore,
+        pub model: ELMo
+    }
+
+    // bundles the weights, the resolved config and both vocabularies into a single zip file
+    pub fn export_archive(output_path: &str, vars: &VarStore, params: &JsonELMo, token2int: &HashMap<String, usize>, char2int: &HashMap<char, usize>) -> Result<(), Box<dyn Error>> {
+
+        let mut weights = Vec::new();
+        vars.save_to_stream(&mut weights)?;
+
+        let entries = [
+            (WEIGHTS_ENTRY, weights),
+            (CONFIG_ENTRY, serde_json::to_vec_pretty(params)?),
+            (TOKEN2INT_ENTRY, serde_json::to_vec(token2int)?),
+            (CHAR2INT_ENTRY, serde_json::to_vec(char2int)?)
+        ];
+
+        let mut writer = ZipWriter::new(File::create(output_path)?);
+        for (name, content) in entries {
+            writer.start_file(name, FileOptions::default())?;
+            writer.write_all(&content)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    // restores from a checkpoint the variables whose names and shapes match, for transfer learning. The
+    // others are left as initialized, typically the to_vocab head of a model with a new vocabulary. Returns
+    // the names of the loaded and of the skipped variables, both sorted
+    pub fn warm_start(vars: &mut VarStore, checkpoint_file: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+
+        let pretrained: HashMap<String, Tensor> = Tensor::load_multi_from_stream_with_device(Cursor::new(read_checkpoint(checkpoint_file)?), vars.device())?.into_iter().collect();
+        let mut variables = vars.variables().into_iter().collect::<Vec<(String, Tensor)>>();
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut loaded = Vec::new();
+        let mut skipped = Vec::new();
+        for (name, mut var) in variables {
+            match pretrained.get(&name) {
+                Some(tensor) if tensor.size() == var.size() => {
+                    tch::no_grad(|| var.copy_(tensor));
+                    println!("warm start: loaded {}", name);
+                    loaded.push(name);
+                },
+                _ => {
+                    println!("warm start: skipped {} (missing or mismatched shape in {})", name, checkpoint_file);
+                    skipped.push(name);
+                }
+            }
+        }
+        Ok((loaded, skipped))
+    }
+
+    // unpacks an archive written by export_archive, and rebuilds the model on the given device
+    pub fn import_archive(archive_path: &str, device: Device) -> Result<ModelArchive, Box<dyn Error>> {
+
+        let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+        let mut read_entry = |name: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut content = Vec::new();
+            archive.by_name(name).map_err(|e| format!("archive has no {}: {}", name, e))?.read_to_end(&mut content)?;
+            Ok(content)
+        };
+
+        let mut params: JsonELMo = serde_json::from_slice(&read_entry(CONFIG_ENTRY)?)?;
+        params.device = device;
+        let token2int: HashMap<String, usize> = serde_json::from_slice(&read_entry(TOKEN2INT_ENTRY)?)?;
+        let char2int: HashMap<char, usize> = serde_json::from_slice(&read_entry(CHAR2INT_ENTRY)?)?;
+
+        // the model is built from the resolved config, so the variables match the saved weights
+        let mut vars = VarStore::new(device);
+        let unk_id = *token2int.get(&params.str_unk).ok_or("archive token2int has no str_unk")? as i64;
+        let model = ELMo::new(&vars.root(), &params).with_unk_id(unk_id);
+        vars.set_kind(params.model_kind());
+        vars.load_from_stream(Cursor::new(read_entry(WEIGHTS_ENTRY)?))?;
+
+        Ok(ModelArchive { params: params, token2int: token2int, char2int: char2int, vars: vars, model: model })
+    }
+
+}
@@ -0,0 +1,45 @@
+
+
+
+use std::env;
+use std::error::Error;
+use elmo_trainer::Embedder;
+use elmo_trainer::archiving;
+use elmo_trainer::files_handling;
+use tch::Device;
+
+
+// prints the contextual embeddings of a line of text, one token per line followed by its values, or with
+// --export writes the embeddings of a sentences file, one per line, to an output file and its alignment file.
+// args are: <archive file> <sentence tokens...> or <archive file> --export <sentences file> <output file>
+fn main() -> Result<(), Box<dyn Error>> {
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        return Err("embed expects a model archive and a sentence: embed <archive file> <sentence tokens...>, or embed <archive file> --export <sentences file> <output file>".into());
+    }
+
+    let archive = archiving::import_archive(&args[1], Device::cuda_if_available())?;
+    let skip_blank_lines = archive.params.skip_blank_lines;
+    let embedder = Embedder::from_archive(archive)?;
+
+    if args[2] == "--export" {
+        if args.len() != 5 {
+            return Err("embed --export expects a sentences file and an output file: embed <archive file> --export <sentences file> <output file>".into());
+        }
+        let sentences = files_handling::load_sentences(&args[3], skip_blank_lines)?;
+        let n_exported = embedder.export(sentences, &args[4])?;
+        println!("exported the embeddings of {} sentences to {}", n_exported, args[4]);
+        return Ok(())
+    }
+
+    let sentence = args[2..].join(" ");
+
+    let tokens = embedder.tokens(&sentence)?;
+    let rows = Vec::<Vec<f32>>::try_from(&embedder.embed_sentence(&sentence)?)?;
+    for (token, row) in tokens.iter().zip(rows) {
+        let values = row.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ");
+        println!("{}\t{}", token, values);
+    }
+    Ok(())
+}
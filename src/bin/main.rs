@@ -3,17 +3,61 @@
 
 use std::env;
 use std::error::Error;
+use std::time::Instant;
 use elmo_trainer::ConfigElmo;
 use elmo_trainer::ELMoText;
-use elmo_trainer::Loader;
 use elmo_trainer::Splitter;
+use elmo_trainer::build_loaders;
+use elmo_trainer::dump_batches;
 use elmo_trainer::files_handling;
 use elmo_trainer::Preprocessor;
-use elmo_trainer::DatasetBuilder;
+use elmo_trainer::write_token_counts;
+use elmo_trainer::{coverage_report, write_coverage_report};
 use elmo_trainer::training::ElmoTrainer;
+use elmo_trainer::training::{free_running_agreement, loader_free_running_agreement};
+use elmo_trainer::training::nbest_next_tokens;
+use elmo_trainer::training::evaluate_corpus;
+use elmo_trainer::training::set_deterministic;
+use elmo_trainer::training::check_compute_capability;
+use elmo_trainer::training::build_with_cpu_fallback;
+use elmo_trainer::training::{confusion_report, most_frequent_ids, write_confusion_report};
+use elmo_trainer::training::write_manifest;
+use elmo_trainer::training::export_scalar_mix;
+use elmo_trainer::training::RngState;
+use elmo_trainer::archiving;
 use elmo_trainer::ELMo;
-use tch::Tensor;
-use tch::nn;
+use tch::{nn, Device};
+
+
+// evaluates a trained model archive on an external corpus, args are: eval <archive file> <corpus file>
+fn eval(args: &[String]) -> Result<(), Box<dyn Error>> {
+
+    if args.len() != 4 {
+        return Err("eval expects a model archive and a corpus file: main eval <archive file> <corpus file>".into());
+    }
+
+    let archive = archiving::import_archive(&args[2], Device::cuda_if_available())?;
+    let (loss, acc, perplexity) = evaluate_corpus(&archive, &args[3])?;
+    println!("got {} loss ({} perplexity), {} acc on {}", loss, perplexity, acc, args[3]);
+    Ok(())
+}
+
+
+// prints the most probable next tokens after a seed sentence, args are: nbest <archive file> <n> <seed tokens...>
+fn nbest(args: &[String]) -> Result<(), Box<dyn Error>> {
+
+    if args.len() < 5 {
+        return Err("nbest expects a model archive, n and a seed sentence: main nbest <archive file> <n> <seed tokens...>".into());
+    }
+
+    let archive = archiving::import_archive(&args[2], Device::cuda_if_available())?;
+    let n = args[3].parse::<usize>()?;
+    let elmo_text = ELMoText::try_new(Vec::new(), archive.token2int.clone(), archive.char2int.clone(), &archive.params)?;
+    for (token, prob) in nbest_next_tokens(&archive.model, &elmo_text, &args[4..].join(" "), n, &archive.params)? {
+        println!("{}\t{}", token, prob);
+    }
+    Ok(())
+}
 
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -21,7 +65,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     //
     // loading training parameteres
     println!("entering program...");
+    let run_timer = Instant::now();
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("eval") => return eval(&args),
+        Some("nbest") => return nbest(&args),
+        _ => {}
+    }
     
     println!("building parameters...");
     let mut params = match ConfigElmo::new(&args) {
@@ -29,70 +79,166 @@ fn main() -> Result<(), Box<dyn Error>> {
         Err(e) => panic!("{}", e)
     };
     println!("{}", params);
+    if params.deterministic {
+        set_deterministic(true);
+    }
+    let _output_lock = params.output_dir().map(|output_dir| files_handling::lock_output_dir(&output_dir, params.force)).transpose()?;
+    if let Some(min_compute_capability) = params.min_compute_capability {
+        check_compute_capability(params.device, min_compute_capability, &params.compute_capability_action)?;
+    }
     // -- end of loading parameters --
     //
 
     //
-    // preprocess of sentences
+    // preprocess of sentences, dev and test corpora (when given) only go through the string work
     let corpus_file = params.corpus_file.clone().unwrap();
-    let mut sentences = files_handling::load_sentences(&corpus_file)?;
+    let mut sentences = files_handling::load_sentences(&corpus_file, params.skip_blank_lines)?;
+    let mut dev_sentences = params.dev_file.as_ref().map(|f| files_handling::load_sentences(f, params.skip_blank_lines)).transpose()?;
+    let mut test_sentences = params.test_file.as_ref().map(|f| files_handling::load_sentences(f, params.skip_blank_lines)).transpose()?;
     let mut preprocessor = Preprocessor::new();
-    let (token2int,char2int) = preprocessor.preprocess(&mut sentences, &mut params);
+    let (token2int,char2int) = preprocessor.preprocess(&mut sentences, &mut params)?;
+    for held_out in [&mut dev_sentences, &mut test_sentences].into_iter().flatten() {
+        preprocessor.prepare_sentences(held_out, &params);
+    }
+    if let Some(output_dir) = params.output_dir() {
+        write_token_counts(preprocessor.token_counts(), &format!("{}/token_counts.tsv", output_dir))?;
+        files_handling::save_vocab(&format!("{}/{}", output_dir, files_handling::TOKEN2INT_FILE), &token2int)?;
+        files_handling::save_char_vocab(&format!("{}/{}", output_dir, files_handling::CHAR2INT_FILE), &char2int)?;
+        if let Some(coverage_file) = &params.coverage_file {
+            let report = coverage_report(&token2int, &char2int, coverage_file, &params, 20)?;
+            println!("{} is covered at {:.4} of its tokens and {:.4} of its chars", coverage_file, report.token_coverage, report.char_coverage);
+            write_coverage_report(&report, &format!("{}/coverage_report.json", output_dir))?;
+        }
+    }
     // -- end of preprocessing sentences
     //
 
     //
-    // Create an ELMo textual loader - data builder that moves data from strings to ints
-    let n_samples = (&sentences).len() as i64;
-    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    // Create an ELMo textual loader - data builder that moves data from strings to ints.
+    // dev and test corpora reuse the vocabularies built on train
+    let dev_text_loader = dev_sentences.map(|s| ELMoText::try_new(s, token2int.clone(), char2int.clone(), &params)).transpose()?;
+    let test_text_loader = test_sentences.map(|s| ELMoText::try_new(s, token2int.clone(), char2int.clone(), &params)).transpose()?;
+    let elmo_text_loader = ELMoText::try_new(sentences, token2int, char2int, &params)?;
+    if let Some(n_samples) = params.leakage_check_samples {
+        elmo_text_loader.check_label_leakage(n_samples as usize)?;
+        println!("no label leakage in the first {} examples", n_samples);
+    }
     // -- end of data building --
     //
 
     //
-    // Create an instance of the ELMo model
-    let mut vars = nn::VarStore::new(params.device);
-    let model = ELMo::new(&vars.root(), &params);
+    // Create an instance of the ELMo model, on the cpu if cuda fails and cpu_fallback is set.
+    // the loaders are built after, so they follow the device the model ended on
+    let ((mut vars, model), device) = build_with_cpu_fallback(params.device, params.cpu_fallback, |device| {
+        let mut vars = nn::VarStore::new(device);
+        let model = ELMo::new(&vars.root(), &params).with_unk_id(elmo_text_loader.unk_id());
+        vars.set_kind(params.model_kind()); // lstm initial states follow the kind of the weights
+        Ok((vars, model))
+    })?;
+    params.device = device;
+    if let Some(init_file) = &params.char_embedding_init_file {
+        let n_loaded = model.init_char_embedding(init_file, elmo_text_loader.char2int())?;
+        println!("loaded {} pretrained char vectors from {}", n_loaded, init_file);
+    }
+    if let Some(warm_start_file) = &params.warm_start_file {
+        let (loaded, skipped) = archiving::warm_start(&mut vars, warm_start_file)?;
+        println!("warm started {} variables from {}, {} were left initialized", loaded.len(), warm_start_file, skipped.len());
+    }
     // -- end of instantiating model --
     //
 
     //
-    // spliting data to train, dev and test sets, and moving to loaders (iterators over examples)
-    let splitter = Splitter::new();
-    let splits: Vec<Tensor> = splitter.get_split_train_dev_test_indices(n_samples);
-    let mut iters = splits.iter().map(|split| {
-        
-        let indices = TryInto::<Vec<i64>>::try_into(split).unwrap();
-        let (xs, ys): (Vec<_>, Vec<_>) = indices.iter()
-        .map(|i| elmo_text_loader.get_example(*i as usize).unwrap())
-        .unzip();
-
-        Loader::new(xs, ys, params.device, params.batch_size, params.seq_length)
-    });
+    // spliting data to train, dev and test sets, and moving to loaders (iterators over examples).
+    // when dev or test corpora are given, the splitter is bypassed
+    let splitter = match &params.splits_file {
+        Some(splits_file) => Splitter::from_splits_file(splits_file)?,
+        None => Splitter::with_mode_and_ratios(params.split_mode(), params.split_ratio)?
+    };
+    let splitter = match params.seed {
+        Some(seed) => splitter.with_seed(seed),
+        None => splitter
+    };
+    let (mut trainset_iter, mut devset_iter, testset_iter) = build_loaders(&elmo_text_loader, dev_text_loader.as_ref(), test_text_loader.as_ref(), &splitter, &params)?;
     // -- end of creating train, dev, test iterators
     //
     
+    //
+    // dump the first encoded batches for inspection, then continue to training
+    if let (Some(n), Some(output_dir)) = (params.dump_batches, params.output_dir()) {
+        let dump_file = format!("{}/batches_dump.json", output_dir);
+        if let Some(seed) = params.seed {
+            RngState { seed: seed, epoch: 0 }.restore(); // the generator training starts the first epoch with
+        }
+        let n_dumped = dump_batches(&mut trainset_iter, &elmo_text_loader, n, &dump_file)?;
+        println!("dumped {} encoded batches to {}", n_dumped, dump_file);
+    }
+    // -- end of dumping batches --
+    //
+
     //
     // running the training process with train and dev iterators
-    let mut trainset_iter = iters.next().ok_or("iters is but should have multiple loaders empty")?;
-    let mut devset_iter = iters.next();
     let elmo_train = ElmoTrainer::new();
-    if let Err(e) = elmo_train.run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params) {
-        panic!("problem during training: {}", e)
+    let train_progress = match elmo_train.run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params) {
+        Ok(train_progress) => train_progress,
+        Err(e) => panic!("problem during training: {}", e)
     };
+    if let Some(output_dir) = params.output_dir() {
+        write_manifest(&format!("{}/manifest.json", output_dir), &params, &vars, &train_progress, run_timer.elapsed())?;
+    }
+    if let (true, Some(output_dir)) = (params.export_scalar_mix, params.output_dir()) {
+        export_scalar_mix(&format!("{}/scalar_mix.json", output_dir), &vars)?;
+    }
     // -- end of training process --
     //
 
+    //
+    // export the trained model with its vocabularies, for later evaluation or inference
+    if let Some(archive_file) = params.archive_file() {
+        archiving::export_archive(&archive_file, &vars, &params, elmo_text_loader.token2int(), elmo_text_loader.char2int())?;
+        println!("exported model archive to {}", archive_file);
+    }
+    // -- end of exporting model --
+    //
+
+    //
+    // free running evaluation on the dev corpus, or on the dev split of the splitter, the model is fed back its own predictions
+    if params.dev_free_running {
+        let max_length = params.free_running_max_length as usize;
+        let agreement = match (&dev_text_loader, &devset_iter) {
+            (Some(dev_text_loader), _) => Some(free_running_agreement(&model, dev_text_loader, max_length)?),
+            (None, Some(devset_iter)) => Some(loader_free_running_agreement(&model, &elmo_text_loader, devset_iter, max_length)?),
+            (None, None) => None
+        };
+        match agreement {
+            Some(agreement) => println!("got {} free running agreement on dev set", agreement),
+            None => println!("no dev set, skipping the free running evaluation")
+        }
+    }
+    // -- end of free running evaluation --
+    //
+
     // 
-    // do testing on test set with saved model
-    if params.output_file.is_some() {
+    // do testing on test set with saved model. With a dev_file and no test_file all the corpus is trained on,
+    // there is no test set and testing is skipped
+    if testset_iter.is_none() {
+        println!("no test set, a dev_file is given without a test_file, skipping testing");
+    }
+    if let (Some(checkpoint_file), Some(mut testset_iter)) = (params.test_checkpoint_file(), testset_iter) {
 
-        vars.load(&params.output_file.unwrap().as_str())?;
-        let mut testset_iter = iters.next().ok_or("iters doesn't have a testing loader but testing is called")?;
-        assert!(iters.next().is_none());
+        archiving::load_checkpoint(&mut vars, &checkpoint_file)?;
+        println!("testing the checkpoint {}", checkpoint_file);
     
-        let test_acc = elmo_train.run_testing(&mut testset_iter, &model)?;
-        println!("got {} acc on test set", test_acc);
-        // -- end of testing --
+        let (_, test_acc, test_perplexity) = elmo_train.run_testing(&mut testset_iter, &model)?;
+        println!("got {} acc and {} perplexity on test set", test_acc, test_perplexity);
+
+        // which tokens the model predicts in place of the frequent gold tokens
+        if let (Some(n_tracked), Some(output_dir)) = (params.confusion_report, params.output_dir()) {
+            let text_loader = test_text_loader.as_ref().unwrap_or(&elmo_text_loader);
+            let tracked = most_frequent_ids(preprocessor.token_counts(), text_loader.token2int(), n_tracked as usize);
+            let report = confusion_report(&model, &mut testset_iter, &tracked);
+            write_confusion_report(&report, text_loader, &format!("{}/confusion_report.tsv", output_dir))?;
+        }
+    // -- end of testing --
         //
 
     }
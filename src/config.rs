@@ -1,39 +1,197 @@
 
 // imports
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tch::Device;
+use tch::{Device, Kind};
+use crate::loader::data_loading::{SplitMode, Splitter, IGNORE_INDEX};
+use crate::model::SENTENCE_POOLINGS;
 use std::{fs::{self}, error::Error, fmt::Display};
 
+// the optimizers that can be selected with the optimizer config
+pub const OPTIMIZERS: [&str; 3] = ["adam", "sgd", "rmsprop"];
 
-// all the parameters for training
-#[derive(Clone, Debug)]
+
+// all the parameters for training. They are serialized as resolved (after defaults and validation)
+// when the model is exported, the device is not, it is chosen again when the model is loaded
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JsonELMo {
     pub corpus_file: Option<String>,
     pub output_file: Option<String>,
+    pub dev_file: Option<String>,
+    pub test_file: Option<String>,
+    pub char_embedding_init_file: Option<String>,
+    pub warm_start_file: Option<String>,
+    pub resume_from: Option<String>,
+    pub coverage_file: Option<String>,
     pub token_vocab_size: i64,
     pub char_vocab_size: i64,
+    pub char_vocab_overflow: String,
+    pub partition_chars_by_script: bool,
+    pub char_script_partitions: Option<Vec<i64>>,
     pub min_count: i64,
     pub max_len_token: i64,
+    pub max_len_token_percentile: Option<f64>,
+    pub char_padding_side: String,
+    pub max_chars_per_example: Option<i64>,
     pub char_start: char,
     pub char_end: char,
     pub char_unk: char,
     pub str_unk: String,
+    pub skip_blank_lines: bool,
+    pub skip_oov_sentences: bool,
+    pub check_boundary_chars: bool,
+    pub split_mode: String,
+    pub split_ratio: [f64; 3],
+    pub splits_file: Option<String>,
+    pub write_splits: bool,
+    pub collapse_whitespace: bool,
+    pub delimiter: String,
+    pub lowercase_tokens: bool,
+    pub lowercase_chars: bool,
+    pub byte_fallback: bool,
+    pub add_sentence_boundaries: bool,
     pub batch_size: i64,
     pub seq_length: i64,
+    pub min_chunk_tokens: Option<i64>,
     pub char_embedding_dim: i64,
+    pub freeze_char_embedding: bool,
     pub in_channels: i64,
     pub out_channels: Vec<i64>,
     pub kernel_size: Vec<i64>,
+    pub share_conv_weights: bool,
     pub highways: i64,
+    pub char_level_out_dim: Option<i64>,
+    pub auto_dims: bool,
+    pub char_out_activation: String,
+    pub char_cnn_grad_scale: f64,
+    pub seq_chunk_size: Option<i64>,
+    pub sentence_pooling: String,
     pub in_dim: i64,
     pub hidden_dim: i64,
     pub n_lstm_layers: i64,
+    pub native_bidirectional: bool,
+    pub forget_bias: f64,
+    pub model_dtype: String,
+    pub embedding_dropout: f64,
+    pub input_mask_prob: f64,
+    pub char_backoff_weight: f64,
+    pub forward_loss_weight: f64,
+    pub backward_loss_weight: f64,
+    pub unk_loss_weight: f64,
     pub dropout: f64,
+    #[serde(skip, default = "Device::cuda_if_available")]
     pub device: Device,
+    pub deterministic: bool,
+    pub pin_memory: bool,
     pub max_iter: i64,
+    pub max_steps: Option<i64>,
+    pub curriculum: bool,
+    pub curriculum_epochs: i64,
+    pub validation_interval: Option<i64>,
+    pub max_eval_batches: Option<i64>,
+    pub eval_seed: i64,
     pub learning_rate: f64,
-    pub clip_norm: f64,
-    pub break_early: bool
+    pub optimizer: String,
+    pub momentum: Option<f64>,
+    pub layer_lr_multipliers: Vec<f64>,
+    pub grad_clip_norm: Option<f64>,
+    pub break_early: bool,
+    pub checkpoint_min_delta: f64,
+    pub compress_checkpoints: bool,
+    pub min_compute_capability: Option<f64>,
+    pub compute_capability_action: String,
+    pub cpu_fallback: bool,
+    pub export_scalar_mix: bool,
+    pub force: bool,
+    pub loss_reduction: String,
+    pub seed: Option<i64>,
+    pub pad_id: i64,
+    pub padded_batches: bool,
+    pub divergence_patience: Option<i64>,
+    pub patience: i64,
+    pub checkpoint_every: Option<i64>,
+    pub scheduled_sampling_prob: f64,
+    pub scheduled_sampling_epochs: i64,
+    pub embedding_window: Option<i64>,
+    pub embedding_window_overlap: i64,
+    pub char_whitelist: Option<String>,
+    pub char_blacklist: Option<String>,
+    pub target_metric_value: Option<f64>,
+    pub dev_free_running: bool,
+    pub confusion_report: Option<i64>,
+    pub free_running_max_length: i64,
+    pub log_interval: i64,
+    pub log_grad_norms: bool,
+    pub grad_cosine_samples: Option<i64>,
+    pub log_memory: bool,
+    pub debug_shapes: bool,
+    pub leakage_check_samples: Option<i64>,
+    pub dump_batches: Option<usize>
+}
+
+impl JsonELMo {
+
+    // the directory of output_file, where all other run outputs are written as well
+    pub fn output_dir(&self) -> Option<String> {
+        self.output_file.as_ref().map(|output_file| match output_file.rsplit_once('/') {
+            Some((dir_path, _)) => dir_path.to_string(),
+            None => String::from(".")
+        })
+    }
+
+    // the model archive (weights, resolved config and vocabularies) is written next to output_file
+    pub fn archive_file(&self) -> Option<String> {
+        self.output_dir().map(|output_dir| format!("{}/model_archive.zip", output_dir))
+    }
+
+    // the char level net feeds the lstm, so its output dim must be in_dim, and the conv runs over a single
+    // channel (the embedded token). With auto_dims mismatching values are replaced (with a log), else they fail
+    pub fn resolve_dims(&mut self) -> Result<(), Box<dyn Error>> {
+        let derived = [("char_level_out_dim", self.char_level_out_dim.unwrap_or(self.in_dim), self.in_dim), ("in_channels", self.in_channels, 1)];
+        for (name, value, expected) in derived {
+            if value != expected && !self.auto_dims {
+                return Err(format!("{} is {} but should be {}, fix it or set auto_dims", name, value, expected).into())
+            }
+            if value != expected {
+                println!("auto_dims: {} is {}, using {}", name, value, expected);
+            }
+        }
+        self.char_level_out_dim = Some(self.in_dim);
+        self.in_channels = 1;
+        Ok(())
+    }
+
+    // how the corpus is split to train, dev and test, random unless split_mode is sequential
+    pub fn split_mode(&self) -> SplitMode {
+        match self.split_mode.as_str() {
+            "sequential" => SplitMode::Sequential,
+            _ => SplitMode::Random
+        }
+    }
+
+    // the kind of the model variables, float unless model_dtype is double
+    pub fn model_kind(&self) -> Kind {
+        match self.model_dtype.as_str() {
+            "double" => Kind::Double,
+            _ => Kind::Float
+        }
+    }
+
+    // the best checkpoint (by the monitored perplexity) is kept next to output_file, with a best_ prefix
+    pub fn best_checkpoint_file(&self) -> Option<String> {
+        self.output_file.as_ref().map(|output_file| match output_file.rsplit_once('/') {
+            Some((dir_path, file_name)) => format!("{}/best_{}", dir_path, file_name),
+            None => format!("best_{}", output_file)
+        })
+    }
+
+    // the checkpoint the test set is evaluated on, the best checkpoint when training wrote one, else output_file
+    pub fn test_checkpoint_file(&self) -> Option<String> {
+        match self.best_checkpoint_file() {
+            Some(best_checkpoint_file) if std::path::Path::new(&best_checkpoint_file).exists() => Some(best_checkpoint_file),
+            _ => self.output_file.clone()
+        }
+    }
 }
 
 impl Display for JsonELMo {
@@ -41,42 +199,198 @@ impl Display for JsonELMo {
         write!(f, "using hyper-params:
         token_vocab_size: {}
         char_vocab_size: {}
+        char_vocab_overflow: {},
+        partition_chars_by_script: {},
+        char_script_partitions: {:?},
         min_count: {}
         max_len_token: {}
+        max_len_token_percentile: {:?},
+        char_padding_side: {},
+        max_chars_per_example: {:?},
+        collapse_whitespace: {}
+        delimiter: {:?},
+        skip_blank_lines: {},
+        skip_oov_sentences: {},
+        check_boundary_chars: {},
+        split_mode: {},
+        split_ratio: {:?},
+        splits_file: {:?},
+        write_splits: {},
+        lowercase_tokens: {}
+        lowercase_chars: {}
+        byte_fallback: {},
+        add_sentence_boundaries: {},
         char_embedding_dim: {},
+        freeze_char_embedding: {},
         in_channels: {},
         out_channels: {:?},
         kernel_size: {:?},
+        share_conv_weights: {},
         highways: {},
+        char_level_out_dim: {:?},
+        auto_dims: {},
+        char_out_activation: {},
+        char_cnn_grad_scale: {},
+        seq_chunk_size: {:?},
+        sentence_pooling: {},
         in_dim: {},
         hidden_dim: {},
         n_lstm_layers: {},
+        native_bidirectional: {},
+        forget_bias: {},
+        embedding_dropout: {},
+        input_mask_prob: {},
+        char_backoff_weight: {},
+        forward_loss_weight: {},
+        backward_loss_weight: {},
+        unk_loss_weight: {},
+        model_dtype: {},
         device: {:?},
+        deterministic: {},
+        pin_memory: {},
         max_iter: {},
+        max_steps: {:?},
+        curriculum: {},
+        curriculum_epochs: {},
+        validation_interval: {:?},
+        max_eval_batches: {:?},
+        eval_seed: {},
         learning_rate: {},
+        optimizer: {},
+        momentum: {:?},
+        layer_lr_multipliers: {:?},
         break_early: {},
+        checkpoint_min_delta: {},
+        compress_checkpoints: {},
+        min_compute_capability: {:?},
+        compute_capability_action: {},
+        cpu_fallback: {},
+        export_scalar_mix: {},
+        force: {},
+        loss_reduction: {},
+        pad_id: {},
+        padded_batches: {},
+        divergence_patience: {:?},
+        patience: {},
+        checkpoint_every: {:?},
+        scheduled_sampling_prob: {},
+        scheduled_sampling_epochs: {},
+        embedding_window: {:?},
+        embedding_window_overlap: {},
+        char_whitelist: {:?},
+        char_blacklist: {:?},
+        seed: {:?},
+        target_metric_value: {:?},
+        dev_free_running: {},
+        confusion_report: {:?},
+        free_running_max_length: {},
+        log_interval: {},
+        log_grad_norms: {},
+        grad_cosine_samples: {:?},
+        log_memory: {},
+        debug_shapes: {},
+        leakage_check_samples: {:?},
         batch_size: {},
         seq_length: {},
-        clip_norm: {}",
+        min_chunk_tokens: {:?},
+        grad_clip_norm: {}",
         self.token_vocab_size,
         self.char_vocab_size, 
+        self.char_vocab_overflow,
+        self.partition_chars_by_script,
+        self.char_script_partitions,
         self.min_count, 
         self.max_len_token, 
+        self.max_len_token_percentile,
+        self.char_padding_side,
+        self.max_chars_per_example,
+        self.collapse_whitespace,
+        self.delimiter,
+        self.skip_blank_lines,
+        self.skip_oov_sentences,
+        self.check_boundary_chars,
+        self.split_mode,
+        self.split_ratio,
+        self.splits_file,
+        self.write_splits,
+        self.lowercase_tokens,
+        self.lowercase_chars,
+        self.byte_fallback,
+        self.add_sentence_boundaries,
         self.char_embedding_dim, 
+        self.freeze_char_embedding,
         self.in_channels, 
         self.out_channels, 
         self.kernel_size, 
+        self.share_conv_weights,
         self.highways, 
+        self.char_level_out_dim,
+        self.auto_dims,
+        self.char_out_activation,
+        self.char_cnn_grad_scale,
+        self.seq_chunk_size,
+        self.sentence_pooling,
         self.in_dim, 
         self.hidden_dim, 
         self.n_lstm_layers, 
+        self.native_bidirectional,
+        self.forget_bias,
+        self.embedding_dropout,
+        self.input_mask_prob,
+        self.char_backoff_weight,
+        self.forward_loss_weight,
+        self.backward_loss_weight,
+        self.unk_loss_weight,
+        self.model_dtype,
         self.device, 
+        self.deterministic,
+        self.pin_memory,
         self.max_iter, 
+        self.max_steps,
+        self.curriculum,
+        self.curriculum_epochs,
+        self.validation_interval,
+        self.max_eval_batches,
+        self.eval_seed,
         self.learning_rate,
+        self.optimizer,
+        self.momentum,
+        self.layer_lr_multipliers,
         self.break_early,
+        self.checkpoint_min_delta,
+        self.compress_checkpoints,
+        self.min_compute_capability,
+        self.compute_capability_action,
+        self.cpu_fallback,
+        self.export_scalar_mix,
+        self.force,
+        self.loss_reduction,
+        self.pad_id,
+        self.padded_batches,
+        self.divergence_patience,
+        self.patience,
+        self.checkpoint_every,
+        self.scheduled_sampling_prob,
+        self.scheduled_sampling_epochs,
+        self.embedding_window,
+        self.embedding_window_overlap,
+        self.char_whitelist,
+        self.char_blacklist,
+        self.seed,
+        self.target_metric_value,
+        self.dev_free_running,
+        self.confusion_report,
+        self.free_running_max_length,
+        self.log_interval,
+        self.log_grad_norms,
+        self.grad_cosine_samples,
+        self.log_memory,
+        self.debug_shapes,
+        self.leakage_check_samples,
         self.batch_size,
         self.seq_length,
-        self.clip_norm
+        self.min_chunk_tokens,
+        match self.grad_clip_norm { Some(max_norm) => format!("{} (clipping)", max_norm), None => String::from("None (no clipping)") }
     )
     }
 }
@@ -96,7 +410,8 @@ impl ConfigElmo {
         }
 
         let json = ConfigElmo::read_json(&args[1]);
-        let params = ConfigElmo::validate(json)?;
+        let mut params = ConfigElmo::validate(json)?;
+        params.resolve_dims()?;
 
         // create output directory if not exists
         let output_file = params.output_file.clone();
@@ -121,6 +436,13 @@ impl ConfigElmo {
 
 }
 
+// the default values for training, without corpus and output files. A base for parameters built in code
+impl Default for JsonELMo {
+    fn default() -> Self {
+        ConfigElmo::defaults(None, None)
+    }
+}
+
 
 pub trait Conigure {
     type Item;
@@ -146,30 +468,115 @@ impl Conigure for ConfigElmo {
         Self::Item {
             token_vocab_size: 300_000,
             char_vocab_size: 262,
+            char_vocab_overflow: String::from("truncate"),
+            partition_chars_by_script: false,
+            char_script_partitions: None,
             min_count: 3,
             max_len_token: 50,
+            max_len_token_percentile: None,
+            char_padding_side: String::from("post"),
+            max_chars_per_example: None,
             char_embedding_dim: 16,
+            freeze_char_embedding: false,
             in_channels: 1,
             kernel_size: vec![1, 2, 3, 4, 5, 6, 7],
+            share_conv_weights: false,
             out_channels: vec![32, 32, 64 ,128, 256, 512, 1024],
             highways: 1,
+            char_level_out_dim: None,
+            auto_dims: false,
+            char_out_activation: String::from("none"),
+            char_cnn_grad_scale: 1.0,
+            seq_chunk_size: None,
+            sentence_pooling: String::from("mean"),
             in_dim: 128,
             hidden_dim: 1024,
             n_lstm_layers: 1, // 2
+            native_bidirectional: false,
+            forget_bias: 1.0,
+            model_dtype: String::from("float"),
+            embedding_dropout: 0.0,
+            input_mask_prob: 0.0,
+            char_backoff_weight: 0.0,
+            forward_loss_weight: 1.0,
+            backward_loss_weight: 1.0,
+            unk_loss_weight: 1.0,
             dropout: 0.1,
             max_iter: 10,
+            max_steps: None,
+            curriculum: false,
+            curriculum_epochs: 1,
+            validation_interval: None,
+            max_eval_batches: None,
+            eval_seed: 0,
             batch_size: 128,
             seq_length: 20,
-            clip_norm: 3.0,
+            min_chunk_tokens: None,
+            grad_clip_norm: None,
             learning_rate: 0.001,               // maybe different
+            optimizer: String::from("adam"),
+            momentum: None,
+            layer_lr_multipliers: vec![],
             break_early: false,
+            checkpoint_min_delta: 0.0,
+            compress_checkpoints: false,
+            min_compute_capability: None,
+            compute_capability_action: String::from("warn"),
+            cpu_fallback: false,
+            export_scalar_mix: false,
+            force: false,
+            loss_reduction: String::from("token_mean"),
+            seed: None,
+            pad_id: IGNORE_INDEX,
+            padded_batches: false,
+            divergence_patience: None,
+            patience: 0,
+            checkpoint_every: None,
+            scheduled_sampling_prob: 0.0,
+            scheduled_sampling_epochs: 1,
+            embedding_window: None,
+            embedding_window_overlap: 0,
+            char_whitelist: None,
+            char_blacklist: None,
+            target_metric_value: None,
+            dev_free_running: false,
+            confusion_report: None,
+            free_running_max_length: 20,
+            log_interval: 100,
+            log_grad_norms: false,
+            grad_cosine_samples: None,
+            log_memory: false,
+            debug_shapes: false,
+            leakage_check_samples: None,
+            dump_batches: None,
             device: Device::cuda_if_available(),
+            deterministic: false,
+            pin_memory: false,
             char_start: '$',
             char_end: '^',
             char_unk: '~',
             str_unk: String::from("UNK"),
+            skip_blank_lines: true,
+            skip_oov_sentences: false,
+            check_boundary_chars: true,
+            split_mode: String::from("random"),
+            split_ratio: [0.8, 0.1, 0.1],
+            splits_file: None,
+            write_splits: false,
+            collapse_whitespace: true,
+            delimiter: String::from(" "),
+            lowercase_tokens: true,
+            lowercase_chars: true,
+            byte_fallback: false,
+            add_sentence_boundaries: true,
             corpus_file: corpus_file,
             output_file: output_file,
+            dev_file: None,
+            test_file: None,
+            char_embedding_init_file: None,
+            warm_start_file: None,
+            resume_from: None,
+            coverage_file: None,
         }
 
     }
@@ -185,6 +592,10 @@ impl Conigure for ConfigElmo {
             .expect(format!("cannot cast {} to string", field).as_str())
         };
 
+        let validate_string = |field: &str| -> Result<String, Box<dyn Error>> {
+            Ok(json.get(field).ok_or("field not given")?.as_str().ok_or("not string")?.to_string())
+        };
+
         let validate_float = |field: &str| -> Result<f64, Box<dyn Error>> {
             json.get(field).ok_or("field not given")?.as_f64().ok_or("not float".into())
         };
@@ -199,6 +610,12 @@ impl Conigure for ConfigElmo {
             Ok(val)
         };
 
+        let validate_non_negative_int = |field: &str| -> Result<i64, Box<dyn Error>> {
+            let val = json.get(field).ok_or("field not given")?.as_i64().ok_or::<String>("not int".into())?;
+            if val < 0 { return Err("not non-negative int".into()) }
+            Ok(val)
+        };
+
         let validate_vec = |field: &str| -> Result<Vec<i64>, Box<dyn Error>> {
             let arr = json.get(field).ok_or("field not given")?.as_array().ok_or::<String>("not vec".into())?;
             let mut values = Vec::new();
@@ -210,11 +627,24 @@ impl Conigure for ConfigElmo {
             Ok(values)
         };
 
+        let validate_float_vec = |field: &str| -> Result<Vec<f64>, Box<dyn Error>> {
+            let arr = json.get(field).ok_or("field not given")?.as_array().ok_or::<String>("not vec".into())?;
+            arr.iter().map(|val| val.as_f64().ok_or("not float".into())).collect()
+        };
+
         // validate input and output in json - most be given
         let corpus_file = validate_str("corpus_file").to_string();
         let output_file = validate_str("output_file").to_string();
         let mut params = ConfigElmo::defaults(Some(corpus_file), Some(output_file));
 
+        // validate optional dev and test corpora, given separately from the train corpus
+        if let Ok(dev_file) = validate_string("dev_file") {
+            params.dev_file = Some(dev_file);
+        }
+        if let Ok(test_file) = validate_string("test_file") {
+            params.test_file = Some(test_file);
+        }
+
         // validate optional input parameters
         if let Ok(token_vocab_size) = validate_positive_int("token_vocab_size") {
             params.token_vocab_size = token_vocab_size;
@@ -234,7 +664,7 @@ impl Conigure for ConfigElmo {
         if let Ok(in_channels) = validate_positive_int("in_channels") {
             params.in_channels = in_channels;
         }
-        if let Ok(highways) = validate_positive_int("highways") {
+        if let Ok(highways) = validate_non_negative_int("highways") {
             params.highways = highways;
         }
         if let Ok(in_dim) = validate_positive_int("in_dim") {
@@ -255,14 +685,26 @@ impl Conigure for ConfigElmo {
         if let Ok(seq_length) = validate_positive_int("seq_length") {
             params.seq_length = seq_length;
         }
+        if let Ok(min_chunk_tokens) = validate_positive_int("min_chunk_tokens") {
+            params.min_chunk_tokens = Some(min_chunk_tokens);
+        }
+        if let Ok(log_interval) = validate_positive_int("log_interval") {
+            params.log_interval = log_interval;
+        }
+        if let Ok(dump_batches) = validate_positive_int("dump_batches") {
+            if dump_batches < 1 {
+                return Err(format!("dump_batches should be positive, got {}", dump_batches).into())
+            }
+            params.dump_batches = Some(dump_batches as usize);
+        }
         if let Ok(dropout) = validate_float("dropout") {
             params.dropout = dropout;
         }
         if let Ok(learning_rate) = validate_float("learning_rate") {
             params.learning_rate = learning_rate;
         }
-        if let Ok(clip_norm) = validate_float("clip_norm") {
-            params.clip_norm = clip_norm;
+        if let Ok(target_metric_value) = validate_float("target_metric_value") {
+            params.target_metric_value = Some(target_metric_value);
         }
         if let Ok(out_channels) = validate_vec("out_channels") {
             params.out_channels = out_channels;
@@ -273,6 +715,350 @@ impl Conigure for ConfigElmo {
         if let Ok(break_early) = validate_bool("break_early") {
             params.break_early = break_early;
         }
+        if let Ok(log_grad_norms) = validate_bool("log_grad_norms") {
+            params.log_grad_norms = log_grad_norms;
+        }
+        if let Ok(native_bidirectional) = validate_bool("native_bidirectional") {
+            // each direction of a native bidirectional layer reads both directions of the layer below, so the
+            // next token predictions would see the token they predict. The model is built for probing only,
+            // the trainer refuses to train it (LanguageModel::check_trainable)
+            params.native_bidirectional = native_bidirectional;
+        }
+        if let Ok(collapse_whitespace) = validate_bool("collapse_whitespace") {
+            params.collapse_whitespace = collapse_whitespace;
+        }
+        if let Ok(lowercase_tokens) = validate_bool("lowercase_tokens") {
+            params.lowercase_tokens = lowercase_tokens;
+        }
+        if let Ok(lowercase_chars) = validate_bool("lowercase_chars") {
+            params.lowercase_chars = lowercase_chars;
+        }
+        if let Ok(max_steps) = validate_positive_int("max_steps") {
+            params.max_steps = Some(max_steps);
+        }
+        if let Ok(add_sentence_boundaries) = validate_bool("add_sentence_boundaries") {
+            params.add_sentence_boundaries = add_sentence_boundaries;
+        }
+        if let Ok(forget_bias) = validate_float("forget_bias") {
+            params.forget_bias = forget_bias;
+        }
+        if let Ok(checkpoint_min_delta) = validate_float("checkpoint_min_delta") {
+            if checkpoint_min_delta < 0.0 { return Err("checkpoint_min_delta should be non-negative".into()) }
+            params.checkpoint_min_delta = checkpoint_min_delta;
+        }
+        if let Ok(model_dtype) = validate_string("model_dtype") {
+            if !["float", "double"].contains(&model_dtype.as_str()) {
+                return Err(format!("model_dtype should be float or double, got {}", model_dtype).into())
+            }
+            if model_dtype == "double" && params.device.is_cuda() {
+                return Err("model_dtype double is not supported on cuda, use float or run on cpu".into())
+            }
+            params.model_dtype = model_dtype;
+        }
+        if let Ok(freeze_char_embedding) = validate_bool("freeze_char_embedding") {
+            params.freeze_char_embedding = freeze_char_embedding;
+        }
+        if let Ok(char_embedding_init_file) = validate_string("char_embedding_init_file") {
+            params.char_embedding_init_file = Some(char_embedding_init_file);
+        }
+        if let Ok(char_out_activation) = validate_string("char_out_activation") {
+            if !["none", "relu", "tanh", "layernorm"].contains(&char_out_activation.as_str()) {
+                return Err(format!("char_out_activation should be one of none, relu, tanh, layernorm, got {}", char_out_activation).into())
+            }
+            params.char_out_activation = char_out_activation;
+        }
+        if let Ok(dev_free_running) = validate_bool("dev_free_running") {
+            params.dev_free_running = dev_free_running;
+        }
+        if let Ok(free_running_max_length) = validate_positive_int("free_running_max_length") {
+            params.free_running_max_length = free_running_max_length;
+        }
+        if let Ok(layer_lr_multipliers) = validate_float_vec("layer_lr_multipliers") {
+            if !layer_lr_multipliers.is_empty() && layer_lr_multipliers.len() as i64 != params.n_lstm_layers {
+                return Err(format!("layer_lr_multipliers has {} values, but there are {} lstm layers", layer_lr_multipliers.len(), params.n_lstm_layers).into())
+            }
+            params.layer_lr_multipliers = layer_lr_multipliers;
+        }
+        if let Ok(skip_blank_lines) = validate_bool("skip_blank_lines") {
+            params.skip_blank_lines = skip_blank_lines;
+        }
+        if let Ok(max_chars_per_example) = validate_positive_int("max_chars_per_example") {
+            params.max_chars_per_example = Some(max_chars_per_example);
+        }
+        if let Ok(embedding_dropout) = validate_float("embedding_dropout") {
+            if !(0.0..1.0).contains(&embedding_dropout) { return Err("embedding_dropout should be in [0, 1)".into()) }
+            params.embedding_dropout = embedding_dropout;
+        }
+        if let Ok(input_mask_prob) = validate_float("input_mask_prob") {
+            if !(0.0..1.0).contains(&input_mask_prob) { return Err("input_mask_prob should be in [0, 1)".into()) }
+            params.input_mask_prob = input_mask_prob;
+        }
+        if let Ok(share_conv_weights) = validate_bool("share_conv_weights") {
+            params.share_conv_weights = share_conv_weights;
+        }
+        if let Ok(deterministic) = validate_bool("deterministic") {
+            params.deterministic = deterministic;
+        }
+        if let Ok(confusion_report) = validate_positive_int("confusion_report") {
+            params.confusion_report = Some(confusion_report);
+        }
+        if let Ok(char_padding_side) = validate_string("char_padding_side") {
+            if !["post", "pre", "both"].contains(&char_padding_side.as_str()) {
+                return Err(format!("char_padding_side should be one of post, pre, both, got {}", char_padding_side).into())
+            }
+            params.char_padding_side = char_padding_side;
+        }
+        // either every_epoch (the default) or a positive number of optimizer steps
+        if let Some(validation_interval) = json.get("validation_interval") {
+            params.validation_interval = match (validation_interval.as_str(), validation_interval.as_i64()) {
+                (Some("every_epoch"), _) => None,
+                (_, Some(steps)) if steps > 0 => Some(steps),
+                _ => return Err(format!("validation_interval should be every_epoch or a positive number of steps, got {}", validation_interval).into())
+            };
+        }
+        if let Ok(warm_start_file) = validate_string("warm_start_file") {
+            params.warm_start_file = Some(warm_start_file);
+        }
+        if let Ok(curriculum) = validate_bool("curriculum") {
+            params.curriculum = curriculum;
+        }
+        if let Ok(curriculum_epochs) = validate_positive_int("curriculum_epochs") {
+            params.curriculum_epochs = curriculum_epochs;
+        }
+        if let Ok(log_memory) = validate_bool("log_memory") {
+            params.log_memory = log_memory;
+        }
+        if let Ok(char_vocab_overflow) = validate_string("char_vocab_overflow") {
+            if !["truncate", "error"].contains(&char_vocab_overflow.as_str()) {
+                return Err(format!("char_vocab_overflow should be one of truncate, error, got {}", char_vocab_overflow).into())
+            }
+            params.char_vocab_overflow = char_vocab_overflow;
+        }
+        if let Ok(char_level_out_dim) = validate_positive_int("char_level_out_dim") {
+            params.char_level_out_dim = Some(char_level_out_dim);
+        }
+        if let Ok(auto_dims) = validate_bool("auto_dims") {
+            params.auto_dims = auto_dims;
+        }
+        // the char backoff targets are the next input tokens, which holds within a sentence when the
+        // sentences end with EOS, and only if the inputs are not masked
+        if let Ok(char_backoff_weight) = validate_float("char_backoff_weight") {
+            if char_backoff_weight < 0.0 {
+                return Err(format!("char_backoff_weight should be non-negative, got {}", char_backoff_weight).into())
+            }
+            if char_backoff_weight > 0.0 && (!params.add_sentence_boundaries || params.input_mask_prob > 0.0) {
+                return Err("char_backoff_weight needs add_sentence_boundaries and no input_mask_prob".into())
+            }
+            params.char_backoff_weight = char_backoff_weight;
+        }
+        if let Ok(char_cnn_grad_scale) = validate_float("char_cnn_grad_scale") {
+            if char_cnn_grad_scale < 0.0 {
+                return Err(format!("char_cnn_grad_scale should be non-negative, got {}", char_cnn_grad_scale).into())
+            }
+            params.char_cnn_grad_scale = char_cnn_grad_scale;
+        }
+        if let Ok(skip_oov_sentences) = validate_bool("skip_oov_sentences") {
+            params.skip_oov_sentences = skip_oov_sentences;
+        }
+        if let Ok(max_eval_batches) = validate_positive_int("max_eval_batches") {
+            params.max_eval_batches = Some(max_eval_batches);
+        }
+        if let Ok(eval_seed) = validate_non_negative_int("eval_seed") {
+            params.eval_seed = eval_seed;
+        }
+        if let Ok(split_mode) = validate_string("split_mode") {
+            if !["random", "sequential"].contains(&split_mode.as_str()) {
+                return Err(format!("split_mode should be one of random, sequential, got {}", split_mode).into())
+            }
+            params.split_mode = split_mode;
+        }
+        if let Ok(pin_memory) = validate_bool("pin_memory") {
+            params.pin_memory = pin_memory;
+        }
+        // char_script_partitions is resolved by the preprocessor from the char vocabulary, it isn't read
+        if let Ok(partition_chars_by_script) = validate_bool("partition_chars_by_script") {
+            params.partition_chars_by_script = partition_chars_by_script;
+        }
+        if let Ok(compress_checkpoints) = validate_bool("compress_checkpoints") {
+            params.compress_checkpoints = compress_checkpoints;
+        }
+        if let Ok(check_boundary_chars) = validate_bool("check_boundary_chars") {
+            params.check_boundary_chars = check_boundary_chars;
+        }
+        if let Ok(min_compute_capability) = validate_float("min_compute_capability") {
+            if min_compute_capability <= 0.0 { return Err("min_compute_capability should be positive".into()) }
+            params.min_compute_capability = Some(min_compute_capability);
+        }
+        if let Ok(compute_capability_action) = validate_string("compute_capability_action") {
+            if !["warn", "error"].contains(&compute_capability_action.as_str()) {
+                return Err(format!("compute_capability_action should be one of warn, error, got {}", compute_capability_action).into())
+            }
+            params.compute_capability_action = compute_capability_action;
+        }
+        if let Ok(seq_chunk_size) = validate_positive_int("seq_chunk_size") {
+            params.seq_chunk_size = Some(seq_chunk_size);
+        }
+        if let Ok(cpu_fallback) = validate_bool("cpu_fallback") {
+            params.cpu_fallback = cpu_fallback;
+        }
+        if let Ok(force) = validate_bool("force") {
+            params.force = force;
+        }
+        if let Ok(loss_reduction) = validate_string("loss_reduction") {
+            if !["token_mean", "sentence_mean", "sum"].contains(&loss_reduction.as_str()) {
+                return Err(format!("loss_reduction should be one of token_mean, sentence_mean, sum, got {}", loss_reduction).into())
+            }
+            params.loss_reduction = loss_reduction;
+        }
+        if let Ok(seed) = validate_non_negative_int("seed") {
+            params.seed = Some(seed);
+        }
+        if let Ok(pad_id) = validate_non_negative_int("pad_id") {
+            params.pad_id = pad_id;
+        }
+        if let Ok(divergence_patience) = validate_positive_int("divergence_patience") {
+            params.divergence_patience = Some(divergence_patience);
+        }
+        if let Ok(scheduled_sampling_prob) = validate_float("scheduled_sampling_prob") {
+            if !(0.0..=1.0).contains(&scheduled_sampling_prob) { return Err("scheduled_sampling_prob should be in [0, 1]".into()) }
+            params.scheduled_sampling_prob = scheduled_sampling_prob;
+        }
+        if let Ok(scheduled_sampling_epochs) = validate_positive_int("scheduled_sampling_epochs") {
+            params.scheduled_sampling_epochs = scheduled_sampling_epochs;
+        }
+        if let Ok(embedding_window) = validate_positive_int("embedding_window") {
+            params.embedding_window = Some(embedding_window);
+        }
+        if let Ok(embedding_window_overlap) = validate_non_negative_int("embedding_window_overlap") {
+            if matches!(params.embedding_window, Some(window) if embedding_window_overlap >= window) {
+                return Err("embedding_window_overlap should be smaller than embedding_window".into())
+            }
+            params.embedding_window_overlap = embedding_window_overlap;
+        }
+        if let Ok(export_scalar_mix) = validate_bool("export_scalar_mix") {
+            params.export_scalar_mix = export_scalar_mix;
+        }
+        if let Ok(char_whitelist) = validate_string("char_whitelist") {
+            if char_whitelist.is_empty() { return Err("char_whitelist should have at least one char".into()) }
+            params.char_whitelist = Some(char_whitelist);
+        }
+        if let Ok(char_blacklist) = validate_string("char_blacklist") {
+            params.char_blacklist = Some(char_blacklist);
+        }
+        if let Ok(grad_cosine_samples) = validate_positive_int("grad_cosine_samples") {
+            params.grad_cosine_samples = Some(grad_cosine_samples);
+        }
+        if let Ok(splits_file) = validate_string("splits_file") {
+            params.splits_file = Some(splits_file);
+        }
+        if let Ok(write_splits) = validate_bool("write_splits") {
+            params.write_splits = write_splits;
+        }
+        if let Ok(byte_fallback) = validate_bool("byte_fallback") {
+            params.byte_fallback = byte_fallback;
+        }
+        if let Ok(delimiter) = validate_string("delimiter") {
+            if delimiter.is_empty() {
+                return Err("delimiter should have at least one char".into())
+            }
+            params.delimiter = delimiter;
+        }
+        for (name, weight) in [("forward_loss_weight", &mut params.forward_loss_weight), ("backward_loss_weight", &mut params.backward_loss_weight)] {
+            if let Ok(value) = validate_float(name) {
+                if value < 0.0 {
+                    return Err(format!("{} should be non-negative, got {}", name, value).into())
+                }
+                *weight = value;
+            }
+        }
+        if let Ok(leakage_check_samples) = validate_positive_int("leakage_check_samples") {
+            params.leakage_check_samples = Some(leakage_check_samples);
+        }
+        if let Ok(split_ratio) = validate_float_vec("split_ratio") {
+            params.split_ratio = Splitter::check_ratios(&split_ratio)?;
+        }
+        if let Ok(max_len_token_percentile) = validate_float("max_len_token_percentile") {
+            if max_len_token_percentile <= 0.0 || max_len_token_percentile > 100.0 {
+                return Err(format!("max_len_token_percentile should be in (0, 100], got {}", max_len_token_percentile).into())
+            }
+            if json.get("max_len_token").is_some() {
+                return Err("max_len_token_percentile resolves max_len_token, it can't be given as well".into())
+            }
+            params.max_len_token_percentile = Some(max_len_token_percentile);
+        }
+        if let Ok(debug_shapes) = validate_bool("debug_shapes") {
+            params.debug_shapes = debug_shapes;
+        }
+        if let Ok(unk_loss_weight) = validate_float("unk_loss_weight") {
+            if unk_loss_weight < 0.0 {
+                return Err(format!("unk_loss_weight should be non-negative, got {}", unk_loss_weight).into())
+            }
+            params.unk_loss_weight = unk_loss_weight;
+        }
+        if let Ok(coverage_file) = validate_string("coverage_file") {
+            params.coverage_file = Some(coverage_file);
+        }
+        if let Ok(patience) = validate_non_negative_int("patience") {
+            params.patience = patience;
+        }
+        if let Ok(checkpoint_every) = validate_positive_int("checkpoint_every") {
+            params.checkpoint_every = Some(checkpoint_every);
+        }
+        if let Ok(resume_from) = validate_string("resume_from") {
+            params.resume_from = Some(resume_from);
+        }
+        if let Ok(sentence_pooling) = validate_string("sentence_pooling") {
+            if !SENTENCE_POOLINGS.contains(&sentence_pooling.as_str()) {
+                return Err(format!("sentence_pooling should be one of mean, max, last, cls, got {}", sentence_pooling).into())
+            }
+            params.sentence_pooling = sentence_pooling;
+        }
+        if let Ok(grad_clip_norm) = validate_float("grad_clip_norm") {
+            if grad_clip_norm <= 0.0 {
+                return Err(format!("grad_clip_norm should be positive, got {}", grad_clip_norm).into())
+            }
+            params.grad_clip_norm = Some(grad_clip_norm);
+        }
+        // clip_norm was never applied, it is read as grad_clip_norm
+        if let Ok(clip_norm) = validate_float("clip_norm") {
+            if params.grad_clip_norm.is_some() {
+                return Err("clip_norm is deprecated, it is replaced by grad_clip_norm, give only grad_clip_norm".into())
+            }
+            if clip_norm <= 0.0 {
+                return Err(format!("clip_norm should be positive, got {}", clip_norm).into())
+            }
+            println!("clip_norm is deprecated, use grad_clip_norm instead. Clipping the gradient norm to {}", clip_norm);
+            params.grad_clip_norm = Some(clip_norm);
+        }
+        if let Ok(optimizer) = validate_string("optimizer") {
+            if !OPTIMIZERS.contains(&optimizer.as_str()) {
+                return Err(format!("optimizer should be one of {}, got {}", OPTIMIZERS.join(", "), optimizer).into())
+            }
+            params.optimizer = optimizer;
+        }
+        if let Ok(momentum) = validate_float("momentum") {
+            if momentum < 0.0 {
+                return Err(format!("momentum should be non-negative, got {}", momentum).into())
+            }
+            if params.optimizer != "sgd" {
+                return Err(format!("momentum is read by the sgd optimizer only, got optimizer {}", params.optimizer).into())
+            }
+            params.momentum = Some(momentum);
+        }
+        if let Ok(padded_batches) = validate_bool("padded_batches") {
+            if padded_batches && params.input_mask_prob > 0.0 {
+                return Err("input_mask_prob masks the stream chunks, it can't be used with padded_batches".into())
+            }
+            params.padded_batches = padded_batches;
+        }
+        // the sampled inputs are predictions of the forward lm from the previous inputs, the backward lm would read
+        // its own targets from them, and the char backoff targets (the next inputs) would not be the gold tokens
+        if params.scheduled_sampling_prob > 0.0 && params.backward_loss_weight > 0.0 {
+            return Err("scheduled_sampling_prob samples the inputs from the forward lm, it needs backward_loss_weight 0".into())
+        }
+        if params.scheduled_sampling_prob > 0.0 && params.char_backoff_weight > 0.0 {
+            return Err("char_backoff_weight needs the gold next inputs, it can't be used with scheduled_sampling_prob".into())
+        }
         Ok(params)
 
     }
@@ -282,7 +1068,7 @@ impl Conigure for ConfigElmo {
 
 pub mod files_handling {
 
-    use std::{io::{Lines, BufReader, self, BufRead}, fs::File, error::Error};
+    use std::{io::{Lines, BufReader, self, BufRead, Write}, fs::File, error::Error, collections::HashMap};
 
     fn read_file(file_path: &str) -> Result<Lines<BufReader<File>>, Box<dyn Error>> {
 
@@ -292,18 +1078,206 @@ pub mod files_handling {
         }
     }
 
-    pub fn load_sentences(file_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    // loads a sentence per line. With skip_blank_lines, empty and whitespace only lines (as between
+    // paragraphs) are skipped and their number is reported, they would otherwise become empty examples
+    pub fn load_sentences(file_path: &str, skip_blank_lines: bool) -> Result<Vec<String>, Box<dyn Error>> {
 
-        let lines = read_file(file_path)?;        
-        let sentences = lines.into_iter().map(|line| parse_line(line.unwrap())).collect::<Vec<String>>();
+        let lines = read_corpus_lines(file_path)?;
+        let mut sentences = lines.into_iter().map(parse_line).collect::<Vec<String>>();
+        if skip_blank_lines {
+            let n_lines = sentences.len();
+            sentences.retain(|s| !s.is_empty());
+            println!("skipped {} blank lines of {}", n_lines - sentences.len(), file_path);
+        }
         Ok(sentences)
     }
 
+    // the lines of a corpus file. zip and tar.gz (.tgz) archives are read as the concatenation
+    // of their member files, in sorted order of names. Needs the corpus_archives feature
+    fn read_corpus_lines(file_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+
+        let is_archive = [".zip", ".tar.gz", ".tgz"].iter().any(|ext| file_path.ends_with(ext));
+        if !is_archive {
+            return Ok(read_file(file_path)?.collect::<Result<Vec<String>, _>>()?)
+        }
+
+        #[cfg(feature = "corpus_archives")]
+        {
+            let members = match file_path.ends_with(".zip") {
+                true => archives::read_zip_members(file_path)?,
+                false => archives::read_tar_gz_members(file_path)?
+            };
+            Ok(members.into_iter().flat_map(|(_, content)| content.lines().map(|l| l.to_string()).collect::<Vec<String>>()).collect())
+        }
+
+        #[cfg(not(feature = "corpus_archives"))]
+        Err(format!("{} is an archive, reading archives needs the corpus_archives feature", file_path).into())
+    }
+
+    // loads char vectors from a word2vec-style text file, a char and its values on each line,
+    // with an optional "count dim" header line. All vectors should have the same dimension
+    pub fn load_char_vectors(file_path: &str) -> Result<HashMap<char, Vec<f64>>, Box<dyn Error>> {
+
+        let mut vectors = HashMap::new();
+        let mut dim = None;
+        for (i, line) in read_file(file_path)?.enumerate() {
+            let line = line?;
+            let fields = line.split(' ').filter(|f| !f.is_empty()).collect::<Vec<&str>>();
+            if fields.is_empty() || (i == 0 && fields.len() == 2 && fields.iter().all(|f| f.parse::<usize>().is_ok())) {
+                continue;
+            }
+
+            let mut chars = fields[0].chars();
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(format!("line {} of {} doesn't start with a single char", i, file_path).into())
+            };
+            let values = fields[1..].iter().map(|f| f.parse::<f64>()).collect::<Result<Vec<f64>, _>>()?;
+            if *dim.get_or_insert(values.len()) != values.len() {
+                return Err(format!("line {} of {} has {} values, expected {}", i, file_path, values.len(), dim.unwrap()).into());
+            }
+            vectors.insert(c, values);
+        }
+        Ok(vectors)
+    }
+
+    // the vocabulary files written to output_dir after preprocessing, inference needs the same id mappings
+    pub const TOKEN2INT_FILE: &str = "token2int.json";
+    pub const CHAR2INT_FILE: &str = "char2int.json";
+
+    fn save_map<K: serde::Serialize + Eq + std::hash::Hash>(path: &str, vocab: &HashMap<K, usize>) -> Result<(), Box<dyn Error>> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, vocab)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // a vocabulary map from a json file, its ids should be 0..n each used once
+    fn load_map<K: serde::de::DeserializeOwned + Eq + std::hash::Hash>(path: &str) -> Result<HashMap<K, usize>, Box<dyn Error>> {
+        let file = File::open(path).map_err(|e| format!("couldn't open the vocabulary file {}: {}", path, e))?;
+        let vocab: HashMap<K, usize> = serde_json::from_reader(io::BufReader::new(file)).map_err(|e| format!("malformed vocabulary file {}: {}", path, e))?;
+        let mut ids = vocab.values().cloned().collect::<Vec<usize>>();
+        ids.sort();
+        if ids.iter().enumerate().any(|(i, id)| i != *id) {
+            return Err(format!("malformed vocabulary file {}: ids should be 0..{} each used once", path, vocab.len()).into())
+        }
+        Ok(vocab)
+    }
+
+    // writes token2int as a json object of token to id
+    pub fn save_vocab(path: &str, token2int: &HashMap<String, usize>) -> Result<(), Box<dyn Error>> {
+        save_map(path, token2int)
+    }
+
+    // reads a token2int written by save_vocab, errors if the file is missing or malformed
+    pub fn load_vocab(path: &str) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+        load_map(path)
+    }
+
+    // as save_vocab, for char2int
+    pub fn save_char_vocab(path: &str, char2int: &HashMap<char, usize>) -> Result<(), Box<dyn Error>> {
+        save_map(path, char2int)
+    }
+
+    // as load_vocab, for char2int, a key of more than one char is malformed
+    pub fn load_char_vocab(path: &str) -> Result<HashMap<char, usize>, Box<dyn Error>> {
+        load_map(path)
+    }
+
+    // the lock file that marks an output_dir as used by a running training
+    pub const LOCK_FILE: &str = ".lock";
+
+    // holds the lock of an output_dir, the lock file is removed when it is dropped (at the clean exit of the run).
+    // A lock taken over with force belongs to the new run, the old run leaves the lock file when it ends
+    #[derive(Debug)]
+    pub struct OutputLock {
+        lock_file: String,
+        owner: String
+    }
+
+    impl Drop for OutputLock {
+        fn drop(&mut self) {
+            if std::fs::read_to_string(&self.lock_file).map(|owner| owner.trim() == self.owner).unwrap_or(false) {
+                let _ = std::fs::remove_file(&self.lock_file);
+            }
+        }
+    }
+
+    // the owner written to a lock file, distinct for every lock of the process
+    fn lock_owner() -> String {
+        static N_LOCKS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        format!("pid {} lock {}.{}", std::process::id(), nanos, N_LOCKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+
+    // locks output_dir for a training run, so two runs don't overwrite each other's checkpoints. Errors if the
+    // directory is already locked, unless force, then the existing lock (say of a crashed run) is taken over
+    pub fn lock_output_dir(output_dir: &str, force: bool) -> Result<OutputLock, Box<dyn Error>> {
+
+        let lock_file = format!("{}/{}", output_dir, LOCK_FILE);
+        let mut options = std::fs::OpenOptions::new();
+        match force {
+            true => options.write(true).create(true).truncate(true),
+            false => options.write(true).create_new(true)
+        };
+        let mut file = match options.open(&lock_file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let owner = std::fs::read_to_string(&lock_file).unwrap_or_default();
+                return Err(format!("{} is locked by another run ({}), set force to take it over, or remove {} if that run is gone", output_dir, owner.trim(), lock_file).into())
+            },
+            Err(e) => return Err(Box::new(e))
+        };
+        let owner = lock_owner();
+        writeln!(file, "{}", owner)?;
+        Ok(OutputLock { lock_file: lock_file, owner: owner })
+    }
+
     fn parse_line(line: String) -> String {
 
-        // line is a string of text, it is trimmed for trailing and ending spaces, lower cased.
-        let line_str = [&line.trim().to_lowercase()].map(|x| x.to_string()).to_vec().join(" ");
-        line_str
+        // line is a string of text, it is trimmed for trailing and ending spaces.
+        // lower casing is done later, separately for tokens and chars (lowercase_tokens, lowercase_chars)
+        line.trim().to_string()
+    }
+
+    #[cfg(feature = "corpus_archives")]
+    mod archives {
+
+        use std::{io::Read, fs::File, error::Error};
+        use flate2::read::GzDecoder;
+
+        // (name, content) of the files in a zip archive, sorted by name
+        pub(super) fn read_zip_members(file_path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+            let mut archive = zip::ZipArchive::new(File::open(file_path)?)?;
+            let mut members = Vec::new();
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i)?;
+                if member.is_file() {
+                    let mut content = String::new();
+                    member.read_to_string(&mut content)?;
+                    members.push((member.name().to_string(), content));
+                }
+            }
+            members.sort();
+            Ok(members)
+        }
+
+        // (name, content) of the files in a tar.gz archive, sorted by name
+        pub(super) fn read_tar_gz_members(file_path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+            let mut archive = tar::Archive::new(GzDecoder::new(File::open(file_path)?));
+            let mut members = Vec::new();
+            for member in archive.entries()? {
+                let mut member = member?;
+                if member.header().entry_type().is_file() {
+                    let name = member.path()?.to_string_lossy().to_string();
+                    let mut content = String::new();
+                    member.read_to_string(&mut content)?;
+                    members.push((name, content));
+                }
+            }
+            members.sort();
+            Ok(members)
+        }
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+
+pub mod embedding {
+
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::collections::HashMap;
+    use tch::{Device, Kind, Tensor};
+    use crate::{ELMo, ELMoText, JsonELMo, Preprocessor, DatasetBuilder};
+    use crate::archiving::ModelArchive;
+
+    // the alignment file is written next to the embeddings file, with this suffix
+    pub const ALIGNMENT_SUFFIX: &str = ".tokens";
+
+    // contextual embeddings of a long sequence from overlapping windows. xs is of shape (n, token_length), each
+    // window covers window tokens and starts overlap tokens before the end of the previous one, the last window
+    // is aligned to the end of the sequence. Tokens covered by several windows get the mean of their embeddings,
+    // so tokens near a window boundary also get the context on their other side. The output is (n, out_linear)
+    pub fn windowed_embed(model: &ELMo, xs: &Tensor, window: i64, overlap: i64) -> Tensor {
+
+        assert!(overlap < window, "the window overlap should be smaller than the window");
+        let n = xs.size()[0];
+        if n <= window {
+            return model.embed(&xs.unsqueeze(0)).squeeze_dim(0)
+        }
+
+        let mut starts = (0..n - window).step_by((window - overlap) as usize).collect::<Vec<i64>>();
+        starts.push(n - window);
+        let mut sums: Option<Tensor> = None;
+        let counts = Tensor::zeros([n, 1], (Kind::Float, model.device()));
+        for start in starts {
+            let embeddings = model.embed(&xs.narrow(0, start, window).unsqueeze(0)).squeeze_dim(0);
+            let sums = sums.get_or_insert_with(|| Tensor::zeros([n, embeddings.size()[1]], (embeddings.kind(), embeddings.device())));
+            let _ = sums.narrow(0, start, window).g_add_(&embeddings);
+            let _ = counts.narrow(0, start, window).g_add_scalar_(1.0);
+        }
+        sums.unwrap() / counts
+    }
+
+    // the contextual embeddings of a sentence, (n, token_length) => (n, out_linear), windowed if embedding_window
+    // is set (with embedding_window_overlap) and the sentence is longer than the window
+    pub fn contextual_embeddings(model: &ELMo, xs: &Tensor, params: &JsonELMo) -> Tensor {
+        match params.embedding_window {
+            Some(window) => windowed_embed(model, xs, window, params.embedding_window_overlap),
+            None => model.embed(&xs.unsqueeze(0)).squeeze_dim(0)
+        }
+    }
+
+    // contextual embeddings of raw sentences with a trained model, for downstream use without the trainer.
+    // A sentence goes through the preprocessing of training (normalization, char filtering, SOS and EOS
+    // if add_sentence_boundaries) and the char encoding of ELMoText, so unk tokens and chars, padding and
+    // the start and end of token chars are the same as in the training inputs
+    pub struct Embedder {
+        model: ELMo,
+        elmo_text: ELMoText,
+        params: JsonELMo
+    }
+
+    impl Embedder {
+
+        pub fn new(model: ELMo, token2int: HashMap<String, usize>, char2int: HashMap<char, usize>, params: &JsonELMo) -> Result<Self, Box<dyn Error>> {
+
+            // a long sentence is embedded whole (or windowed with embedding_window), not split to examples
+            let mut params = params.clone();
+            params.max_chars_per_example = None;
+            let elmo_text = ELMoText::try_new(Vec::new(), token2int, char2int, &params)?;
+            Ok(Self { model: model, elmo_text: elmo_text, params: params })
+        }
+
+        pub fn from_archive(archive: ModelArchive) -> Result<Self, Box<dyn Error>> {
+            Embedder::new(archive.model, archive.token2int, archive.char2int, &archive.params)
+        }
+
+        // the tokens of a sentence after preprocessing, one per row of embed_sentence
+        pub fn tokens(&self, sentence: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            let mut sentences = vec![sentence.to_string()];
+            Preprocessor::new().prepare_sentences(&mut sentences, &self.params);
+            let sentence = sentences.first().ok_or("nothing left of the sentence after preprocessing")?;
+            let tokens = self.elmo_text.tokenize(sentence);
+            match tokens.iter().all(|token| token.is_empty()) {
+                true => Err("nothing left of the sentence after preprocessing".into()),
+                false => Ok(tokens)
+            }
+        }
+
+        // the contextual embeddings of a sentence in eval mode, of shape (n, out_linear) on the cpu, n is
+        // the number of tokens (see tokens)
+        pub fn embed_sentence(&self, sentence: &str) -> Result<Tensor, Box<dyn Error>> {
+            let xs = self.elmo_text.encode_tokens(&self.tokens(sentence)?);
+            Ok(contextual_embeddings(&self.model, &xs, &self.params).to_kind(Kind::Float).to_device(Device::Cpu))
+        }
+
+        // exports the embeddings of raw sentences with export_embeddings, after the preprocessing of training.
+        // The sentence indices of the alignment file are of the preprocessed sentences, duplicates are dropped
+        // as in training. Returns the number of exported sentences
+        pub fn export(&self, sentences: Vec<String>, output_file: &str) -> Result<usize, Box<dyn Error>> {
+            let mut sentences = sentences;
+            Preprocessor::new().prepare_sentences(&mut sentences, &self.params);
+            let elmo_text = ELMoText::new(sentences, self.elmo_text.token2int().clone(), self.elmo_text.char2int().clone(), &self.params);
+            let indices = (0..elmo_text.get_len()).collect::<Vec<i64>>();
+            export_embeddings(&self.model, &elmo_text, &indices, output_file, &self.params)?;
+            Ok(indices.len())
+        }
+    }
+
+    // writes the contextual embeddings of the given sentences to output_file, one token per row
+    // (space separated values). A parallel alignment file maps each row, in order, to its sentence index,
+    // position and surface token. The surface form is kept also for tokens that are UNK in the vocabulary.
+    // The embeddings are windowed as in contextual_embeddings, with embedding_window of params
+    pub fn export_embeddings(model: &ELMo, elmo_text: &ELMoText, indices: &[i64], output_file: &str, params: &JsonELMo) -> Result<(), Box<dyn Error>> {
+
+        let mut embeddings_writer = BufWriter::new(File::create(output_file)?);
+        let mut alignment_writer = BufWriter::new(File::create(format!("{}{}", output_file, ALIGNMENT_SUFFIX))?);
+
+        for index in indices {
+
+            // encoding of the whole sentence (no shift), (n, max_len_token) => (n, out_linear)
+            let tokens = elmo_text.get_tokens(*index as usize)?;
+            let xs = elmo_text.encode_tokens(&tokens);
+            let embeddings: Tensor = contextual_embeddings(model, &xs, params).to_kind(Kind::Float).to_device(Device::Cpu);
+            let rows = Vec::<Vec<f32>>::try_from(&embeddings)?;
+            if rows.len() != tokens.len() {
+                return Err(format!("sentence {} has {} tokens but {} embedding rows", index, tokens.len(), rows.len()).into())
+            }
+
+            for (position, (token, row)) in tokens.iter().zip(rows).enumerate() {
+                let values = row.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ");
+                writeln!(embeddings_writer, "{}", values)?;
+                writeln!(alignment_writer, "{}\t{}\t{}", index, position, token)?;
+            }
+        }
+
+        embeddings_writer.flush()?;
+        alignment_writer.flush()?;
+        Ok(())
+    }
+
+    // the dataset of an hdf5 export that maps each sentence to the key of its embeddings dataset
+    #[cfg(feature = "hdf5_export")]
+    pub const SENTENCE_TO_INDEX: &str = "sentence_to_index";
+
+    // writes the contextual embeddings of the given sentences to an hdf5 file, in the layout of the allennlp
+    // elmo command: a (n_tokens, out_linear) float dataset per sentence keyed by its index, and a
+    // sentence_to_index json string dataset that maps the sentence text (tokens joined by spaces) to its key.
+    // The embeddings are windowed as in export_embeddings
+    #[cfg(feature = "hdf5_export")]
+    pub fn export_hdf5(model: &ELMo, elmo_text: &ELMoText, indices: &[i64], output_file: &str, params: &JsonELMo) -> Result<(), Box<dyn Error>> {
+
+        let file = hdf5::File::create(output_file)?;
+        let mut sentence_to_index = serde_json::Map::new();
+
+        for index in indices {
+
+            let tokens = elmo_text.get_tokens(*index as usize)?;
+            let xs = elmo_text.encode_tokens(&tokens);
+            let embeddings: Tensor = contextual_embeddings(model, &xs, params).to_kind(Kind::Float).to_device(Device::Cpu);
+            let shape = embeddings.size();
+            let values = Vec::<f32>::try_from(&embeddings.reshape([-1]))?;
+
+            let key = index.to_string();
+            let dataset = file.new_dataset::<f32>().shape((shape[0] as usize, shape[1] as usize)).create(key.as_str())?;
+            dataset.write_raw(values.as_slice())?;
+            sentence_to_index.insert(tokens.join(" "), serde_json::Value::String(key));
+        }
+
+        let sentence_to_index = serde_json::Value::Object(sentence_to_index).to_string().parse::<hdf5::types::VarLenUnicode>()?;
+        file.new_dataset::<hdf5::types::VarLenUnicode>().create(SENTENCE_TO_INDEX)?.write_scalar(&sentence_to_index)?;
+        Ok(())
+    }
+
+}
@@ -5,14 +5,42 @@ mod preprocessor;
 mod loader;
 mod model;
 mod trainer;
+mod embedder;
+mod archive;
 
 pub use config::ConfigElmo;
 pub use config::JsonELMo;
 pub use config::files_handling;
+pub use config::OPTIMIZERS;
 pub use loader::data_loading::DatasetBuilder;
 pub use loader::data_loading::ELMoText;
+pub use loader::data_loading::Direction;
+pub use loader::data_loading::ExampleStream;
+pub use loader::data_loading::StreamBatches;
 pub use loader::data_loading::Splitter;
+pub use loader::data_loading::SplitMode;
 pub use loader::data_loading::Loader;
+pub use loader::data_loading::PaddedLoader;
+pub use loader::data_loading::IGNORE_INDEX;
+pub use loader::data_loading::build_loaders;
+pub use loader::data_loading::dump_batches;
 pub use preprocessor::do_preprocess::Preprocessor;
+pub use preprocessor::do_preprocess::merge_vocabs;
+pub use preprocessor::do_preprocess::write_token_counts;
+pub use preprocessor::do_preprocess::{CoverageReport, coverage_report, write_coverage_report};
+pub use preprocessor::do_preprocess::token_length_percentile;
+pub use preprocessor::do_preprocess::{Script, char_script, script_partitions};
 pub use model::ELMo;
-pub use trainer::training;
\ No newline at end of file
+pub use model::LanguageModel;
+pub use model::ScalarMix;
+pub use model::{ShapeLog, SHAPE_LOG_CAPACITY};
+pub use model::embedding_dropout;
+pub use model::receptive_field;
+pub use model::scale_grad;
+pub use model::logits_entropy;
+pub use model::{SENTENCE_POOLINGS, pool_sentence};
+pub use model::chunked_seq;
+pub use trainer::training;
+pub use embedder::embedding;
+pub use embedder::embedding::Embedder;
+pub use archive::archiving;
@@ -4,11 +4,17 @@ pub mod data_loading {
 
     use std::collections::HashMap;
     use std::error::Error;
+    use std::fs::File;
+    use serde_json::json;
     use tch::Device;
     use tch::IndexOp;
     use tch::Kind;
     use tch::Tensor;
     use crate::config::JsonELMo;
+    use crate::preprocessor::do_preprocess::{normalize_token, normalize_delimiters, byte_char, char_byte, CharFilter, boundary_tokens};
+
+    // the label of the padded positions of padded batches, ignored by the cross entropy. No token has this id
+    pub const IGNORE_INDEX: i64 = -100;
 
     // a loader similar to Iter2 of tch, but it knows to receive a vector of tensors and not a tensor of tensors
     pub struct Loader {
@@ -16,7 +22,18 @@ pub mod data_loading {
         ys: Vec<Tensor>,
         device: Device,
         pub batch_size: i64,
-        pub seq_length: i64
+        pub seq_length: i64,
+        pub min_chunk_tokens: Option<i64>,
+        pub input_mask: Option<(f64, Vec<i64>)>,
+        pub curriculum_epochs: i64,
+        pub eval_sample: Option<(usize, u64)>,
+        pub pin_memory: bool,
+        pub pad_id: i64,
+        pub char_pad_id: i64,
+        pub padded_batches: bool,
+        pub token_encodings: Option<Tensor>,
+        pub unk_id: Option<i64>,
+        eval_round: u64
     }
 
     impl Loader {
@@ -34,10 +51,57 @@ pub mod data_loading {
                 ys: ys,
                 device: device,
                 batch_size: batch_size,
-                seq_length: seq_length
+                seq_length: seq_length,
+                min_chunk_tokens: None,
+                input_mask: None,
+                curriculum_epochs: 0,
+                eval_sample: None,
+                pin_memory: false,
+                pad_id: IGNORE_INDEX,
+                char_pad_id: 0,
+                padded_batches: false,
+                token_encodings: None,
+                unk_id: None,
+                eval_round: 0
             }
         }
 
+        // number of examples (sentences) in the loader
+        pub fn len(&self) -> usize {
+            self.xs.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.xs.is_empty()
+        }
+
+        // the (xs, ys) examples of the loader, whole sentences in its current order
+        pub fn examples(&self) -> impl Iterator<Item = (&Tensor, &Tensor)> {
+            self.xs.iter().zip(&self.ys)
+        }
+
+        // number of tokens over all examples, the stream is cut from them
+        pub fn n_tokens(&self) -> i64 {
+            self.ys.iter().map(|y| y.size()[0]).sum()
+        }
+
+        // number of batches the stream yields, without building it. Follows StreamLoader: full batches,
+        // then a batch of the remaining whole chunks, then the final partial chunk if it's kept
+        pub fn n_batches(&self) -> usize {
+            let n_tokens = self.n_tokens();
+            let slice = self.batch_size * self.seq_length;
+            let mut n_batches = n_tokens / slice;
+            let remaining = n_tokens % slice;
+            if remaining >= self.seq_length {
+                n_batches += 1;
+            }
+            let partial = remaining % self.seq_length;
+            if matches!(self.min_chunk_tokens, Some(min_chunk_tokens) if partial > 0 && partial >= min_chunk_tokens) {
+                n_batches += 1;
+            }
+            n_batches as usize
+        }
+
         pub(in crate) fn shuffle(&mut self) -> &mut Loader {
 
             // shuffles xs and vs tensors together with random permutation and sends self back
@@ -52,9 +116,77 @@ pub mod data_loading {
 
         }
 
+        // a reproducible shuffle, fisher yates over a xorshift generator seeded by seed. It doesn't touch the
+        // global torch generator, so the training randomness is the same with or without it
+        pub fn shuffle_seeded(&mut self, seed: u64) -> &mut Loader {
+
+            let mut state = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+            let mut order = (0..self.xs.len()).collect::<Vec<usize>>();
+            for i in (1..order.len()).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                order.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+
+            self.xs = order.iter().map(|i| self.xs[*i].shallow_clone()).collect::<Vec<Tensor>>();
+            self.ys = order.iter().map(|i| self.ys[*i].shallow_clone()).collect::<Vec<Tensor>>();
+            self
+
+        }
+
+        // the batches of an evaluation. With eval_sample (max batches, seed) only the first max batches are
+        // evaluated, after a seeded shuffle that changes in every evaluation (seed + round), so the sample
+        // rotates over the set. Otherwise all of the batches are evaluated
+        pub fn eval_batches(&mut self) -> Box<dyn Iterator<Item = (Tensor, Tensor)>> {
+            match self.eval_sample {
+                Some((max_batches, seed)) => {
+                    let round = self.eval_round;
+                    self.eval_round += 1;
+                    Box::new(self.shuffle_seeded(seed.wrapping_add(round)).batches().take(max_batches))
+                },
+                None => Box::new(self.shuffle().batches())
+            }
+        }
+
+        // the batches of a pass in the current order, the stream cut to chunks (see to_stream), or with
+        // padded_batches whole sentences padded to the longest of the batch (see to_padded). The labels of the
+        // padded positions are IGNORE_INDEX, so the loss, the accuracy and the perplexity leave them out
+        pub fn batches(&mut self) -> Box<dyn Iterator<Item = (Tensor, Tensor)>> {
+            match self.padded_batches {
+                true => Box::new(self.to_padded().map(|(xs, ys, mask)| (xs, ys.masked_fill(&mask.logical_not(), IGNORE_INDEX)))),
+                false => Box::new(self.to_stream())
+            }
+        }
+
+        // orders the examples from the shortest to the longest sentence, ties are in random order
+        pub fn sort_by_length(&mut self) -> &mut Loader {
+
+            self.shuffle();
+            let mut order = (0..self.xs.len()).collect::<Vec<usize>>();
+            order.sort_by_key(|i| self.ys[*i].size()[0]);
+
+            self.xs = order.iter().map(|i| self.xs[*i].shallow_clone()).collect::<Vec<Tensor>>();
+            self.ys = order.iter().map(|i| self.ys[*i].shallow_clone()).collect::<Vec<Tensor>>();
+            self
+
+        }
+
+        // the order of the examples in a training epoch: by length in the first curriculum_epochs, then shuffled
+        pub fn epoch_order(&mut self, epoch: i64) -> &mut Loader {
+            match epoch < self.curriculum_epochs {
+                true => self.sort_by_length(),
+                false => self.shuffle()
+            }
+        }
+
         pub(in crate) fn to_stream(&mut self) -> StreamLoader {
 
-            // converts vectors of tensors (xs, ys) to initalized StreamLoader that receives pure tensors
+            // converts vectors of tensors (xs, ys) to initalized StreamLoader that receives pure tensors.
+            // The stream is cut into consecutive, non overlapping chunks of seq_length tokens. There is no
+            // overlap option: the context of a chunk's first token is lost, since the lstm state isn't carried
+            // between chunks. The final chunk is usually shorter than seq_length, it is dropped unless
+            // min_chunk_tokens is set and the chunk has at least that many tokens.
 
             let xs = Tensor::concat(&self.xs, 0); // of shape (N_tokens, max_token_length)
             let ys = Tensor::concat(&self.ys, 0); // of shape (N_tokens)
@@ -69,6 +201,9 @@ pub mod data_loading {
                 device: self.device, 
                 batch_size: self.batch_size,
                 seq_length: self.seq_length,
+                min_chunk_tokens: self.min_chunk_tokens,
+                input_mask: self.input_mask.as_ref().map(|(p, mask_encoding)| (*p, Tensor::from_slice(mask_encoding))),
+                pin_memory: self.pin_memory,
                 max_token_length: dims_xs[1],
                 start_index: 0, 
                 end_index: dims_xs[0]
@@ -77,23 +212,119 @@ pub mod data_loading {
 
     }
 
+    // batches whole sentences instead of cutting the token stream: batch_size examples per batch (in the current
+    // order of the loader), padded to the longest sentence of the batch, the inputs with char_pad_id (a padded
+    // position reads as an empty token) and the labels with pad_id. It yields (xs, ys, mask) of
+    // shapes (batch_size, max_seq, max_len_token), (batch_size, max_seq) and (batch_size, max_seq), the mask is
+    // true on real tokens, so a loss can ignore the padded positions. The last batch may be smaller
+    pub struct PaddedLoader {
+        xs: Vec<Tensor>,
+        ys: Vec<Tensor>,
+        device: Device,
+        batch_size: usize,
+        pad_id: i64,
+        char_pad_id: i64,
+        start_index: usize
+    }
+
+    impl Loader {
+
+        pub fn to_padded(&self) -> PaddedLoader {
+            PaddedLoader {
+                xs: self.xs.iter().map(|x| x.shallow_clone()).collect(),
+                ys: self.ys.iter().map(|y| y.shallow_clone()).collect(),
+                device: self.device,
+                batch_size: self.batch_size as usize,
+                pad_id: self.pad_id,
+                char_pad_id: self.char_pad_id,
+                start_index: 0
+            }
+        }
+    }
+
+    impl Iterator for PaddedLoader {
+        type Item = (Tensor, Tensor, Tensor);
+
+        fn next(&mut self) -> Option<Self::Item> {
+
+            if self.start_index >= self.xs.len() {
+                return None
+            }
+            let end_index = (self.start_index + self.batch_size).min(self.xs.len());
+            let xs = &self.xs[self.start_index..end_index];
+            let ys = &self.ys[self.start_index..end_index];
+            self.start_index = end_index;
+
+            // each x is of shape (sentence_length, max_len_token), each y of shape (sentence_length)
+            let max_seq = ys.iter().map(|y| y.size()[0]).max().unwrap_or(0);
+            let max_len_token = xs[0].size()[1];
+            let n = xs.len() as i64;
+            let xs_batch = Tensor::full([n, max_seq, max_len_token], self.char_pad_id, (Kind::Int64, Device::Cpu));
+            let ys_batch = Tensor::full([n, max_seq], self.pad_id, (Kind::Int64, Device::Cpu));
+            let mask = Tensor::zeros([n, max_seq], (Kind::Bool, Device::Cpu));
+            for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+                let length = y.size()[0];
+                xs_batch.get(i as i64).narrow(0, 0, length).copy_(&x.to_kind(Kind::Int64).to_device(Device::Cpu));
+                ys_batch.get(i as i64).narrow(0, 0, length).copy_(&y.to_kind(Kind::Int64).to_device(Device::Cpu));
+                let _ = mask.get(i as i64).narrow(0, 0, length).fill_(1);
+            }
+
+            Some((xs_batch.to_device(self.device), ys_batch.to_device(self.device), mask.to_device(self.device)))
+        }
+    }
+
     pub(in crate) struct StreamLoader {
         xs: Tensor,
         ys: Tensor,
         device: Device,
         batch_size: i64,
         seq_length: i64,
+        min_chunk_tokens: Option<i64>,
+        input_mask: Option<(f64, Tensor)>,
+        pin_memory: bool,
         max_token_length: i64,
         start_index: i64,
         end_index: i64
     }
 
+    impl StreamLoader {
+
+        // moves a batch slice to the loader device as int64. With pin_memory and a cuda device, the host copy
+        // is pinned first so the transfer to the GPU is asynchronous, on CPU it is a plain copy
+        fn to_target(&self, batch: Tensor) -> Tensor {
+            match self.pin_memory && self.device.is_cuda() {
+                true => batch.to_kind(Kind::Int64).pin_memory(self.device).to_device_(self.device, Kind::Int64, true, false),
+                false => batch.to_kind(Kind::Int64).to_device(self.device)
+            }
+        }
+
+        // replaces each input token encoding with the mask encoding with probability p, labels are kept.
+        // xs is of shape (batch_size, seq_length, max_token_length)
+        fn mask_inputs(&self, xs: Tensor) -> Tensor {
+            match &self.input_mask {
+                Some((p, mask_encoding)) if *p > 0.0 => {
+                    let dims = xs.size();
+                    let masked = Tensor::empty([dims[0], dims[1], 1], (Kind::Float, xs.device())).bernoulli_float_(*p).to_kind(Kind::Bool);
+                    mask_encoding.to_device(xs.device()).expand_as(&xs).where_self(&masked, &xs)
+                },
+                _ => xs
+            }
+        }
+    }
+
     // implementation of Iterator to a StreamLoader.
     // The iterator generates pairs of (batch_size, seq_length, ...) tensor inside a training iteration. 
     impl Iterator for StreamLoader {
         type Item = (Tensor, Tensor);
 
         fn next(&mut self) -> Option<Self::Item> {
+            self.next_batch().map(|(xs, ys)| (self.mask_inputs(xs), ys))
+        }
+    }
+
+    impl StreamLoader {
+
+        fn next_batch(&mut self) -> Option<(Tensor, Tensor)> {
             
             // stop condition -> ends loop over examples
             if self.start_index >= self.end_index {
@@ -102,35 +333,29 @@ pub mod data_loading {
 
             // slice is the size of examples loaded each next()
             let slice = self.batch_size * self.seq_length;
-            let mut end_batch = self.start_index + slice;
+            let remaining = self.end_index - self.start_index;
 
-            // that handles last smaller batch
-            if end_batch > self.end_index {
+            if remaining >= slice {
 
-                // in this case, skipping the less-than seq_length words
+                // in this case, get the batch and reshape to (batch_size, seq_length, ... )
 
-                end_batch = self.end_index; 
-                let mut xs_batch = self.xs.i(self.start_index..end_batch).to_kind(Kind::Int64).to_device(self.device);
-                let mut ys_batch = self.ys.i(self.start_index..end_batch).to_kind(Kind::Int64).to_device(self.device);
+                let end_batch = self.start_index + slice;
+                let xs_batch = self.to_target(self.xs.i(self.start_index..end_batch).reshape(&[self.batch_size, self.seq_length, -1])); // (batch_size, seq_length, max_token_length)
+                let ys_batch = self.to_target(self.ys.i(self.start_index..end_batch).reshape(&[self.batch_size, self.seq_length])); // (batch_size, seq_length)    
 
-                // xs shape is (N, max_token_length). We want to reshape to roughly have dim1 = seq_length
-                let dims = Vec::<i64>::try_from(xs_batch.internal_shape_as_tensor()).unwrap();
-                if dims[1] < self.seq_length {
+                // promote starting index for foloowing next()
+                self.start_index = end_batch;
 
-                    return None
+                Some((xs_batch, ys_batch))
 
-                } else {
+            } else if remaining >= self.seq_length {
 
-                    // find the biggest number that devides with seq length up to N
-                    for i in (dims[1]-self.seq_length..dims[1]).rev() {
-                        if i % self.seq_length == 0 {
+                // that handles last smaller batch, take the biggest number of rows that devides with seq_length,
+                // the rest of the tokens are left for the final partial chunk
 
-                            xs_batch = xs_batch.i(..i).reshape([-1, self.seq_length, self.max_token_length]);
-                            ys_batch = ys_batch.i(..i).reshape([-1, self.seq_length]);
-                            break;
-                        }
-                    }
-                }
+                let end_batch = self.start_index + remaining - remaining % self.seq_length;
+                let xs_batch = self.to_target(self.xs.i(self.start_index..end_batch).reshape([-1, self.seq_length, self.max_token_length]));
+                let ys_batch = self.to_target(self.ys.i(self.start_index..end_batch).reshape([-1, self.seq_length]));
 
                 // promote starting index for following next()
                 self.start_index = end_batch;
@@ -139,20 +364,25 @@ pub mod data_loading {
 
             } else {
 
-                // in this case, get the batch and reshape to (batch_size, seq_length, ... )
+                // the final partial chunk, shorter than seq_length. It is kept as a (1, remaining, ...) batch
+                // only if it has at least min_chunk_tokens tokens, tiny chunks carry almost no context
 
-                let xs_batch = self.xs.i(self.start_index..end_batch).reshape(&[self.batch_size, self.seq_length, -1]).to_kind(Kind::Int64).to_device(self.device); // (batch_size, seq_length, max_token_length)
-                let ys_batch = self.ys.i(self.start_index..end_batch).reshape(&[self.batch_size, self.seq_length]).to_kind(Kind::Int64).to_device(self.device); // (batch_size, seq_length)    
+                let start_batch = self.start_index;
+                self.start_index = self.end_index;
 
-                // promote starting index for foloowing next()
-                self.start_index = end_batch;
-
-                Some((xs_batch, ys_batch))
+                match self.min_chunk_tokens {
+                    Some(min_chunk_tokens) if remaining >= min_chunk_tokens => {
+                        let xs_batch = self.to_target(self.xs.i(start_batch..self.end_index).reshape([1, remaining, self.max_token_length]));
+                        let ys_batch = self.to_target(self.ys.i(start_batch..self.end_index).reshape([1, remaining]));
+                        Some((xs_batch, ys_batch))
+                    },
+                    _ => None
+                }
             }
 
             // xs_batch should be (batch_size, seq_length, max_token_length)
             // ys_batch should be (batch_size, seq_length)
-            // last iteration might be smaller
+            // last iterations might be smaller
 
 
         }
@@ -170,10 +400,18 @@ pub mod data_loading {
         token2int: HashMap<String, usize>,
         char2int: HashMap<char, usize>,
         max_len_token: usize,
+        char_padding_side: String,
         char_start: char,
         char_end: char,
         char_unk: char,
-        str_unk: String
+        str_unk: String,
+        collapse_whitespace: bool,
+        delimiter: String,
+        lowercase_tokens: bool,
+        lowercase_chars: bool,
+        boundary_tokens: &'static [&'static str],
+        byte_fallback: bool,
+        char_filter: CharFilter
     }
 
     impl ELMoText {
@@ -181,17 +419,201 @@ pub mod data_loading {
             token2int: HashMap<String, usize>, 
             char2int: HashMap<char, usize>, 
             params: &JsonELMo) -> Self {
+
+            let sentences = match params.skip_oov_sentences {
+                true => ELMoText::drop_oov_sentences(sentences, &token2int, params),
+                false => sentences
+            };
             
             Self {
                 sentences: sentences,
                 token2int: token2int,
                 char2int: char2int,
                 max_len_token: params.max_len_token as usize,
+                char_padding_side: params.char_padding_side.clone(),
                 char_start: params.char_start,
                 char_end: params.char_end,
                 char_unk: params.char_unk,
-                str_unk: params.str_unk.to_string()
+                str_unk: params.str_unk.to_string(),
+                collapse_whitespace: params.collapse_whitespace,
+                delimiter: params.delimiter.clone(),
+                lowercase_tokens: params.lowercase_tokens,
+                lowercase_chars: params.lowercase_chars,
+                boundary_tokens: boundary_tokens(params.add_sentence_boundaries),
+                byte_fallback: params.byte_fallback,
+                char_filter: CharFilter::new(params)
+            }
+        }
+
+        // as new, but errors if check_boundary_chars and char_start or char_end are missing from char2int.
+        // Without them every token would start and end with the unk char. new doesn't check the vocabulary
+        pub fn try_new(sentences: Vec<String>, 
+            token2int: HashMap<String, usize>, 
+            char2int: HashMap<char, usize>, 
+            params: &JsonELMo) -> Result<Self, Box<dyn Error>> {
+
+            if params.check_boundary_chars {
+                for (name, c) in [("char_start", params.char_start), ("char_end", params.char_end)] {
+                    if !char2int.contains_key(&c) {
+                        return Err(format!("{} {:?} is not in the char vocabulary, it wasn't reserved by the preprocessor", name, c).into())
+                    }
+                }
+            }
+            Ok(ELMoText::new(sentences, token2int, char2int, params))
+        }
+
+        // drops sentences whose tokens are all out of the token vocabulary, their labels would all be UNK.
+        // The sentence boundaries don't count as content, sentences without any other token are kept
+        fn drop_oov_sentences(sentences: Vec<String>, token2int: &HashMap<String, usize>, params: &JsonELMo) -> Vec<String> {
+            let n_sentences = sentences.len();
+            let char_filter = CharFilter::new(params);
+            let reserved = boundary_tokens(params.add_sentence_boundaries);
+            let kept = sentences.into_iter().filter(|sentence| {
+                let sentence = normalize_delimiters(&char_filter.apply(sentence), &params.delimiter);
+                let mut content = sentence.split_whitespace().filter(|t| !reserved.contains(t)).peekable();
+                content.peek().is_none() || content.any(|t| token2int.contains_key(&normalize_token(t, params.lowercase_tokens, reserved)))
+            }).collect::<Vec<String>>();
+            println!("dropped {} of {} sentences with only out of vocabulary tokens", n_sentences - kept.len(), n_sentences);
+            kept
+        }
+
+        // the reserved encoding of a masked input token. All of its positions are the unk char, real tokens
+        // always start with the start char, so it can't be confused with any of them
+        pub fn mask_encoding(&self) -> Vec<i64> {
+            let unk_char_id = *self.char2int.get(&self.char_unk).expect("didn't find unk char symbol") as i64;
+            vec![unk_char_id; self.max_len_token]
+        }
+
+        // the char the tokens are padded with to max_len_token, it is also the input of the padded positions of
+        // padded batches
+        pub fn char_pad_id(&self) -> i64 {
+            *self.char2int.get(&' ').expect("didn't find pad symbol") as i64
+        }
+
+        // the label of the tokens out of the vocabulary, the id of str_unk
+        pub fn unk_id(&self) -> i64 {
+            *self.token2int.get(&self.str_unk).expect("didn't find unk token symbol") as i64
+        }
+
+        // the char encodings of the whole token vocabulary, row i is the encoding of the token with id i,
+        // of shape (token_vocab_size, max_len_token). Ids missing from token2int get the unk token encoding
+        pub fn token_encodings(&self) -> Tensor {
+            let n_tokens = self.token2int.values().max().map(|i| i + 1).unwrap_or(0);
+            let mut tokens = vec![self.str_unk.to_string(); n_tokens];
+            for (token, i) in &self.token2int {
+                tokens[*i] = token.to_string();
             }
+            self.encode_tokens(&tokens)
+        }
+
+        // the token vocabulary the labels are encoded with
+        pub fn token2int(&self) -> &HashMap<String, usize> {
+            &self.token2int
+        }
+
+        // the char vocabulary the tokens are encoded with
+        pub fn char2int(&self) -> &HashMap<char, usize> {
+            &self.char2int
+        }
+
+        // the surface tokens of a sentence, as they are split for encoding. When collapse_whitespace is set
+        // (as in Preprocessor), repeated whitespace doesn't create empty tokens
+        pub fn get_tokens(&self, index: usize) -> Result<Vec<String>, Box<dyn Error>> {
+            let example = self.sentences.get(index).ok_or("example index not found in examples indices")?;
+            Ok(self.tokenize(example))
+        }
+
+        // the split of get_tokens, for a sentence out of the examples. Tokens are split on the chars of delimiter
+        // (and spaces). Leading and trailing delimiters never make empty tokens, repeated delimiters inside the
+        // sentence are collapsed unless collapse_whitespace is off
+        pub fn tokenize(&self, sentence: &str) -> Vec<String> {
+            // filtered chars are dropped also from text that didn't go through the preprocessor
+            let sentence = normalize_delimiters(&self.char_filter.apply(sentence), &self.delimiter);
+            match self.collapse_whitespace {
+                true => sentence.split_whitespace().map(|x| x.to_owned()).collect::<Vec<String>>(),
+                false => sentence.trim_matches(' ').split(' ').map(|x| x.trim().to_owned()).collect::<Vec<String>>()
+            }
+        }
+
+        // move tokens from strings of chars to int encodings of fixed maximal length,
+        // the output is of shape (n, max_len_token), n is the number of tokens.
+        pub fn encode_tokens(&self, tokens: &[String]) -> Tensor {
+            let inputs = tokens.iter().map(|token| Tensor::from_slice(&self.map_chars_to_ints(token))).collect::<Vec<Tensor>>();
+            Tensor::concat(&inputs, 0).reshape([-1, self.max_len_token as i64])
+        }
+
+        // back from char ids to the token string, without start, end and pad chars. Byte chars are decoded
+        // back to the chars of their utf-8 bytes
+        pub fn decode_chars(&self, char_ids: &[i64]) -> String {
+            let int2char = self.char2int.iter().map(|(c, i)| (*i as i64, *c)).collect::<HashMap<i64, char>>();
+            let chars = char_ids.iter()
+            .filter_map(|i| int2char.get(i))
+            .filter(|c| **c != self.char_start && **c != self.char_end && **c != ' ')
+            .collect::<Vec<&char>>();
+
+            let mut decoded = String::new();
+            let mut bytes = Vec::new();
+            for c in chars {
+                match char_byte(*c) {
+                    Some(byte) => bytes.push(byte),
+                    None => {
+                        decoded.push_str(&String::from_utf8_lossy(&bytes));
+                        bytes.clear();
+                        decoded.push(*c);
+                    }
+                }
+            }
+            decoded.push_str(&String::from_utf8_lossy(&bytes));
+            decoded
+        }
+
+        // back from a label to its token string
+        pub fn decode_label(&self, label: i64) -> String {
+            self.token2int.iter().find(|(_, i)| **i as i64 == label).map(|(t, _)| t.to_string()).unwrap_or(self.str_unk.to_string())
+        }
+
+        fn map_chars_to_ints(&self, token: &str) -> Vec<i64> {
+
+            // map a token to a series of char ids, wrapped with start and end chars
+            // replace uknown chars with unk char symbol
+            let mut token_vec = normalize_token(token, self.lowercase_chars, self.boundary_tokens).chars().filter(|c| self.char_filter.keep(*c)).collect::<Vec<char>>();
+            token_vec.insert(0, self.char_start);
+            token_vec.push(self.char_end);
+
+            let unk_char_id = self.char2int.get(&self.char_unk).expect("didn't find unk char symbol");
+            let mut char_ids = token_vec.iter().flat_map(|c| {
+                match (self.char2int.get(c), self.byte_fallback) {
+                    (Some(char_id), _) => vec![*char_id as i64],
+                    // with byte_fallback an unknown char is its utf-8 bytes, each a byte char
+                    (None, true) => {
+                        let mut buffer = [0u8; 4];
+                        c.encode_utf8(&mut buffer).bytes().map(|byte| *self.char2int.get(&byte_char(byte)).unwrap_or(unk_char_id) as i64).collect()
+                    },
+                    // otherwise unknown chars are replaced with unk char symbol
+                    (None, false) => vec![*unk_char_id as i64]
+                }
+            }).collect::<Vec<i64>>();
+            
+            // obey to max_len_token with pad or truncate
+            // pad done with ' ', after the chars (post), before them (pre) or around them (both, extra pad after)
+            let token_len = char_ids.len();
+            let pad = self.char_pad_id();
+            if self.max_len_token <= token_len {
+                char_ids.truncate(self.max_len_token);
+                return char_ids
+            }
+
+            let n_pad = self.max_len_token - token_len;
+            let n_pre = match self.char_padding_side.as_str() {
+                "pre" => n_pad,
+                "both" => n_pad / 2,
+                _ => 0
+            };
+            let mut padded = vec![pad; n_pre];
+            padded.extend(char_ids);
+            padded.resize(self.max_len_token, pad);
+            padded
+
         }
     }
 
@@ -204,6 +626,20 @@ pub mod data_loading {
         }
 
         fn get_example(&self, index: usize) -> Result<(Tensor, Tensor), Self::Error> {
+            self.get_directional_example(index, Direction::Forward)
+        }
+    }
+
+    // the direction of the language model an example is built for
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Direction {
+        Forward,
+        Backward
+    }
+
+    impl ELMoText {
+
+        pub fn get_directional_example(&self, index: usize, direction: Direction) -> Result<(Tensor, Tensor), Box<dyn Error>> {
             
             // Tensor for chars: each element in the tensor is a tensor of char encodings.
             // the output is of shape (n, max_len_token), n is the length of the sentence.
@@ -211,63 +647,36 @@ pub mod data_loading {
             // Tensor for labels: each element in the tensor is a label of a token in the sentence.
             // the output is of shape (n, 1), n is the length of the sentence.
             
-            // n will be the same for an example and its matching labels. The example will miss 
-            // its last element, labels will miss the first label.
-
-            let mut inputs: Vec<Tensor> = Vec::new();
-            let example = self.sentences.get(index).ok_or("example index not found in examples indices")?;            
-
-            let map_chars_to_ints = | token: &Vec<char>| -> Vec<i64> {
-
-                // map a token to a series of char ids
-                // replace uknown chars with unk char symbol
-                let unk_char_id = self.char2int.get(&self.char_unk).expect("didn't find unk char symbol");
-                let mut char_ids = token.into_iter().map(|c| {
-                    let char_id = self.char2int.get(c).unwrap_or(unk_char_id);
-                    *char_id as i64
-                    // replacing unknown chars with unk char symbol, not handling seq bytes
-                }).collect::<Vec<i64>>();
-                
-                // obey to max_len_token with pad or truncate
-                // pad done with ' '
-                let token_len = char_ids.len();
-                let pad = *self.char2int.get(&' ').expect("didn't find pad symbol") as i64;
-                if self.max_len_token <= token_len {
-                    char_ids.truncate(self.max_len_token);
-                } else {
-                    for _ in token_len..self.max_len_token {
-                        char_ids.push(pad);
-                    }
-                }
-                char_ids
+            // n will be the same for an example and its matching labels. For the forward direction the example
+            // will miss its last element, labels will miss the first label. The backward direction is the opposite.
 
-            };
-
-            let tokens = example.clone().split(" ").map(|x| x.trim().to_owned()).collect::<Vec<String>>();
+            let tokens = self.get_tokens(index)?;
             let unk_id = self.token2int.get(&self.str_unk).expect("didn't find unk token symbol");
             let mut labels = (&tokens).iter().map(|t| {
-                let label = self.token2int.get(t).cloned().unwrap_or(*unk_id);
+                let label = self.token2int.get(&normalize_token(t, self.lowercase_tokens, self.boundary_tokens)).cloned().unwrap_or(*unk_id);
                 Tensor::from_slice(&[label as i64])
             } ).collect::<Vec<Tensor>>();
 
             // move each token from string of chars to int encoding of fixed maximal length
-            for token in &tokens {
-                let mut token_vec = token.split("").filter(|x| x.len()>0).map(|x| x.chars().nth(0).unwrap()).collect::<Vec<char>>();
-                token_vec.insert(0, self.char_start);
-                token_vec.push(self.char_end);
-
-                let char_ids = map_chars_to_ints(&token_vec);
-                let char_tensor = Tensor::from_slice(&char_ids);
-                inputs.push(char_tensor);
-            }
+            let mut inputs = tokens.iter().map(|token| Tensor::from_slice(&self.map_chars_to_ints(token))).collect::<Vec<Tensor>>();
 
             // now, inputs is a vec of tensors, each element is a tensor with a series of ints that represent a token.
-            // to keep in mind that we will predict the 1 token from the 0 token, 2 from 1, ... n-1 from n-2.
-            // so we don't use the last token as an input, and don't use the first token as a label
+            // to keep in mind that forward we will predict the 1 token from the 0 token, 2 from 1, ... n-1 from n-2.
+            // so we don't use the last token as an input, and don't use the first token as a label.
+            // backward we predict the n-2 token from the n-1 token, ... 0 from 1, so we don't use the first token
+            // as an input and don't use the last token as a label.
 
             let n = inputs.len();
-            let _ = labels.remove(0);
-            let _ = inputs.remove(n-1);
+            match direction {
+                Direction::Forward => {
+                    let _ = labels.remove(0);
+                    let _ = inputs.remove(n-1);
+                },
+                Direction::Backward => {
+                    let _ = labels.remove(n-1);
+                    let _ = inputs.remove(0);
+                }
+            }
             assert_eq!(inputs.len(), labels.len());
 
             // move to tensors
@@ -283,22 +692,261 @@ pub mod data_loading {
             Ok(output)
 
         }
+
+        // the positions of an example (xs, ys) of the given tokens where the input is the encoding of its label,
+        // the label leaks to the input and predicting it is trivial. A repeated token (tokens i and i + 1 are the
+        // same) is not a leak, unk labels are skipped as their token is unknown
+        pub fn leaking_positions(&self, tokens: &[String], xs: &Tensor, ys: &Tensor) -> Vec<i64> {
+            let unk_id = *self.token2int.get(&self.str_unk).expect("didn't find unk token symbol") as i64;
+            let labels = Vec::<i64>::try_from(ys.reshape([-1])).expect("labels are int64");
+            let same = |a: Option<&String>, b: Option<&String>| match (a, b) {
+                (Some(a), Some(b)) => normalize_token(a, self.lowercase_tokens, self.boundary_tokens) == normalize_token(b, self.lowercase_tokens, self.boundary_tokens),
+                _ => false
+            };
+            labels.iter().enumerate()
+            .filter(|(i, label)| **label != unk_id && !same(tokens.get(*i), tokens.get(i + 1)))
+            .filter(|(i, label)| xs.get(*i as i64).equal(&self.encode_tokens(&[self.decode_label(**label)]).get(0)))
+            .map(|(i, _)| i as i64)
+            .collect()
+        }
+
+        // a self-check of the shift of get_example, on the first n_samples examples in both directions.
+        // Errors on the first example whose labels leak to its inputs
+        pub fn check_label_leakage(&self, n_samples: usize) -> Result<(), Box<dyn Error>> {
+            for index in 0..n_samples.min(self.sentences.len()) {
+                let tokens = self.get_tokens(index)?;
+                for direction in [Direction::Forward, Direction::Backward] {
+                    let (xs, ys) = self.get_directional_example(index, direction)?;
+                    let positions = self.leaking_positions(&tokens, &xs, &ys);
+                    if !positions.is_empty() {
+                        return Err(format!("label leakage in example {} ({:?}), the inputs at positions {:?} are their labels", index, direction, positions).into())
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // both the forward and the backward (input, label) pairs of an example, for bidirectional training
+        pub fn get_bidirectional_example(&self, index: usize) -> Result<((Tensor, Tensor), (Tensor, Tensor)), Box<dyn Error>> {
+            let forward = self.get_directional_example(index, Direction::Forward)?;
+            let backward = self.get_directional_example(index, Direction::Backward)?;
+            Ok((forward, backward))
+        }
+
+        // a lazy iterator over the examples of the given indices, in their order. Each example is encoded
+        // only when it is reached, for on the fly batching of splits too large to encode at once
+        pub fn stream_examples<'a, I: Iterator<Item = i64> + 'a>(&'a self, indices: I) -> ExampleStream<'a> {
+            ExampleStream {
+                elmo_text: self,
+                indices: Box::new(indices)
+            }
+        }
+    }
+
+    pub struct ExampleStream<'a> {
+        elmo_text: &'a ELMoText,
+        indices: Box<dyn Iterator<Item = i64> + 'a>
+    }
+
+    impl<'a> Iterator for ExampleStream<'a> {
+        type Item = Result<(Tensor, Tensor), Box<dyn Error>>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.indices.next().map(|index| self.elmo_text.get_example(index as usize))
+        }
+    }
+
+    impl<'a> ExampleStream<'a> {
+
+        // batches the streamed examples on the fly, cut as StreamLoader cuts the stream of a Loader (batch_size,
+        // seq_length and min_chunk_tokens of params), without collecting the examples first
+        pub fn batches(self, params: &JsonELMo) -> StreamBatches<'a> {
+            StreamBatches {
+                examples: self,
+                xs: Vec::new(),
+                ys: Vec::new(),
+                n_buffered: 0,
+                exhausted: false,
+                device: params.device,
+                batch_size: params.batch_size,
+                seq_length: params.seq_length,
+                min_chunk_tokens: params.min_chunk_tokens
+            }
+        }
     }
 
+    // the batches of an ExampleStream, in the stream order. The buffer holds the tokens not yet batched, it is
+    // filled with examples until it has a batch worth of tokens, so it never holds more than a batch and a
+    // sentence. It yields the batches of StreamLoader over the same examples, or the first encoding error
+    pub struct StreamBatches<'a> {
+        examples: ExampleStream<'a>,
+        xs: Vec<Tensor>,
+        ys: Vec<Tensor>,
+        n_buffered: i64,
+        exhausted: bool,
+        device: Device,
+        batch_size: i64,
+        seq_length: i64,
+        min_chunk_tokens: Option<i64>
+    }
+
+    impl<'a> Iterator for StreamBatches<'a> {
+        type Item = Result<(Tensor, Tensor), Box<dyn Error>>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+
+            let slice = self.batch_size * self.seq_length;
+            while self.n_buffered < slice && !self.exhausted {
+                match self.examples.next() {
+                    Some(Ok((x, y))) => {
+                        self.n_buffered += y.size()[0];
+                        self.xs.push(x);
+                        self.ys.push(y);
+                    },
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.exhausted = true
+                }
+            }
+            if self.n_buffered == 0 {
+                return None
+            }
+
+            let xs = Tensor::concat(&self.xs, 0); // of shape (n_buffered, max_token_length)
+            let ys = Tensor::concat(&self.ys, 0); // of shape (n_buffered)
+            let max_token_length = xs.size()[1];
+
+            // full batches first, then the remaining whole chunks, then the final partial chunk if it's kept
+            let n_batched = match self.n_buffered {
+                n if n >= slice => slice,
+                n if n >= self.seq_length => n - n % self.seq_length,
+                n => {
+                    self.xs.clear();
+                    self.ys.clear();
+                    self.n_buffered = 0;
+                    return match self.min_chunk_tokens {
+                        Some(min_chunk_tokens) if n >= min_chunk_tokens => {
+                            let xs_batch = xs.reshape([1, n, max_token_length]).to_kind(Kind::Int64).to_device(self.device);
+                            let ys_batch = ys.reshape([1, n]).to_kind(Kind::Int64).to_device(self.device);
+                            Some(Ok((xs_batch, ys_batch)))
+                        },
+                        _ => None
+                    }
+                }
+            };
+
+            let xs_batch = xs.narrow(0, 0, n_batched).reshape([-1, self.seq_length, max_token_length]).to_kind(Kind::Int64).to_device(self.device);
+            let ys_batch = ys.narrow(0, 0, n_batched).reshape([-1, self.seq_length]).to_kind(Kind::Int64).to_device(self.device);
+
+            // the tokens after the batch stay in the buffer for the next one
+            self.n_buffered -= n_batched;
+            self.xs = vec![xs.narrow(0, n_batched, self.n_buffered)];
+            self.ys = vec![ys.narrow(0, n_batched, self.n_buffered)];
+            Some(Ok((xs_batch, ys_batch)))
+        }
+    }
+
+
+    // random splits are of a random permutation of the examples. Sequential splits keep the corpus order,
+    // train is the first part of the corpus, then dev and test, so a time ordered corpus doesn't leak the future
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum SplitMode {
+        Random,
+        Sequential
+    }
 
     // An implementation to get a random permutation that is split to train, dev and test sets indices
     // given N number of samples in the corpus
-    pub struct Splitter;
+    pub struct Splitter {
+        mode: SplitMode,
+        ratios: [f64; 3],
+        splits: Option<Vec<Vec<i64>>>,
+        seed: Option<i64>
+    }
+
+    // the splits file written to output_dir with write_splits
+    pub const SPLITS_FILE: &str = "splits.json";
+
     impl Splitter {
 
         pub fn new() -> Self {
-            Self {}
+            Self { mode: SplitMode::Random, ratios: [0.8, 0.1, 0.1], splits: None, seed: None }
+        }
+
+        pub fn with_mode(mode: SplitMode) -> Self {
+            Self { mode: mode, ratios: [0.8, 0.1, 0.1], splits: None, seed: None }
+        }
+
+        // a random splitter of the given train, dev and test ratios, errors unless they sum to 1
+        pub fn with_ratios(ratios: [f64; 3]) -> Result<Self, Box<dyn Error>> {
+            Splitter::with_mode_and_ratios(SplitMode::Random, ratios)
+        }
+
+        pub fn with_mode_and_ratios(mode: SplitMode, ratios: [f64; 3]) -> Result<Self, Box<dyn Error>> {
+            Ok(Self { mode: mode, ratios: Splitter::check_ratios(&ratios)?, splits: None, seed: None })
+        }
+
+        // the random permutation of the split follows from the seed alone, the same seed gives the same splits
+        pub fn with_seed(self, seed: i64) -> Self {
+            Self { seed: Some(seed), ..self }
+        }
+
+        // the train, dev and test ratios should be 3 non-negative values that sum to 1 (up to 1e-6)
+        pub fn check_ratios(ratios: &[f64]) -> Result<[f64; 3], Box<dyn Error>> {
+            let ratios: [f64; 3] = ratios.try_into().map_err(|_| format!("split ratios should be 3 values (train, dev, test), got {}", ratios.len()))?;
+            if ratios.iter().any(|ratio| *ratio < 0.0) {
+                return Err(format!("split ratios should be non-negative, got {:?}", ratios).into())
+            }
+            if (ratios.iter().sum::<f64>() - 1.0).abs() > 1e-6 {
+                return Err(format!("split ratios should sum to 1, got {:?}", ratios).into())
+            }
+            Ok(ratios)
+        }
+
+        // a splitter that gives the train, dev and test indices of a splits file (as written by write_splits)
+        // instead of splitting, so a run reproduces the exact splits of another one
+        pub fn from_splits_file(splits_file: &str) -> Result<Self, Box<dyn Error>> {
+            let json: serde_json::Value = serde_json::from_reader(File::open(splits_file).map_err(|e| format!("couldn't open the splits file {}: {}", splits_file, e))?)?;
+            let splits = ["train", "dev", "test"].iter().map(|name| {
+                let indices = json.get(name).and_then(|indices| indices.as_array()).ok_or(format!("splits file {} has no {} indices", splits_file, name))?;
+                indices.iter().map(|i| i.as_i64().ok_or(format!("splits file {} has a non integer {} index", splits_file, name))).collect::<Result<Vec<i64>, String>>()
+            }).collect::<Result<Vec<Vec<i64>>, String>>()?;
+            Ok(Self { mode: SplitMode::Random, ratios: [0.8, 0.1, 0.1], splits: Some(splits), seed: None })
+        }
+
+        // the splits of a splits file should cover each of the n_samples examples of the corpus exactly once,
+        // a file of another corpus errors instead of training on a wrong split. Other splitters always do
+        pub fn check_splits(&self, n_samples: i64) -> Result<(), Box<dyn Error>> {
+            let splits = match &self.splits {
+                Some(splits) => splits,
+                None => return Ok(())
+            };
+            let n_split = splits.iter().map(|split| split.len() as i64).sum::<i64>();
+            if n_split != n_samples {
+                return Err(format!("the splits file has {} samples, the corpus has {}", n_split, n_samples).into())
+            }
+            let mut seen = vec![false; n_samples as usize];
+            for index in splits.iter().flatten() {
+                if !(0..n_samples).contains(index) {
+                    return Err(format!("the splits file has the index {}, out of the {} samples of the corpus", index, n_samples).into())
+                }
+                if seen[*index as usize] {
+                    return Err(format!("the splits file has the index {} more than once", index).into())
+                }
+                seen[*index as usize] = true;
+            }
+            Ok(())
+        }
+
+        // writes the train, dev and test indices of splits (of get_split_train_dev_test_indices) to a json file
+        pub fn write_splits(splits: &[Tensor], splits_file: &str) -> Result<(), Box<dyn Error>> {
+            let indices = splits.iter().map(Vec::<i64>::try_from).collect::<Result<Vec<Vec<i64>>, _>>()?;
+            let json = json!({"train": indices[0], "dev": indices[1], "test": indices[2]});
+            std::fs::write(splits_file, serde_json::to_string(&json)?)?;
+            Ok(())
         }
 
         fn get_split_train_dev_test_ratio(&self) -> [f64; 3] {
-            let split_ratio = [0.8, 0.1, 0.1]; // train, dev and test
-            assert!(split_ratio.iter().sum::<f64>() == 1.0, "ratios must sum to 1");
-            split_ratio
+            self.ratios // train, dev and test
         }
 
         fn get_split_train_dev_test_sizes(&self, n_samples: i64) -> Vec<i64> {
@@ -318,13 +966,217 @@ pub mod data_loading {
         pub fn get_split_train_dev_test_indices(&self, n_samples: i64) -> Vec<Tensor> {
             
             assert!(n_samples > 0, "number of samples for training most be positive");
+
+            // splits of a file bypass the split, they should cover the same number of samples
+            if let Some(splits) = &self.splits {
+                let n_split = splits.iter().map(|split| split.len() as i64).sum::<i64>();
+                assert!(n_split == n_samples, "the splits file has {} samples, the corpus has {}", n_split, n_samples);
+                return splits.iter().map(|split| Tensor::from_slice(split)).collect()
+            }
             
             let split_points: Vec<i64> = self.get_split_train_dev_test_sizes(n_samples);
-            let indices: Tensor = Tensor::randperm(n_samples, (Kind::Int64, Device::Cpu));
+            let indices: Tensor = self.permutation(n_samples);
             let split_indices: Vec<Tensor> = indices.split_with_sizes(&split_points, 0);
             split_indices
         }
 
+        // the order of the samples that is split, random (seeded with a seed) or the corpus order
+        fn permutation(&self, n_samples: i64) -> Tensor {
+            match self.mode {
+                SplitMode::Random => {
+                    if let Some(seed) = self.seed {
+                        tch::manual_seed(seed);
+                    }
+                    Tensor::randperm(n_samples, (Kind::Int64, Device::Cpu))
+                },
+                SplitMode::Sequential => Tensor::arange(n_samples, (Kind::Int64, Device::Cpu))
+            }
+        }
+
+        // (train, validation) indices of k folds for cross validation. The samples are ordered as in a split
+        // and cut to k disjoint validation folds of n_samples / k, the last fold takes the remainder. Each
+        // sample is in the validation of exactly one fold, and in the train of all the others. The split ratios
+        // and a splits file don't apply to folds
+        pub fn k_fold_indices(&self, n_samples: i64, k: usize) -> Vec<(Tensor, Tensor)> {
+
+            assert!(k >= 2, "k fold needs at least 2 folds");
+            assert!(n_samples >= k as i64, "k fold needs at least k samples, got {} for {} folds", n_samples, k);
+
+            let indices = self.permutation(n_samples);
+            let fold_size = n_samples / k as i64;
+            (0..k as i64).map(|fold| {
+                let start = fold * fold_size;
+                let size = if fold == k as i64 - 1 { n_samples - start } else { fold_size };
+                let validation = indices.narrow(0, start, size);
+                let train = Tensor::cat(&[indices.narrow(0, 0, start), indices.narrow(0, start + size, n_samples - start - size)], 0);
+                (train, validation)
+            }).collect()
+        }
+
+    }
+
+
+    // train loader, and dev and test loaders when available
+    pub type SplitLoaders = (Loader, Option<Loader>, Option<Loader>);
+
+    // moves the examples of the given indices to a Loader
+    pub(in crate) fn to_loader(elmo_text: &ELMoText, indices: &[i64], params: &JsonELMo) -> Result<Loader, Box<dyn Error>> {
+        stream_to_loader(elmo_text.stream_examples(indices.iter().cloned()), params)
+    }
+
+    // collects streamed examples to a Loader, the examples are encoded one at a time. A Loader keeps all of its
+    // examples, as training reorders them each epoch, a single pass over a split batches with ExampleStream::batches
+    fn stream_to_loader(examples: ExampleStream, params: &JsonELMo) -> Result<Loader, Box<dyn Error>> {
+
+        let char_pad_id = examples.elmo_text.char_pad_id();
+        let unk_id = examples.elmo_text.unk_id();
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+        for example in examples {
+            let (x, y) = example?;
+            xs.push(x);
+            ys.push(y);
+        }
+
+        let mut loader = Loader::new(xs, ys, params.device, params.batch_size, params.seq_length);
+        loader.min_chunk_tokens = params.min_chunk_tokens;
+        loader.pin_memory = params.pin_memory;
+        loader.pad_id = params.pad_id;
+        loader.char_pad_id = char_pad_id;
+        loader.padded_batches = params.padded_batches;
+        loader.unk_id = Some(unk_id);
+        Ok(loader)
+    }
+
+    // creates the train, dev and test loaders. The train corpus is split by the splitter, unless dev or test
+    // corpora are given separately. In that case the splitter is bypassed, all the train corpus is used for
+    // training and the given corpora for dev and test. Those must be encoded with the train vocabularies.
+    pub fn build_loaders(train_text: &ELMoText, dev_text: Option<&ELMoText>, test_text: Option<&ELMoText>, splitter: &Splitter, params: &JsonELMo) -> Result<SplitLoaders, Box<dyn Error>> {
+
+        if dev_text.is_none() && test_text.is_none() {
+
+            splitter.check_splits(train_text.get_len())?;
+            let splits: Vec<Tensor> = splitter.get_split_train_dev_test_indices(train_text.get_len());
+            if let (true, Some(output_dir)) = (params.write_splits, params.output_dir()) {
+                Splitter::write_splits(&splits, &format!("{}/{}", output_dir, SPLITS_FILE))?;
+            }
+            let mut loaders = splits.iter().map(|split| {
+                stream_to_loader(train_text.stream_examples(split.iter::<i64>()?), params)
+            }).collect::<Result<Vec<Loader>, Box<dyn Error>>>()?.into_iter();
+
+            let trainset_iter = loaders.next().ok_or("splits should have a train loader")?;
+            let loaders = (with_scheduled_sampling(with_curriculum(with_input_mask(trainset_iter, train_text, params), params), train_text, params), with_eval_sample(loaders.next(), params), loaders.next());
+            check_batches(&loaders)?;
+            return Ok(loaders)
+        }
+
+        for text in [dev_text, test_text].into_iter().flatten() {
+            if text.token2int != train_text.token2int || text.char2int != train_text.char2int {
+                return Err("dev and test corpora must be encoded with the vocabularies built on the train corpus".into())
+            }
+        }
+
+        let all_indices = |text: &ELMoText| (0..text.get_len()).collect::<Vec<i64>>();
+        let trainset_iter = to_loader(train_text, &all_indices(train_text), params)?;
+        let devset_iter = dev_text.map(|text| to_loader(text, &all_indices(text), params)).transpose()?;
+        let testset_iter = test_text.map(|text| to_loader(text, &all_indices(text), params)).transpose()?;
+        let loaders = (with_scheduled_sampling(with_curriculum(with_input_mask(trainset_iter, train_text, params), params), train_text, params), with_eval_sample(devset_iter, params), testset_iter);
+        check_batches(&loaders)?;
+        Ok(loaders)
+    }
+
+    // input masking is a training noise, it is set only on the train loader
+    fn with_input_mask(mut trainset_iter: Loader, train_text: &ELMoText, params: &JsonELMo) -> Loader {
+        if params.input_mask_prob > 0.0 {
+            trainset_iter.input_mask = Some((params.input_mask_prob, train_text.mask_encoding()));
+        }
+        trainset_iter
+    }
+
+    // scheduled sampling feeds predicted tokens as inputs, the train loader keeps the encodings of the vocabulary.
+    // They take token_vocab_size * max_len_token ints on the device
+    fn with_scheduled_sampling(mut trainset_iter: Loader, train_text: &ELMoText, params: &JsonELMo) -> Loader {
+        if params.scheduled_sampling_prob > 0.0 {
+            trainset_iter.token_encodings = Some(train_text.token_encodings().to_device(params.device));
+        }
+        trainset_iter
+    }
+
+    // the dev evaluation can be capped to a rotating sample of batches, the test evaluation is always full
+    fn with_eval_sample(devset_iter: Option<Loader>, params: &JsonELMo) -> Option<Loader> {
+        devset_iter.map(|mut devset_iter| {
+            devset_iter.eval_sample = params.max_eval_batches.map(|max_batches| (max_batches as usize, params.eval_seed as u64));
+            devset_iter
+        })
+    }
+
+    // curriculum learning orders the train examples by length in the first epochs, dev and test are not affected
+    fn with_curriculum(mut trainset_iter: Loader, params: &JsonELMo) -> Loader {
+        if params.curriculum {
+            trainset_iter.curriculum_epochs = params.curriculum_epochs;
+        }
+        trainset_iter
+    }
+
+    // a non empty split that yields no batch would silently skip training or evaluation, fail early instead
+    fn check_batches(loaders: &SplitLoaders) -> Result<(), Box<dyn Error>> {
+        let (trainset_iter, devset_iter, testset_iter) = loaders;
+        for (name, loader) in [("train", Some(trainset_iter)), ("dev", devset_iter.as_ref()), ("test", testset_iter.as_ref())] {
+            if let Some(loader) = loader {
+                if !loader.is_empty() && loader.n_batches() == 0 {
+                    return Err(format!(
+                        "the {} split has {} examples ({} tokens) but produces no batch with batch_size {} and seq_length {}, use a larger corpus or a smaller batch_size or seq_length",
+                        name, loader.len(), loader.n_tokens(), loader.batch_size, loader.seq_length
+                    ).into())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the label token of the padded positions in dumped batches, their labels are IGNORE_INDEX
+    const PAD_LABEL_TOKEN: &str = "<pad>";
+
+    // writes the first n encoded batches of the loader to a human readable json, as the batches of the first
+    // training epoch (epoch_order(0), stream chunks or padded_batches). The order of the loader is kept, so with
+    // the generator of the first epoch (the seed), training sees the same batches. Each batch has the char ids
+    // and labels, together with the tokens they decode to, padded labels are <pad>. Returns the number of written
+    // batches, can be less than n for a small loader. The batches are dumped before input masking, the masking
+    // is random at every pass, each batch records the input_mask_prob it is masked with in training
+    pub fn dump_batches(loader: &mut Loader, elmo_text: &ELMoText, n: usize, output_file: &str) -> Result<usize, Box<dyn Error>> {
+
+        let input_mask = loader.input_mask.take();
+        let input_mask_prob = input_mask.as_ref().map(|(p, _)| *p).unwrap_or(0.0);
+        let order = (loader.xs.iter().map(|x| x.shallow_clone()).collect::<Vec<Tensor>>(), loader.ys.iter().map(|y| y.shallow_clone()).collect::<Vec<Tensor>>());
+        let epoch_batches = loader.epoch_order(0).batches().take(n).collect::<Vec<(Tensor, Tensor)>>();
+        (loader.xs, loader.ys) = order;
+        loader.input_mask = input_mask;
+        let mut batches = Vec::new();
+        for (i, (xs, ys)) in epoch_batches.into_iter().enumerate() {
+
+            // xs of shape (batch_size, seq_length, max_token_length), ys of shape (batch_size, seq_length)
+            let char_ids = Vec::<Vec<Vec<i64>>>::try_from(&xs.to_device(Device::Cpu))?;
+            let labels = Vec::<Vec<i64>>::try_from(&ys.to_device(Device::Cpu))?;
+            let tokens = char_ids.iter().map(|seq| seq.iter().map(|ids| elmo_text.decode_chars(ids)).collect::<Vec<String>>()).collect::<Vec<_>>();
+            let label_tokens = labels.iter().map(|seq| seq.iter().map(|l| match *l == IGNORE_INDEX {
+                true => PAD_LABEL_TOKEN.to_string(),
+                false => elmo_text.decode_label(*l)
+            }).collect::<Vec<String>>()).collect::<Vec<_>>();
+
+            batches.push(json!({
+                "batch": i,
+                "tokens": tokens,
+                "label_tokens": label_tokens,
+                "char_ids": char_ids,
+                "labels": labels,
+                "input_mask_prob": input_mask_prob
+            }));
+        }
+
+        let n_batches = batches.len();
+        let f = File::create(output_file)?;
+        serde_json::to_writer_pretty(f, &batches)?;
+        Ok(n_batches)
     }
 
 }
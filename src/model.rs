@@ -1,22 +1,90 @@
 
 // imports
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
 use std::iter::zip;
 use std::ops::Mul;
-use tch::{nn, Tensor, IndexOp, Device};
-use tch::nn::{ModuleT, RNN};
-use crate::config::JsonELMo;
+use std::sync::{Arc, Mutex};
+use tch::{nn, Tensor, Device, Kind};
+use tch::nn::{ModuleT, RNN, LSTMState};
+use crate::config::{JsonELMo, files_handling};
+use crate::loader::data_loading::IGNORE_INDEX;
 
 // an self-implementation of biLSTM and a char-level 
 // convolution as described in the ELMo paper https://aclanthology.org/N18-1202.pdf
 
+// the maximum number of chars any filter of the char cnn sees. A filter spans kernel_size chars, and the
+// max pool is over all the positions of the token, so it doesn't widen the span of a single filter. The
+// token is padded (or truncated) to max_len_token chars, start and end chars included, a wider kernel
+// has no valid position in it
+pub fn receptive_field(kernel_size: &[i64], max_len_token: i64) -> i64 {
+    let field = kernel_size.iter().cloned().max().unwrap_or(0);
+    if field > max_len_token {
+        println!("warning: the char cnn receptive field ({} chars) exceeds the padded token length max_len_token ({})", field, max_len_token);
+    }
+    field
+}
+
+// the shapes entering and leaving the modules (CharLevelNet, each CnnBlock, UniLM) in forward passes, logged
+// with debug_shapes, to trace where a shape mismatch comes from. The modules of a model share the records,
+// each module logs under its own name. Only the last SHAPE_LOG_CAPACITY records are kept over a training run
+#[derive(Clone, Debug, Default)]
+pub struct ShapeLog {
+    name: String,
+    records: Option<Arc<Mutex<VecDeque<ShapeRecord>>>>
+}
+
+pub const SHAPE_LOG_CAPACITY: usize = 1024;
+
+// (module, input shape, output shape)
+pub type ShapeRecord = (String, Vec<i64>, Vec<i64>);
+
+impl ShapeLog {
+    pub fn new(debug_shapes: bool) -> Self {
+        Self { name: String::new(), records: debug_shapes.then(|| Arc::new(Mutex::new(VecDeque::new()))) }
+    }
+
+    // the log of a submodule, names are joined with dots as the variables (char_level.conv_0)
+    fn scoped(&self, name: &str) -> Self {
+        let name = match self.name.is_empty() {
+            true => name.to_string(),
+            false => format!("{}.{}", self.name, name)
+        };
+        Self { name: name, records: self.records.clone() }
+    }
+
+    fn log(&self, input: &Tensor, output: &Tensor) {
+        if let Some(records) = &self.records {
+            println!("shapes {}: {:?} => {:?}", self.name, input.size(), output.size());
+            let mut records = records.lock().expect("shape log lock");
+            if records.len() == SHAPE_LOG_CAPACITY {
+                records.pop_front();
+            }
+            records.push_back((self.name.to_string(), input.size(), output.size()));
+        }
+    }
+
+    // the (module, input shape, output shape) records in the order of the forward passes, empty when off
+    pub fn records(&self) -> Vec<ShapeRecord> {
+        self.records.as_ref().map(|records| records.lock().expect("shape log lock").iter().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        if let Some(records) = &self.records {
+            records.lock().expect("shape log lock").clear();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(in self) struct CnnBlock {
     conv: nn::Conv<[i64; 2]>,
-    kernel_size: i64
+    kernel_size: i64,
+    shape_log: ShapeLog
 }
 
 impl CnnBlock {
-    fn new(vars: &nn::Path, in_channels: i64, out_channels: i64, kernel_size: i64, embedding_dim: i64) -> Self {
+    fn new(vars: &nn::Path, in_channels: i64, out_channels: i64, kernel_size: i64, embedding_dim: i64, shape_log: ShapeLog) -> Self {
 
         // If a word is of length k charachters, the convolution is What's described as
         // a narrow convolution between a charachter within a word, C_k of shape (d, l),  
@@ -30,6 +98,7 @@ impl CnnBlock {
         Self {
             conv: conv,
             kernel_size: kernel_size,
+            shape_log: shape_log
         }
 
     }
@@ -71,6 +140,7 @@ impl ModuleT for CnnBlock {
         assert!(Vec::<i64>::try_from(pool_out.internal_shape_as_tensor()).unwrap()[2] == 1);
         let out = pool_out.squeeze_dim(2);
 
+        self.shape_log.log(xs, &out);
         out
     }
 }
@@ -109,12 +179,82 @@ impl ModuleT for Highway {
     }
 }
 
+// drops whole char embedding vectors, each (.., embedding_dim) row is zeroed with probability p and the
+// kept rows are scaled by 1 / (1 - p). It's a no-op at non-train time
+pub fn embedding_dropout(xs_embedded: &Tensor, p: f64, train: bool) -> Tensor {
+    if !train || p == 0.0 {
+        return xs_embedded.shallow_clone()
+    }
+    let mut mask_shape = xs_embedded.size();
+    *mask_shape.last_mut().unwrap() = 1;
+    let mask = Tensor::empty(&mask_shape, (xs_embedded.kind(), xs_embedded.device())).bernoulli_float_(1.0 - p) / (1.0 - p);
+    xs_embedded * mask
+}
+
+// a scaled identity: the forward pass returns xs as is, the backward pass multiplies the gradient by scale.
+// xs * scale + xs.detach() * (1 - scale) has the value of xs, and only its first term has a gradient
+pub fn scale_grad(xs: &Tensor, scale: f64) -> Tensor {
+    if scale == 1.0 {
+        return xs.shallow_clone()
+    }
+    xs * scale + xs.detach() * (1.0 - scale)
+}
+
+// the entropy of the softmax distribution of each row of logits, (n, vocab_size) => (n). The log
+// probabilities come from log_softmax, that subtracts the log-sum-exp, so large logits don't overflow
+pub fn logits_entropy(logits: &Tensor) -> Tensor {
+    let log_probs = logits.log_softmax(-1, logits.kind());
+    -(log_probs.exp() * &log_probs).sum_dim_intlist([-1].as_slice(), false, logits.kind())
+}
+
+// the ways to pool the token representations of a sentence to a single vector
+pub const SENTENCE_POOLINGS: [&str; 4] = ["mean", "max", "last", "cls"];
+
+// pools token representations of shape (batch_size, seq_length, dim) to one vector per sentence, (batch_size, dim).
+// mask of shape (batch_size, seq_length) is true on real tokens, so padding is left out of mean and max. last
+// takes the last real token and cls the first position (SOS with add_sentence_boundaries)
+pub fn pool_sentence(hs: &Tensor, mask: &Tensor, pooling: &str) -> Tensor {
+    let mask = mask.to_kind(Kind::Bool).to_device(hs.device());
+    match pooling {
+        "max" => hs.masked_fill(&mask.logical_not().unsqueeze(-1), f64::NEG_INFINITY).amax([1].as_slice(), false),
+        "last" => {
+            let last = (mask.sum_dim_intlist([1].as_slice(), false, Kind::Int64) - 1).clamp_min(0);
+            hs.gather(1, &last.view([-1, 1, 1]).expand([-1, 1, hs.size()[2]], false), false).squeeze_dim(1)
+        },
+        "cls" => hs.select(1, 0),
+        _ => {
+            let weights = mask.to_kind(hs.kind()).unsqueeze(-1);
+            (hs * &weights).sum_dim_intlist([1].as_slice(), false, hs.kind()) / weights.sum_dim_intlist([1].as_slice(), false, hs.kind()).clamp_min(1.0)
+        }
+    }
+}
+
+// reverses each sentence of xs in time within its length, the padded positions stay at the end. xs is of shape
+// (..., batch_size, seq_length, dim) and mask of shape (batch_size, seq_length), true on real tokens. Applied
+// twice it gives xs back
+fn reverse_within_mask(xs: &Tensor, mask: &Tensor) -> Tensor {
+    let dims = xs.size();
+    let (batch_size, seq_length) = (dims[dims.len() - 3], dims[dims.len() - 2]);
+    let lengths = mask.to_kind(Kind::Int64).to_device(xs.device()).sum_dim_intlist([1].as_slice(), true, Kind::Int64); // (batch_size, 1)
+    let positions = Tensor::arange(seq_length, (Kind::Int64, xs.device())).unsqueeze(0); // (1, seq_length)
+    let index = (&lengths - 1 - &positions).where_self(&positions.lt_tensor(&lengths), &positions.expand([batch_size, seq_length], false));
+    let mut index_shape = vec![1; dims.len()];
+    index_shape[dims.len() - 3] = batch_size;
+    index_shape[dims.len() - 2] = seq_length;
+    xs.gather(dims.len() as i64 - 2, &index.view(index_shape.as_slice()).expand(dims.as_slice(), false), false)
+}
+
 #[derive(Debug)]
 pub(in self) struct CharLevelNet {
-    embedding: nn::Embedding,
+    embeddings: Vec<nn::Embedding>,
     conv_blocks: Vec<CnnBlock>,
+    conv_blocks_order: Vec<usize>,
     highways: Vec<Highway>,
     out_linear: nn::Linear,
+    out_activation: String,
+    out_norm: Option<nn::LayerNorm>,
+    embedding_dropout: f64,
+    shape_log: ShapeLog,
     device: Device
 }
 
@@ -126,35 +266,79 @@ impl CharLevelNet {
          out_channels: Vec<i64>, 
          kernel_size: Vec<i64>, 
          highways: i64, 
-         char_level_out_dim: i64) -> Self {
-
+         char_level_out_dim: i64,
+         freeze_embedding: bool,
+         out_activation: String,
+         embedding_dropout: f64,
+         share_conv_weights: bool,
+         script_partitions: Option<Vec<i64>>,
+         shape_log: ShapeLog) -> Self {
+
+
+        // a single char embedding, or with char_script_partitions one embedding per script (embed_0, embed_1, ...),
+        // each of the rows of a contiguous range of char ids, in id order. All of them feed the same conv blocks
+        let embeddings = match script_partitions {
+            Some(sizes) => {
+                assert_eq!(sizes.iter().sum::<i64>(), vocab_size, "the char script partitions should cover the char_vocab_size char ids");
+                sizes.iter().enumerate().map(|(i, size)| nn::embedding(vars / format!("embed_{}", i), *size, embedding_dim, Default::default())).collect()
+            },
+            None => vec![nn::embedding(vars / "embed", vocab_size, embedding_dim, Default::default())]
+        };
+
+        // only the embedding is frozen, the conv filters and the rest of the char level net are still trained
+        if freeze_embedding {
+            for embedding in &embeddings {
+                let _ = embedding.ws.set_requires_grad(false);
+            }
+        }
 
         // creation of M convolution blocks based M kernel sizes and M out channels
-        let embedding = nn::embedding(vars / "embed", vocab_size, embedding_dim, Default::default());
+        // with share_conv_weights, blocks of the same kernel size and out channels reuse the conv of the first
+        // of them (named by its position). Each shared block saves out_channels * (in_channels * embedding_dim * kernel_size + 1)
+        // parameters, its output is still concatenated, so the total filters don't change
         let mut conv_blocks = Vec::new();
-        for (out_channel, kernel_size) in zip(&out_channels, kernel_size) {
-            let conv_block = CnnBlock::new(vars, in_channels, *out_channel, kernel_size, embedding_dim);
+        let mut conv_blocks_order = Vec::new();
+        let mut shared_blocks: HashMap<(i64, i64), usize> = HashMap::new();
+        for (i, (out_channel, kernel_size)) in zip(&out_channels, kernel_size).enumerate() {
+            if let (true, Some(block_index)) = (share_conv_weights, shared_blocks.get(&(*out_channel, kernel_size))) {
+                conv_blocks_order.push(*block_index);
+                continue;
+            }
+            let conv_block = CnnBlock::new(&(vars / format!("conv_{}", i)), in_channels, *out_channel, kernel_size, embedding_dim, shape_log.scoped(&format!("conv_{}", i)));
+            shared_blocks.insert((*out_channel, kernel_size), conv_blocks.len());
+            conv_blocks_order.push(conv_blocks.len());
             conv_blocks.push(conv_block);
         }
 
         // total filters should be the sum over out_channels
         let total_filters: i64 = (&out_channels).iter().sum();
 
-        // creation of N highways
+        // creation of N highways, with N = 0 the conv outputs move directly to out_linear
         let mut highway_layers = Vec::new();
-        for _ in 0..highways {
-            let highway = Highway::new(vars, total_filters, total_filters);
+        for i in 0..highways {
+            let highway = Highway::new(&(vars / format!("highway_{}", i)), total_filters, total_filters);
             highway_layers.push(highway);
         }
 
         // move to some representation dimenstion
         let out_linear = nn::linear(vars / "to_dim", total_filters, char_level_out_dim, Default::default());
+
+        // an optional nonlinearity after out_linear, layernorm is the only one with variables
+        let out_norm = match out_activation.as_str() {
+            "layernorm" => Some(nn::layer_norm(vars / "out_norm", vec![char_level_out_dim], Default::default())),
+            _ => None
+        };
         
         Self {
-            embedding: embedding,
+            embeddings: embeddings,
             conv_blocks: conv_blocks,
+            conv_blocks_order: conv_blocks_order,
             highways: highway_layers,
             out_linear: out_linear,
+            out_activation: out_activation,
+            out_norm: out_norm,
+            embedding_dropout: embedding_dropout,
+            shape_log: shape_log,
             device: vars.device()
         }
 
@@ -163,6 +347,49 @@ impl CharLevelNet {
 
 }
 
+impl CharLevelNet {
+
+    // the char embedding rows of all the char ids, the script partitions concatenated in id order
+    fn embedding_weights(&self) -> Tensor {
+        match self.embeddings.as_slice() {
+            [embedding] => embedding.ws.shallow_clone(),
+            embeddings => Tensor::cat(&embeddings.iter().map(|embedding| &embedding.ws).collect::<Vec<&Tensor>>(), 0)
+        }
+    }
+
+    // the embedding weights a char id is a row of, and its row in them
+    fn embedding_row(&self, id: i64) -> Option<(&Tensor, i64)> {
+        let mut start = 0;
+        for embedding in &self.embeddings {
+            let size = embedding.ws.size()[0];
+            if id < start + size {
+                return Some((&embedding.ws, id - start))
+            }
+            start += size;
+        }
+        None
+    }
+
+    // copies pretrained vectors into the rows of their chars, chars without a vector keep their random init.
+    // returns the number of rows loaded
+    fn load_embedding(&self, vectors: &HashMap<char, Vec<f64>>, char2int: &HashMap<char, usize>) -> Result<usize, Box<dyn Error>> {
+
+        let embedding_dim = self.embeddings[0].ws.size()[1];
+        let mut loaded = 0;
+        for (c, vector) in vectors {
+            if vector.len() as i64 != embedding_dim {
+                return Err(format!("char vectors are of dim {}, but char_embedding_dim is {}", vector.len(), embedding_dim).into());
+            }
+            if let Some((ws, row)) = char2int.get(c).and_then(|id| self.embedding_row(*id as i64)) {
+                let values = Tensor::from_slice(vector).to_kind(ws.kind()).to_device(ws.device());
+                tch::no_grad(|| ws.get(row).copy_(&values));
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+}
+
 impl ModuleT for CharLevelNet {
     
     fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
@@ -173,14 +400,16 @@ impl ModuleT for CharLevelNet {
         let seq_length = &dims[1];
         
         // iterate over tokens, do convolution to each token at a time
+        let embedding_weights = self.embedding_weights();
         let mut outputs = Vec::new();
         for s in 0..*seq_length {
 
             let xs_tokens: Tensor = xs.slice(1, s, s+1, 1); // should be (batch_size, 1, token_length)
-            let xs_embedded = xs_tokens.apply(&self.embedding); // should be (batch_size, 1, token_length, embedding_dim)
+            let xs_embedded = Tensor::embedding(&embedding_weights, &xs_tokens, -1, false, false); // should be (batch_size, 1, token_length, embedding_dim)
+            let xs_embedded = embedding_dropout(&xs_embedded, self.embedding_dropout, train);
             let mut token_outputs = Vec::new();
-            for conv_block in &self.conv_blocks {
-                let out = conv_block.forward_t(&xs_embedded, train); // out is of shape (batch_size, n_filters)
+            for block_index in &self.conv_blocks_order {
+                let out = self.conv_blocks[*block_index].forward_t(&xs_embedded, train); // out is of shape (batch_size, n_filters)
                 token_outputs.push(out);
             }
 
@@ -199,6 +428,15 @@ impl ModuleT for CharLevelNet {
 
         // move to linear out (batch_size, seq_length, total_filters) => (batch_size, seq_length, out_linear)
         let out = outs.apply(&self.out_linear);
+
+        // dims don't change by the activation, (batch_size, seq_length, out_linear)
+        let out = match (self.out_activation.as_str(), &self.out_norm) {
+            ("relu", _) => out.relu(),
+            ("tanh", _) => out.tanh(),
+            ("layernorm", Some(out_norm)) => out.apply(out_norm),
+            _ => out
+        };
+        self.shape_log.log(xs, &out);
         out
 
 
@@ -206,33 +444,64 @@ impl ModuleT for CharLevelNet {
 }
 
 
+// runs a unidirectional lstm over xs (batch_size, seq_length, in_dim) in chunks of chunk_size positions,
+// the (h, c) state of each chunk initializes the next one, so the output is the same as one pass over the whole
+// sequence. It bounds the size of the activations of a single lstm call, at inference also the peak memory
+// (with autograd all chunks are kept for the backward pass). None runs the whole sequence at once
+pub fn chunked_seq(lstm: &nn::LSTM, xs: &Tensor, chunk_size: Option<i64>) -> (Tensor, LSTMState) {
+    let seq_length = xs.size()[1];
+    let chunk_size = match chunk_size {
+        Some(chunk_size) if chunk_size < seq_length => chunk_size,
+        _ => return lstm.seq(xs)
+    };
+    let mut state = lstm.zero_state(xs.size()[0]);
+    let mut outputs = Vec::new();
+    for chunk in xs.split(chunk_size, 1) {
+        let (out, next_state) = lstm.seq_init(&chunk, &state);
+        outputs.push(out);
+        state = next_state;
+    }
+    (Tensor::cat(&outputs, 1), state)
+}
+
 #[derive(Debug)]
 pub(in self) struct UniLM {
     lstm_layers: Vec<nn::LSTM>,
     to_rep: nn::Linear,
     dropout: f64,
+    seq_chunk_size: Option<i64>,
+    shape_log: ShapeLog,
     device: Device
 }
 
 impl UniLM {
-    fn new(vars: &nn::Path, n_lstm_layers: i64, in_dim: i64, hidden_dim: i64, dropout: f64) -> Self {
+    fn new(vars: &nn::Path, n_lstm_layers: i64, in_dim: i64, hidden_dim: i64, dropout: f64, bidirectional: bool, forget_bias: f64, seq_chunk_size: Option<i64>, shape_log: ShapeLog) -> Self {
 
-        // creation of N unidirectional lstm layers
+        // creation of N lstm layers, unidirectional unless the native tch bidirectional lstm is asked.
+        // a bidirectional layer outputs both directions concatenated, (batch_size, seq_length, 2 * hidden_dim)
+        let lstm_config = nn::RNNConfig { bidirectional: bidirectional, ..Default::default() };
+        let num_directions = if bidirectional { 2 } else { 1 };
         let mut lstm_layers = Vec::new();
-        for _ in 0..n_lstm_layers {
-
-            // default on rnn gives everything we need except for dropout, taken care in forward
-            let lm = nn::lstm(vars / "lstm", in_dim, hidden_dim, Default::default());
+        for j in 0..n_lstm_layers {
+
+            // default on rnn gives everything we need except for dropout, taken care in forward,
+            // and the forget gate bias that is set after the default init.
+            // each layer is an optimizer group of its own (j+1), for per layer learning rates
+            let layer_vars = (vars / format!("lstm_{}", j)).set_group(j as usize + 1);
+            let lm = nn::lstm(&layer_vars, in_dim, hidden_dim, lstm_config);
+            UniLM::init_forget_bias(&layer_vars, hidden_dim, num_directions, forget_bias);
             lstm_layers.push(lm);
         }
 
-        // move to some representaion layer
-        let to_rep = nn::linear(vars / "to_dim_lstm", hidden_dim, in_dim, Default::default());
+        // move to some representaion layer, from both directions when bidirectional
+        let to_rep = nn::linear(vars / "to_dim_lstm", num_directions * hidden_dim, in_dim, Default::default());
 
         Self {
             lstm_layers: lstm_layers,
             to_rep: to_rep,
             dropout: dropout,
+            seq_chunk_size: seq_chunk_size,
+            shape_log: shape_log,
             device: vars.device()
         }
 
@@ -240,47 +509,128 @@ impl UniLM {
     }
 }
 
-impl ModuleT for UniLM {
+impl UniLM {
 
-    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+    // tch gates are ordered (input, forget, cell, output) in the biases, each of hidden_dim.
+    // the forget slice of bias_ih is set to forget_bias and of bias_hh to zero, so the effective bias is forget_bias
+    fn init_forget_bias(layer_vars: &nn::Path, hidden_dim: i64, num_directions: i64, forget_bias: f64) {
+        let suffixes = ["", "_reverse"];
+        for suffix in &suffixes[..num_directions as usize] {
+            let bias_ih = layer_vars.get(&format!("bias_ih_l0{}", suffix)).expect("lstm has no bias_ih variable");
+            let bias_hh = layer_vars.get(&format!("bias_hh_l0{}", suffix)).expect("lstm has no bias_hh variable");
+            tch::no_grad(|| {
+                let _ = bias_ih.narrow(0, hidden_dim, hidden_dim).fill_(forget_bias);
+                let _ = bias_hh.narrow(0, hidden_dim, hidden_dim).fill_(0.0);
+            });
+        }
+    }
+
+    // the same pass as forward_t, that also keeps the final (h, c) state of the top lstm layer
+    fn forward_with_state(&self, xs: &Tensor, train: bool) -> (Tensor, LSTMState) {
         
         // xs should be (batch_size, seq_length, out_linear)
 
-        // need residual connections, so lstm out should be the same size of input
-        let mut out_point = xs.to_owned().shallow_clone().to_device(self.device);
+        // need residual connections, so lstm out should be the same size of input.
+        // outputs[0] is the input, outputs[j+1] is the output of layer j, which is the input to layer j+1.
+        // each layer outputs to_rep(lstm_j(input_j)) + input_j
         let mut outputs = vec![xs.to_owned().shallow_clone().to_device(self.device)];
+        let mut top_state = None;
 
         for (j, lstm) in (&self.lstm_layers).iter().enumerate() {
 
-            outputs.push(out_point.shallow_clone().to_device(self.device));
+            let layer_input = &outputs[j];
 
-            // adding dropout at non-test time
-            let out_lstm = lstm.seq(&out_point.dropout(self.dropout, train).to_device(self.device));
-            out_point = out_lstm.0;
+            // adding dropout at non-test time, the sequence is chunked when seq_chunk_size is set
+            let out_lstm = chunked_seq(lstm, &layer_input.dropout(self.dropout, train).to_device(self.device), self.seq_chunk_size);
+            let out_point = out_lstm.0;
+            top_state = Some(out_lstm.1);
             
             // out moves back to shape (batch_size, seq_length, hidden_dim) => (batch_size, seq_length, out_linear)
-            out_point = out_point.apply(&self.to_rep);
-
-            // adding residual to out
-            out_point += outputs[j].shallow_clone().to_device(self.device);
+            // and the residual is added, the input to this layer
+            let out_point = out_point.apply(&self.to_rep) + layer_input;
+            outputs.push(out_point);
 
         }
 
-        // move n_lstm_layers * (batch_size, seq_length, out_linear) =>  (n_lstm_layers, batch_size, seq_length, out_linear)
+        // move (n_lstm_layers + 1) * (batch_size, seq_length, out_linear) =>  (n_lstm_layers + 1, batch_size, seq_length, out_linear)
         let out = Tensor::stack(&outputs, 0).to_device(self.device);
-        out
+        self.shape_log.log(xs, &out);
+        (out, top_state.expect("UniLM needs at least one lstm layer"))
+
+    }
+}
 
+impl ModuleT for UniLM {
+
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        self.forward_with_state(xs, train).0
+    }
+}
+
+// the learned combination of the layers of the biLM into a single representation, as in the elmo paper:
+// gamma * sum_j softmax(s)_j * layer_j, over the char level layer and each lstm layer. The weights start
+// uniform and gamma at 1. In ELMo the lm predictions are made from the mix of each direction, so the weights
+// are trained with the lm loss (and further with whatever downstream loss the representation feeds)
+#[derive(Debug)]
+pub struct ScalarMix {
+    weights: Tensor,
+    gamma: Tensor
+}
+
+impl ScalarMix {
+    pub fn new(vars: &nn::Path, n_layers: i64) -> Self {
+        Self {
+            weights: vars.var("weights", &[n_layers], nn::Init::Const(0.0)),
+            gamma: vars.var("gamma", &[1], nn::Init::Const(1.0))
+        }
+    }
+
+    // the weighted sum of layers, all of the same shape, the output has that shape too
+    pub fn forward(&self, layers: &[Tensor]) -> Tensor {
+        assert_eq!(layers.len() as i64, self.weights.size()[0], "scalar mix got a different number of layers than it has weights");
+        let weights = self.weights.softmax(0, self.weights.kind());
+        let mixed = layers.iter().enumerate().map(|(j, layer)| weights.get(j as i64) * layer).reduce(|total, layer| total + layer).expect("scalar mix needs at least one layer");
+        &self.gamma * mixed
+    }
+}
+
+// a language model trained by ElmoTrainer. Besides the logits of the next tokens, it can add an auxiliary
+// loss computed from the same forward pass, that is trained along the language modeling loss
+pub trait LanguageModel: ModuleT {
+    fn forward_with_auxiliary(&self, xs: &Tensor, _ys: &Tensor, train: bool) -> (Tensor, Option<Tensor>) {
+        (self.forward_t(xs, train), None)
+    }
+
+    // the weight of the language modeling loss of the logits in the trained loss
+    fn forward_loss_weight(&self) -> f64 {
+        1.0
+    }
+
+    // errors if the language modeling loss of the model can't be trained, checked before training
+    fn check_trainable(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct ELMo {
     forward_lm: UniLM,
-    backward_lm: UniLM,
+    backward_lm: Option<UniLM>,
     to_vocab: nn::Linear,
+    to_chars: Option<nn::Linear>,
+    scalar_mix: ScalarMix,
+    char_backoff_weight: f64,
+    forward_loss_weight: f64,
+    backward_loss_weight: f64,
+    char_cnn_grad_scale: f64,
+    char_vocab_size: i64,
+    max_len_token: i64,
     n_lstm_layers: i64,
     char_level: CharLevelNet,
     token_vocab_size: i64,
+    unk_id: Option<i64>,
+    shape_log: ShapeLog,
+    sentence_pooling: String,
     device: Device
 }
 
@@ -299,55 +649,326 @@ impl ELMo {
         let out_channels = params.out_channels.clone();
         let kernel_size = params.kernel_size.clone();
         let highways = params.highways;
+        let char_level_out_dim = params.char_level_out_dim.unwrap_or(in_dim);
         let dropout = params.dropout;
+        let native_bidirectional = params.native_bidirectional;
+        let forget_bias = params.forget_bias;
+        let freeze_char_embedding = params.freeze_char_embedding;
+        let char_out_activation = params.char_out_activation.clone();
+        let char_embedding_dropout = params.embedding_dropout;
+        let share_conv_weights = params.share_conv_weights;
+        let seq_chunk_size = params.seq_chunk_size;
         
-        let char_level = CharLevelNet::new(vars, char_vocab_size, char_embedding_dim, in_channels, out_channels, kernel_size, highways, in_dim);
-        let forward_lm = UniLM::new(vars, n_lstm_layers, in_dim, hidden_dim, dropout);
-        let backward_lm = UniLM::new(vars, n_lstm_layers, in_dim, hidden_dim, dropout);
+        let _ = receptive_field(&kernel_size, params.max_len_token);
+
+        // each submodule has its own path, variables are then named by the submodule they belong to
+        let shape_log = ShapeLog::new(params.debug_shapes);
+        let char_level = CharLevelNet::new(&(vars / "char_level"), char_vocab_size, char_embedding_dim, in_channels, out_channels, kernel_size, highways, char_level_out_dim, freeze_char_embedding, char_out_activation, char_embedding_dropout, share_conv_weights, params.char_script_partitions.clone(), shape_log.scoped("char_level"));
+        // with native_bidirectional, a single bidirectional lm replaces the hand-rolled forward and backward lms
+        let forward_lm = UniLM::new(&(vars / "forward_lm"), n_lstm_layers, in_dim, hidden_dim, dropout, native_bidirectional, forget_bias, seq_chunk_size, shape_log.scoped("forward_lm"));
+        let backward_lm = match native_bidirectional {
+            true => None,
+            false => Some(UniLM::new(&(vars / "backward_lm"), n_lstm_layers, in_dim, hidden_dim, dropout, false, forget_bias, seq_chunk_size, shape_log.scoped("backward_lm")))
+        };
         let to_vocab = nn::linear(vars / "to_vocab", in_dim, token_vocab_size, Default::default());
+        let scalar_mix = ScalarMix::new(&(vars / "scalar_mix"), n_lstm_layers + 1);
+
+        // the char backoff head predicts every char of the next token, it exists only when its loss is trained
+        let to_chars = match params.char_backoff_weight > 0.0 {
+            true => Some(nn::linear(vars / "to_chars", in_dim, params.max_len_token * char_vocab_size, Default::default())),
+            false => None
+        };
 
         Self {
             forward_lm: forward_lm,
             backward_lm: backward_lm,
             to_vocab: to_vocab,
+            to_chars: to_chars,
+            scalar_mix: scalar_mix,
+            char_backoff_weight: params.char_backoff_weight,
+            forward_loss_weight: params.forward_loss_weight,
+            backward_loss_weight: params.backward_loss_weight,
+            char_cnn_grad_scale: params.char_cnn_grad_scale,
+            char_vocab_size: char_vocab_size,
+            max_len_token: params.max_len_token,
             n_lstm_layers: n_lstm_layers,
             char_level: char_level,
             token_vocab_size: token_vocab_size,
+            unk_id: None,
+            shape_log: shape_log,
+            sentence_pooling: params.sentence_pooling.clone(),
             device: vars.device()
         }
 
 
     }
+
+    // the label of the tokens out of the vocabulary, the id of str_unk in token2int (ELMoText::unk_id).
+    // The char backoff loss is on the positions of this label, a model with a char backoff head needs it
+    pub fn with_unk_id(self, unk_id: i64) -> Self {
+        Self { unk_id: Some(unk_id), ..self }
+    }
+
+    // the shapes logged by the modules with debug_shapes
+    pub fn shape_log(&self) -> &ShapeLog {
+        &self.shape_log
+    }
+
+    // the final (h, c) state of the forward lm top lstm layer, for sentence level tasks.
+    // xs is of shape (batch_size, seq_length, token_length), h and c are of shape (batch_size, hidden_dim),
+    // or (batch_size, 2 * hidden_dim) with both directions of a native bidirectional lstm
+    pub fn lstm_states(&self, xs: &Tensor) -> (Tensor, Tensor) {
+
+        let xs_embedded = self.char_level.forward_t(xs, false);
+        let (_, state) = self.forward_lm.forward_with_state(&xs_embedded, false);
+
+        // the state is of shape (num_directions, batch_size, hidden_dim) for a single layer
+        let batch_size = xs.size()[0];
+        let h = state.h().transpose(0, 1).reshape([batch_size, -1]).to_device(self.device);
+        let c = state.c().transpose(0, 1).reshape([batch_size, -1]).to_device(self.device);
+        (h, c)
+    }
 }
 
-impl ModuleT for ELMo {
+impl ELMo {
+
+    // the outputs of both lms, each of shape (n_lstm_layers + 1, batch_size, seq_length, out_linear) with the char
+    // level embedding as layer 0. The backward lm runs over the inputs reversed in time, and its outputs are
+    // reversed back, so at position t it has seen the inputs t to seq_length - 1. With a mask (batch_size,
+    // seq_length), true on real tokens, each sentence is reversed within its length instead, so the padding of a
+    // padded batch never reaches the backward lm. There is no backward output with native_bidirectional, its
+    // single lm already mixes both directions
+    fn directional_outputs(&self, xs: &Tensor, mask: Option<&Tensor>, train: bool) -> (Tensor, Option<Tensor>) {
 
-    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
-        
         // xs is of shape (batch_size, seq_length, token_length)
         // move through char enconding => (batch_size, seq_length, out_linear)
-        let xs_embedded = &self.char_level.forward_t(xs, train);
+        // the gradient into the char level net is scaled by char_cnn_grad_scale, to balance it with the lstm
+        let xs_embedded = scale_grad(&self.char_level.forward_t(&xs.to_device(self.device), train), self.char_cnn_grad_scale);
+
+        let forward_lm_outs = self.forward_lm.forward_t(&xs_embedded, train);
+        let backward_lm_outs = self.backward_lm.as_ref().map(|backward_lm| match mask {
+            Some(mask) => reverse_within_mask(&backward_lm.forward_t(&reverse_within_mask(&xs_embedded, mask), train), mask),
+            None => backward_lm.forward_t(&xs_embedded.flip([1]), train).flip([2])
+        });
+        (forward_lm_outs, backward_lm_outs)
+    }
+
+    // the elmo representation, the scalar mix of the char level embedding and every lstm layer,
+    // (batch_size, seq_length, out_linear). Each layer is the mean of both directions, which keeps out_linear
+    fn mix(&self, forward_lm_outs: &Tensor, backward_lm_outs: Option<&Tensor>) -> Tensor {
+        let layers = match backward_lm_outs {
+            Some(backward_lm_outs) => (forward_lm_outs + backward_lm_outs) / 2.0,
+            None => forward_lm_outs.shallow_clone()
+        };
+        self.scalar_mix.forward(&layers.unbind(0))
+    }
+
+    fn representation(&self, xs: &Tensor, train: bool) -> Tensor {
+        let (forward_lm_outs, backward_lm_outs) = self.directional_outputs(xs, None, train);
+        self.mix(&forward_lm_outs, backward_lm_outs.as_ref())
+    }
+
+    // the output the next token is predicted from, (batch_size, seq_length, out_linear), the scalar mix of the
+    // forward lm layers alone, the backward lm has already seen the next token. With native_bidirectional the
+    // forward lm layers hold both directions, as they can't be told apart
+    fn next_token_out(&self, forward_lm_outs: &Tensor) -> Tensor {
+        self.mix(forward_lm_outs, None)
+    }
 
-        // xs_embedded should be (batch_size, seq_length, out_linear)
-        let xs_embedded_flip = Tensor::flip(&xs_embedded.to_owned().shallow_clone(), &[0]).to_device(self.device);
+    // the backward lm loss, the mean cross entropy of the previous token predictions, made from the scalar mix of
+    // the backward lm layers. At position t the backward lm predicts the input t - 1, whose id is the label of position t - 2 (labels are the next inputs), so the
+    // positions from 2 are trained. In the stream, the input before a sentence start is SOS, its id is then read
+    // as the EOS label of the sentence before. The padded positions of a padded batch predict nothing. None
+    // without a backward lm or on chunks shorter than 3 tokens
+    fn backward_loss_of(&self, backward_lm_outs: &Tensor, ys: &Tensor) -> Option<Tensor> {
+        let seq_length = ys.size()[1];
+        if seq_length < 3 {
+            return None
+        }
+        let logits = self.mix(backward_lm_outs, None).narrow(1, 2, seq_length - 2).apply(&self.to_vocab).reshape([-1, self.token_vocab_size]);
+        let ys = ys.to_device(self.device);
+        let targets = ys.narrow(1, 0, seq_length - 2).masked_fill(&ys.narrow(1, 2, seq_length - 2).eq(IGNORE_INDEX), IGNORE_INDEX).reshape([-1]);
+        Some(logits.cross_entropy_for_logits(&targets))
+    }
 
-        // both should be (n_lstm_layers, batch_size, seq_length, out_linear)
-        let forward_lm_outs = self.forward_lm.forward_t(xs_embedded, train);
-        let backward_lm_outs = self.backward_lm.forward_t(&xs_embedded_flip, train);
+    // the unweighted token mean losses of the forward lm and of the backward lm (None as in backward_loss_of)
+    pub fn directional_losses(&self, xs: &Tensor, ys: &Tensor, train: bool) -> (Tensor, Option<Tensor>) {
+        let (forward_lm_outs, backward_lm_outs) = self.directional_outputs(xs, Some(&ys.ne(IGNORE_INDEX)), train);
+        let logits = self.next_token_out(&forward_lm_outs).apply(&self.to_vocab).reshape([-1, self.token_vocab_size]);
+        let forward_loss = logits.cross_entropy_for_logits(&ys.to_device(self.device).reshape([-1]));
+        (forward_loss, backward_lm_outs.and_then(|backward_lm_outs| self.backward_loss_of(&backward_lm_outs, ys)))
+    }
 
-        // the elmo representation is a combination of all the outputs (2L) + xs
-        // for the simple case, I take the sum of xs and the two last outputs
-        let forward_last = forward_lm_outs.i(self.n_lstm_layers-1..self.n_lstm_layers).squeeze_dim(0);
-        let backward_last = backward_lm_outs.i(self.n_lstm_layers-1..self.n_lstm_layers).squeeze_dim(0);
+    // the biLM loss, the directional losses weighted by forward_loss_weight and backward_loss_weight
+    pub fn bilm_loss(&self, xs: &Tensor, ys: &Tensor, train: bool) -> Tensor {
+        let (forward_loss, backward_loss) = self.directional_losses(xs, ys, train);
+        let forward_loss = forward_loss * self.forward_loss_weight;
+        match backward_loss {
+            Some(backward_loss) => forward_loss + backward_loss * self.backward_loss_weight,
+            None => forward_loss
+        }
+    }
 
-        // compute output representation as a mix of 3 representations
-        let weights = [0.2, 0.4, 0.4];
-        let out: Tensor = weights[0] * xs_embedded + weights[1] * forward_last + weights[2] * backward_last; 
+    // the outputs of each layer of both directions in eval mode, for a downstream representation layer, each of
+    // shape (n_lstm_layers + 1, batch_size, seq_length, out_linear). Layer 0 is the char level embedding, the
+    // backward outputs are aligned with the inputs. The backward outputs are None with native_bidirectional
+    pub fn directional_layers(&self, xs: &Tensor) -> (Tensor, Option<Tensor>) {
+        tch::no_grad(|| self.directional_outputs(xs, None, false))
+    }
 
-        // The representation transfers to vocabulary size, (batch_size, seq_length, out_linear) => (batch_size, seq_length, token_vocab_size)
+    // the forward and backward outputs of each layer concatenated, (n_lstm_layers + 1, batch_size, seq_length,
+    // 2 * out_linear) as in the elmo paper, the char level embedding is repeated in layer 0. With
+    // native_bidirectional the single lm outputs are returned as they are, of out_linear
+    pub fn bidirectional_layers(&self, xs: &Tensor) -> Tensor {
+        match self.directional_layers(xs) {
+            (forward_lm_outs, Some(backward_lm_outs)) => Tensor::cat(&[forward_lm_outs, backward_lm_outs], 3),
+            (forward_lm_outs, None) => forward_lm_outs
+        }
+    }
+
+    // one vector per sentence in eval mode, the top layer of both directions concatenated (as in
+    // bidirectional_layers) pooled by sentence_pooling. xs is of shape (batch_size, seq_length, token_length) and
+    // mask of shape (batch_size, seq_length), true on real tokens (as from a PaddedLoader). The backward lm runs
+    // over each sentence reversed within its length, so it starts at the last real token and the pads never reach
+    // it. The output is of shape (batch_size, dim), the same for a sentence alone or in a padded batch
+    pub fn sentence_embed(&self, xs: &Tensor, mask: &Tensor) -> Tensor {
+        let top_layer = tch::no_grad(|| match self.directional_outputs(xs, Some(mask), false) {
+            (forward_lm_outs, Some(backward_lm_outs)) => Tensor::cat(&[forward_lm_outs.get(self.n_lstm_layers), backward_lm_outs.get(self.n_lstm_layers)], 2),
+            (forward_lm_outs, None) => forward_lm_outs.get(self.n_lstm_layers)
+        });
+        pool_sentence(&top_layer, mask, &self.sentence_pooling)
+    }
+
+    // warm starts the char embedding from char_embedding_init_file, rows are matched by char2int
+    pub fn init_char_embedding(&self, file_path: &str, char2int: &HashMap<char, usize>) -> Result<usize, Box<dyn Error>> {
+        let vectors = files_handling::load_char_vectors(file_path)?;
+        self.char_level.load_embedding(&vectors, char2int)
+    }
+
+    // log p(token_i | context) of the gold tokens of a single example, in eval mode. xs is of shape
+    // (seq_length, token_length) and ys of shape (seq_length), as from get_example. The output is of
+    // shape (seq_length), its sum is the log likelihood of the sentence, for external scoring
+    pub fn token_log_probs(&self, xs: &Tensor, ys: &Tensor) -> Tensor {
+        tch::no_grad(|| {
+            let logits = self.forward_t(&xs.unsqueeze(0).to_device(self.device), false); // (seq_length, token_vocab_size)
+            let log_probs = logits.log_softmax(-1, logits.kind());
+            log_probs.gather(1, &ys.reshape([-1, 1]).to_device(self.device), false).squeeze_dim(1)
+        })
+    }
+
+    // the outputs of the forward lm in eval mode, the char level embedding first and then the output of each
+    // lstm layer (after the projection and the residual). xs is of shape (batch_size, seq_length, token_length),
+    // the output is (n_lstm_layers + 1, batch_size, seq_length, out_linear)
+    pub fn layer_outputs(&self, xs: &Tensor) -> Tensor {
+        tch::no_grad(|| {
+            let xs_embedded = self.char_level.forward_t(&xs.to_device(self.device), false);
+            self.forward_lm.forward_t(&xs_embedded, false)
+        })
+    }
+
+    // the char backoff loss, for positions whose label is UNK. A rare token gets no credit from the token
+    // softmax, so the next token output is also trained to spell the chars of the token it should predict, which
+    // is the next input token (xs is shifted by one). The last position of a chunk has no next input and is
+    // left out. The loss is the mean char cross entropy over the UNK positions, 0 when there are none, and
+    // None without a char backoff head. out is the next token output, (batch_size, seq_length, out_linear)
+    fn char_backoff_loss_of(&self, out: &Tensor, xs: &Tensor, ys: &Tensor) -> Option<Tensor> {
+
+        let to_chars = self.to_chars.as_ref()?;
+        let seq_length = xs.size()[1];
+        let unk_label = self.unk_id.expect("the char backoff loss needs the unk id, see ELMo::with_unk_id");
+
+        // (batch_size, seq_length - 1, max_len_token, char_vocab_size) against (batch_size, seq_length - 1, max_len_token).
+        // The chars of a padded next position (its label is IGNORE_INDEX) are not targets
+        let labels = ys.narrow(1, 0, seq_length - 1).to_device(self.device);
+        let ignored = labels.eq(IGNORE_INDEX).unsqueeze(-1).expand([-1, -1, self.max_len_token], false).reshape([-1]);
+        let char_logits = out.narrow(1, 0, seq_length - 1).apply(to_chars).reshape([-1, self.char_vocab_size]);
+        let targets = xs.narrow(1, 1, seq_length - 1).to_device(self.device).reshape([-1]).masked_fill(&ignored, IGNORE_INDEX);
+        let char_losses = char_logits.cross_entropy_loss::<Tensor>(&targets, None, tch::Reduction::None, IGNORE_INDEX, 0.0);
+
+        // one weight per char of a target, on UNK positions only
+        let is_unk = labels.eq(unk_label).to_kind(char_losses.kind());
+        let weights = is_unk.unsqueeze(-1).expand([-1, -1, self.max_len_token], false).reshape([-1]);
+        let n_unk = weights.sum(weights.kind()).clamp_min(1.0);
+        Some(self.char_backoff_weight * (char_losses * &weights).sum(weights.kind()) / n_unk)
+    }
+
+    // the weighted char backoff loss of a batch, xs is of shape (batch_size, seq_length, token_length) and ys of
+    // shape (batch_size, seq_length). None if char_backoff_weight is 0
+    pub fn char_backoff_loss(&self, xs: &Tensor, ys: &Tensor, train: bool) -> Option<Tensor> {
+        let (forward_lm_outs, _) = self.directional_outputs(xs, None, train);
+        self.char_backoff_loss_of(&self.next_token_out(&forward_lm_outs), xs, ys)
+    }
+
+    // the entropy of the predictive distribution at each position of a single example, in eval mode. xs is
+    // of shape (seq_length, token_length), as from get_example, the output is of shape (seq_length)
+    pub fn token_entropies(&self, xs: &Tensor) -> Tensor {
+        tch::no_grad(|| logits_entropy(&self.forward_t(&xs.unsqueeze(0).to_device(self.device), false)))
+    }
+
+    // the device the model variables are on
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    // contextual embeddings of a batch of char encoded tokens, computed in eval mode.
+    // xs is of shape (batch_size, seq_length, token_length), the output is (batch_size, seq_length, out_linear)
+    pub fn embed(&self, xs: &Tensor) -> Tensor {
+        tch::no_grad(|| self.representation(&xs.to_device(self.device), false))
+    }
+
+    // traces the char level net in eval mode and saves it as a torchscript module. The trace is of a fixed
+    // input shape, int64 char ids of (batch_size, seq_length, max_len_token), its output is of shape
+    // (batch_size, seq_length, char_level_out_dim). libtorch has no onnx exporter, the saved module converts
+    // to onnx with torch.onnx.export in python (torch.jit.load, then export with an input of the same shape)
+    #[cfg(feature = "char_encoder_export")]
+    pub fn export_char_encoder(&self, output_file: &str, batch_size: i64, seq_length: i64) -> Result<(), Box<dyn Error>> {
+        let xs = Tensor::zeros([batch_size, seq_length, self.max_len_token], (tch::Kind::Int64, self.device));
+        let module = tch::no_grad(|| tch::CModule::create_by_tracing("CharLevelNet", "forward", &[xs], &mut |inputs| {
+            vec![self.char_level.forward_t(&inputs[0], false)]
+        }))?;
+        module.save(output_file)?;
+        Ok(())
+    }
+}
+
+impl ModuleT for ELMo {
+
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        
+        let (forward_lm_outs, _) = self.directional_outputs(xs, None, train);
+        let out = self.next_token_out(&forward_lm_outs);
+
+        // The next token output transfers to vocabulary size, (batch_size, seq_length, out_linear) => (batch_size, seq_length, token_vocab_size)
         // then also unify two first dims for loss computation
         let logits = out.apply(&self.to_vocab).reshape(&[-1, self.token_vocab_size]);
         logits
         
     }
-}
\ No newline at end of file
+}
+
+impl LanguageModel for ELMo {
+
+    // the next token logits of the forward lm, and as auxiliary loss the previous token loss of the backward lm
+    // (the biLM objective adds both directions) plus the char backoff loss, all from a single pass
+    fn forward_with_auxiliary(&self, xs: &Tensor, ys: &Tensor, train: bool) -> (Tensor, Option<Tensor>) {
+        let (forward_lm_outs, backward_lm_outs) = self.directional_outputs(xs, Some(&ys.ne(IGNORE_INDEX)), train);
+        let out = self.next_token_out(&forward_lm_outs);
+        let logits = out.apply(&self.to_vocab).reshape(&[-1, self.token_vocab_size]);
+        let backward_loss = backward_lm_outs.as_ref().and_then(|backward_lm_outs| self.backward_loss_of(backward_lm_outs, ys)).map(|loss| loss * self.backward_loss_weight);
+        let auxiliary_loss = [backward_loss, self.char_backoff_loss_of(&out, xs, ys)].into_iter().flatten().reduce(|total, loss| total + loss);
+        (logits, auxiliary_loss)
+    }
+
+    fn forward_loss_weight(&self) -> f64 {
+        self.forward_loss_weight
+    }
+
+    // with native_bidirectional (no separate backward lm) the reverse direction of a layer has read the next
+    // tokens, the next token predictions see the token they predict. Such a model is for building and probing only
+    fn check_trainable(&self) -> Result<(), Box<dyn Error>> {
+        match self.backward_lm {
+            Some(_) => Ok(()),
+            None => Err("native_bidirectional can't be trained, the next token leaks through the reverse direction, use the separate forward and backward lms".into())
+        }
+    }
+}
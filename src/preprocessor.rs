@@ -3,9 +3,14 @@
 pub mod do_preprocess {
 
     use std::collections::HashMap;
+    use std::ops::Range;
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
     use counter::Counter;
     use itertools::Itertools;
-    use crate::config::JsonELMo;
+    use serde::Serialize;
+    use crate::config::{JsonELMo, files_handling};
 
     // collect a Hashmap into a vector of tuples in reversed key order
     pub(in crate) trait CollectT {
@@ -35,10 +40,270 @@ pub mod do_preprocess {
         }
     }
 
-    pub struct Preprocessor;
+    // the reserved sentence boundaries tokens, added to every sentence if add_sentence_boundaries
+    pub(in crate) const RESERVED_TOKENS: [&str; 2] = ["SOS", "EOS"];
+
+    // the boundary tokens the sentences are padded with, none without add_sentence_boundaries
+    pub(in crate) fn boundary_tokens(add_sentence_boundaries: bool) -> &'static [&'static str] {
+        match add_sentence_boundaries {
+            true => &RESERVED_TOKENS,
+            false => &[]
+        }
+    }
+
+    // with byte_fallback, the 256 byte values have chars of their own in char2int, taken from the supplementary
+    // private use area. A char out of char2int is encoded as the byte chars of its utf-8 bytes
+    pub(in crate) const BYTE_CHARS_START: u32 = 0xF0000;
+
+    pub(in crate) fn byte_char(byte: u8) -> char {
+        char::from_u32(BYTE_CHARS_START + byte as u32).expect("byte chars are valid chars")
+    }
+
+    pub(in crate) fn char_byte(c: char) -> Option<u8> {
+        (c as u32).checked_sub(BYTE_CHARS_START).filter(|b| *b < 256).map(|b| b as u8)
+    }
+
+    // lower case a token when asked, the reserved boundary tokens are kept as they are
+    pub(in crate) fn normalize_token(token: &str, lowercase: bool, reserved: &[&str]) -> String {
+        match lowercase && !reserved.contains(&token) {
+            true => token.to_lowercase(),
+            false => token.to_string()
+        }
+    }
+
+    // the chars kept in the text, from char_whitelist and char_blacklist. When both are set the blacklist wins,
+    // a char is kept if it is in the whitelist and not in the blacklist. Without a whitelist every char not in the
+    // blacklist is kept. The space is always kept, it separates the tokens
+    #[derive(Clone, Debug, Default)]
+    pub(in crate) struct CharFilter {
+        whitelist: Option<Vec<char>>,
+        blacklist: Vec<char>
+    }
+
+    impl CharFilter {
+
+        pub(in crate) fn new(params: &JsonELMo) -> Self {
+            Self {
+                whitelist: params.char_whitelist.as_ref().map(|chars| chars.chars().collect()),
+                blacklist: params.char_blacklist.as_ref().map(|chars| chars.chars().collect()).unwrap_or_default()
+            }
+        }
+
+        pub(in crate) fn keep(&self, c: char) -> bool {
+            c == ' ' || (self.whitelist.as_ref().map(|whitelist| whitelist.contains(&c)).unwrap_or(true) && !self.blacklist.contains(&c))
+        }
+
+        pub(in crate) fn apply(&self, text: &str) -> String {
+            match self.whitelist.is_none() && self.blacklist.is_empty() {
+                true => text.to_string(),
+                false => text.chars().filter(|c| self.keep(*c)).collect()
+            }
+        }
+    }
+
+    // the chars of delimiter separate tokens, they are replaced by the space that separates the tokens of the
+    // prepared sentences, so the space is always a delimiter as well
+    pub(in crate) fn normalize_delimiters(sentence: &str, delimiter: &str) -> String {
+        match delimiter == " " {
+            true => sentence.to_string(),
+            false => sentence.chars().map(|c| if delimiter.contains(c) { ' ' } else { c }).collect()
+        }
+    }
+
+    // the nearest rank percentile of the encoded token lengths of the sentences, each token with its start and
+    // end chars, as counted by max_len_token. With add_sentence_boundaries the boundaries aren't corpus tokens and aren't counted
+    pub fn token_length_percentile(sentences: &[String], percentile: f64, add_sentence_boundaries: bool) -> i64 {
+        let reserved = boundary_tokens(add_sentence_boundaries);
+        let mut lengths = sentences.iter().flat_map(|s| s.split_whitespace().filter(|t| !reserved.contains(t)).map(|t| t.chars().count() as i64 + 2)).collect::<Vec<i64>>();
+        if lengths.is_empty() {
+            return 2
+        }
+        lengths.sort();
+        let rank = ((percentile / 100.0 * lengths.len() as f64).ceil() as usize).clamp(1, lengths.len());
+        lengths[rank - 1]
+    }
+
+    // normalize every token of a space separated sentence, spaces are kept
+    fn normalize_sentence(sentence: &str, lowercase: bool, reserved: &[&str]) -> String {
+        sentence.split(' ').map(|t| normalize_token(t, lowercase, reserved)).collect::<Vec<String>>().join(" ")
+    }
+
+    // splits a space separated sentence to consecutive pieces of at most budget chars (spaces not counted),
+    // a single token longer than the budget is a piece of its own
+    fn split_by_char_budget(sentence: &str, budget: usize) -> Vec<String> {
+        let mut pieces: Vec<Vec<&str>> = vec![];
+        let mut piece_chars = 0;
+        for token in sentence.split(' ') {
+            let token_chars = token.chars().count();
+            match pieces.last_mut() {
+                Some(piece) if piece_chars + token_chars <= budget => piece.push(token),
+                _ => {
+                    pieces.push(vec![token]);
+                    piece_chars = 0;
+                }
+            }
+            piece_chars += token_chars;
+        }
+        pieces.into_iter().map(|piece| piece.join(" ")).collect()
+    }
+
+    // the unicode script of a char, by the blocks of the common scripts. Chars that are not alphabetic (digits,
+    // punctuation, spaces) are Common, and the reserved start, end and unk chars are tagged Reserved by script_partitions
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub enum Script {
+        Common,
+        Latin,
+        Greek,
+        Cyrillic,
+        Hebrew,
+        Arabic,
+        Devanagari,
+        Hangul,
+        Hiragana,
+        Katakana,
+        Han,
+        Other,
+        Reserved
+    }
+
+    pub fn char_script(c: char) -> Script {
+        if !c.is_alphabetic() {
+            return Script::Common
+        }
+        match c as u32 {
+            0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF => Script::Latin,
+            0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x400..=0x52F => Script::Cyrillic,
+            0x590..=0x5FF => Script::Hebrew,
+            0x600..=0x6FF | 0x750..=0x77F => Script::Arabic,
+            0x900..=0x97F => Script::Devanagari,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+            0x3040..=0x309F => Script::Hiragana,
+            0x30A0..=0x30FF => Script::Katakana,
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF => Script::Han,
+            _ => Script::Other
+        }
+    }
+
+    // the runs of consecutive char ids of the same script, in id order. With partition_chars_by_script every
+    // script is a single run, a partition of the char embedding rows (char_script_partitions). The reserved chars
+    // and the byte chars of byte_fallback, that follow them, are tagged Reserved and are the last run
+    pub fn script_partitions(char2int: &HashMap<char, usize>, reserved: &[char]) -> Vec<(Script, Range<usize>)> {
+        let mut partitions: Vec<(Script, Range<usize>)> = Vec::new();
+        for (c, id) in char2int.iter().sorted_by_key(|(_, id)| **id) {
+            let script = match reserved.contains(c) || char_byte(*c).is_some() {
+                true => Script::Reserved,
+                false => char_script(*c)
+            };
+            match partitions.last_mut() {
+                Some((last, range)) if *last == script && range.end == *id => range.end = id + 1,
+                _ => partitions.push((script, *id..id + 1))
+            }
+        }
+        partitions
+    }
+
+    // merges two token2int vocabularies into one, with a remap table (old id => merged id) for each of them,
+    // so embedding rows can be reindexed. The first vocabulary keeps its ids, tokens only in the second one
+    // follow in their original id order. Different tokens that shared an id get distinct merged ids.
+    pub fn merge_vocabs(first: &HashMap<String, usize>, second: &HashMap<String, usize>) -> (HashMap<String, usize>, HashMap<usize, usize>, HashMap<usize, usize>) {
+
+        let by_id = |vocab: &HashMap<String, usize>| vocab.iter().sorted_by_key(|(_, i)| **i).map(|(t, i)| (t.to_string(), *i)).collect::<Vec<(String, usize)>>();
+
+        let mut merged: HashMap<String, usize> = HashMap::new();
+        let mut remaps = Vec::new();
+        for vocab in [first, second] {
+            let mut remap = HashMap::new();
+            for (token, id) in by_id(vocab) {
+                let n = merged.len();
+                let merged_id = *merged.entry(token).or_insert(n);
+                remap.insert(id, merged_id);
+            }
+            remaps.push(remap);
+        }
+
+        let remap_second = remaps.pop().unwrap();
+        let remap_first = remaps.pop().unwrap();
+        (merged, remap_first, remap_second)
+    }
+
+    // writes token frequencies to a tsv of token and count lines, by descending count (ties by token)
+    pub fn write_token_counts(token_counts: &HashMap<String, usize>, output_file: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(output_file)?);
+        for (token, count) in token_counts.iter().sorted_by(|(t1, c1), (t2, c2)| c2.cmp(c1).then(t1.cmp(t2))) {
+            writeln!(writer, "{}\t{}", token, count)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // how well trained vocabularies cover another corpus: the fractions of its token and char occurrences that
+    // are in token2int and char2int, and its most frequent oov tokens with their counts
+    #[derive(Debug, Serialize)]
+    pub struct CoverageReport {
+        pub n_tokens: usize,
+        pub n_chars: usize,
+        pub token_coverage: f64,
+        pub char_coverage: f64,
+        pub oov_tokens: Vec<(String, usize)>
+    }
+
+    // the coverage of the sentences of corpus_file by token2int and char2int. The sentences go through the
+    // preprocessing of training (without sentence boundaries, they are always covered), tokens and chars are
+    // lower cased as for the vocabularies. At most top_n oov tokens are listed, by descending count (ties by
+    // token). An empty corpus is fully covered
+    pub fn coverage_report(token2int: &HashMap<String, usize>, char2int: &HashMap<char, usize>, corpus_file: &str, params: &JsonELMo, top_n: usize) -> Result<CoverageReport, Box<dyn Error>> {
+
+        // the vocabularies were counted with the boundary tokens of training
+        let reserved = boundary_tokens(params.add_sentence_boundaries);
+        let mut params = params.clone();
+        params.add_sentence_boundaries = false;
+        params.max_chars_per_example = None;
+        let mut sentences = files_handling::load_sentences(corpus_file, params.skip_blank_lines)?;
+        Preprocessor::new().prepare_sentences(&mut sentences, &params);
+
+        let tokens = sentences.iter().flat_map(|s| s.split_whitespace()).collect::<Vec<&str>>();
+        let oov_counts = tokens.iter()
+        .map(|t| normalize_token(t, params.lowercase_tokens, reserved))
+        .filter(|t| !token2int.contains_key(t))
+        .collect::<Counter<String>>();
+        let chars = tokens.iter().flat_map(|t| normalize_token(t, params.lowercase_chars, reserved).chars().collect::<Vec<char>>()).collect::<Vec<char>>();
+        let n_oov_chars = chars.iter().filter(|c| !char2int.contains_key(c)).count();
+
+        let coverage = |n_oov: usize, n: usize| if n == 0 { 1.0 } else { 1.0 - n_oov as f64 / n as f64 };
+        let oov_tokens = oov_counts.iter()
+        .sorted_by(|(t1, c1), (t2, c2)| c2.cmp(c1).then(t1.cmp(t2)))
+        .take(top_n)
+        .map(|(t, c)| (t.to_string(), *c))
+        .collect::<Vec<(String, usize)>>();
+
+        Ok(CoverageReport {
+            n_tokens: tokens.len(),
+            n_chars: chars.len(),
+            token_coverage: coverage(oov_counts.values().sum(), tokens.len()),
+            char_coverage: coverage(n_oov_chars, chars.len()),
+            oov_tokens: oov_tokens
+        })
+    }
+
+    // writes a coverage report to a json file
+    pub fn write_coverage_report(report: &CoverageReport, output_file: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(output_file, serde_json::to_string_pretty(report)?)?;
+        Ok(())
+    }
+
+    pub struct Preprocessor {
+        token_counts: HashMap<String, usize>
+    }
     impl Preprocessor {
 
-        pub fn new() -> Self { Self {} }
+        pub fn new() -> Self { Self { token_counts: HashMap::new() } }
+
+        // the frequency of every token counted in the last preprocess, before the vocabulary cutoffs
+        // (token_vocab_size, min_count). Reserved tokens are not counted
+        pub fn token_counts(&self) -> &HashMap<String, usize> {
+            &self.token_counts
+        }
 
         // remove duplicated sentences, mutate the sentences in self
         fn unique(&self, sentences: &mut Vec<String>) {
@@ -46,72 +311,158 @@ pub mod do_preprocess {
             *sentences = reduced_sentences;
         }
 
-        // uses the counter to get a vector of unique words
-        fn count_tokens(&self, sentences: &Vec<String>, token_vocab_size: &mut i64, min_count: i64, str_unk: &str) -> Vec<String> {
+        // uses the counter to get a vector of unique words. The reserved tokens take the first ids,
+        // they are kept regardless of min_count and count towards token_vocab_size
+        fn count_tokens(&mut self, sentences: &Vec<String>, token_vocab_size: &mut i64, min_count: i64, str_unk: &str, reserved: &[&str]) -> Vec<String> {
 
             println!("counting from {} sentences", sentences.len());
             let chunk = sentences.join(" ");
-            let token2count = chunk.split_whitespace().map(|x| x.to_string()).collect::<Counter<_>>();
-            let mut tokens = token2count.k_most_common_ordered(*token_vocab_size as usize);
+            let token2count = chunk.split_whitespace().filter(|x| !reserved.contains(x)).map(|x| x.to_string()).collect::<Counter<_>>();
+            self.token_counts = token2count.iter().map(|(t, c)| (t.to_string(), *c)).collect();
+            let mut tokens = token2count.k_most_common_ordered((*token_vocab_size as usize).saturating_sub(reserved.len()));
             tokens.push((String::from(str_unk), tokens.len()));
-            let tokens = tokens
-            .into_iter()
-            .filter(|(_, c)| *c as i64 >= min_count)
-            .map(|(t, _)| t)
+            let tokens = reserved.iter().map(|t| t.to_string())
+            .chain(tokens
+                .into_iter()
+                .filter(|(_, c)| *c as i64 >= min_count)
+                .map(|(t, _)| t))
             .collect::<Vec<String>>();
             *token_vocab_size = tokens.len() as i64;
             println!("working on token vocab : {}", *token_vocab_size);
             tokens
         }
 
-        // uses the counter to get a vector of unique chars
-        fn count_chars(&self, vocab: &Vec<String>, char_vocab_size: &mut i64, char_start: char, char_end: char, char_unk: char) -> Vec<char> {
+        // uses the counter to get a vector of unique chars. At most char_vocab_size corpus chars are kept, when the
+        // corpus has more the overflow policy either truncates to the most frequent ones (the rest become unk) or fails.
+        // The start, end and unk chars are added after them, then the 256 byte chars with byte_fallback, and
+        // char_vocab_size is updated to the final size
+        fn count_chars(&self, vocab: &Vec<String>, char_vocab_size: &mut i64, char_start: char, char_end: char, char_unk: char, overflow: &str, by_script: bool, byte_fallback: bool) -> Result<Vec<char>, Box<dyn Error>> {
             let char_chunk = vocab.join("");
-            let mut char2count = char_chunk.chars().collect::<Counter<_>>().k_most_common_ordered(*char_vocab_size as usize);
+            let char2count = char_chunk.chars().collect::<Counter<_>>();
+            let n_distinct = char2count.len();
+            if n_distinct > *char_vocab_size as usize {
+                if overflow == "error" {
+                    return Err(format!("the corpus has {} distinct chars, more than char_vocab_size {}", n_distinct, *char_vocab_size).into())
+                }
+                println!("truncating char vocab: the corpus has {} distinct chars, keeping the {} most frequent, the rest are encoded as unk", n_distinct, *char_vocab_size);
+            }
+            let mut char2count = char2count.k_most_common_ordered(*char_vocab_size as usize);
+            // the kept chars are grouped by script (stable, by frequency within a script)
+            if by_script {
+                char2count.sort_by_key(|(c, _)| char_script(*c));
+            }
             let n = char2count.len();
             char2count.extend([(char_start, n), (char_end, 1 + n), (char_unk, 2 + n)]);
+            if byte_fallback {
+                char2count.extend((0..=255u8).map(|byte| (byte_char(byte), 3 + n + byte as usize)));
+            }
             let chars = char2count.into_iter().map(|(c, _)| c).collect::<Vec<char>>();
             *char_vocab_size = chars.len() as i64;
             println!("working on char vocab : {}", *char_vocab_size);
-            chars
+            Ok(chars)
         }
 
 
-        pub fn preprocess(&mut self, sentences: &mut Vec<String>, params: &mut JsonELMo) -> (HashMap<String, usize>, HashMap<char, usize>) {
+        // the string work done on sentences before encoding, without building vocabularies.
+        // Used directly for dev and test corpora, that are encoded with the train vocabularies
+        pub fn prepare_sentences(&mut self, sentences: &mut Vec<String>, params: &JsonELMo) {
 
-            // extract elmo parameters
-            let token_vocab_size = &mut params.token_vocab_size;
-            let char_vocab_size = &mut params.char_vocab_size;
-            let min_count = params.min_count;
             let char_start = params.char_start;
             let char_end = params.char_end;
             let char_unk = params.char_unk;
-            let str_unk = &params.str_unk;
+            let collapse_whitespace = params.collapse_whitespace;
+            let add_sentence_boundaries = params.add_sentence_boundaries;
+            let max_chars_per_example = params.max_chars_per_example;
+            let byte_fallback = params.byte_fallback;
 
             // strip duplicated sentences
             self.unique(sentences);
 
-            // some string work on sentences 
+            // some string work on sentences, first dropping the chars out of the whitelist or in the blacklist
+            let char_filter = CharFilter::new(params);
             sentences.iter_mut().for_each(|s| { 
+                *s = char_filter.apply(s);
+                *s = normalize_delimiters(s, &params.delimiter); // tokens are separated by spaces from here on
                 *s = s.trim_matches(' ').to_string(); // remove leading and trailing spaces
+                if collapse_whitespace {
+                    *s = s.split_whitespace().collect::<Vec<&str>>().join(" "); // collapse repeated whitespace to single spaces
+                }
                 *s = s.chars().filter(|x| x != &char_start && x != &char_end && x != &char_unk).collect::<String>(); // filtering future EOT and SOT chars
-                *s = "SOS ".to_string() + s;             // pad sentences with SOS + EOS symbols 
-                *s += " EOS";
+                if byte_fallback {
+                    *s = s.chars().filter(|x| char_byte(*x).is_none()).collect::<String>(); // and the reserved byte chars
+                }
             });
 
-            // create vocabulary of words
-            let tokens = self.count_tokens(&sentences, token_vocab_size, min_count, str_unk);
+            // a giant line would make a single huge example, split it to examples within the chars budget
+            if let Some(budget) = max_chars_per_example {
+                let mut n_split = 0;
+                *sentences = sentences.iter().flat_map(|s| {
+                    let pieces = split_by_char_budget(s, budget as usize);
+                    n_split += (pieces.len() > 1) as usize;
+                    pieces
+                }).collect();
+                if n_split > 0 {
+                    println!("split {} sentences over max_chars_per_example {}", n_split, budget);
+                }
+            }
+
+            sentences.iter_mut().for_each(|s| {
+                if add_sentence_boundaries {
+                    *s = format!("{} {} {}", RESERVED_TOKENS[0], s, RESERVED_TOKENS[1]); // pad sentences with SOS + EOS symbols
+                }
+            });
+        }
+
+        pub fn preprocess(&mut self, sentences: &mut Vec<String>, params: &mut JsonELMo) -> Result<(HashMap<String, usize>, HashMap<char, usize>), Box<dyn Error>> {
+
+            self.prepare_sentences(sentences, params);
+
+            // max_len_token from the token lengths of the corpus, when a percentile is given. It is at least
+            // the widest conv kernel, that has to fit in a token
+            if let Some(percentile) = params.max_len_token_percentile {
+                let max_kernel_size = params.kernel_size.iter().copied().max().unwrap_or(1);
+                params.max_len_token = token_length_percentile(sentences, percentile, params.add_sentence_boundaries).max(max_kernel_size);
+                println!("resolved max_len_token to {}, the {} percentile of the token lengths", params.max_len_token, percentile);
+            }
+
+            // extract elmo parameters
+            let token_vocab_size = &mut params.token_vocab_size;
+            let char_vocab_size = &mut params.char_vocab_size;
+            let char_vocab_overflow = &params.char_vocab_overflow;
+            let partition_chars_by_script = params.partition_chars_by_script;
+            let min_count = params.min_count;
+            let char_start = params.char_start;
+            let char_end = params.char_end;
+            let char_unk = params.char_unk;
+            let str_unk = &params.str_unk;
+            let lowercase_tokens = params.lowercase_tokens;
+            let lowercase_chars = params.lowercase_chars;
+            let reserved = boundary_tokens(params.add_sentence_boundaries);
+
+            // create vocabulary of words, lower cased for labels if lowercase_tokens
+            let token_sentences = sentences.iter().map(|s| normalize_sentence(s, lowercase_tokens, reserved)).collect::<Vec<String>>();
+            let tokens = self.count_tokens(&token_sentences, token_vocab_size, min_count, str_unk, reserved);
             let token2int: HashMap<String, usize> = <String as CollectT>::collect_gen(tokens);
 
-            // create vocabulary of chars
-            let chars = self.count_chars(&sentences, char_vocab_size, char_start, char_end, char_unk);
+            // create vocabulary of chars, lower cased for char inputs if lowercase_chars
+            let char_sentences = sentences.iter().map(|s| normalize_sentence(s, lowercase_chars, reserved)).collect::<Vec<String>>();
+            let chars = self.count_chars(&char_sentences, char_vocab_size, char_start, char_end, char_unk, char_vocab_overflow, partition_chars_by_script, params.byte_fallback)?;
             let char2int: HashMap<char, usize> = <char as CollectT>::collect_gen(chars);
+            if char2int.len() as i64 != *char_vocab_size {
+                return Err(format!("the char embedding has char_vocab_size {} rows, one per char id, but there are {} char ids", *char_vocab_size, char2int.len()).into())
+            }
+
+            // the sizes of the per script partitions of the char embedding, in id order
+            params.char_script_partitions = match partition_chars_by_script {
+                true => Some(script_partitions(&char2int, &[char_start, char_end, char_unk]).into_iter().map(|(_, range)| range.len() as i64).collect()),
+                false => None
+            };
 
             // token2int is bound with vocab_size tokens, minimum occurrences of min count. 
-            // It countains and SOS, EOS and UNK tokens. 
+            // It countains UNK token, and SOS, EOS tokens (ids 0 and 1) if add_sentence_boundaries.
             // char2int has all the chars in the corpus + start + end chars + unk char, that has been filtered from the sentences.
             
-            (token2int, char2int)
+            Ok((token2int, char2int))
         }
 
     }
@@ -1,25 +1,53 @@
 
 pub mod training {
 
+    use std::collections::{BTreeMap, HashMap};
     use std::error::Error;
     use std::fmt::Display;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
     use std::ops::Add;
-    use std::time::Instant;
-    use tch::{Tensor, Kind};
-    use tch::nn::{VarStore, ModuleT, Optimizer, Adam, OptimizerConfig};
-    use crate::config::JsonELMo;
-    use crate::{ELMo, Loader};
+    use std::time::{Duration, Instant};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use tch::{Device, Tensor, Kind};
+    use tch::nn::{VarStore, ModuleT, Optimizer, Adam, Sgd, RmsProp, OptimizerConfig};
+    use crate::model::LanguageModel;
+    use crate::config::{JsonELMo, OPTIMIZERS};
+    use crate::{DatasetBuilder, ELMo, ELMoText, Loader, Preprocessor, files_handling};
+    use crate::archiving::{self, ModelArchive};
+    use crate::preprocessor::do_preprocess::RESERVED_TOKENS;
+    use crate::loader::data_loading::IGNORE_INDEX;
+
+    // the options of a train step: (optimizer, grad_clip_norm, loss_reduction, unk_loss_weight, unk_id)
+    pub type StepOptions<'a> = (&'a mut Optimizer, Option<f64>, &'a str, f64, Option<i64>);
 
     pub trait TrainModel {
         
         // train forces (x,y) labels (classification)
-        fn train(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, learning_rate: f64, max_iter: i64, model: &impl ModuleT, vars: &mut VarStore, clip_norm: f64, save_model: Option<String>, to_break_early: bool) -> Result<(), Box<dyn Error>>;
-        fn validate(&self, devset_iter: &mut Loader, model: &impl ModuleT) -> (f64, f64);
-        fn step(&self, xs: Tensor, ys: Tensor, model: &impl ModuleT, loss: &mut f64, accuracy: &mut f64, opt_vars: Option<(&mut Optimizer, f64)>);       
+        fn train(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, model: &impl LanguageModel, vars: &mut VarStore, params: &JsonELMo) -> Result<TrainingProgress, Box<dyn Error>>;
+        fn validate(&self, devset_iter: &mut Loader, model: &impl LanguageModel) -> (f64, f64, f64);
+        fn step(&self, xs: Tensor, ys: Tensor, model: &impl LanguageModel, loss: &mut f64, accuracy: &mut f64, opt_vars: Option<StepOptions>);       
         fn predict(&self, targets: &Tensor, logits: &Tensor) -> f64;
-        fn init_optimizer(&self, vars: &VarStore, learning_rate: f64) -> Result<Optimizer, Box<dyn Error>>;
+        fn init_optimizer(&self, vars: &VarStore, learning_rate: f64, optimizer: &str, momentum: Option<f64>) -> Result<Optimizer, Box<dyn Error>>;
         fn break_early(&self, _train_progress: &TrainingProgress) -> bool { false }
-        fn save_model(&self, out_path: &str, vars: &VarStore) -> Result<(), Box<dyn Error>> { Ok(vars.save(out_path)?) }
+        // the dev loss went up in each of the last patience evaluations, unlike break_early it doesn't look at the train loss
+        fn diverging(&self, train_progress: &TrainingProgress, patience: i64) -> bool {
+            match &train_progress.dev_loss {
+                Some(dev_loss) if dev_loss.len() > patience as usize => dev_loss.windows(2).rev().take(patience as usize).all(|pair| pair[1] > pair[0]),
+                _ => false
+            }
+        }
+        // the dev loss hasn't improved on its best for the last patience evaluations
+        fn patience_exhausted(&self, train_progress: &TrainingProgress, patience: i64) -> bool {
+            match (&train_progress.dev_loss, train_progress.best_dev_index()) {
+                (Some(dev_loss), Some(best)) => (dev_loss.len() - 1 - best) as i64 >= patience,
+                _ => false
+            }
+        }
+        fn target_reached(&self, train_progress: &TrainingProgress, target_metric_value: f64) -> bool { matches!(train_progress.last_perplexity(), Some(p) if p <= target_metric_value) }
+        fn should_checkpoint(&self, best_metric: Option<f64>, metric: f64, min_delta: f64) -> bool { match best_metric { Some(best) => metric < best && best - metric >= min_delta, None => true } }
+        fn save_model(&self, out_path: &str, vars: &VarStore, compress: bool) -> Result<(), Box<dyn Error>> { archiving::save_checkpoint(vars, out_path, compress) }
     }
 
     pub struct ElmoTrainer;
@@ -30,43 +58,188 @@ pub mod training {
              Self {} 
         }
 
-        pub fn run_training(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, model: &ELMo, vars: &mut VarStore, params: &JsonELMo) -> Result<(), Box<dyn Error>> {
+        pub fn run_training(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, model: &ELMo, vars: &mut VarStore, params: &JsonELMo) -> Result<TrainingProgress, Box<dyn Error>> {
 
-            self.train(trainset_iter, devset_iter, params.learning_rate, params.max_iter, model, vars, params.clip_norm, params.output_file.clone(), params.break_early)?;
-            Ok(())
+            let train_progress = self.train(trainset_iter, devset_iter, model, vars, params)?;
+            Ok(train_progress)
         }
 
-        pub fn run_testing(&self, testset_iter: &mut Loader, model: &ELMo) -> Result<f64, Box<dyn Error>> {
-            let (_, acc) = self.validate(testset_iter, model);
-            Ok(acc)
+        // the (loss, accuracy, perplexity) of the model on a test set
+        pub fn run_testing(&self, testset_iter: &mut Loader, model: &ELMo) -> Result<(f64, f64, f64), Box<dyn Error>> {
+            let (loss, acc, perplexity) = self.validate(testset_iter, model);
+            Ok((loss, acc, perplexity))
+        }
+
+        // the mean loss and accuracy per batch and the token perplexity of evaluation batches, in eval mode.
+        // batch_size is the batch size of the loader the batches come from
+        pub fn evaluate_batches(&self, batches: impl Iterator<Item = (Tensor, Tensor)>, batch_size: i64, model: &impl LanguageModel) -> (f64, f64, f64) {
+
+            let mut total = 0.0;
+            let mut loss = 0.0;
+            let mut accuracy = 0.0;
+            let mut token_loss = 0.0;
+            let mut n_tokens = 0.0;
+
+            for (xs, ys) in batches {
+
+                // already in device
+                // xs of shape (sequence_length, max_token_length)
+                // ys of shape (sequence_length)                
+                // the step adds the mean cross entropy of the batch, weighted back by its tokens for the perplexity
+                let batch_tokens = n_labeled(&ys);
+                let loss_before = loss;
+                self.step(xs, ys, model, &mut loss, &mut accuracy, None);
+                token_loss += (loss - loss_before) * batch_tokens;
+                n_tokens += batch_tokens;
+                total += batch_size as f64;
+            }
+
+            (loss / total, accuracy / total, token_perplexity(token_loss, n_tokens))
+
         }
 
     }
 
     impl TrainModel for ElmoTrainer {
         
-        fn train(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, learning_rate: f64, max_iter: i64, model: &impl ModuleT, vars: &mut VarStore, clip_norm: f64, output_file: Option<String>, to_break_early: bool) -> Result<(), Box<dyn Error>> {
+        fn train(&self, trainset_iter: &mut Loader, devset_iter: &mut Option<Loader>, model: &impl LanguageModel, vars: &mut VarStore, params: &JsonELMo) -> Result<TrainingProgress, Box<dyn Error>> {
+
+            model.check_trainable()?;
+
+            // extract training parameters
+            let learning_rate = params.learning_rate;
+            let layer_lr_multipliers = params.layer_lr_multipliers.clone();
+            let max_iter = params.max_iter;
+            let max_steps = params.max_steps;
+            let validation_interval = params.validation_interval;
+            let grad_clip_norm = params.grad_clip_norm;
+            let output_file = params.output_file.clone();
+            let best_checkpoint_file = params.best_checkpoint_file();
+            let checkpoint_min_delta = params.checkpoint_min_delta;
+            let compress_checkpoints = params.compress_checkpoints;
+            let loss_reduction = params.loss_reduction.clone();
+            let unk_loss_weight = params.unk_loss_weight;
+            let unk_id = trainset_iter.unk_id;
+            if unk_loss_weight != 1.0 && unk_id.is_none() {
+                return Err("unk_loss_weight weighs the unk targets, the train loader has no unk_id".into())
+            }
+            let seed = params.seed;
+            let divergence_patience = params.divergence_patience;
+            let patience = params.patience;
+            let checkpoint_every = params.checkpoint_every;
+            let output_dir = params.output_dir();
+            let scheduled_sampling_prob = params.scheduled_sampling_prob;
+            let scheduled_sampling_epochs = params.scheduled_sampling_epochs;
+            let token_encodings = trainset_iter.token_encodings.as_ref().map(|encodings| encodings.shallow_clone());
+            let to_break_early = params.break_early;
+            let target_metric_value = params.target_metric_value;
+            let log_interval = params.log_interval;
+            let log_grad_norms = params.log_grad_norms;
+            let grad_cosine_samples = params.grad_cosine_samples;
+            let log_memory = params.log_memory;
             
-            let mut opt = self.init_optimizer(&vars, learning_rate)?;
+            let mut opt = self.init_optimizer(&vars, learning_rate, &params.optimizer, params.momentum)?;
+            for (group, group_learning_rate) in group_learning_rates(learning_rate, &layer_lr_multipliers).into_iter().enumerate() {
+                opt.set_lr_group(group, group_learning_rate);
+            }
             let mut train_progress = match devset_iter {
                 Some(_) => TrainingProgress::init_with_dev(),
                 None => TrainingProgress::init_no_dev()
             };
+            let mut global_step: i64 = 0;
+            let mut start_epoch = 0;
+            let mut best_perplexity: Option<f64> = None;
+            let mut best_snapshot: Option<BTreeMap<String, Tensor>> = None;
+            if let Some(resume_from) = &params.resume_from {
+                archiving::load_checkpoint(vars, resume_from)?;
+                let state = TrainingCheckpoint::load(resume_from)?;
+                println!("resumed training from {} at epoch {} (step {})", resume_from, state.iteration, state.step);
+                start_epoch = state.iteration;
+                global_step = state.step;
+
+                // the progress so far, the best metric and the best variables continue the interrupted run,
+                // the patience and the best checkpoint count the epochs before the checkpoint too
+                if let Some(resumed_progress) = state.train_progress {
+                    if resumed_progress.dev_loss.is_some() != devset_iter.is_some() {
+                        return Err(format!("{} was trained {} a dev set, it can't be resumed {} one", resume_from, if devset_iter.is_some() { "without" } else { "with" }, if devset_iter.is_some() { "with" } else { "without" }).into())
+                    }
+                    train_progress = resumed_progress;
+                }
+                best_perplexity = state.best_perplexity;
+                best_snapshot = TrainingCheckpoint::load_best_snapshot(resume_from)?;
+            }
+            let mut steps_exhausted = false;
+            let mut last_validation: Option<(f64, f64, f64)> = None;
+            let mut next_epoch = start_epoch;
+            if patience > 0 && devset_iter.is_none() {
+                println!("early stopping on patience {} is disabled without a dev set", patience);
+            }
             
-            for epoch in 0..max_iter {
+            for epoch in start_epoch..max_iter {
+
+                // with a seed, the shuffling and the dropout of every epoch follow from (seed, epoch) alone
+                if let Some(seed) = seed {
+                    RngState { seed: seed, epoch: epoch }.restore();
+                }
 
                 let timer = Instant::now();
                 let mut total = 0.0;
                 let mut epoch_loss = 0.0;
                 let mut epoch_accuracy = 0.0;
+                let mut epoch_token_loss = 0.0;
+                let mut epoch_tokens = 0.0;
                 let batch_size = (&trainset_iter).batch_size;
+                let sampling_prob = sampling_schedule(scheduled_sampling_prob, scheduled_sampling_epochs, epoch);
 
-                for (xs, ys) in trainset_iter.shuffle().to_stream().into_iter() {
+                for (xs, ys) in trainset_iter.epoch_order(epoch).batches() {
 
                     // xs of shape (batch_size, seq_length, max_token_length)
                     // ys of shape (batch_size, seq_length)
-                    self.step(xs, ys, model, &mut epoch_loss, &mut epoch_accuracy, Some((&mut opt, clip_norm)));
+                    let xs = match (&token_encodings, sampling_prob > 0.0) {
+                        (Some(token_encodings), true) => {
+                            let logits = tch::no_grad(|| model.forward_t(&xs, false));
+                            scheduled_sampling(&xs, &logits, token_encodings, sampling_prob)
+                        },
+                        _ => xs
+                    };
+                    let n_tokens = n_labeled(&ys);
+                    let loss_before = epoch_loss;
+                    self.step(xs, ys, model, &mut epoch_loss, &mut epoch_accuracy, Some((&mut opt, grad_clip_norm, loss_reduction.as_str(), unk_loss_weight, unk_id)));
+                    epoch_token_loss += (epoch_loss - loss_before) * n_tokens;
+                    epoch_tokens += n_tokens;
                     total += batch_size as f64;
+                    global_step += 1;
+
+                    // gradients are kept after the step, log their norm per submodule for debugging
+                    if log_grad_norms && global_step % log_interval == 0 {
+                        for (module, norm) in grad_norms(vars) {
+                            println!("step: {}, grad norm {}: {}", global_step, module, norm);
+                        }
+                    }
+                    if let (Some(n_samples), true) = (grad_cosine_samples, global_step % log_interval == 0) {
+                        for (first, second, cosine) in layer_grad_cosines(vars, n_samples) {
+                            println!("step: {}, grad cosine {} / {}: {}", global_step, first, second, cosine);
+                        }
+                    }
+                    if log_memory && global_step % log_interval == 0 {
+                        log_memory_usage(global_step, vars.device())?;
+                    }
+
+                    // with a steps interval the dev set is evaluated mid epoch, epochs record the latest evaluation
+                    if let (Some(dev_iter), Some(interval)) = (devset_iter.as_mut(), validation_interval) {
+                        if global_step % interval == 0 {
+                            let (dev_loss, dev_accuracy, dev_perplexity) = self.validate(dev_iter, model);
+                            println!("step: {}, dev loss: {}, dev acc: {}, dev perplexity: {}", global_step, dev_loss, dev_accuracy, dev_perplexity);
+                            train_progress.validation_step.push(global_step);
+                            last_validation = Some((dev_loss, dev_accuracy, dev_perplexity));
+                        }
+                    }
+
+                    // the steps limit can be reached mid epoch, the partial epoch is still evaluated and recorded
+                    if matches!(max_steps, Some(max_steps) if global_step >= max_steps) {
+                        steps_exhausted = true;
+                        break;
+                    }
                 }
 
                 // update training progress
@@ -75,20 +248,32 @@ pub mod training {
 
                 let mut progress_entry = TrainingProgress {
                     epoch: vec![epoch], 
+                    step: vec![global_step],
                     epoch_loss: vec![epoch_loss], 
                     epoch_accuracy: vec![epoch_accuracy], 
+                    epoch_perplexity: vec![token_perplexity(epoch_token_loss, epoch_tokens)],
                     dev_loss: None, 
                     dev_accuracy: None, 
+                    dev_perplexity: None,
+                    validation_step: vec![],
                     time: vec![timer.elapsed().as_secs() as i64]
                 };
 
                 // add dev set calculation, update and early break
                 if devset_iter.is_some() {
 
+                    // evaluated at the end of every epoch, or of an epoch before the first interval is reached
                     let dev_iter = devset_iter.as_mut().unwrap();
-                    let (dev_loss, dev_accuracy) = self.validate(dev_iter, model);
+                    let (dev_loss, dev_accuracy, dev_perplexity) = match (validation_interval, last_validation) {
+                        (Some(_), Some(validation)) => validation,
+                        _ => {
+                            progress_entry.validation_step.push(global_step);
+                            self.validate(dev_iter, model)
+                        }
+                    };
                     progress_entry.dev_loss = Some(vec![dev_loss]);
                     progress_entry.dev_accuracy = Some(vec![dev_accuracy]);
+                    progress_entry.dev_perplexity = Some(vec![dev_perplexity]);
 
                     if to_break_early && self.break_early(&train_progress) {
                         break;
@@ -99,60 +284,151 @@ pub mod training {
                 // print progress
                 train_progress = train_progress.add(progress_entry);
                 println!("{}", train_progress);
+                next_epoch = epoch + 1;
+
+                // overwrite the best checkpoint only on an improvement of at least checkpoint_min_delta
+                if let (Some(best_file), Some(perplexity)) = (&best_checkpoint_file, train_progress.last_perplexity()) {
+                    if self.should_checkpoint(best_perplexity, perplexity, checkpoint_min_delta) {
+                        self.save_model(best_file, vars, compress_checkpoints)?;
+                        if let Some(seed) = seed {
+                            RngState { seed: seed, epoch: next_epoch }.save(best_file)?;
+                        }
+                        best_perplexity = Some(perplexity);
+                    }
+                }
+
+                // a periodic checkpoint to resume from, in output_dir
+                if let (Some(every), Some(output_dir)) = (checkpoint_every, &output_dir) {
+                    if next_epoch % every == 0 {
+                        let checkpoint_file = format!("{}/checkpoint_{}.ot", output_dir, next_epoch);
+                        self.save_model(&checkpoint_file, vars, compress_checkpoints)?;
+                        let state = TrainingCheckpoint {
+                            iteration: next_epoch,
+                            step: global_step,
+                            optimizer: params.optimizer.clone(),
+                            best_perplexity: best_perplexity,
+                            train_progress: Some(train_progress.clone())
+                        };
+                        state.save(&checkpoint_file)?;
+                        TrainingCheckpoint::save_best_snapshot(best_snapshot.as_ref(), &checkpoint_file)?;
+                        if let Some(seed) = seed {
+                            RngState { seed: seed, epoch: next_epoch }.save(&checkpoint_file)?;
+                        }
+                    }
+                }
+
+                // stop successfuly once the monitored perplexity crosses the target
+                if let Some(target) = target_metric_value {
+                    if self.target_reached(&train_progress, target) {
+                        println!("reached target perplexity {} at epoch {}", target, epoch);
+                        break;
+                    }
+                }
+
+                // keep a copy of the best variables, and stop after patience evaluations without an improvement
+                if patience > 0 && devset_iter.is_some() {
+                    if train_progress.best_dev_index() == Some(train_progress.epochs() - 1) {
+                        best_snapshot = Some(snapshot_vars(vars));
+                    }
+                    if self.patience_exhausted(&train_progress, patience) {
+                        println!("stopped training, the dev loss didn't improve in {} evaluations, at epoch {}", patience, epoch);
+                        break;
+                    }
+                }
+
+                if let Some(patience) = divergence_patience {
+                    if self.diverging(&train_progress, patience) {
+                        println!("stopped training on a diverging dev loss, it went up in {} evaluations in a row, at epoch {}", patience, epoch);
+                        break;
+                    }
+                }
+
+                if steps_exhausted {
+                    println!("stopped training on max_steps limit ({} steps) at epoch {}", global_step, epoch);
+                    break;
+                }
 
             }
 
-            if output_file.is_some() {
-                self.save_model(output_file.unwrap().as_str(), vars)?;
+            if !steps_exhausted {
+                println!("stopped training on max_iter limit ({} epochs) after {} steps", max_iter, global_step);
+            }
+
+            if let Some(target) = target_metric_value {
+                println!("target perplexity {} was met: {}", target, self.target_reached(&train_progress, target));
+            }
+
+            // training ends with the best model on dev, also when it wasn't stopped early
+            if let (Some(snapshot), Some(best)) = (&best_snapshot, train_progress.best_dev_index()) {
+                restore_vars(vars, snapshot);
+                println!("restored the best model, found at epoch {} with dev loss {}", train_progress.epoch[best], train_progress.dev_loss.as_ref().unwrap()[best]);
+            }
+
+            if let Some(output_file) = &output_file {
+                self.save_model(output_file, vars, compress_checkpoints)?;
+                if let Some(seed) = seed {
+                    RngState { seed: seed, epoch: next_epoch }.save(output_file)?;
+                }
             }
             
             println!("finished training");
-            Ok(())
+            Ok(train_progress)
 
         
         }
 
-        fn step(&self, xs: Tensor, ys: Tensor, model: &impl ModuleT, loss: &mut f64, accuracy: &mut f64, opt_vars: Option<(&mut Optimizer, f64)>) {
+        fn step(&self, xs: Tensor, ys: Tensor, model: &impl LanguageModel, loss: &mut f64, accuracy: &mut f64, opt_vars: Option<StepOptions>) {
             
             let train_mode = match &opt_vars {
                 Some(_) => true,
                 None => false
             };
 
-            let logits = model.forward_t(&xs, train_mode); // move throught model...
+            let (logits, auxiliary_loss) = model.forward_with_auxiliary(&xs, &ys, train_mode); // move throught model...
             // logits of shape (batch_size * seq_length, token_vocab_size), match the targets to that shape
             let targets = ys.reshape(&[-1]);
             let batch_loss = logits.cross_entropy_for_logits(&targets);
             if train_mode {
                 let opt_vars = opt_vars.unwrap();
                 let opt = opt_vars.0;
-                let _clip_norm = opt_vars.1;
-                opt.backward_step(&batch_loss);
-                //opt.backward_step_clip(&batch_loss, _clip_norm);
+                let grad_clip_norm = opt_vars.1;
+                let loss_reduction = opt_vars.2;
+                let unk_loss_weight = opt_vars.3;
+                let unk_id = opt_vars.4;
+                // the trained loss follows loss_reduction and unk_loss_weight (on the targets of unk_id), the reported loss
+                // is always the token mean. The auxiliary loss is trained, but the reported loss is of the forward language
+                // model alone (unweighted)
+                let train_loss = match (loss_reduction, unk_id.filter(|_| unk_loss_weight != 1.0)) {
+                    ("token_mean", None) => batch_loss.shallow_clone(),
+                    (_, unk_id) => {
+                        let token_losses = logits.log_softmax(-1, logits.kind()).nll_loss_nd::<Tensor>(&targets, None, tch::Reduction::None, -100);
+                        let token_losses = match unk_id {
+                            Some(unk_id) => unk_weighted_losses(&token_losses, &targets, unk_id, unk_loss_weight),
+                            None => token_losses
+                        };
+                        reduce_loss(&token_losses, &targets, ys.size()[0], loss_reduction)
+                    }
+                } * model.forward_loss_weight();
+                let train_loss = match auxiliary_loss {
+                    Some(auxiliary_loss) => &train_loss + auxiliary_loss,
+                    None => train_loss
+                };
+                // with grad_clip_norm the gradients are rescaled to at most that total norm before the step
+                opt.zero_grad();
+                train_loss.backward();
+                if let Some(max_norm) = grad_clip_norm {
+                    opt.clip_grad_norm(max_norm);
+                }
+                opt.step();
             }
 
             *loss += f64::try_from(batch_loss.mean(Kind::Float)).unwrap();
             *accuracy += self.predict(&targets, &logits);
         }
 
-        fn validate(&self, devset_iter: &mut Loader, model: &impl ModuleT) -> (f64, f64) {
-
-            let mut total = 0.0;
-            let mut loss = 0.0;
-            let mut accuracy = 0.0;
-            let batch_size = (&devset_iter).batch_size;
-
-            for (xs, ys) in devset_iter.shuffle().to_stream().into_iter() {
-
-                // already in device
-                // xs of shape (sequence_length, max_token_length)
-                // ys of shape (sequence_length)                
-                self.step(xs, ys, model, &mut loss, &mut accuracy, None);
-                total += batch_size as f64;
-            }
-
-            (loss / total, accuracy / total)
-
+        fn validate(&self, devset_iter: &mut Loader, model: &impl LanguageModel) -> (f64, f64, f64) {
+            let batch_size = devset_iter.batch_size;
+            self.evaluate_batches(devset_iter.eval_batches(), batch_size, model)
         }
 
         fn predict(&self, targets: &Tensor, logits: &Tensor) -> f64 {
@@ -160,16 +436,23 @@ pub mod training {
             // targets are of shape (batch_size * sequence_length)
             // logits are of shape (batch_size * sequence_length, vocab_size)
 
-            // create predictions from logits based on argmax
+            // create predictions from logits based on argmax, the padded positions (IGNORE_INDEX) are left out
             let predictions = logits.argmax(1, false);
-            let compare = predictions.eq_tensor(targets);
-            let accuracy = compare.mean(Kind::Float).double_value(&[]);
+            let labeled = targets.ne(IGNORE_INDEX);
+            let compare = predictions.eq_tensor(targets).logical_and(&labeled);
+            let accuracy = compare.sum(Kind::Float).double_value(&[]) / n_labeled(targets).max(1.0);
             accuracy
         }
 
-        fn init_optimizer(&self, vars: &VarStore, learning_rate: f64) -> Result<Optimizer, Box<dyn Error>> {
+        // one of OPTIMIZERS with its default settings, momentum is read by sgd only
+        fn init_optimizer(&self, vars: &VarStore, learning_rate: f64, optimizer: &str, momentum: Option<f64>) -> Result<Optimizer, Box<dyn Error>> {
 
-            let opt: Optimizer = Adam::default().build(&vars, learning_rate)?;
+            let opt: Optimizer = match optimizer {
+                "adam" => Adam::default().build(vars, learning_rate)?,
+                "sgd" => Sgd { momentum: momentum.unwrap_or(0.0), ..Default::default() }.build(vars, learning_rate)?,
+                "rmsprop" => RmsProp::default().build(vars, learning_rate)?,
+                _ => return Err(format!("optimizer should be one of {}, got {}", OPTIMIZERS.join(", "), optimizer).into())
+            };
             Ok(opt)
 
         }
@@ -199,37 +482,647 @@ pub mod training {
     }
 
 
-    #[derive(Debug)]
+    // a detached copy of every variable, by name
+    pub fn snapshot_vars(vars: &VarStore) -> BTreeMap<String, Tensor> {
+        vars.variables().into_iter().map(|(name, var)| (name, var.detach().copy())).collect()
+    }
+
+    // copies a snapshot back into the variables of the same names
+    pub fn restore_vars(vars: &mut VarStore, snapshot: &BTreeMap<String, Tensor>) {
+        for (name, mut var) in vars.variables() {
+            if let Some(tensor) = snapshot.get(&name) {
+                tch::no_grad(|| var.copy_(tensor));
+            }
+        }
+    }
+
+    // the workspace config cuBLAS needs for deterministic results, see the CUDA docs on reproducibility
+    pub const CUBLAS_WORKSPACE_CONFIG: &str = ":4096:8";
+
+    // sets the process up for reproducible runs on GPU, should be called at startup before any cuda work.
+    // It disables cuDNN (its kernels, benchmarked or not, can be nondeterministic) and its benchmark mode, the
+    // ops fall back to the native cuda kernels. Expect slower GPU training. cuBLAS reads CUBLAS_WORKSPACE_CONFIG
+    // when the cuda context is created, which can happen before this call (say by the cuda device check of the
+    // config), so it should be exported before the process starts: CUBLAS_WORKSPACE_CONFIG=:4096:8. It is set
+    // here as well, with a warning when it wasn't exported. tch has no binding for the libtorch
+    // use_deterministic_algorithms switch, so other ops without a deterministic implementation stay as they
+    // are. On CPU it has no effect on the results.
+    pub fn set_deterministic(deterministic: bool) {
+        if deterministic && std::env::var("CUBLAS_WORKSPACE_CONFIG").is_err() {
+            println!("warning: CUBLAS_WORKSPACE_CONFIG wasn't exported, cuBLAS may ignore it, export CUBLAS_WORKSPACE_CONFIG={} before the run", CUBLAS_WORKSPACE_CONFIG);
+            std::env::set_var("CUBLAS_WORKSPACE_CONFIG", CUBLAS_WORKSPACE_CONFIG);
+        }
+        tch::Cuda::set_user_enabled_cudnn(!deterministic);
+        tch::Cuda::cudnn_set_benchmark(!deterministic);
+    }
+
+    // the compute capability (major.minor) of a cuda device. The tch bindings don't expose it, so it is read
+    // from nvidia-smi. None on cpu, or when nvidia-smi is missing or can't report it
+    pub fn compute_capability(device: Device) -> Option<f64> {
+        let index = match device {
+            Device::Cuda(index) => index,
+            _ => return None
+        };
+        let output = std::process::Command::new("nvidia-smi")
+            .args(["--query-gpu=compute_cap", "--format=csv,noheader", &format!("--id={}", index)])
+            .output().ok()?;
+        if !output.status.success() {
+            return None
+        }
+        String::from_utf8(output.stdout).ok()?.trim().parse::<f64>().ok()
+    }
+
+    // checks the device against a minimum compute capability, old gpus otherwise fail later in libtorch kernels.
+    // action is warn (print and go on) or error. Returns the capability found, nothing is checked when it is unknown
+    pub fn check_compute_capability(device: Device, min_compute_capability: f64, action: &str) -> Result<Option<f64>, Box<dyn Error>> {
+        let capability = match compute_capability(device) {
+            Some(capability) => capability,
+            None => {
+                if device.is_cuda() {
+                    println!("couldn't read the compute capability of {:?}, skipping the check", device);
+                }
+                return Ok(None)
+            }
+        };
+        if capability < min_compute_capability {
+            let message = format!("{:?} has compute capability {}, below the minimum of {}", device, capability, min_compute_capability);
+            match action {
+                "error" => return Err(message.into()),
+                _ => println!("warning: {}", message)
+            }
+        }
+        Ok(Some(capability))
+    }
+
+    // runs build on device. When it fails on a cuda device, by an error or by a libtorch panic (driver issues
+    // can make cuda look available and then fail on the first allocation), and cpu_fallback is set, build is
+    // retried on the cpu with a warning. Returns what was built and the device it was built on
+    pub fn build_with_cpu_fallback<T>(device: Device, cpu_fallback: bool, build: impl Fn(Device) -> Result<T, Box<dyn Error>>) -> Result<(T, Device), Box<dyn Error>> {
+        let error: Box<dyn Error> = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| build(device))) {
+            Ok(Ok(built)) => return Ok((built, device)),
+            Ok(Err(e)) => e,
+            Err(payload) => match payload.downcast::<String>() {
+                Ok(message) => (*message).into(),
+                Err(payload) => payload.downcast::<&str>().map(|message| (*message).into()).unwrap_or_else(|_| "unknown panic".into())
+            }
+        };
+        if !device.is_cuda() || !cpu_fallback {
+            return Err(error)
+        }
+        println!("warning: failed on {:?} ({}), falling back to cpu", device, error);
+        Ok((build(Device::Cpu)?, Device::Cpu))
+    }
+
+    // the (current, peak) resident memory of the process in bytes, read from /proc/self/status (VmRSS, VmHWM),
+    // so it is available on linux only
+    pub fn memory_usage() -> Result<(u64, u64), Box<dyn Error>> {
+        let status = std::fs::read_to_string("/proc/self/status")?;
+        let read_kb = |key: &str| -> Result<u64, Box<dyn Error>> {
+            let line = status.lines().find(|line| line.starts_with(key)).ok_or(format!("no {} in /proc/self/status", key))?;
+            let kb = line.trim_start_matches(key).trim().trim_end_matches("kB").trim().parse::<u64>()?;
+            Ok(kb * 1024)
+        };
+        Ok((read_kb("VmRSS:")?, read_kb("VmHWM:")?))
+    }
+
+    // prints the memory usage at a training step. tch has no binding for the cuda caching allocator stats,
+    // so on GPU the device memory isn't reported, only the host memory of the process
+    pub fn log_memory_usage(step: i64, device: Device) -> Result<(), Box<dyn Error>> {
+        let (current, peak) = memory_usage()?;
+        let mb = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let note = match device {
+            Device::Cuda(_) => " (host only, device memory is not reported)",
+            _ => ""
+        };
+        println!("step: {}, memory{}: current {:.1} MB, peak {:.1} MB", step, note, mb(current), mb(peak));
+        Ok(())
+    }
+
+    // evaluates an archived model on an external corpus, that goes through the same string work as the
+    // train corpus and is encoded with the archived vocabularies. Returns the (loss, accuracy, perplexity) on all of it
+    pub fn evaluate_corpus(archive: &ModelArchive, corpus_file: &str) -> Result<(f64, f64, f64), Box<dyn Error>> {
+
+        let params = &archive.params;
+        let mut sentences = files_handling::load_sentences(corpus_file, params.skip_blank_lines)?;
+        Preprocessor::new().prepare_sentences(&mut sentences, params);
+        let elmo_text = ELMoText::try_new(sentences, archive.token2int.clone(), archive.char2int.clone(), params)?;
+
+        // the corpus is batched as it is encoded, a single pass doesn't need all of its examples at once
+        let mut stream_error = None;
+        let batches = elmo_text.stream_examples(0..elmo_text.get_len()).batches(params).map_while(|batch| batch.map_err(|e| stream_error = Some(e)).ok());
+        let metrics = ElmoTrainer::new().evaluate_batches(batches, params.batch_size, &archive.model);
+        match stream_error {
+            Some(e) => Err(e),
+            None => Ok(metrics)
+        }
+    }
+
+    // exp of the mean cross entropy over the tokens, from the summed token losses. The padded positions of padded
+    // batches aren't tokens, they aren't counted (see n_labeled). Infinite when there are no tokens
+    pub fn token_perplexity(token_loss: f64, n_tokens: f64) -> f64 {
+        match n_tokens > 0.0 {
+            true => (token_loss / n_tokens).exp(),
+            false => f64::INFINITY
+        }
+    }
+
+    // gold token id => (top-1 predicted token id => count)
+    pub type ConfusionReport = BTreeMap<i64, BTreeMap<i64, usize>>;
+
+    // the ids of the n most frequent tokens of the train corpus, by the token counts of the preprocessor
+    // (ties by token). Token ids don't follow the frequency order, tokens out of the vocabulary are skipped
+    pub fn most_frequent_ids(token_counts: &HashMap<String, usize>, token2int: &HashMap<String, usize>, n: usize) -> Vec<i64> {
+        let mut counts = token_counts.iter().filter(|(token, _)| token2int.contains_key(*token)).collect::<Vec<(&String, &usize)>>();
+        counts.sort_by(|(t1, c1), (t2, c2)| c2.cmp(c1).then(t1.cmp(t2)));
+        counts.into_iter().take(n).map(|(token, _)| token2int[token] as i64).collect()
+    }
+
+    // accumulates the top-1 predictions of the model for each gold token, over the loader stream (in its
+    // current order). To bound memory only the tracked tokens (most_frequent_ids) are tracked as gold tokens
+    pub fn confusion_report(model: &ELMo, loader: &mut Loader, tracked: &[i64]) -> ConfusionReport {
+
+        let mut report: ConfusionReport = BTreeMap::new();
+        for (xs, ys) in loader.batches() {
+            let logits = tch::no_grad(|| model.forward_t(&xs, false));
+            let predictions = Vec::<i64>::try_from(&logits.argmax(1, false)).unwrap();
+            let targets = Vec::<i64>::try_from(&ys.reshape([-1])).unwrap();
+            for (target, prediction) in targets.into_iter().zip(predictions) {
+                if tracked.contains(&target) {
+                    *report.entry(target).or_default().entry(prediction).or_default() += 1;
+                }
+            }
+        }
+        report
+    }
+
+    // writes a line per gold token: the token, its count and its predictions by descending count,
+    // tab separated as prediction:count
+    pub fn write_confusion_report(report: &ConfusionReport, elmo_text: &ELMoText, output_file: &str) -> Result<(), Box<dyn Error>> {
+
+        let mut writer = BufWriter::new(File::create(output_file)?);
+        for (target, predictions) in report {
+            let total = predictions.values().sum::<usize>();
+            let mut predictions = predictions.iter().collect::<Vec<(&i64, &usize)>>();
+            predictions.sort_by(|(p1, c1), (p2, c2)| c2.cmp(c1).then(p1.cmp(p2)));
+            let predictions = predictions.iter().map(|(p, c)| format!("{}:{}", elmo_text.decode_label(**p), c)).collect::<Vec<String>>();
+            writeln!(writer, "{}\t{}\t{}", elmo_text.decode_label(*target), total, predictions.join("\t"))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // free running (not teacher forced) evaluation. For each sentence, the model starts from the first token
+    // and is fed back its own argmax predictions, for up to max_length steps. Returns the fraction of steps in
+    // which the prediction agrees with the reference token. It is costly: step t runs the model over t tokens,
+    // so a sentence takes O(max_length^2) token passes instead of a single pass of n tokens.
+    pub fn free_running_agreement(model: &ELMo, elmo_text: &ELMoText, max_length: usize) -> Result<f64, Box<dyn Error>> {
+        let examples = (0..elmo_text.get_len() as usize).map(|index| elmo_text.get_example(index)).collect::<Result<Vec<(Tensor, Tensor)>, Box<dyn Error>>>()?;
+        free_running_agreement_of(model, elmo_text, examples.iter().map(|(xs, ys)| (xs, ys)), max_length)
+    }
+
+    // as free_running_agreement, on the examples of a loader, as the dev split of the splitter. elmo_text
+    // (of the same vocabularies) encodes and decodes the predicted tokens
+    pub fn loader_free_running_agreement(model: &ELMo, elmo_text: &ELMoText, loader: &Loader, max_length: usize) -> Result<f64, Box<dyn Error>> {
+        free_running_agreement_of(model, elmo_text, loader.examples(), max_length)
+    }
+
+    fn free_running_agreement_of<'a>(model: &ELMo, elmo_text: &ELMoText, examples: impl Iterator<Item = (&'a Tensor, &'a Tensor)>, max_length: usize) -> Result<f64, Box<dyn Error>> {
+
+        let mut agreed = 0;
+        let mut total = 0;
+        for (inputs, labels) in examples {
+
+            // inputs of shape (sentence_length-1, max_token_length), starting from the first token only
+            let labels = Vec::<i64>::try_from(labels)?;
+            let mut fed = inputs.narrow(0, 0, 1).to_device(Device::Cpu);
+
+            for label in labels.iter().take(max_length) {
+
+                // logits of shape (t, token_vocab_size), the last row predicts the next token
+                let logits = tch::no_grad(|| model.forward_t(&fed.unsqueeze(0).to_device(model.device()), false));
+                let predicted = logits.get(-1).argmax(0, false).int64_value(&[]);
+                agreed += (predicted == *label) as usize;
+                total += 1;
+
+                let predicted_token = elmo_text.decode_label(predicted);
+                fed = Tensor::concat(&[fed, elmo_text.encode_tokens(&[predicted_token])], 0);
+            }
+        }
+
+        Ok(agreed as f64 / total.max(1) as f64)
+    }
+
+    // the n most probable next tokens after a seed sentence, as (token, probability) pairs sorted by descending
+    // probability, in eval mode. The seed goes through the preprocessing of training and the tokenization of
+    // ELMoText (as Embedder::tokens), without an EOS, and it starts with SOS if add_sentence_boundaries
+    pub fn nbest_next_tokens(model: &ELMo, elmo_text: &ELMoText, seed: &str, n: usize, params: &JsonELMo) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+
+        let mut seed_params = params.clone();
+        seed_params.add_sentence_boundaries = false;
+        seed_params.max_chars_per_example = None;
+        let mut sentences = vec![seed.to_string()];
+        Preprocessor::new().prepare_sentences(&mut sentences, &seed_params);
+        let mut tokens = sentences.first().map(|s| elmo_text.tokenize(s)).unwrap_or_default();
+        tokens.retain(|t| !t.is_empty());
+        if params.add_sentence_boundaries && tokens.first().map(|t| t.as_str()) != Some(RESERVED_TOKENS[0]) {
+            tokens.insert(0, RESERVED_TOKENS[0].to_string());
+        }
+        if tokens.is_empty() {
+            return Err("nbest needs a seed of at least one token".into())
+        }
+
+        // logits of shape (n_tokens, token_vocab_size), the last row predicts the token after the seed
+        let xs = elmo_text.encode_tokens(&tokens).unsqueeze(0).to_device(model.device());
+        let logits = tch::no_grad(|| model.forward_t(&xs, false));
+        let probs = logits.get(-1).softmax(0, Kind::Double);
+        let (top_probs, top_ids) = probs.topk(n.min(probs.size()[0] as usize) as i64, 0, true, true);
+
+        let top_probs = Vec::<f64>::try_from(&top_probs)?;
+        let top_ids = Vec::<i64>::try_from(&top_ids)?;
+        Ok(top_ids.into_iter().zip(top_probs).map(|(id, prob)| (elmo_text.decode_label(id), prob)).collect())
+    }
+
+    // the learning rate of each optimizer group. Group 0 has all the variables outside the lstm layers,
+    // group j+1 the lstm_j layers of the lms, their rate is scaled by the j-th multiplier (1 if not given)
+    pub fn group_learning_rates(learning_rate: f64, layer_lr_multipliers: &[f64]) -> Vec<f64> {
+        let mut learning_rates = vec![learning_rate];
+        learning_rates.extend(layer_lr_multipliers.iter().map(|m| learning_rate * m));
+        learning_rates
+    }
+
+    // the submodule a variable belongs to, by its name prefix. For example char_level.highway_0
+    // for char_level.highway_0.w_t.weight, forward_lm.lstm_1 for forward_lm.lstm_1.weight_ih_l0, to_vocab for to_vocab.bias
+    pub fn module_prefix(name: &str) -> String {
+        let components = name.split('.').collect::<Vec<&str>>();
+        let depth = 2.min(components.len().saturating_sub(1)).max(1);
+        components[..depth].join(".")
+    }
+
+    // variable names of the var store bucketed by their submodule prefix
+    pub fn group_by_module(names: &[String]) -> BTreeMap<String, Vec<String>> {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for name in names {
+            groups.entry(module_prefix(name)).or_default().push(name.to_string());
+        }
+        groups.values_mut().for_each(|g| g.sort());
+        groups
+    }
+
+    // the norm of the gradients of each submodule, variables without a gradient are ignored
+    pub fn grad_norms(vars: &VarStore) -> BTreeMap<String, f64> {
+        let variables = vars.variables();
+        let names = variables.keys().cloned().collect::<Vec<String>>();
+        group_by_module(&names).into_iter().map(|(module, names)| {
+            let squared_norm = names.iter()
+            .map(|name| variables[name].grad())
+            .filter(|grad| grad.defined())
+            .map(|grad| grad.norm().double_value(&[]).powi(2))
+            .sum::<f64>();
+            (module, squared_norm.sqrt())
+        }).collect()
+    }
+
+    // the cosine similarity of two flattened gradient vectors, in [-1, 1]. A zero vector has no direction, its
+    // similarity to anything is 0
+    pub fn grad_cosine(first: &Tensor, second: &Tensor) -> f64 {
+        let (first, second) = (first.flatten(0, -1).to_kind(Kind::Double), second.flatten(0, -1).to_kind(Kind::Double));
+        let norms = first.norm().double_value(&[]) * second.norm().double_value(&[]);
+        match norms > 0.0 {
+            true => (first.dot(&second).double_value(&[]) / norms).clamp(-1.0, 1.0),
+            false => 0.0
+        }
+    }
+
+    // the gradients of each lstm layer (forward_lm.lstm_0, ...) flattened, every variable of the layer sampled at
+    // n_samples evenly spaced positions. The layers of an lm have the same shapes, so the samples are aligned
+    // across the layers, and the cost of the cosines doesn't grow with the model
+    pub fn layer_grads(vars: &VarStore, n_samples: i64) -> BTreeMap<String, Tensor> {
+        let variables = vars.variables();
+        let names = variables.keys().cloned().collect::<Vec<String>>();
+        group_by_module(&names).into_iter()
+        .filter(|(module, _)| module.split('.').next_back().map(|last| last.starts_with("lstm_")).unwrap_or(false))
+        .filter_map(|(module, names)| {
+            let samples = names.iter()
+            .map(|name| variables[name].grad())
+            .filter(|grad| grad.defined())
+            .map(|grad| {
+                let grad = grad.flatten(0, -1);
+                let numel = grad.size()[0];
+                let positions = Tensor::linspace(0, numel - 1, n_samples.min(numel), (Kind::Double, grad.device())).to_kind(Kind::Int64);
+                grad.index_select(0, &positions)
+            })
+            .collect::<Vec<Tensor>>();
+            match samples.is_empty() {
+                true => None,
+                false => Some((module, Tensor::cat(&samples, 0)))
+            }
+        }).collect()
+    }
+
+    // the gradient cosine of each pair of consecutive lstm layers of an lm, on n_samples of every variable.
+    // Values near 1 are aligned layers, negative values conflicting ones
+    pub fn layer_grad_cosines(vars: &VarStore, n_samples: i64) -> Vec<(String, String, f64)> {
+        let grads = layer_grads(vars, n_samples).into_iter().collect::<Vec<(String, Tensor)>>();
+        grads.windows(2)
+        .filter(|pair| pair[0].0.split('.').next() == pair[1].0.split('.').next())
+        .map(|pair| (pair[0].0.clone(), pair[1].0.clone(), grad_cosine(&pair[0].1, &pair[1].1)))
+        .collect()
+    }
+
+    // a stable 64 bit FNV-1a hash of a file, to identify the corpus a run was trained on
+    pub fn file_hash(file_path: &str) -> Result<String, Box<dyn Error>> {
+        let bytes = std::fs::read(file_path)?;
+        let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3));
+        Ok(format!("{:016x}", hash))
+    }
+
+    // writes a json summary of a training run, for experiment tracking: the resolved config, the corpus hash,
+    // the vocabulary sizes, the number of parameters, build info, the metrics of the last epoch and the wall
+    // clock time of the run
+    pub fn write_manifest(output_file: &str, params: &JsonELMo, vars: &VarStore, train_progress: &TrainingProgress, wall_clock: Duration) -> Result<(), Box<dyn Error>> {
+
+        let n_parameters: i64 = vars.variables().values().map(|v| v.numel() as i64).sum();
+        let corpus_hash = params.corpus_file.as_ref().map(|corpus_file| file_hash(corpus_file)).transpose()?;
+        let last = |values: &Option<Vec<f64>>| values.as_ref().and_then(|values| values.last().cloned());
+
+        let manifest = json!({
+            "config": params,
+            "corpus_file": params.corpus_file,
+            "corpus_hash": corpus_hash,
+            "token_vocab_size": params.token_vocab_size,
+            "char_vocab_size": params.char_vocab_size,
+            "n_parameters": n_parameters,
+            "build": {
+                "version": env!("CARGO_PKG_VERSION"),
+                "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+                "os": std::env::consts::OS,
+                "arch": std::env::consts::ARCH
+            },
+            "final_metrics": {
+                "epochs": train_progress.epochs(),
+                "steps": train_progress.last_step(),
+                "train_loss": train_progress.epoch_loss.last(),
+                "train_accuracy": train_progress.epoch_accuracy.last(),
+                "dev_loss": last(&train_progress.dev_loss),
+                "dev_accuracy": last(&train_progress.dev_accuracy),
+                "perplexity": train_progress.last_perplexity()
+            },
+            "wall_clock_seconds": wall_clock.as_secs_f64()
+        });
+
+        std::fs::write(output_file, serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+
+    // the rng state of a checkpoint is saved next to it, in a file with this suffix
+    pub const RNG_STATE_SUFFIX: &str = ".rng.json";
+
+    // tch doesn't expose the state of the libtorch generator, so with a seed the generator is reseeded at the
+    // start of every epoch from (seed, epoch). The state at an epoch boundary is then fully described by the seed
+    // and the next epoch, which is what a checkpoint records, and restoring it continues the same randomness.
+    // The optimizer moments aren't saved by tch either, a continuation from a checkpoint restarts them
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct RngState {
+        pub seed: i64,
+        pub epoch: i64
+    }
+
+    impl RngState {
+
+        // the seed of the generator at the start of epoch, mixed so that nearby seeds and epochs don't collide
+        pub fn epoch_seed(&self) -> i64 {
+            let mixed = (self.seed as u64).wrapping_mul(0x9E3779B97F4A7C15) ^ (self.epoch as u64).wrapping_mul(0xC2B2AE3D27D4EB4F);
+            (mixed >> 1) as i64
+        }
+
+        // reseeds the libtorch generators (cpu and cuda) to the state at the start of epoch
+        pub fn restore(&self) {
+            tch::manual_seed(self.epoch_seed());
+        }
+
+        pub fn save(&self, checkpoint_file: &str) -> Result<(), Box<dyn Error>> {
+            std::fs::write(format!("{}{}", checkpoint_file, RNG_STATE_SUFFIX), serde_json::to_string(self)?)?;
+            Ok(())
+        }
+
+        pub fn load(checkpoint_file: &str) -> Result<RngState, Box<dyn Error>> {
+            Ok(serde_json::from_str(&std::fs::read_to_string(format!("{}{}", checkpoint_file, RNG_STATE_SUFFIX))?)?)
+        }
+    }
+
+    // the training state of a periodic checkpoint is saved next to it, in a file with this suffix
+    pub const CHECKPOINT_STATE_SUFFIX: &str = ".state.json";
+
+    // the best variables (patience on a dev set) of a periodic checkpoint are saved next to it, in a file with this suffix
+    pub const BEST_SNAPSHOT_SUFFIX: &str = ".best.ot";
+
+    // where training continues from a periodic checkpoint: the next iteration (epoch) and the optimizer steps
+    // taken so far, the best monitored perplexity and the progress of the epochs before it. The optimizer is
+    // only a hint, its state isn't saved by tch and restarts on resume. Checkpoints of older versions have no
+    // best perplexity or progress, their resumed runs start these over
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TrainingCheckpoint {
+        pub iteration: i64,
+        pub step: i64,
+        pub optimizer: String,
+        #[serde(default)]
+        pub best_perplexity: Option<f64>,
+        #[serde(default)]
+        pub train_progress: Option<TrainingProgress>
+    }
+
+    impl TrainingCheckpoint {
+
+        pub fn save(&self, checkpoint_file: &str) -> Result<(), Box<dyn Error>> {
+            std::fs::write(format!("{}{}", checkpoint_file, CHECKPOINT_STATE_SUFFIX), serde_json::to_string(self)?)?;
+            Ok(())
+        }
+
+        pub fn load(checkpoint_file: &str) -> Result<TrainingCheckpoint, Box<dyn Error>> {
+            Ok(serde_json::from_str(&std::fs::read_to_string(format!("{}{}", checkpoint_file, CHECKPOINT_STATE_SUFFIX))?)?)
+        }
+
+        // saves the best variables so far, when kept, and removes a stale file of an earlier run otherwise
+        pub fn save_best_snapshot(snapshot: Option<&BTreeMap<String, Tensor>>, checkpoint_file: &str) -> Result<(), Box<dyn Error>> {
+            let snapshot_file = format!("{}{}", checkpoint_file, BEST_SNAPSHOT_SUFFIX);
+            match snapshot {
+                Some(snapshot) => Tensor::save_multi(&snapshot.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect::<Vec<_>>(), &snapshot_file)?,
+                None if std::path::Path::new(&snapshot_file).exists() => std::fs::remove_file(&snapshot_file)?,
+                None => ()
+            }
+            Ok(())
+        }
+
+        pub fn load_best_snapshot(checkpoint_file: &str) -> Result<Option<BTreeMap<String, Tensor>>, Box<dyn Error>> {
+            let snapshot_file = format!("{}{}", checkpoint_file, BEST_SNAPSHOT_SUFFIX);
+            match std::path::Path::new(&snapshot_file).exists() {
+                true => Ok(Some(Tensor::load_multi(&snapshot_file)?.into_iter().collect())),
+                false => Ok(None)
+            }
+        }
+    }
+
+    // the scheduled sampling probability of an epoch, it grows linearly from 0 in the first epoch (pure teacher
+    // forcing) to max_prob at epoch ramp_epochs, and stays there
+    pub fn sampling_schedule(max_prob: f64, ramp_epochs: i64, epoch: i64) -> f64 {
+        max_prob * (epoch as f64 / ramp_epochs as f64).min(1.0)
+    }
+
+    // scheduled sampling: each input token after the first is replaced, with probability p, by the token the model
+    // predicted at the previous position (logits of a pass over the gold inputs, (batch_size * seq_length, vocab)).
+    // Only the autoregressive input changes, the labels stay the gold tokens. The backward lm reads the same inputs,
+    // so the config requires backward_loss_weight 0 with it (and no char backoff). token_encodings are the char
+    // encodings of the vocabulary (ELMoText::token_encodings), xs is of shape (batch_size, seq_length, max_len_token)
+    pub fn scheduled_sampling(xs: &Tensor, logits: &Tensor, token_encodings: &Tensor, p: f64) -> Tensor {
+        let dims = xs.size();
+        if p <= 0.0 || dims[1] < 2 {
+            return xs.shallow_clone()
+        }
+        let predicted = logits.argmax(-1, false).reshape([-1]);
+        let sampled = token_encodings.index_select(0, &predicted).reshape([dims[0], dims[1], dims[2]]).narrow(1, 0, dims[1] - 1);
+        let gold = xs.narrow(1, 1, dims[1] - 1);
+        let replaced = Tensor::empty([dims[0], dims[1] - 1, 1], (Kind::Float, xs.device())).bernoulli_float_(p).to_kind(Kind::Bool);
+        let next_inputs = sampled.to_kind(xs.kind()).where_self(&replaced, &gold);
+        Tensor::cat(&[xs.narrow(1, 0, 1), next_inputs], 1)
+    }
+
+    // reduces the per token losses of a batch of n_rows sequences, of shape (n_rows * seq_length), to the trained
+    // loss. token_mean averages over all tokens. sentence_mean averages the mean of each row, the rows of stream
+    // batches have the same length so it equals token_mean, and it only differs if that changes. sum adds the
+    // token losses, its gradients are n_rows * seq_length times those of token_mean, so they grow with the batch
+    // size (and the final partial batch of an epoch weighs less); adam is mostly invariant to that scale, sgd is
+    // not. The positions of an IGNORE_INDEX target (padding) are left out of the means
+    pub fn reduce_loss(token_losses: &Tensor, targets: &Tensor, n_rows: i64, loss_reduction: &str) -> Tensor {
+        let labeled = targets.ne(IGNORE_INDEX).to_kind(token_losses.kind()).reshape(token_losses.size());
+        let token_losses = token_losses * &labeled;
+        match loss_reduction {
+            "sum" => token_losses.sum(token_losses.kind()),
+            "sentence_mean" => {
+                let row_tokens = labeled.reshape([n_rows, -1]).sum_dim_intlist([1].as_slice(), false, labeled.kind()).clamp_min(1.0);
+                (token_losses.reshape([n_rows, -1]).sum_dim_intlist([1].as_slice(), false, token_losses.kind()) / row_tokens).mean(token_losses.kind())
+            },
+            _ => token_losses.sum(token_losses.kind()) / labeled.sum(labeled.kind()).clamp_min(1.0)
+        }
+    }
+
+    // the number of labeled positions of a batch of targets, the padded positions (IGNORE_INDEX) aren't counted
+    pub fn n_labeled(targets: &Tensor) -> f64 {
+        targets.ne(IGNORE_INDEX).sum(Kind::Int64).double_value(&[])
+    }
+
+    // the token losses with the positions of an unk target scaled by unk_loss_weight, the others are kept. Below 1
+    // it discourages predicting unk, on a corpus with many oov tokens the model would otherwise default to it.
+    // unk_id is the id of str_unk in token2int (Loader::unk_id)
+    pub fn unk_weighted_losses(token_losses: &Tensor, targets: &Tensor, unk_id: i64, unk_loss_weight: f64) -> Tensor {
+        let weights = targets.eq(unk_id).to_kind(token_losses.kind()) * (unk_loss_weight - 1.0) + 1.0;
+        token_losses * weights.reshape(token_losses.size())
+    }
+
+    // the variables of the scalar mix, its raw per layer weights (before the softmax) and its gamma
+    pub const SCALAR_MIX_WEIGHTS: &str = "scalar_mix.weights";
+    pub const SCALAR_MIX_GAMMA: &str = "scalar_mix.gamma";
+
+    // writes the scalar mix of the model to a json file: gamma, and per layer (the char level layer first, then
+    // each lstm layer) its raw weight and its softmax normalized weight, which tells how much the layer matters.
+    // The mix is trained with the lm loss, before any training the weights are uniform and gamma is 1.
+    // Errors if the variables have no scalar mix
+    pub fn export_scalar_mix(output_file: &str, vars: &VarStore) -> Result<(), Box<dyn Error>> {
+
+        let variables = vars.variables();
+        let raw_weights = variables.get(SCALAR_MIX_WEIGHTS).ok_or("the model has no scalar mix weights")?.to_kind(Kind::Double).to_device(Device::Cpu);
+        let gamma = variables.get(SCALAR_MIX_GAMMA).ok_or("the model has no scalar mix gamma")?.to_kind(Kind::Double).to_device(Device::Cpu);
+        let weights = Vec::<f64>::try_from(&raw_weights.softmax(0, Kind::Double))?;
+        let raw_weights = Vec::<f64>::try_from(&raw_weights)?;
+
+        let layers = raw_weights.iter().zip(weights).enumerate().map(|(layer, (raw_weight, weight))| json!({
+            "layer": layer,
+            "raw_weight": raw_weight,
+            "weight": weight
+        })).collect::<Vec<_>>();
+        let scalar_mix = json!({
+            "gamma": gamma.double_value(&[0]),
+            "layers": layers
+        });
+
+        std::fs::write(output_file, serde_json::to_string_pretty(&scalar_mix)?)?;
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct TrainingProgress {
         epoch: Vec<i64>,
+        step: Vec<i64>,
         epoch_loss: Vec<f64>,
         epoch_accuracy: Vec<f64>,
+        epoch_perplexity: Vec<f64>,
         dev_loss: Option<Vec<f64>>,
         dev_accuracy: Option<Vec<f64>>,
+        dev_perplexity: Option<Vec<f64>>,
+        validation_step: Vec<i64>,
         time: Vec<i64>
     }
 
     impl TrainingProgress {
-        fn init_with_dev() -> Self {
+        pub(in crate) fn init_with_dev() -> Self {
             Self {
                 epoch: vec![],
+                step: vec![],
                 epoch_loss: vec![],
                 epoch_accuracy: vec![],
+                epoch_perplexity: vec![],
                 dev_loss: Some(vec![]),
                 dev_accuracy: Some(vec![]),
+                dev_perplexity: Some(vec![]),
+                validation_step: vec![],
                 time: vec![]
             }
         }
-        fn init_no_dev() -> Self {
+        pub(in crate) fn init_no_dev() -> Self {
             Self {
                 epoch: vec![],
+                step: vec![],
                 epoch_loss: vec![],
                 epoch_accuracy: vec![],
+                epoch_perplexity: vec![],
                 dev_loss: None,
                 dev_accuracy: None,
+                dev_perplexity: None,
+                validation_step: vec![],
                 time: vec![]
             }
         }
+
+        // the perplexity of the last epoch, the monitored metric. Computed on dev if it is evaluated, else on train
+        pub fn last_perplexity(&self) -> Option<f64> {
+            match &self.dev_perplexity {
+                Some(dev_perplexity) => dev_perplexity.last().cloned(),
+                None => self.epoch_perplexity.last().cloned()
+            }
+        }
+
+        // the index of the epoch with the lowest dev loss (the first of ties), None without a dev set
+        pub fn best_dev_index(&self) -> Option<usize> {
+            let dev_loss = self.dev_loss.as_ref()?;
+            (0..dev_loss.len()).fold(None, |best: Option<usize>, i| match best {
+                Some(best) if dev_loss[best] <= dev_loss[i] => Some(best),
+                _ => Some(i)
+            })
+        }
+
+        // the number of epochs recorded, a partial epoch (stopped on max_steps) counts as one
+        pub fn epochs(&self) -> usize {
+            self.epoch.len()
+        }
+
+        // the number of optimizer steps taken until the end of the last recorded epoch
+        pub fn last_step(&self) -> Option<i64> {
+            self.step.last().cloned()
+        }
+
+        // the steps at which the dev set was evaluated
+        pub fn validation_steps(&self) -> &[i64] {
+            &self.validation_step
+        }
     }
 
     impl Add for TrainingProgress {
@@ -240,12 +1133,18 @@ pub mod training {
             let mut new_epoch = self.epoch;
             new_epoch.extend(rhs.epoch);
 
+            let mut new_step = self.step;
+            new_step.extend(rhs.step);
+
             let mut new_epoch_loss = self.epoch_loss;
             new_epoch_loss.extend(rhs.epoch_loss);
 
             let mut new_epoch_accuracy = self.epoch_accuracy;
             new_epoch_accuracy.extend(rhs.epoch_accuracy);
 
+            let mut new_epoch_perplexity = self.epoch_perplexity;
+            new_epoch_perplexity.extend(rhs.epoch_perplexity);
+
             let mut new_dev_loss = None;
             if self.dev_loss.is_some() {
                 let mut prior_dev_loss = self.dev_loss.unwrap();
@@ -262,15 +1161,29 @@ pub mod training {
                 new_dev_accuracy = Some(prioer_dev_accuracy);
             }
 
+            let mut new_dev_perplexity = None;
+            if self.dev_perplexity.is_some() {
+                let mut prior_dev_perplexity = self.dev_perplexity.unwrap();
+                prior_dev_perplexity.extend(rhs.dev_perplexity.unwrap_or(vec![]));
+                new_dev_perplexity = Some(prior_dev_perplexity);
+            }
+
+            let mut new_validation_step = self.validation_step;
+            new_validation_step.extend(rhs.validation_step);
+
             let mut new_time = self.time;
             new_time.extend(rhs.time);
 
             let new_training_progress = TrainingProgress {
                 epoch: new_epoch,
+                step: new_step,
                 epoch_loss: new_epoch_loss,
                 epoch_accuracy: new_epoch_accuracy,
+                epoch_perplexity: new_epoch_perplexity,
                 dev_loss: new_dev_loss,
                 dev_accuracy: new_dev_accuracy,
+                dev_perplexity: new_dev_perplexity,
+                validation_step: new_validation_step,
                 time: new_time
             };
 
@@ -287,11 +1200,13 @@ pub mod training {
             assert!(n > 0, "can't use display before first iteration");
             
             let epoch = self.epoch.get(n-1).unwrap();
+            let step = self.step.get(n-1).unwrap();
             let epoch_loss = self.epoch_loss.get(n-1).unwrap();
             let epoch_acc = self.epoch_accuracy.get(n-1).unwrap();
+            let epoch_perplexity = self.epoch_perplexity.get(n-1).unwrap();
             let time = self.time.get(n-1).unwrap();
 
-            let mut to_print = format!("epoch: {}, time (train): {}, train loss: {}, train acc: {}, ", epoch, time, epoch_loss, epoch_acc);
+            let mut to_print = format!("epoch: {}, step: {}, time (train): {}, train loss: {}, train acc: {}, train perplexity: {}, ", epoch, step, time, epoch_loss, epoch_acc, epoch_perplexity);
 
             if let Some(dev_loss) = &self.dev_loss {
                 to_print += &format!("dev loss: {}, ", dev_loss.get(n-1).unwrap());
@@ -301,6 +1216,10 @@ pub mod training {
                 to_print += &format!("dev acc: {}", dev_accuracy.get(n-1).unwrap());
             }
 
+            if let Some(dev_perplexity) = &self.dev_perplexity {
+                to_print += &format!(", dev perplexity: {}", dev_perplexity.get(n-1).unwrap());
+            }
+
             write!(f, "{}", to_print)
 
         }
@@ -0,0 +1,16 @@
+
+// set_deterministic changes the process environment and the cuDNN globals, its test runs in a binary of its
+// own so that it doesn't race with the tests of integration_test
+use elmo_trainer::training::{self, set_deterministic};
+
+
+#[test]
+fn deterministic_mode_is_set_on_cpu() {
+
+    set_deterministic(true);
+    assert_eq!(std::env::var("CUBLAS_WORKSPACE_CONFIG").unwrap(), training::CUBLAS_WORKSPACE_CONFIG);
+    assert!(!tch::Cuda::user_enabled_cudnn());
+    set_deterministic(false);
+    assert!(tch::Cuda::user_enabled_cudnn());
+
+}
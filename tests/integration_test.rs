@@ -1,13 +1,15 @@
 
-use elmo_trainer::{Preprocessor, ELMoText, JsonELMo, ELMo, Splitter, training::ElmoTrainer, DatasetBuilder, Loader};
-use tch::{Device, nn, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use elmo_trainer::{Preprocessor, ELMoText, JsonELMo, ConfigElmo, ELMo, LanguageModel, ScalarMix, embedding_dropout, receptive_field, scale_grad, logits_entropy, chunked_seq, pool_sentence, Splitter, SplitMode, training::ElmoTrainer, DatasetBuilder, Loader, embedding, Embedder, files_handling, build_loaders, dump_batches, merge_vocabs, write_token_counts, token_length_percentile, coverage_report, write_coverage_report, Script, char_script, script_partitions, Direction, archiving, IGNORE_INDEX, SHAPE_LOG_CAPACITY};
+use elmo_trainer::training::{TrainModel, TrainingProgress, group_by_module, free_running_agreement, loader_free_running_agreement, group_learning_rates, evaluate_corpus, confusion_report, most_frequent_ids, write_confusion_report, memory_usage, log_memory_usage, write_manifest, compute_capability, check_compute_capability, build_with_cpu_fallback, export_scalar_mix, nbest_next_tokens, reduce_loss, token_perplexity, RngState, sampling_schedule, scheduled_sampling, snapshot_vars, TrainingCheckpoint, grad_cosine, layer_grad_cosines, unk_weighted_losses};
+use tch::{Device, Kind, nn, Tensor};
+use tch::nn::{ModuleT, RNN};
 
 
-#[test]
-fn integration_without_configure() {
-
-    // example sentences (10 for validation)
-    let mut sentences = [
+// example sentences (10 for validation)
+fn example_sentences() -> Vec<String> {
+    [
         "This is a first sentence",
         "This is a second sentence",
         "This is a third sentence",
@@ -18,42 +20,62 @@ fn integration_without_configure() {
         "a b c d e",
         "Blue sky , yellow sun",
         "sky is blue not yellow"
-    ].map(|x| x.to_string()).to_vec();
+    ].map(|x| x.to_string()).to_vec()
+}
 
-    // example parameters
-    let mut params = JsonELMo { 
-        corpus_file: None, 
-        output_file: None,
+// example parameters
+fn example_params() -> JsonELMo {
+    JsonELMo {
         token_vocab_size: 50, // the examples have less
         char_vocab_size: 50, // the examples have less
         min_count: 1,
         max_len_token: 20,
-        char_start: '$',
-        char_end: '^',
-        char_unk: '~',
-        str_unk: String::from("UNK"),
+        lowercase_tokens: false, // the examples are given as is, not loaded from file
+        lowercase_chars: false,
         batch_size: 1,
         seq_length: 1,
         char_embedding_dim: 5,
-        in_channels: 1,
         out_channels: vec![20],
         kernel_size: vec![1],
-        highways: 1, 
-        in_dim: 10, 
+        in_dim: 10,
         hidden_dim: 10,
-        n_lstm_layers: 1, 
         dropout: 0.0,
-        device: Device::cuda_if_available(),
-        max_iter: 2, 
-        learning_rate: 0.1, 
-        clip_norm: 0.0, 
-        break_early: false 
-    };
+        max_iter: 2,
+        learning_rate: 0.1,
+        log_interval: 1,
+        ..Default::default()
+    }
+}
+
+// the progress of a run with the given dev losses, one per epoch, as read from a checkpoint state. Only trainings
+// build a progress, its fields are private
+fn dev_progress(dev_losses: &[f64]) -> TrainingProgress {
+    let n = dev_losses.len();
+    serde_json::from_value(serde_json::json!({
+        "epoch": (0..n as i64).collect::<Vec<i64>>(),
+        "step": (0..n as i64).collect::<Vec<i64>>(),
+        "epoch_loss": vec![1.0; n],
+        "epoch_accuracy": vec![0.5; n],
+        "epoch_perplexity": vec![1.0f64.exp(); n],
+        "dev_loss": dev_losses,
+        "dev_accuracy": vec![0.5; n],
+        "dev_perplexity": dev_losses.iter().map(|l| l.exp()).collect::<Vec<f64>>(),
+        "validation_step": (0..n as i64).collect::<Vec<i64>>(),
+        "time": vec![0; n]
+    })).unwrap()
+}
+
+
+#[test]
+fn integration_without_configure() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
 
     //
     // preprocess of sentences
     let mut preprocessor = Preprocessor::new();
-    let (token2int,char2int) = preprocessor.preprocess(&mut sentences, &mut params);
+    let (token2int,char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
     assert_eq!(token2int.len(), 38); // self counted, with spacial tokens
     // -- end of preprocessing sentences
     //
@@ -100,4 +122,3068 @@ fn integration_without_configure() {
     //
 
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn stream_drops_tiny_trailing_chunk() {
+
+    // one long sentence of 8 tokens, chunked to seq_length 3 => 3, 3 and a trailing chunk of 2
+    let xs = vec![Tensor::arange(8 * 4, (Kind::Int64, Device::Cpu)).reshape(&[8, 4])];
+    let ys = vec![Tensor::arange(8, (Kind::Int64, Device::Cpu))];
+    let mut loader = Loader::new(xs, ys, Device::Cpu, 1, 3);
+
+    loader.min_chunk_tokens = Some(3);
+    let lengths = loader.batches().map(|(_, ys)| ys.size()[1]).collect::<Vec<i64>>();
+    assert_eq!(lengths, vec![3, 3]);
+
+    loader.min_chunk_tokens = Some(2);
+    let lengths = loader.batches().map(|(_, ys)| ys.size()[1]).collect::<Vec<i64>>();
+    assert_eq!(lengths, vec![3, 3, 2]);
+
+}
+
+#[test]
+fn lstm_states_have_batch_hidden_shape() {
+
+    let params = example_params();
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    // (batch_size, seq_length, token_length) of char ids
+    let xs = Tensor::randint(params.char_vocab_size, [2, 3, params.max_len_token], (Kind::Int64, params.device));
+    let (h, c) = model.lstm_states(&xs);
+    assert_eq!(h.size(), vec![2, params.hidden_dim]);
+    assert_eq!(c.size(), vec![2, params.hidden_dim]);
+    assert_eq!(h.device(), params.device);
+
+}
+
+#[test]
+fn model_forwards_without_highways() {
+
+    let mut params = example_params();
+    params.highways = 0;
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    // logits are of shape (batch_size * seq_length, token_vocab_size)
+    let xs = Tensor::randint(params.char_vocab_size, [2, 3, params.max_len_token], (Kind::Int64, params.device));
+    let logits = model.forward_t(&xs, false);
+    assert_eq!(logits.size(), vec![2 * 3, params.token_vocab_size]);
+
+}
+
+#[test]
+fn embeddings_alignment_has_row_per_token() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_embeddings_alignment");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("embeddings.txt").to_str().unwrap().to_string();
+    embedding::export_embeddings(&model, &elmo_text_loader, &[0, 1], &output_file, &params).unwrap();
+
+    let rows = fs::read_to_string(&output_file).unwrap();
+    let alignment = fs::read_to_string(format!("{}{}", output_file, embedding::ALIGNMENT_SUFFIX)).unwrap();
+    let rows = rows.lines().collect::<Vec<&str>>();
+    let alignment = alignment.lines().map(|l| l.split('\t').map(|x| x.to_string()).collect::<Vec<String>>()).collect::<Vec<_>>();
+    assert_eq!(rows.len(), alignment.len());
+    assert_eq!(rows[0].split(' ').count() as i64, params.in_dim);
+
+    // entries follow the sentences order and their tokens positions
+    let mut expected = elmo_text_loader.get_tokens(0).unwrap().into_iter().enumerate().map(|(p, t)| vec!["0".to_string(), p.to_string(), t]).collect::<Vec<_>>();
+    expected.extend(elmo_text_loader.get_tokens(1).unwrap().into_iter().enumerate().map(|(p, t)| vec!["1".to_string(), p.to_string(), t]));
+    assert_eq!(alignment, expected);
+
+    // the embedder exports raw sentences after the preprocessing of training, as the preprocessed sentences
+    let indices = (0..elmo_text_loader.get_len()).collect::<Vec<i64>>();
+    embedding::export_embeddings(&model, &elmo_text_loader, &indices, &output_file, &params).unwrap();
+    let expected_alignment = fs::read_to_string(format!("{}{}", output_file, embedding::ALIGNMENT_SUFFIX)).unwrap();
+    let embedder = Embedder::new(model, elmo_text_loader.token2int().clone(), elmo_text_loader.char2int().clone(), &params).unwrap();
+    assert_eq!(embedder.export(example_sentences(), &output_file).unwrap(), indices.len());
+    assert_eq!(fs::read_to_string(format!("{}{}", output_file, embedding::ALIGNMENT_SUFFIX)).unwrap(), expected_alignment);
+
+    // a windowed export has the embeddings of embed_sentence
+    let mut windowed = params.clone();
+    windowed.embedding_window = Some(3);
+    windowed.embedding_window_overlap = 1;
+    let vars = nn::VarStore::new(windowed.device);
+    let embedder = Embedder::new(ELMo::new(&vars.root(), &windowed), elmo_text_loader.token2int().clone(), elmo_text_loader.char2int().clone(), &windowed).unwrap();
+    let sentence = example_sentences()[0].clone();
+    embedder.export(vec![sentence.clone()], &output_file).unwrap();
+    let expected = Vec::<Vec<f32>>::try_from(&embedder.embed_sentence(&sentence).unwrap()).unwrap();
+    assert!(expected.len() > 3);
+    let expected = expected.iter().map(|row| row.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ")).collect::<Vec<String>>();
+    assert_eq!(fs::read_to_string(&output_file).unwrap().lines().collect::<Vec<&str>>(), expected);
+
+}
+
+#[test]
+fn repeated_whitespace_tokenizes_to_two_tokens() {
+
+    let mut params = example_params();
+    let mut sentences = vec!["a  b".to_string()];
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert_eq!(sentences, vec!["SOS a b EOS".to_string()]);
+
+    // ELMoText splits the same way, also on sentences that didn't go through the preprocessor
+    let elmo_text_loader = ELMoText::new(vec!["a  b".to_string()], token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap(), vec!["a".to_string(), "b".to_string()]);
+
+}
+
+#[test]
+fn target_perplexity_stops_at_crossing_epoch() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.seed = Some(5);
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let run = |max_iter: i64, target_metric_value: Option<f64>| {
+        let mut params = params.clone();
+        params.max_iter = max_iter;
+        params.target_metric_value = target_metric_value;
+        tch::manual_seed(0);
+        let mut vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new().with_seed(5), &params).unwrap();
+        ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap()
+    };
+
+    // any perplexity crosses an infinite target, none crosses 0
+    assert_eq!(run(5, Some(f64::INFINITY)).epochs(), 1);
+    assert_eq!(run(3, Some(0.0)).epochs(), 3);
+
+    // the seeded run stops at the first epoch that reaches the dev perplexity of the second epoch, and is within it
+    let second_perplexity = run(2, None).last_perplexity().unwrap();
+    let stopped = run(10, Some(second_perplexity));
+    assert!(stopped.epochs() <= 2);
+    assert!(stopped.last_perplexity().unwrap() <= second_perplexity);
+
+}
+
+#[test]
+fn separate_dev_and_test_corpora_bypass_the_splitter() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let fixtures = format!("{}/tests/fixtures", env!("CARGO_MANIFEST_DIR"));
+    let mut dev_sentences = files_handling::load_sentences(&format!("{}/dev.txt", fixtures), params.skip_blank_lines).unwrap();
+    let mut test_sentences = files_handling::load_sentences(&format!("{}/test.txt", fixtures), params.skip_blank_lines).unwrap();
+
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    preprocessor.prepare_sentences(&mut dev_sentences, &params);
+    preprocessor.prepare_sentences(&mut test_sentences, &params);
+
+    let dev_text_loader = ELMoText::new(dev_sentences, token2int.clone(), char2int.clone(), &params);
+    let test_text_loader = ELMoText::new(test_sentences, token2int.clone(), char2int.clone(), &params);
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    // all of the corpus is used for training, dev and test are the fixtures, not splits
+    let (trainset_iter, devset_iter, testset_iter) = build_loaders(&elmo_text_loader, Some(&dev_text_loader), Some(&test_text_loader), &Splitter::new(), &params).unwrap();
+    assert_eq!(trainset_iter.len() as i64, elmo_text_loader.get_len());
+    assert_eq!(devset_iter.unwrap().len(), 2);
+    assert_eq!(testset_iter.unwrap().len(), 3);
+
+    // with a dev corpus alone, the corpus is still all trained on and there is no test set to run
+    let (trainset_iter, devset_iter, testset_iter) = build_loaders(&elmo_text_loader, Some(&dev_text_loader), None, &Splitter::new(), &params).unwrap();
+    assert_eq!(trainset_iter.len() as i64, elmo_text_loader.get_len());
+    assert_eq!(devset_iter.unwrap().len(), 2);
+    assert!(testset_iter.is_none());
+
+}
+
+#[test]
+fn variables_are_grouped_by_module_prefix() {
+
+    let params = example_params();
+    let vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+
+    let names = vars.variables().keys().cloned().collect::<Vec<String>>();
+    let groups = group_by_module(&names);
+    let modules = groups.keys().map(|k| k.as_str()).collect::<Vec<&str>>();
+    assert_eq!(modules, vec![
+        "backward_lm.lstm_0", "backward_lm.to_dim_lstm",
+        "char_level.conv_0", "char_level.embed", "char_level.highway_0", "char_level.to_dim",
+        "forward_lm.lstm_0", "forward_lm.to_dim_lstm",
+        "scalar_mix", "to_vocab"
+    ]);
+
+    // every variable is in exactly one bucket, the one of its prefix
+    assert_eq!(groups.values().map(|g| g.len()).sum::<usize>(), names.len());
+    assert!(groups["char_level.highway_0"].iter().all(|n| n.starts_with("char_level.highway_0.")));
+    assert_eq!(groups["to_vocab"], vec!["to_vocab.bias".to_string(), "to_vocab.weight".to_string()]);
+
+}
+
+#[test]
+fn lowercase_tokens_shares_labels_but_keeps_char_case() {
+
+    let mut params = example_params();
+    params.lowercase_tokens = true;
+    params.lowercase_chars = false;
+    let mut sentences = vec!["The the".to_string()];
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    // tokens are SOS The the EOS, inputs are SOS The the, labels are The the EOS
+    let (inputs, labels) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(labels.int64_value(&[0]), labels.int64_value(&[1]));
+    assert!(!inputs.get(1).equal(&inputs.get(2)));
+
+    // the boundary tokens are kept as they are only with add_sentence_boundaries, else they are corpus tokens
+    let (token2int, _) = preprocessor.preprocess(&mut vec!["SOS a".to_string()], &mut params).unwrap();
+    assert!(token2int.contains_key("SOS") && !token2int.contains_key("sos"));
+    params.add_sentence_boundaries = false;
+    let (token2int, _) = preprocessor.preprocess(&mut vec!["SOS a".to_string()], &mut params).unwrap();
+    assert!(token2int.contains_key("sos") && !token2int.contains_key("SOS"));
+
+}
+
+#[test]
+fn dump_batches_writes_exactly_n_batches() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (mut trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+
+    let output_dir = std::env::temp_dir().join("elmo_dump_batches");
+    fs::create_dir_all(&output_dir).unwrap();
+    let dump_file = output_dir.join("batches_dump.json").to_str().unwrap().to_string();
+    let n_dumped = dump_batches(&mut trainset_iter, &elmo_text_loader, 3, &dump_file).unwrap();
+    assert_eq!(n_dumped, 3);
+
+    let dumped: serde_json::Value = serde_json::from_str(&fs::read_to_string(&dump_file).unwrap()).unwrap();
+    let batches = dumped.as_array().unwrap();
+    assert_eq!(batches.len(), 3);
+    assert!(batches.iter().all(|b| b.get("tokens").is_some() && b.get("char_ids").is_some() && b.get("labels").is_some()));
+
+    // with input masking the dump has the unmasked inputs, and the masking probability
+    params.input_mask_prob = 1.0;
+    let (mut trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    dump_batches(&mut trainset_iter, &elmo_text_loader, 3, &dump_file).unwrap();
+    let dumped: serde_json::Value = serde_json::from_str(&fs::read_to_string(&dump_file).unwrap()).unwrap();
+    let mask_encoding = serde_json::json!(elmo_text_loader.mask_encoding());
+    for batch in dumped.as_array().unwrap() {
+        assert_eq!(batch["input_mask_prob"], 1.0);
+        assert!(batch["char_ids"].as_array().unwrap().iter().flat_map(|seq| seq.as_array().unwrap()).all(|ids| *ids != mask_encoding));
+    }
+
+    // padded batches in the order of the first epoch, the padded labels decode as padding
+    params.input_mask_prob = 0.0;
+    params.padded_batches = true;
+    params.batch_size = 2;
+    let (mut trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    tch::manual_seed(3);
+    dump_batches(&mut trainset_iter, &elmo_text_loader, 2, &dump_file).unwrap();
+    tch::manual_seed(3);
+    let expected = trainset_iter.epoch_order(0).batches().take(2).collect::<Vec<(Tensor, Tensor)>>();
+    let dumped: serde_json::Value = serde_json::from_str(&fs::read_to_string(&dump_file).unwrap()).unwrap();
+    for (batch, (xs, ys)) in dumped.as_array().unwrap().iter().zip(&expected) {
+        assert_eq!(batch["char_ids"], serde_json::json!(Vec::<Vec<Vec<i64>>>::try_from(xs).unwrap()));
+        let labels = Vec::<Vec<i64>>::try_from(ys).unwrap();
+        for (seq, label_seq) in labels.iter().zip(batch["label_tokens"].as_array().unwrap()) {
+            for (label, token) in seq.iter().zip(label_seq.as_array().unwrap()) {
+                assert_eq!(*label == IGNORE_INDEX, token == "<pad>");
+            }
+        }
+    }
+    assert!(expected.iter().any(|(_, ys)| ys.eq(IGNORE_INDEX).any().int64_value(&[]) == 1));
+
+    // a negative count is rejected
+    let config_file = output_dir.join("config.json").to_str().unwrap().to_string();
+    fs::write(&config_file, format!("{{\"corpus_file\": \"corpus.txt\", \"output_file\": {:?}, \"dump_batches\": -1}}", output_dir.join("model.ot"))).unwrap();
+    let error = ConfigElmo::new(&["main".to_string(), config_file]).err().unwrap();
+    assert!(error.to_string().contains("dump_batches should be positive"), "{}", error);
+
+}
+
+#[test]
+fn native_bidirectional_lstm_doubles_the_output_dim() {
+
+    let xs = Tensor::randint(50, [2, 3, 20], (Kind::Int64, Device::Cpu));
+    let mut dims = Vec::new();
+    for native_bidirectional in [false, true] {
+        let mut params = example_params();
+        params.device = Device::Cpu;
+        params.native_bidirectional = native_bidirectional;
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+
+        // the downstream wiring still gives logits of shape (batch_size * seq_length, token_vocab_size)
+        assert_eq!(model.forward_t(&xs, false).size(), vec![2 * 3, params.token_vocab_size]);
+        dims.push(model.lstm_states(&xs).0.size()[1]);
+    }
+    assert_eq!(dims[1], 2 * dims[0]);
+
+    // a config can set it for building and probing
+    let output_dir = std::env::temp_dir().join("elmo_native_bidirectional");
+    fs::create_dir_all(&output_dir).unwrap();
+    let config_file = output_dir.join("config.json").to_str().unwrap().to_string();
+    fs::write(&config_file, format!("{{\"corpus_file\": \"corpus.txt\", \"output_file\": {:?}, \"native_bidirectional\": true}}", output_dir.join("model.ot"))).unwrap();
+    assert!(ConfigElmo::new(&["main".to_string(), config_file]).unwrap().get_params().native_bidirectional);
+
+    // the reverse direction sees the next token, so the trainer refuses to train it
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.native_bidirectional = true;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let error = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).err().unwrap();
+    assert!(error.to_string().contains("native_bidirectional can't be trained"), "{}", error);
+
+}
+
+#[test]
+fn merged_vocabs_remaps_are_consistent() {
+
+    let to_vocab = |tokens: &[&str]| tokens.iter().enumerate().map(|(i, t)| (t.to_string(), i)).collect::<HashMap<String, usize>>();
+    let first = to_vocab(&["SOS", "EOS", "sky", "blue"]);
+    let second = to_vocab(&["SOS", "EOS", "sun", "sky", "yellow"]);
+    let (merged, remap_first, remap_second) = merge_vocabs(&first, &second);
+
+    // shared tokens are merged, "sun" had the id of "sky" in the first vocab but they are now distinct
+    assert_eq!(merged.len(), 6);
+    assert_ne!(merged["sun"], merged["sky"]);
+
+    // following a remap table from an original id leads to the same token in the merged vocab
+    for (vocab, remap) in [(&first, &remap_first), (&second, &remap_second)] {
+        assert_eq!(remap.len(), vocab.len());
+        for (token, id) in vocab {
+            assert_eq!(remap[id], merged[token]);
+        }
+    }
+    assert_eq!(remap_second[&3], remap_first[&2]);
+
+}
+
+#[test]
+fn directional_labels_are_offset_from_inputs() {
+
+    let mut sentences = vec!["sky is blue not yellow".to_string()];
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    // token ids of SOS sky is blue not yellow EOS
+    let ids = sentences[0].split(' ').map(|t| token2int[t] as i64).collect::<Vec<i64>>();
+    let n = ids.len() as i64;
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let ((forward_inputs, forward_labels), (backward_inputs, backward_labels)) = elmo_text_loader.get_bidirectional_example(0).unwrap();
+
+    // forward predicts token i+1 from token i, backward predicts token i from token i+1
+    assert_eq!(Vec::<i64>::try_from(&forward_labels).unwrap(), ids[1..].to_vec());
+    assert_eq!(Vec::<i64>::try_from(&backward_labels).unwrap(), ids[..ids.len()-1].to_vec());
+    assert!(backward_inputs.narrow(0, 0, n - 2).equal(&forward_inputs.narrow(0, 1, n - 2)));
+
+    // the forward direction is the default example
+    let (inputs, labels) = elmo_text_loader.get_example(0).unwrap();
+    assert!(inputs.equal(&forward_inputs) && labels.equal(&forward_labels));
+    let (inputs, _) = elmo_text_loader.get_directional_example(0, Direction::Backward).unwrap();
+    assert!(inputs.equal(&backward_inputs));
+
+}
+
+#[test]
+fn max_steps_halts_training_mid_epoch() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_iter = 2;
+    params.max_steps = Some(3);
+
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+
+    // with batch_size and seq_length of 1 an epoch is tens of steps, so the limit is hit in the first one
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    assert_eq!(train_progress.epochs(), 1);
+    assert_eq!(train_progress.last_step(), Some(3));
+
+}
+
+#[test]
+fn sentence_boundaries_are_encoded_and_labeled() {
+
+    let mut params = example_params();
+    let mut sentences = vec!["sky is blue".to_string()];
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert_eq!((token2int["SOS"], token2int["EOS"]), (0, 1));
+    let elmo_text_loader = ELMoText::new(sentences, token2int.clone(), char2int.clone(), &params);
+
+    // SOS sky is blue EOS, inputs start from SOS and labels end with EOS
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap().len(), 5);
+    let (inputs, labels) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(inputs.size()[0], 4);
+    assert_eq!(Vec::<i64>::try_from(&labels).unwrap(), vec![token2int["sky"] as i64, token2int["is"] as i64, token2int["blue"] as i64, 1]);
+    assert_eq!(elmo_text_loader.decode_chars(&Vec::<i64>::try_from(&inputs.get(0)).unwrap()), "SOS");
+
+    // without boundaries, the sentence is encoded as is and they are not in the vocabulary
+    params.add_sentence_boundaries = false;
+    let mut sentences = vec!["sky is blue".to_string()];
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert!(!token2int.contains_key("SOS") && !token2int.contains_key("EOS"));
+    let elmo_text_loader = ELMoText::new(sentences, token2int.clone(), char2int, &params);
+    let (inputs, labels) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(inputs.size()[0], 2);
+    assert_eq!(Vec::<i64>::try_from(&labels).unwrap(), vec![token2int["is"] as i64, token2int["blue"] as i64]);
+
+}
+
+#[test]
+fn forget_gate_bias_is_set_after_construction() {
+
+    let mut params = example_params();
+    params.forget_bias = 2.0;
+    let vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+
+    // gates are (input, forget, cell, output), the forget slice sums to the configured value
+    let variables = vars.variables();
+    let hidden_dim = params.hidden_dim;
+    for lm in ["forward_lm", "backward_lm"] {
+        let bias_ih = &variables[&format!("{}.lstm_0.bias_ih_l0", lm)];
+        let bias_hh = &variables[&format!("{}.lstm_0.bias_hh_l0", lm)];
+        let forget = bias_ih.narrow(0, hidden_dim, hidden_dim) + bias_hh.narrow(0, hidden_dim, hidden_dim);
+        assert!(forget.equal(&Tensor::full([hidden_dim], 2.0, (Kind::Float, forget.device()))));
+        assert!(!bias_ih.narrow(0, 0, hidden_dim).equal(&Tensor::full([hidden_dim], 2.0, (Kind::Float, forget.device()))));
+    }
+
+}
+
+#[test]
+fn archive_round_trip_reproduces_the_embedder() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.device = Device::Cpu;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_archive");
+    fs::create_dir_all(&output_dir).unwrap();
+    let archive_file = output_dir.join("model.zip").to_str().unwrap().to_string();
+    archiving::export_archive(&archive_file, &vars, &params, &token2int, &char2int).unwrap();
+    let archive = archiving::import_archive(&archive_file, Device::Cpu).unwrap();
+    assert_eq!(archive.token2int, token2int);
+    assert_eq!(archive.char2int, char2int);
+    assert_eq!(archive.params.out_channels, params.out_channels);
+    assert_eq!(archive.params.token_vocab_size, params.token_vocab_size);
+
+    // the same sentence is embedded the same way by both models
+    let elmo_text_loader = ELMoText::new(sentences.clone(), token2int, char2int, &params);
+    let imported_text_loader = ELMoText::new(sentences, archive.token2int.clone(), archive.char2int.clone(), &archive.params);
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let (imported_xs, _) = imported_text_loader.get_example(0).unwrap();
+    assert!(xs.equal(&imported_xs));
+    assert!(model.embed(&xs.unsqueeze(0)).allclose(&archive.model.embed(&imported_xs.unsqueeze(0)), 1e-6, 1e-6, false));
+
+}
+
+
+#[test]
+fn sub_threshold_improvement_does_not_checkpoint() {
+
+    let elmo_train = ElmoTrainer::new();
+    let min_delta = 0.5;
+
+    // the first epoch is always saved, then only improvements of at least min_delta
+    assert!(elmo_train.should_checkpoint(None, 20.0, min_delta));
+    assert!(!elmo_train.should_checkpoint(Some(20.0), 19.9, min_delta));
+    assert!(!elmo_train.should_checkpoint(Some(20.0), 21.0, min_delta));
+    assert!(elmo_train.should_checkpoint(Some(20.0), 19.5, min_delta));
+
+    // with the default delta any improvement is saved
+    assert!(elmo_train.should_checkpoint(Some(20.0), 19.9999, 0.0));
+    assert!(!elmo_train.should_checkpoint(Some(20.0), 20.0, 0.0));
+
+    let mut params = example_params();
+    params.output_file = Some("out/model.ot".to_string());
+    assert_eq!(params.best_checkpoint_file(), Some("out/best_model.ot".to_string()));
+
+    // the test set is evaluated on the best checkpoint once it is written
+    let output_dir = std::env::temp_dir().join("elmo_test_checkpoint");
+    fs::create_dir_all(&output_dir).unwrap();
+    params.output_file = Some(output_dir.join("model.ot").to_str().unwrap().to_string());
+    let best_checkpoint_file = params.best_checkpoint_file().unwrap();
+    let _ = fs::remove_file(&best_checkpoint_file);
+    assert_eq!(params.test_checkpoint_file(), params.output_file);
+    fs::write(&best_checkpoint_file, "").unwrap();
+    assert_eq!(params.test_checkpoint_file(), Some(best_checkpoint_file));
+
+}
+
+#[test]
+fn double_model_dtype_makes_double_parameters() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.device = Device::Cpu;
+    params.model_dtype = String::from("double");
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    vars.set_kind(params.model_kind());
+    assert!(vars.variables().values().all(|v| v.kind() == Kind::Double));
+
+    // the lstm initial states match, a forward pass runs in double
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(model.forward_t(&xs.unsqueeze(0), false).kind(), Kind::Double);
+
+}
+
+#[test]
+fn frozen_char_embedding_is_kept_while_convs_train() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.freeze_char_embedding = true;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let variables = vars.variables();
+    let embedding_before = variables["char_level.embed.weight"].copy();
+    let conv_before = variables["char_level.conv_0.conv.weight"].copy();
+
+    // a single optimizer step on one example
+    let elmo_train = ElmoTrainer::new();
+    let mut opt = elmo_train.init_optimizer(&vars, params.learning_rate, &params.optimizer, params.momentum).unwrap();
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let (mut loss, mut accuracy) = (0.0, 0.0);
+    elmo_train.step(xs.unsqueeze(0).to_device(params.device), ys.unsqueeze(0).to_device(params.device), &model, &mut loss, &mut accuracy, Some((&mut opt, params.grad_clip_norm, params.loss_reduction.as_str(), params.unk_loss_weight, Some(elmo_text_loader.unk_id()))));
+
+    assert!(variables["char_level.embed.weight"].equal(&embedding_before));
+    assert!(!variables["char_level.conv_0.conv.weight"].equal(&conv_before));
+
+}
+
+#[test]
+fn pretrained_char_vectors_are_loaded_by_char2int() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (_, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let embedding_before = vars.variables()["char_level.embed.weight"].copy();
+
+    // a header, a vector for 'a' and one for a char that isn't in the corpus
+    let output_dir = std::env::temp_dir().join("elmo_char_vectors");
+    fs::create_dir_all(&output_dir).unwrap();
+    let init_file = output_dir.join("chars.vec").to_str().unwrap().to_string();
+    fs::write(&init_file, "2 5\na 0.1 0.2 0.3 0.4 0.5\n@ 1 1 1 1 1\n").unwrap();
+    assert_eq!(model.init_char_embedding(&init_file, &char2int).unwrap(), 1);
+
+    let embedding = &vars.variables()["char_level.embed.weight"];
+    let a_row = char2int[&'a'] as i64;
+    let b_row = char2int[&'b'] as i64;
+    let expected = Tensor::from_slice(&[0.1f32, 0.2, 0.3, 0.4, 0.5]).to_device(embedding.device());
+    assert!(embedding.get(a_row).allclose(&expected, 1e-6, 1e-6, false));
+    assert!(embedding.get(b_row).equal(&embedding_before.get(b_row)));
+
+    // vectors of another dimension are rejected
+    fs::write(&init_file, "a 0.1 0.2 0.3\n").unwrap();
+    assert!(model.init_char_embedding(&init_file, &char2int).is_err());
+
+}
+
+#[test]
+fn split_without_batches_gives_descriptive_error() {
+
+    // 10 sentences of 6 training tokens each, the dev split is a single sentence, shorter than seq_length
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.seq_length = 10;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    let error = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).err().unwrap().to_string();
+    assert!(error.contains("the dev split has 1 examples (6 tokens)"));
+    assert!(error.contains("smaller batch_size or seq_length"));
+
+    // a train stream of 48 tokens is 4 chunks of 10, and a batch_size of 2 makes 2 batches
+    params.seq_length = 1;
+    let (mut trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    trainset_iter.seq_length = 10;
+    trainset_iter.batch_size = 2;
+    assert_eq!(trainset_iter.n_batches(), 2);
+    assert_eq!(trainset_iter.batches().count(), 2);
+
+}
+
+#[test]
+fn char_out_activations_keep_shape_and_change_output() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.device = Device::Cpu;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let xs = xs.unsqueeze(0);
+
+    // the same seed gives the same weights, only the activation differs
+    let embed_with = |activation: &str| {
+        let mut params = params.clone();
+        params.char_out_activation = activation.to_string();
+        tch::manual_seed(0);
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        model.embed(&xs)
+    };
+
+    let none = embed_with("none");
+    assert!(none.equal(&embed_with("none")));
+    for activation in ["relu", "tanh", "layernorm"] {
+        let out = embed_with(activation);
+        assert_eq!(out.size(), none.size());
+        assert!(!out.allclose(&none, 1e-6, 1e-6, false));
+    }
+
+}
+
+#[test]
+fn free_running_agreement_is_stable_on_a_deterministic_model() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    // evaluation is in eval mode without dropout, the same model agrees the same way every time
+    let agreement = free_running_agreement(&model, &elmo_text_loader, 3).unwrap();
+    assert!((0.0..=1.0).contains(&agreement));
+    assert_eq!(agreement, free_running_agreement(&model, &elmo_text_loader, 3).unwrap());
+
+    // the examples of a loader agree as the sentences they are encoded from, the dev split is evaluated so
+    let examples = (0..elmo_text_loader.get_len() as usize).map(|i| elmo_text_loader.get_example(i).unwrap());
+    let (xs, ys): (Vec<Tensor>, Vec<Tensor>) = examples.unzip();
+    let loader = Loader::new(xs, ys, params.device, params.batch_size, params.seq_length);
+    assert_eq!(loader_free_running_agreement(&model, &elmo_text_loader, &loader, 3).unwrap(), agreement);
+    let (_, devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    assert!((0.0..=1.0).contains(&loader_free_running_agreement(&model, &elmo_text_loader, &devset_iter.unwrap(), 3).unwrap()));
+
+}
+
+#[test]
+fn layer_learning_rates_follow_the_multipliers() {
+
+    let mut params = example_params();
+    params.n_lstm_layers = 2;
+    params.layer_lr_multipliers = vec![0.5, 2.0];
+    let learning_rates = group_learning_rates(params.learning_rate, &params.layer_lr_multipliers);
+    assert_eq!(learning_rates, vec![params.learning_rate, params.learning_rate * 0.5, params.learning_rate * 2.0]);
+    assert_eq!(group_learning_rates(params.learning_rate, &[]), vec![params.learning_rate]);
+
+    // the optimizer group of each variable matches its layer prefix, a 0 multiplier keeps the first lstm layer of
+    // both lms as initialized while the other variables train
+    params.layer_lr_multipliers = vec![0.0, 2.0];
+    params.max_iter = 1;
+    let mut sentences = example_sentences();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let initial = snapshot_vars(&vars);
+    let (mut trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    ElmoTrainer::new().run_training(&mut trainset_iter, &mut None, &model, &mut vars, &params).unwrap();
+    let variables = vars.variables();
+    assert_eq!(vars.trainable_variables().len(), variables.len());
+    for (name, variable) in variables.iter().filter(|(name, _)| name.contains(".lstm_0.")) {
+        assert!(variable.equal(&initial[name]), "{}", name);
+    }
+    for name in ["forward_lm.lstm_1.weight_ih_l0", "backward_lm.lstm_1.weight_ih_l0", "to_vocab.weight"] {
+        assert!(!variables[name].equal(&initial[name]), "{}", name);
+    }
+
+}
+
+#[test]
+fn token_counts_tsv_is_sorted_and_matches_the_corpus() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.token_vocab_size = 5; // the counts are taken before the vocabulary cutoff
+    let mut preprocessor = Preprocessor::new();
+    let _ = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    let mut expected: HashMap<String, usize> = HashMap::new();
+    for token in example_sentences().iter().flat_map(|s| s.split_whitespace().map(|t| t.to_string()).collect::<Vec<String>>()) {
+        *expected.entry(token).or_default() += 1;
+    }
+    assert_eq!(preprocessor.token_counts(), &expected);
+
+    let output_dir = std::env::temp_dir().join("elmo_token_counts");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("token_counts.tsv").to_str().unwrap().to_string();
+    write_token_counts(preprocessor.token_counts(), &output_file).unwrap();
+
+    let rows = fs::read_to_string(&output_file).unwrap().lines().map(|l| {
+        let (token, count) = l.split_once('\t').unwrap();
+        (token.to_string(), count.parse::<usize>().unwrap())
+    }).collect::<Vec<(String, usize)>>();
+    assert_eq!(rows.len(), expected.len());
+    assert!(rows.windows(2).all(|w| w[0].1 >= w[1].1));
+    assert!(rows.iter().all(|(token, count)| expected[token] == *count));
+    assert_eq!(rows[0], ("is".to_string(), 5));
+
+}
+
+#[test]
+fn blank_lines_are_skipped_from_the_corpus() {
+
+    // 3 sentences, with blank and whitespace only lines between and after them
+    let blank_lines = format!("{}/tests/fixtures/blank_lines.txt", env!("CARGO_MANIFEST_DIR"));
+    let sentences = files_handling::load_sentences(&blank_lines, true).unwrap();
+    assert_eq!(sentences, vec!["the first paragraph", "a second paragraph", "and the last one"]);
+
+    // without skipping, blank lines are kept as empty sentences
+    let sentences = files_handling::load_sentences(&blank_lines, false).unwrap();
+    assert_eq!(sentences.len(), 7);
+    assert_eq!(sentences.iter().filter(|s| s.is_empty()).count(), 4);
+
+}
+
+#[test]
+fn oversized_example_is_split_within_the_chars_budget() {
+
+    // a single giant line of 5000 tokens of 4 chars
+    let giant = (0..5000).map(|i| format!("w{:03}", i % 1000)).collect::<Vec<String>>().join(" ");
+    let mut sentences = vec![giant.clone(), "a short one".to_string()];
+    let mut params = example_params();
+    params.max_chars_per_example = Some(40);
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    // 10 tokens per piece, the short sentence is kept as is and all tokens stay in order
+    assert_eq!(sentences.len(), 501);
+    assert_eq!(sentences[500], "SOS a short one EOS");
+    let rejoined = sentences[..500].iter().map(|s| s.trim_start_matches("SOS ").trim_end_matches(" EOS")).collect::<Vec<&str>>().join(" ");
+    assert_eq!(rejoined, giant);
+
+    // every example is bounded, 10 tokens and the boundaries
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    for index in 0..elmo_text_loader.get_len() as usize {
+        let (inputs, _) = elmo_text_loader.get_example(index).unwrap();
+        assert!(inputs.size()[0] <= 11);
+    }
+
+}
+
+#[test]
+fn trained_archive_is_evaluated_on_a_held_out_corpus() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_iter = 1;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+
+    let output_dir = std::env::temp_dir().join("elmo_eval");
+    fs::create_dir_all(&output_dir).unwrap();
+    let archive_file = output_dir.join("model_archive.zip").to_str().unwrap().to_string();
+    archiving::export_archive(&archive_file, &vars, &params, elmo_text_loader.token2int(), elmo_text_loader.char2int()).unwrap();
+
+    // the held out fixture is encoded with the archived vocabularies
+    let archive = archiving::import_archive(&archive_file, params.device).unwrap();
+    let held_out = format!("{}/tests/fixtures/test.txt", env!("CARGO_MANIFEST_DIR"));
+    let (loss, acc, perplexity) = evaluate_corpus(&archive, &held_out).unwrap();
+    assert!(loss.is_finite() && loss > 0.0);
+    assert!(perplexity.is_finite() && perplexity > 1.0);
+    assert!((0.0..=1.0).contains(&acc));
+
+}
+
+#[test]
+fn embedding_dropout_zeroes_whole_rows_only_in_train() {
+
+    // (batch_size, 1, token_length, embedding_dim) of non zero values
+    let xs_embedded = Tensor::ones([2, 1, 50, 5], (Kind::Float, Device::Cpu));
+    assert!(embedding_dropout(&xs_embedded, 0.5, false).equal(&xs_embedded));
+
+    let dropped = embedding_dropout(&xs_embedded, 0.5, true);
+    assert_eq!(dropped.size(), xs_embedded.size());
+
+    // each row is either all zeros or all scaled by 1 / (1 - p)
+    let rows = Vec::<Vec<f32>>::try_from(&dropped.reshape([-1, 5])).unwrap();
+    let zeroed = rows.iter().filter(|row| row.iter().all(|v| *v == 0.0)).count();
+    assert!(rows.iter().all(|row| row.iter().all(|v| *v == 0.0) || row.iter().all(|v| *v == 2.0)));
+    assert!(zeroed > 0 && zeroed < rows.len());
+
+}
+
+#[cfg(feature = "corpus_archives")]
+#[test]
+fn archived_corpus_reads_as_the_extracted_plaintext() {
+
+    // the archives have two members, added out of order, corpus.txt is their concatenation by name
+    let fixtures = format!("{}/tests/fixtures", env!("CARGO_MANIFEST_DIR"));
+    let plaintext = files_handling::load_sentences(&format!("{}/corpus.txt", fixtures), true).unwrap();
+    assert_eq!(plaintext.len(), 4);
+    assert_eq!(files_handling::load_sentences(&format!("{}/corpus.tar.gz", fixtures), true).unwrap(), plaintext);
+    assert_eq!(files_handling::load_sentences(&format!("{}/corpus.zip", fixtures), true).unwrap(), plaintext);
+
+}
+
+#[test]
+fn input_masking_replaces_a_fraction_of_inputs_only() {
+
+    // 2000 tokens of distinct encodings (none is all zeros), chunked to (10, 20) batches
+    let xs = vec![Tensor::arange(2000 * 4, (Kind::Int64, Device::Cpu)).reshape([2000, 4]) + 1];
+    let ys = vec![Tensor::arange(2000, (Kind::Int64, Device::Cpu))];
+    let mask_encoding = vec![0, 0, 0, 0];
+    let mut loader = Loader::new(xs, ys, Device::Cpu, 10, 20);
+    loader.input_mask = Some((0.3, mask_encoding.clone()));
+
+    let (xs, ys): (Vec<Tensor>, Vec<Tensor>) = loader.batches().unzip();
+    let rows = Vec::<Vec<i64>>::try_from(&Tensor::concat(&xs, 0).reshape([-1, 4])).unwrap();
+    let labels = Vec::<i64>::try_from(&Tensor::concat(&ys, 0).reshape([-1])).unwrap();
+    assert_eq!(labels, (0..2000).collect::<Vec<i64>>());
+
+    // a row is either masked or the original encoding of its token
+    let masked = rows.iter().filter(|row| **row == mask_encoding).count() as f64 / rows.len() as f64;
+    assert!((masked - 0.3).abs() < 0.05, "masked fraction {}", masked);
+    assert!(rows.iter().enumerate().all(|(i, row)| *row == mask_encoding || row[0] == (i * 4 + 1) as i64));
+
+    // the mask encoding of a text is all unk chars, without the start char
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let unk_char_id = char2int[&params.char_unk] as i64;
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.mask_encoding(), vec![unk_char_id; params.max_len_token as usize]);
+
+}
+
+#[test]
+fn receptive_field_of_the_default_kernels() {
+
+    // the default kernels are 1 to 7 chars wide, within the default 50 chars tokens
+    let kernel_size = vec![1, 2, 3, 4, 5, 6, 7];
+    assert_eq!(receptive_field(&kernel_size, 50), 7);
+    assert_eq!(receptive_field(&kernel_size, 5), 7);
+    assert_eq!(receptive_field(&[3], 20), 3);
+
+}
+
+#[test]
+fn shared_conv_blocks_reference_the_same_variable() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.kernel_size = vec![2, 2, 3];
+    params.out_channels = vec![4, 4, 4];
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+
+    let n_parameters = |share_conv_weights: bool| {
+        let mut params = params.clone();
+        params.share_conv_weights = share_conv_weights;
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let _ = model.embed(&xs.unsqueeze(0));
+        let names = vars.variables().keys().cloned().collect::<Vec<String>>();
+        assert_eq!(names.iter().any(|n| n.starts_with("char_level.conv_1.")), !share_conv_weights);
+        assert!(names.iter().any(|n| n.starts_with("char_level.conv_2.")));
+        vars.variables().values().map(|v| v.numel()).sum::<usize>()
+    };
+
+    // the second kernel of size 2 reuses the first conv, 4 * (1 * 5 * 2 + 1) parameters are saved
+    assert_eq!(n_parameters(false) - n_parameters(true), 44);
+
+    // both positions of the shared kernel read the same conv variable, a write through it shows in the filters of
+    // both. Without highways the projection picks the first 10 filters, the 4 of each of the first two blocks
+    params.highways = 0;
+    params.share_conv_weights = true;
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let variables = vars.variables();
+    tch::no_grad(|| {
+        variables["char_level.to_dim.weight"].shallow_clone().copy_(&Tensor::eye(12, (Kind::Float, params.device)).narrow(0, 0, params.in_dim));
+        let _ = variables["char_level.to_dim.bias"].shallow_clone().zero_();
+    });
+    let block_filters = || {
+        let char_level = model.layer_outputs(&xs.unsqueeze(0)).get(0);
+        (char_level.narrow(-1, 0, 4), char_level.narrow(-1, 4, 4))
+    };
+    let (first, second) = block_filters();
+    assert!(first.allclose(&second, 1e-6, 1e-6, false));
+    tch::no_grad(|| {
+        let _ = variables["char_level.conv_0.conv.weight"].shallow_clone().g_add_scalar_(1.0);
+    });
+    let (first_written, second_written) = block_filters();
+    assert!(!first_written.allclose(&first, 1e-6, 1e-6, false));
+    assert!(first_written.allclose(&second_written, 1e-6, 1e-6, false));
+
+}
+
+#[test]
+fn token_log_probs_sum_to_the_sentence_log_likelihood() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let log_probs = model.token_log_probs(&xs, &ys);
+    assert_eq!(log_probs.size(), ys.size());
+    assert!(Vec::<f64>::try_from(&log_probs).unwrap().iter().all(|p| *p <= 0.0));
+
+    // the mean cross entropy loss of the sentence, as in training
+    let logits = model.forward_t(&xs.unsqueeze(0).to_device(params.device), false);
+    let loss = logits.cross_entropy_for_logits(&ys.to_device(params.device)).double_value(&[]);
+    let n = ys.size()[0] as f64;
+    assert!((log_probs.sum(Kind::Double).double_value(&[]) + loss * n).abs() < 1e-4);
+
+}
+
+#[test]
+fn confusion_report_tracks_top_predictions_of_frequent_tokens() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut eval_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+
+    // the tracked tokens are the most frequent by count, "is" (5) then "This" and "a" of the ties at 4, not the lowest ids
+    let tracked = most_frequent_ids(preprocessor.token_counts(), elmo_text_loader.token2int(), 3);
+    let token2int = elmo_text_loader.token2int();
+    assert_eq!(tracked, ["is", "This", "a"].map(|t| token2int[t] as i64).to_vec());
+
+    // the expected entries, from the predictions of the same (eval mode) model over the same stream
+    let mut expected: BTreeMap<i64, BTreeMap<i64, usize>> = BTreeMap::new();
+    for (xs, ys) in eval_iter.batches() {
+        let prediction = model.forward_t(&xs, false).argmax(1, false).int64_value(&[0]);
+        let target = ys.int64_value(&[0, 0]);
+        if tracked.contains(&target) {
+            *expected.entry(target).or_default().entry(prediction).or_default() += 1;
+        }
+    }
+    let report = confusion_report(&model, &mut eval_iter, &tracked);
+    assert_eq!(report, expected);
+    assert!(report.keys().all(|t| tracked.contains(t)));
+
+    let output_dir = std::env::temp_dir().join("elmo_confusion_report");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("confusion_report.tsv").to_str().unwrap().to_string();
+    write_confusion_report(&report, &elmo_text_loader, &output_file).unwrap();
+    let lines = fs::read_to_string(&output_file).unwrap().lines().map(|l| l.to_string()).collect::<Vec<String>>();
+    assert_eq!(lines.len(), report.len());
+    for (target, predictions) in &report {
+        let line = lines.iter().find(|l| l.starts_with(&format!("{}\t", elmo_text_loader.decode_label(*target)))).unwrap();
+        assert_eq!(line.split('\t').nth(1).unwrap().parse::<usize>().unwrap(), predictions.values().sum::<usize>());
+    }
+
+}
+
+#[test]
+fn pre_padding_places_pads_before_the_chars() {
+
+    let mut sentences = vec!["sky is blue".to_string()];
+    let mut params = example_params();
+    params.max_len_token = 8;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let pad = char2int[&' '] as i64;
+    let encoded = ["$", "i", "s", "^"].iter().map(|c| char2int[&c.chars().next().unwrap()] as i64).collect::<Vec<i64>>();
+
+    // "is" is the third token, wrapped with start and end chars it's 4 chars in 8
+    let encode_with = |side: &str| {
+        let mut params = params.clone();
+        params.char_padding_side = side.to_string();
+        let elmo_text_loader = ELMoText::new(sentences.clone(), token2int.clone(), char2int.clone(), &params);
+        let (inputs, _) = elmo_text_loader.get_example(0).unwrap();
+        Vec::<i64>::try_from(&inputs.get(2)).unwrap()
+    };
+
+    assert_eq!(encode_with("post"), [encoded.clone(), vec![pad; 4]].concat());
+    assert_eq!(encode_with("pre"), [vec![pad; 4], encoded.clone()].concat());
+    assert_eq!(encode_with("both"), [vec![pad; 2], encoded, vec![pad; 2]].concat());
+
+}
+
+#[test]
+fn step_validation_interval_evaluates_on_multiples_of_the_interval() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_iter = 2;
+    params.validation_interval = Some(2);
+
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    assert!(devset_iter.is_some());
+    let steps_per_epoch = trainset_iter.n_batches() as i64;
+    assert!(steps_per_epoch >= 2);
+
+    // the first interval falls within the first epoch, so no extra end of epoch evaluation is made
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    let expected = (1..=2 * steps_per_epoch / 2).map(|i| i * 2).collect::<Vec<i64>>();
+    assert_eq!(train_progress.validation_steps(), expected.as_slice());
+
+}
+
+#[test]
+fn warm_start_restores_matching_variables_and_skips_a_new_head() {
+
+    let mut params = example_params();
+    let pretrained_vars = nn::VarStore::new(params.device);
+    let _pretrained = ELMo::new(&pretrained_vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_warm_start");
+    fs::create_dir_all(&output_dir).unwrap();
+    let checkpoint_file = output_dir.join("pretrained.ot").to_str().unwrap().to_string();
+    pretrained_vars.save(&checkpoint_file).unwrap();
+
+    // a new vocabulary changes the shape of the head only
+    params.token_vocab_size += 3;
+    let mut vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+    let head_before = vars.variables()["to_vocab.weight"].copy();
+    let (loaded, skipped) = archiving::warm_start(&mut vars, &checkpoint_file).unwrap();
+    assert_eq!(skipped, vec!["to_vocab.bias".to_string(), "to_vocab.weight".to_string()]);
+    assert_eq!(loaded.len() + skipped.len(), vars.variables().len());
+
+    let variables = vars.variables();
+    let pretrained_variables = pretrained_vars.variables();
+    assert!(variables["to_vocab.weight"].equal(&head_before));
+    for name in loaded {
+        assert!(variables[&name].equal(&pretrained_variables[&name]), "{} was not restored", name);
+    }
+    assert!(variables["forward_lm.lstm_0.weight_ih_l0"].equal(&pretrained_variables["forward_lm.lstm_0.weight_ih_l0"]));
+
+}
+
+#[test]
+fn curriculum_epochs_start_with_the_shortest_sentences() {
+
+    // each token is labeled with the length of its sentence
+    let lengths = [5, 2, 4, 1, 3];
+    let xs = lengths.iter().map(|n| Tensor::ones([*n, 4], (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let ys = lengths.iter().map(|n| Tensor::full([*n], *n, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let mut loader = Loader::new(xs, ys, Device::Cpu, 1, 3);
+    loader.curriculum_epochs = 2;
+
+    for epoch in 0..2 {
+        let labels = loader.epoch_order(epoch).batches().map(|(_, ys)| Vec::<i64>::try_from(&ys.reshape([-1])).unwrap()).collect::<Vec<Vec<i64>>>();
+        assert_eq!(labels[0], vec![1, 2, 2]);
+        assert_eq!(labels.concat(), vec![1, 2, 2, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 5]);
+    }
+
+    // only the train loader is set for curriculum
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.curriculum = true;
+    params.curriculum_epochs = 2;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (trainset_iter, devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    assert_eq!(trainset_iter.curriculum_epochs, 2);
+    assert_eq!(devset_iter.unwrap().curriculum_epochs, 0);
+
+}
+
+#[test]
+fn memory_usage_is_read_and_logged_while_training() {
+
+    let (current, peak) = memory_usage().unwrap();
+    assert!(current > 0);
+    assert!(peak >= current);
+    log_memory_usage(0, Device::Cpu).unwrap();
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.device = Device::Cpu;
+    params.max_steps = Some(2);
+    params.log_interval = 1;
+    params.log_memory = true;
+
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    assert_eq!(train_progress.last_step(), Some(2));
+
+}
+
+#[test]
+fn lstm_layer_output_is_its_input_plus_the_projected_lstm_output() {
+
+    let mut params = example_params();
+    params.n_lstm_layers = 2;
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    let xs = Tensor::randint(params.char_vocab_size, [2, 5, params.max_len_token], (Kind::Int64, Device::Cpu));
+
+    // the char embedding and the output of each layer, the top layer is the last one
+    let outputs = model.layer_outputs(&xs);
+    assert_eq!(outputs.size(), vec![3, 2, 5, params.in_dim]);
+
+    // rebuild the second layer and the projection with the trained names, and redo the layer by hand
+    let mut layer_vars = nn::VarStore::new(Device::Cpu);
+    let lstm = nn::lstm(layer_vars.root() / "forward_lm" / "lstm_1", params.in_dim, params.hidden_dim, Default::default());
+    let to_rep = nn::linear(layer_vars.root() / "forward_lm" / "to_dim_lstm", params.hidden_dim, params.in_dim, Default::default());
+    layer_vars.copy(&vars).unwrap();
+
+    let layer_input = outputs.get(1);
+    let expected = tch::no_grad(|| lstm.seq(&layer_input).0.apply(&to_rep) + &layer_input);
+    assert!(outputs.get(2).allclose(&expected, 1e-6, 1e-6, false));
+
+}
+
+#[test]
+fn char_vocab_overflow_truncates_or_fails() {
+
+    // 26 distinct letters and the space, over a char_vocab_size of 10
+    let sentences = vec!["abcdefghijklm nopqrstuvwxyz aaaa bbb cc".to_string()];
+    let mut params = example_params();
+    params.char_vocab_size = 10;
+
+    let mut truncated_params = params.clone();
+    let (token2int, char2int) = Preprocessor::new().preprocess(&mut sentences.clone(), &mut truncated_params).unwrap();
+    assert_eq!(char2int.len(), 10 + 3);
+    assert_eq!(truncated_params.char_vocab_size, 13);
+    assert!(['a', 'b', 'c', ' '].iter().all(|c| char2int.contains_key(c)));
+    assert!(!char2int.contains_key(&'z'));
+
+    // a truncated char is encoded as unk
+    let elmo_text_loader = ELMoText::new(vec!["z a".to_string()], token2int, char2int.clone(), &truncated_params);
+    let (inputs, _) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(inputs.int64_value(&[0, 1]), char2int[&truncated_params.char_unk] as i64);
+
+    params.char_vocab_overflow = String::from("error");
+    let result = Preprocessor::new().preprocess(&mut sentences.clone(), &mut params.clone());
+    assert!(result.err().unwrap().to_string().contains("more than char_vocab_size"));
+
+}
+
+#[test]
+fn streamed_examples_match_the_eager_ones_in_order() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    // the indices of a split come as a tensor, they are iterated without collecting them
+    let split = Splitter::new().get_split_train_dev_test_indices(elmo_text_loader.get_len()).remove(0);
+    let indices = Vec::<i64>::try_from(&split).unwrap();
+    let eager = indices.iter().map(|i| elmo_text_loader.get_example(*i as usize).unwrap()).collect::<Vec<(Tensor, Tensor)>>();
+
+    let mut n_streamed = 0;
+    for (example, (xs, ys)) in elmo_text_loader.stream_examples(split.iter::<i64>().unwrap()).zip(eager.iter()) {
+        let (streamed_xs, streamed_ys) = example.unwrap();
+        assert!(streamed_xs.equal(xs));
+        assert!(streamed_ys.equal(ys));
+        n_streamed += 1;
+    }
+    assert_eq!(n_streamed, eager.len());
+
+    // batching on the fly gives the batches of the stream of a loader over the same examples, with full
+    // batches, the remaining whole chunks and the final partial chunk
+    params.batch_size = 2;
+    params.seq_length = 3;
+    params.min_chunk_tokens = Some(1);
+    let mut loader = Loader::new(eager.iter().map(|(xs, _)| xs.shallow_clone()).collect(), eager.iter().map(|(_, ys)| ys.shallow_clone()).collect(), params.device, params.batch_size, params.seq_length);
+    loader.min_chunk_tokens = params.min_chunk_tokens;
+    let eager_batches = loader.batches().collect::<Vec<(Tensor, Tensor)>>();
+    let streamed_batches = elmo_text_loader.stream_examples(split.iter::<i64>().unwrap()).batches(&params).collect::<Result<Vec<(Tensor, Tensor)>, _>>().unwrap();
+    assert_eq!(streamed_batches.len(), eager_batches.len());
+    for ((streamed_xs, streamed_ys), (xs, ys)) in streamed_batches.iter().zip(eager_batches.iter()) {
+        assert!(streamed_xs.equal(xs));
+        assert!(streamed_ys.equal(ys));
+    }
+
+}
+
+#[test]
+fn auto_dims_replaces_inconsistent_char_level_dims() {
+
+    let mut params = example_params();
+    params.char_level_out_dim = Some(params.in_dim + 5);
+    params.in_channels = 3;
+    assert!(params.clone().resolve_dims().is_err());
+
+    params.auto_dims = true;
+    params.resolve_dims().unwrap();
+    assert_eq!(params.char_level_out_dim, Some(params.in_dim));
+    assert_eq!(params.in_channels, 1);
+
+    // the model builds and runs with the resolved dims
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let xs = Tensor::randint(params.char_vocab_size, [2, 3, params.max_len_token], (Kind::Int64, params.device));
+    assert_eq!(model.embed(&xs).size(), vec![2, 3, params.in_dim]);
+
+}
+
+#[test]
+fn char_backoff_loss_comes_from_unk_label_positions_only() {
+
+    let mut params = example_params();
+    params.char_backoff_weight = 1.0;
+    let vars = nn::VarStore::new(Device::Cpu);
+    // the unk id comes from token2int, not from the vocabulary size
+    let unk = 1;
+    let model = ELMo::new(&vars.root(), &params).with_unk_id(unk);
+    let xs = Tensor::randint(params.char_vocab_size, [1, 4, params.max_len_token], (Kind::Int64, Device::Cpu));
+
+    // labels are UNK at the given positions, and token 2 elsewhere
+    let backoff = |unk_positions: &[i64]| {
+        let labels = (0..4).map(|i| if unk_positions.contains(&i) { unk } else { 2 }).collect::<Vec<i64>>();
+        let ys = Tensor::from_slice(&labels).reshape([1, 4]);
+        model.char_backoff_loss(&xs, &ys, false).unwrap().double_value(&[])
+    };
+
+    // no UNK, or UNK only at the last position that has no next input
+    assert_eq!(backoff(&[]), 0.0);
+    assert_eq!(backoff(&[3]), 0.0);
+    let last_id = Tensor::full([1, 4], params.token_vocab_size - 1, (Kind::Int64, Device::Cpu));
+    assert_eq!(model.char_backoff_loss(&xs, &last_id, false).unwrap().double_value(&[]), 0.0);
+
+    // padded positions (IGNORE_INDEX labels) are left out, their chars are not targets
+    let padded = Tensor::from_slice(&[unk, IGNORE_INDEX, IGNORE_INDEX, IGNORE_INDEX]).reshape([1, 4]);
+    let padded_loss = model.char_backoff_loss(&xs, &padded, false).unwrap().double_value(&[]);
+    assert!((padded_loss - backoff(&[0])).abs() < 1e-9);
+
+    // the loss is the mean over UNK positions, each contributes its own term
+    let (first, second) = (backoff(&[0]), backoff(&[1]));
+    assert!(first > 0.0 && second > 0.0);
+    assert!((backoff(&[0, 1]) - (first + second) / 2.0).abs() < 1e-5);
+    assert!((backoff(&[0, 1, 3]) - backoff(&[0, 1])).abs() < 1e-9);
+
+    // without a weight there is no char backoff head
+    let params = example_params();
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    assert!(model.char_backoff_loss(&xs, &Tensor::zeros([1, 4], (Kind::Int64, Device::Cpu)), false).is_none());
+
+}
+
+#[cfg(feature = "hdf5_export")]
+#[test]
+fn hdf5_export_stores_the_embeddings_by_sentence() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_hdf5");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("embeddings.hdf5").to_str().unwrap().to_string();
+    embedding::export_hdf5(&model, &elmo_text_loader, &[0, 1], &output_file, &params).unwrap();
+
+    let file = hdf5::File::open(&output_file).unwrap();
+    let sentence_to_index = file.dataset(embedding::SENTENCE_TO_INDEX).unwrap().read_scalar::<hdf5::types::VarLenUnicode>().unwrap();
+    let sentence_to_index: HashMap<String, String> = serde_json::from_str(sentence_to_index.as_str()).unwrap();
+    assert_eq!(sentence_to_index.len(), 2);
+
+    for index in [0, 1] {
+        let tokens = elmo_text_loader.get_tokens(index).unwrap();
+        assert_eq!(sentence_to_index[&tokens.join(" ")], index.to_string());
+
+        // the stored array is the embedding of the sentence, as computed by the model
+        let dataset = file.dataset(&index.to_string()).unwrap();
+        assert_eq!(dataset.shape(), vec![tokens.len(), params.in_dim as usize]);
+        let stored = Tensor::from_slice(&dataset.read_raw::<f32>().unwrap()).reshape([tokens.len() as i64, params.in_dim]);
+        let expected = model.embed(&elmo_text_loader.encode_tokens(&tokens).unsqueeze(0)).squeeze_dim(0).to_kind(Kind::Float);
+        assert!(stored.allclose(&expected, 1e-6, 1e-6, false));
+    }
+
+}
+
+#[test]
+fn char_cnn_gradient_is_scaled_by_the_configured_factor() {
+
+    // the scaled identity keeps the values and scales the gradient
+    let xs = Tensor::from_slice(&[1.0f32, -2.0, 3.0]).set_requires_grad(true);
+    let ys = scale_grad(&xs, 0.25);
+    assert!(ys.equal(&xs));
+    ys.sum(Kind::Float).backward();
+    assert!(xs.grad().allclose(&Tensor::from_slice(&[0.25f32, 0.25, 0.25]), 1e-6, 1e-6, false));
+
+    // the same weights, with and without scaling the char level gradient
+    let mut params = example_params();
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    params.char_cnn_grad_scale = 0.25;
+    let mut scaled_vars = nn::VarStore::new(Device::Cpu);
+    let scaled_model = ELMo::new(&scaled_vars.root(), &params);
+    scaled_vars.copy(&vars).unwrap();
+
+    let xs = Tensor::randint(params.char_vocab_size, [2, 3, params.max_len_token], (Kind::Int64, Device::Cpu));
+    model.forward_t(&xs, false).sum(Kind::Float).backward();
+    scaled_model.forward_t(&xs, false).sum(Kind::Float).backward();
+
+    let (variables, scaled_variables) = (vars.variables(), scaled_vars.variables());
+    let grad = |variables: &HashMap<String, Tensor>, name: &str| variables[name].grad();
+    assert!(grad(&scaled_variables, "char_level.to_dim.weight").allclose(&(grad(&variables, "char_level.to_dim.weight") * 0.25), 1e-5, 1e-6, false));
+    assert!(grad(&scaled_variables, "to_vocab.weight").allclose(&grad(&variables, "to_vocab.weight"), 1e-5, 1e-6, false));
+    assert!(grad(&scaled_variables, "forward_lm.lstm_0.weight_ih_l0").allclose(&grad(&variables, "forward_lm.lstm_0.weight_ih_l0"), 1e-5, 1e-6, false));
+
+}
+
+#[test]
+fn all_oov_sentences_are_dropped_when_asked() {
+
+    let mut sentences = vec!["sky is blue".to_string(), "sky is red".to_string(), "xyz qwe".to_string()];
+    let mut params = example_params();
+    params.min_count = 2;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert!(!token2int.contains_key("xyz") && !token2int.contains_key("qwe"));
+
+    // "SOS xyz qwe EOS" has no in vocabulary token besides the boundaries
+    let elmo_text_loader = ELMoText::new(sentences.clone(), token2int.clone(), char2int.clone(), &params);
+    assert_eq!(elmo_text_loader.get_len(), 3);
+    params.skip_oov_sentences = true;
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.get_len(), 2);
+    assert_eq!(elmo_text_loader.get_tokens(1).unwrap(), vec!["SOS", "sky", "is", "red", "EOS"]);
+
+}
+
+#[test]
+fn capped_evaluations_sample_rotating_dev_batches() {
+
+    // 20 single token sentences labeled by their index, one token per batch
+    let dev_loader = |eval_sample: Option<(usize, u64)>| {
+        let xs = (0..20).map(|_| Tensor::ones([1, 4], (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+        let ys = (0..20).map(|i| Tensor::from_slice(&[i as i64])).collect::<Vec<Tensor>>();
+        let mut loader = Loader::new(xs, ys, Device::Cpu, 1, 1);
+        loader.eval_sample = eval_sample;
+        loader
+    };
+    let sampled = |loader: &mut Loader| loader.eval_batches().map(|(_, ys)| ys.int64_value(&[0, 0])).collect::<Vec<i64>>();
+
+    // different seeds sample different batches, the same seed the same ones
+    let first = sampled(&mut dev_loader(Some((5, 1))));
+    assert_eq!(first.len(), 5);
+    assert_ne!(first, sampled(&mut dev_loader(Some((5, 2)))));
+    assert_eq!(first, sampled(&mut dev_loader(Some((5, 1)))));
+
+    // the sample of the next evaluation rotates
+    let mut loader = dev_loader(Some((5, 1)));
+    assert_ne!(sampled(&mut loader), sampled(&mut loader));
+
+    // without a cap all the batches are evaluated
+    let mut all = sampled(&mut dev_loader(None));
+    all.sort();
+    assert_eq!(all, (0..20).collect::<Vec<i64>>());
+
+}
+
+#[test]
+fn entropy_is_high_for_uniform_and_low_for_peaked_predictions() {
+
+    // a uniform row has the maximal entropy ln(vocab_size), a peaked one almost none. Large logits are stable
+    let logits = Tensor::from_slice(&[0.0f32, 0.0, 0.0, 0.0, 1000.0, 0.0, 0.0, 0.0]).reshape([2, 4]);
+    let entropies = Vec::<f32>::try_from(&logits_entropy(&logits)).unwrap();
+    assert!((entropies[0] - 4f32.ln()).abs() < 1e-5);
+    assert!(entropies[1].abs() < 1e-5);
+
+    // one entropy per position of a sentence, within [0, ln(vocab_size)]
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let entropies = Vec::<f32>::try_from(&model.token_entropies(&xs).to_device(Device::Cpu)).unwrap();
+    assert_eq!(entropies.len() as i64, ys.size()[0]);
+    assert!(entropies.iter().all(|e| *e >= 0.0 && *e <= (params.token_vocab_size as f32).ln() + 1e-4));
+
+}
+
+#[test]
+fn sequential_splits_are_contiguous_and_in_order() {
+
+    let mut params = example_params();
+    params.split_mode = String::from("sequential");
+    assert_eq!(params.split_mode(), SplitMode::Sequential);
+    let splits = Splitter::with_mode(params.split_mode()).get_split_train_dev_test_indices(20);
+    let splits = splits.iter().map(|split| Vec::<i64>::try_from(split).unwrap()).collect::<Vec<Vec<i64>>>();
+    assert_eq!(splits, vec![(0..16).collect::<Vec<i64>>(), vec![16, 17], vec![18, 19]]);
+
+    // random splits cover the same indices, in a permuted order
+    let mut random = Splitter::new().get_split_train_dev_test_indices(20).iter().flat_map(|split| Vec::<i64>::try_from(split).unwrap()).collect::<Vec<i64>>();
+    random.sort();
+    assert_eq!(random, (0..20).collect::<Vec<i64>>());
+
+}
+
+#[test]
+fn pin_memory_is_a_noop_on_cpu() {
+
+    let loader = |pin_memory: bool| {
+        let xs = vec![Tensor::arange(12 * 4, (Kind::Int64, Device::Cpu)).reshape([12, 4])];
+        let ys = vec![Tensor::arange(12, (Kind::Int64, Device::Cpu))];
+        let mut loader = Loader::new(xs, ys, Device::Cpu, 2, 3);
+        loader.pin_memory = pin_memory;
+        loader
+    };
+
+    let pinned = loader(true).batches().collect::<Vec<(Tensor, Tensor)>>();
+    let plain = loader(false).batches().collect::<Vec<(Tensor, Tensor)>>();
+    assert_eq!(pinned.len(), 2);
+    for ((xs, ys), (plain_xs, plain_ys)) in pinned.iter().zip(plain.iter()) {
+        assert_eq!(xs.device(), Device::Cpu);
+        assert!(xs.equal(plain_xs) && ys.equal(plain_ys));
+    }
+
+}
+
+#[test]
+fn chars_are_tagged_and_partitioned_by_script() {
+
+    assert_eq!(char_script('a'), Script::Latin);
+    assert_eq!(char_script('ж'), Script::Cyrillic);
+    assert_eq!(char_script(' '), Script::Common);
+    assert_eq!(char_script('7'), Script::Common);
+
+    // latin and cyrillic words, interleaved by frequency
+    let mut sentences = vec!["ab где ab вж ab".to_string()];
+    let mut params = example_params();
+    params.partition_chars_by_script = true;
+    let mut preprocessor = Preprocessor::new();
+    let (_, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    // one run per script, the reserved chars last, and the runs cover all the ids
+    let reserved = [params.char_start, params.char_end, params.char_unk];
+    let partitions = script_partitions(&char2int, &reserved);
+    let scripts = partitions.iter().map(|(script, _)| *script).collect::<Vec<Script>>();
+    assert_eq!(scripts, vec![Script::Common, Script::Latin, Script::Cyrillic, Script::Reserved]);
+    assert_eq!(partitions.iter().map(|(_, range)| range.len()).sum::<usize>(), char2int.len());
+    for (script, range) in &partitions {
+        for (c, id) in &char2int {
+            if range.contains(id) && *script != Script::Reserved {
+                assert_eq!(char_script(*c), *script);
+            }
+        }
+    }
+
+    // each script gets an embedding of its rows, feeding the same char cnn
+    let sizes = partitions.iter().map(|(_, range)| range.len() as i64).collect::<Vec<i64>>();
+    assert_eq!(params.char_script_partitions, Some(sizes.clone()));
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    let variables = vars.variables();
+    assert!(!variables.contains_key("char_level.embed.weight"));
+    for (i, size) in sizes.iter().enumerate() {
+        assert_eq!(variables[&format!("char_level.embed_{}.weight", i)].size(), vec![*size, params.char_embedding_dim]);
+    }
+    let xs = Tensor::randint(params.char_vocab_size, [1, 2, params.max_len_token], (Kind::Int64, Device::Cpu));
+    assert_eq!(model.embed(&xs).size(), vec![1, 2, params.in_dim]);
+
+    // the byte chars of byte_fallback follow the reserved chars, in the last run
+    let mut params = example_params();
+    params.partition_chars_by_script = true;
+    params.byte_fallback = true;
+    let (_, char2int) = preprocessor.preprocess(&mut vec!["ab где".to_string()], &mut params).unwrap();
+    let partitions = script_partitions(&char2int, &reserved);
+    assert_eq!(partitions.last().unwrap(), &(Script::Reserved, char2int.len() - 259..char2int.len()));
+    assert_eq!(params.char_script_partitions.unwrap().iter().sum::<i64>(), params.char_vocab_size);
+
+    // without partition_chars_by_script there is a single embedding
+    let mut params = example_params();
+    preprocessor.preprocess(&mut vec!["ab где".to_string()], &mut params).unwrap();
+    assert_eq!(params.char_script_partitions, None);
+
+}
+
+#[test]
+fn manifest_summarizes_a_short_run() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_steps = Some(2);
+    let output_dir = std::env::temp_dir().join("elmo_manifest");
+    fs::create_dir_all(&output_dir).unwrap();
+    let corpus_file = output_dir.join("corpus.txt").to_str().unwrap().to_string();
+    fs::write(&corpus_file, sentences.join("\n")).unwrap();
+    params.corpus_file = Some(corpus_file);
+
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int.clone(), char2int.clone(), &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+
+    let manifest_file = output_dir.join("manifest.json").to_str().unwrap().to_string();
+    write_manifest(&manifest_file, &params, &vars, &train_progress, std::time::Duration::from_secs(3)).unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(&manifest_file).unwrap()).unwrap();
+
+    for key in ["config", "corpus_file", "corpus_hash", "token_vocab_size", "char_vocab_size", "n_parameters", "build", "final_metrics", "wall_clock_seconds"] {
+        assert!(manifest.get(key).is_some(), "manifest has no {}", key);
+    }
+    assert_eq!(manifest["token_vocab_size"].as_u64(), Some(token2int.len() as u64));
+    assert_eq!(manifest["char_vocab_size"].as_u64(), Some(char2int.len() as u64));
+    assert_eq!(manifest["corpus_hash"].as_str().unwrap().len(), 16);
+    let n_parameters: i64 = vars.variables().values().map(|v| v.numel() as i64).sum();
+    assert_eq!(manifest["n_parameters"].as_i64(), Some(n_parameters));
+    assert_eq!(manifest["final_metrics"]["steps"].as_i64(), Some(2));
+    assert!(manifest["final_metrics"]["train_loss"].as_f64().unwrap() > 0.0);
+    assert_eq!(manifest["config"]["max_steps"].as_i64(), Some(2));
+    assert_eq!(manifest["wall_clock_seconds"].as_f64(), Some(3.0));
+
+}
+
+#[test]
+fn compressed_checkpoint_round_trips() {
+
+    let params = example_params();
+    let vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_compressed_checkpoint");
+    fs::create_dir_all(&output_dir).unwrap();
+    let checkpoint_file = output_dir.join("model.ot").to_str().unwrap().to_string();
+    archiving::save_checkpoint(&vars, &checkpoint_file, true).unwrap();
+    assert_eq!(&fs::read(&checkpoint_file).unwrap()[..2], &[0x1f, 0x8b]);
+
+    let mut loaded_vars = nn::VarStore::new(params.device);
+    let _loaded_model = ELMo::new(&loaded_vars.root(), &params);
+    archiving::load_checkpoint(&mut loaded_vars, &checkpoint_file).unwrap();
+    let variables = vars.variables();
+    let loaded_variables = loaded_vars.variables();
+    assert_eq!(variables.len(), loaded_variables.len());
+    for (name, variable) in variables {
+        assert!(loaded_variables[&name].equal(&variable), "{} differs after loading", name);
+    }
+
+    // uncompressed checkpoints still load
+    archiving::save_checkpoint(&vars, &checkpoint_file, false).unwrap();
+    assert_ne!(&fs::read(&checkpoint_file).unwrap()[..2], &[0x1f, 0x8b]);
+    archiving::load_checkpoint(&mut loaded_vars, &checkpoint_file).unwrap();
+
+}
+
+#[test]
+fn checkpoints_with_the_root_variable_names_still_load() {
+
+    let mut params = example_params();
+    params.out_channels = vec![20, 10];
+    params.kernel_size = vec![1, 2];
+    params.highways = 1;
+    params.n_lstm_layers = 2;
+
+    // the layout of older versions, every variable at the root and repeated names suffixed by tch
+    let legacy_vars = nn::VarStore::new(params.device);
+    let root = legacy_vars.root();
+    let total_filters = params.out_channels.iter().sum::<i64>();
+    let _ = nn::embedding(&root / "embed", params.char_vocab_size, params.char_embedding_dim, Default::default());
+    for (out_channel, kernel_size) in params.out_channels.iter().zip(&params.kernel_size) {
+        let _ = nn::conv(&root / "conv", params.in_channels, *out_channel, [params.char_embedding_dim, *kernel_size], Default::default());
+    }
+    for _ in 0..params.highways {
+        let _ = nn::linear(&root / "w_t", total_filters, total_filters, Default::default());
+        let _ = nn::linear(&root / "w_h", total_filters, total_filters, Default::default());
+    }
+    let _ = nn::linear(&root / "to_dim", total_filters, params.in_dim, Default::default());
+    for _ in 0..2 {
+        for _ in 0..params.n_lstm_layers {
+            let _ = nn::lstm(&root / "lstm", params.in_dim, params.hidden_dim, Default::default());
+        }
+        let _ = nn::linear(&root / "to_dim_lstm", params.hidden_dim, params.in_dim, Default::default());
+    }
+    let _ = nn::linear(&root / "to_vocab", params.in_dim, params.token_vocab_size, Default::default());
+
+    let output_dir = std::env::temp_dir().join("elmo_legacy_checkpoint");
+    fs::create_dir_all(&output_dir).unwrap();
+    let checkpoint_file = output_dir.join("model.ot").to_str().unwrap().to_string();
+    archiving::save_checkpoint(&legacy_vars, &checkpoint_file, false).unwrap();
+
+    // every old variable is renamed to its submodule, the variables of newer features keep their initialization
+    let mut vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+    let legacy_names = archiving::legacy_names(&vars);
+    let legacy_variables = legacy_vars.variables();
+    assert_eq!(legacy_names.len(), legacy_variables.len());
+    assert_eq!(legacy_names["backward_lm.lstm_1.weight_ih_l0"], format!("lstm.weight_ih_l0__{}", legacy_variables.len() - 8));
+    archiving::load_checkpoint(&mut vars, &checkpoint_file).unwrap();
+    let variables = vars.variables();
+    for (name, legacy_name) in legacy_names {
+        assert!(variables[&name].equal(&legacy_variables[&legacy_name]), "{} isn't loaded from {}", name, legacy_name);
+    }
+
+}
+
+#[test]
+fn missing_boundary_chars_fail_construction() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, mut char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert!(ELMoText::try_new(sentences.clone(), token2int.clone(), char2int.clone(), &params).is_ok());
+
+    // new doesn't check the vocabulary, it never panics on it
+    char2int.remove(&params.char_end);
+    assert!(ELMoText::try_new(sentences.clone(), token2int.clone(), char2int.clone(), &params).is_err());
+    assert_eq!(ELMoText::new(sentences.clone(), token2int.clone(), char2int.clone(), &params).get_len(), sentences.len() as i64);
+
+    params.check_boundary_chars = false;
+    assert!(ELMoText::try_new(sentences, token2int, char2int, &params).is_ok());
+
+}
+
+#[test]
+fn compute_capability_check_passes_on_cpu() {
+
+    assert_eq!(compute_capability(Device::Cpu), None);
+    assert_eq!(check_compute_capability(Device::Cpu, 100.0, "error").unwrap(), None);
+    assert_eq!(check_compute_capability(Device::Cpu, 100.0, "warn").unwrap(), None);
+
+}
+
+#[test]
+fn chunked_lstm_matches_the_full_sequence() {
+
+    let vars = nn::VarStore::new(Device::Cpu);
+    let lstm = nn::lstm(&vars.root(), 6, 8, Default::default());
+    let xs = Tensor::randn(&[2, 10, 6], (Kind::Float, Device::Cpu));
+
+    let (full, full_state) = chunked_seq(&lstm, &xs, None);
+    for chunk_size in [1, 3, 4, 10] {
+        let (chunked, chunked_state) = chunked_seq(&lstm, &xs, Some(chunk_size));
+        assert_eq!(chunked.size(), full.size());
+        assert!(chunked.allclose(&full, 1e-5, 1e-6, false), "chunks of {} don't match", chunk_size);
+        assert!(chunked_state.h().allclose(&full_state.h(), 1e-5, 1e-6, false));
+        assert!(chunked_state.c().allclose(&full_state.c(), 1e-5, 1e-6, false));
+    }
+
+}
+
+#[test]
+fn cuda_failures_fall_back_to_cpu_when_asked() {
+
+    // cuda fails by an error or by a panic, as libtorch does on a broken driver
+    let failing_error = |device: Device| -> Result<i64, Box<dyn std::error::Error>> {
+        match device.is_cuda() { true => Err("cuda init failed".into()), false => Ok(1) }
+    };
+    let failing_panic = |device: Device| -> Result<i64, Box<dyn std::error::Error>> {
+        if device.is_cuda() { panic!("cuda alloc failed") }
+        Ok(2)
+    };
+
+    assert_eq!(build_with_cpu_fallback(Device::Cuda(0), true, failing_error).unwrap(), (1, Device::Cpu));
+    assert_eq!(build_with_cpu_fallback(Device::Cuda(0), true, failing_panic).unwrap(), (2, Device::Cpu));
+    let error = build_with_cpu_fallback(Device::Cuda(0), false, failing_panic).unwrap_err();
+    assert_eq!(error.to_string(), "cuda alloc failed");
+    assert!(build_with_cpu_fallback(Device::Cuda(0), false, failing_error).is_err());
+
+    // nothing to fall back from on the cpu
+    assert_eq!(build_with_cpu_fallback(Device::Cpu, false, failing_error).unwrap(), (1, Device::Cpu));
+    let always_failing = |_: Device| -> Result<i64, Box<dyn std::error::Error>> { Err("broken".into()) };
+    assert!(build_with_cpu_fallback(Device::Cpu, true, always_failing).is_err());
+
+}
+
+#[test]
+fn locked_output_dir_rejects_a_second_run() {
+
+    let output_dir = std::env::temp_dir().join("elmo_output_lock");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_dir = output_dir.to_str().unwrap().to_string();
+    let _ = fs::remove_file(format!("{}/{}", output_dir, files_handling::LOCK_FILE));
+
+    let lock = files_handling::lock_output_dir(&output_dir, false).unwrap();
+    let error = files_handling::lock_output_dir(&output_dir, false).unwrap_err();
+    assert!(error.to_string().contains("is locked by another run"), "{}", error);
+
+    // force takes the lock over, the overtaken run leaves it, and the lock file is gone once the new run ends
+    let forced_lock = files_handling::lock_output_dir(&output_dir, true).unwrap();
+    drop(lock);
+    assert!(std::path::Path::new(&format!("{}/{}", output_dir, files_handling::LOCK_FILE)).exists());
+    assert!(files_handling::lock_output_dir(&output_dir, false).is_err());
+    drop(forced_lock);
+    assert!(!std::path::Path::new(&format!("{}/{}", output_dir, files_handling::LOCK_FILE)).exists());
+    let _relock = files_handling::lock_output_dir(&output_dir, false).unwrap();
+
+}
+
+#[test]
+fn nbest_next_tokens_are_sorted_by_probability() {
+
+    tch::manual_seed(0);
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences.clone(), token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let seed = sentences[0].split_whitespace().take(3).collect::<Vec<&str>>().join(" ");
+    let nbest = nbest_next_tokens(&model, &elmo_text_loader, &seed, 4, &params).unwrap();
+    assert_eq!(nbest.len(), 4);
+    assert!(nbest.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    assert!(nbest.iter().all(|(token, prob)| !token.is_empty() && (0.0..=1.0).contains(prob)));
+
+    // the same seed gives the same predictions, also without its SOS
+    let unbounded_seed = seed.trim_start_matches("SOS").trim();
+    assert_eq!(nbest_next_tokens(&model, &elmo_text_loader, unbounded_seed, 4, &params).unwrap(), nbest);
+
+    // the seed is preprocessed as in training, extra spaces and the reserved chars are dropped
+    let noisy_seed = format!("  {}{} ", seed.replacen(' ', "   ", 1), params.char_start);
+    assert_eq!(nbest_next_tokens(&model, &elmo_text_loader, &noisy_seed, 4, &params).unwrap(), nbest);
+
+}
+
+#[test]
+fn loss_reductions_relate_by_the_number_of_tokens() {
+
+    // 2 rows of 3 tokens
+    let token_losses = Tensor::from_slice(&[1.0f64, 2.0, 3.0, 4.0, 6.0, 8.0]);
+    let targets = Tensor::zeros([6], (Kind::Int64, Device::Cpu));
+    let token_mean = reduce_loss(&token_losses, &targets, 2, "token_mean").double_value(&[]);
+    let sentence_mean = reduce_loss(&token_losses, &targets, 2, "sentence_mean").double_value(&[]);
+    let sum = reduce_loss(&token_losses, &targets, 2, "sum").double_value(&[]);
+
+    assert!((token_mean - 4.0).abs() < 1e-12);
+    assert!((sum - token_mean * 6.0).abs() < 1e-12);
+    // the rows have the same length, so the mean of the row means is the token mean
+    assert!((sentence_mean - token_mean).abs() < 1e-12);
+    assert!((sentence_mean - (2.0 + 6.0) / 2.0).abs() < 1e-12);
+
+    // the padded positions of the second row are left out, of the token mean and of its row mean
+    let padded_targets = Tensor::from_slice(&[0i64, 0, 0, 0, IGNORE_INDEX, IGNORE_INDEX]);
+    assert!((reduce_loss(&token_losses, &padded_targets, 2, "token_mean").double_value(&[]) - 10.0 / 4.0).abs() < 1e-12);
+    assert!((reduce_loss(&token_losses, &padded_targets, 2, "sentence_mean").double_value(&[]) - (2.0 + 4.0) / 2.0).abs() < 1e-12);
+    assert!((reduce_loss(&token_losses, &padded_targets, 2, "sum").double_value(&[]) - 10.0).abs() < 1e-12);
+
+    // on logits, token_mean is the cross entropy the trainer reports
+    let logits = Tensor::from_slice(&[0.5f64, -1.0, 2.0, 0.0, 1.0, 1.0]).reshape([2, 3]);
+    let targets = Tensor::from_slice(&[2i64, 0]);
+    let token_losses = logits.log_softmax(-1, Kind::Double).nll_loss_nd::<Tensor>(&targets, None, tch::Reduction::None, -100);
+    let expected = logits.cross_entropy_for_logits(&targets).double_value(&[]);
+    assert!((reduce_loss(&token_losses, &targets, 1, "token_mean").double_value(&[]) - expected).abs() < 1e-12);
+
+}
+
+#[test]
+fn seeded_runs_repeat_and_record_their_rng_state() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.seed = Some(7);
+    params.max_iter = 2;
+    let output_dir = std::env::temp_dir().join("elmo_rng_state");
+    fs::create_dir_all(&output_dir).unwrap();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    let run = |output_file: String| {
+        let mut params = params.clone();
+        params.output_file = Some(output_file);
+        tch::manual_seed(0);
+        let mut vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+        ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+        vars
+    };
+    let first_file = output_dir.join("first.ot").to_str().unwrap().to_string();
+    let second_file = output_dir.join("second.ot").to_str().unwrap().to_string();
+    let first_vars = run(first_file.clone());
+    let second_vars = run(second_file);
+    let second_variables = second_vars.variables();
+    for (name, variable) in first_vars.variables() {
+        assert!(second_variables[&name].equal(&variable), "{} differs between seeded runs", name);
+    }
+
+    // the checkpoint records the state to continue from, the start of the epoch after the last one
+    let rng_state = RngState::load(&first_file).unwrap();
+    assert_eq!(rng_state, RngState { seed: 7, epoch: 2 });
+    rng_state.restore();
+    let first_draw = Tensor::randn(&[4], (Kind::Float, Device::Cpu));
+    rng_state.restore();
+    assert!(Tensor::randn(&[4], (Kind::Float, Device::Cpu)).equal(&first_draw));
+    assert_ne!(rng_state.epoch_seed(), RngState { seed: 7, epoch: 1 }.epoch_seed());
+
+    // a run interrupted after its first epoch and resumed from its checkpoint ends with the weights of the
+    // uninterrupted run. sgd without momentum keeps no state, the moments of the other optimizers would restart
+    let sgd_run = |name: &str, max_iter: i64, resume_from: Option<String>| {
+        let mut params = params.clone();
+        params.optimizer = String::from("sgd");
+        params.max_iter = max_iter;
+        params.checkpoint_every = Some(1);
+        params.resume_from = resume_from;
+        let run_dir = output_dir.join(name);
+        fs::create_dir_all(&run_dir).unwrap();
+        params.output_file = Some(run_dir.join("model.ot").to_str().unwrap().to_string());
+        tch::manual_seed(0);
+        let mut vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+        ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+        (vars, run_dir)
+    };
+    let (uninterrupted_vars, _) = sgd_run("uninterrupted", 2, None);
+    let (_, interrupted_dir) = sgd_run("interrupted", 1, None);
+    let (resumed_vars, _) = sgd_run("resumed", 2, Some(interrupted_dir.join("checkpoint_1.ot").to_str().unwrap().to_string()));
+    let resumed_variables = resumed_vars.variables();
+    for (name, variable) in uninterrupted_vars.variables() {
+        assert!(resumed_variables[&name].equal(&variable), "{} differs between the resumed and the uninterrupted runs", name);
+    }
+
+}
+
+#[test]
+fn loader_stream_ends_after_the_last_example() {
+
+    // 3 examples of 2 tokens, a batch of one example each
+    let xs = (0..3).map(|i| Tensor::full(&[2, 4], i, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let ys = (0..3).map(|i| Tensor::full(&[2], i, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let mut loader = Loader::new(xs, ys, Device::Cpu, 1, 2);
+
+    let mut stream = loader.batches();
+    for i in 0..3 {
+        let (_, ys) = stream.next().unwrap();
+        assert_eq!(Vec::<i64>::try_from(&ys.reshape([-1])).unwrap(), vec![i, i]);
+    }
+    assert!(stream.next().is_none());
+    assert!(stream.next().is_none());
+
+    // and works with iterator adaptors
+    assert_eq!(loader.batches().enumerate().count(), 3);
+    assert_eq!(loader.batches().take(5).count(), 3);
+
+}
+
+#[test]
+fn padded_batches_pad_to_the_longest_sentence_and_mask_it() {
+
+    // sentences of 3, 1 and 2 tokens
+    let lengths = [3, 1, 2];
+    let xs = lengths.iter().map(|n| Tensor::full(&[*n, 4], 5, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let ys = lengths.iter().map(|n| Tensor::full(&[*n], 7, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+    let mut loader = Loader::new(xs, ys, Device::Cpu, 2, 2);
+    loader.pad_id = 9;
+    loader.char_pad_id = 8;
+
+    let batches = loader.to_padded().collect::<Vec<(Tensor, Tensor, Tensor)>>();
+    assert_eq!(batches.len(), 2);
+    let (xs, ys, mask) = &batches[0];
+    assert_eq!(xs.size(), vec![2, 3, 4]);
+    assert_eq!(ys.size(), vec![2, 3]);
+    assert_eq!(Vec::<bool>::try_from(&mask.reshape([-1])).unwrap(), vec![true, true, true, true, false, false]);
+    assert_eq!(Vec::<i64>::try_from(&ys.reshape([-1])).unwrap(), vec![7, 7, 7, 7, 9, 9]);
+    assert_eq!(Vec::<i64>::try_from(&xs.get(1).get(2)).unwrap(), vec![8; 4]);
+    assert_eq!(Vec::<i64>::try_from(&xs.get(1).get(0)).unwrap(), vec![5; 4]);
+
+    // the last batch has the remaining sentence, padded to its own length
+    let (xs, _, mask) = &batches[1];
+    assert_eq!(xs.size(), vec![1, 2, 4]);
+    assert_eq!(i64::try_from(mask.sum(Kind::Int64)).unwrap(), 2);
+
+    // the trainer batches label the padded positions with IGNORE_INDEX
+    loader.padded_batches = true;
+    let (_, ys) = loader.batches().next().unwrap();
+    assert_eq!(Vec::<i64>::try_from(&ys.reshape([-1])).unwrap(), vec![7, 7, 7, 7, IGNORE_INDEX, IGNORE_INDEX]);
+
+    // the loss, the accuracy and the backward lm loss of a padded batch don't depend on what the pads are
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (long_xs, long_ys) = elmo_text_loader.get_example(0).unwrap();
+    let (short_xs, short_ys) = elmo_text_loader.get_example(1).unwrap();
+    let mut loader = Loader::new(vec![long_xs, short_xs.narrow(0, 0, 3)], vec![long_ys, short_ys.narrow(0, 0, 3)], params.device, 2, 2);
+    loader.padded_batches = true;
+    let n_tokens = loader.n_tokens();
+    let elmo_train = ElmoTrainer::new();
+    let mut metrics = Vec::new();
+    for char_pad_id in [elmo_text_loader.char_pad_id(), 1] {
+        loader.char_pad_id = char_pad_id;
+        let (xs, ys) = loader.batches().next().unwrap();
+        assert_eq!(i64::try_from(ys.ne(IGNORE_INDEX).sum(Kind::Int64)).unwrap(), n_tokens);
+        let (_, auxiliary_loss) = model.forward_with_auxiliary(&xs, &ys, false);
+        let (mut loss, mut accuracy) = (0.0, 0.0);
+        elmo_train.step(xs, ys, &model, &mut loss, &mut accuracy, None);
+        metrics.push((loss, accuracy, auxiliary_loss.unwrap().double_value(&[])));
+    }
+    assert!((metrics[0].0 - metrics[1].0).abs() < 1e-6);
+    assert!((metrics[0].1 - metrics[1].1).abs() < 1e-6);
+    assert!((metrics[0].2 - metrics[1].2).abs() < 1e-6);
+
+}
+
+#[test]
+fn diverging_dev_loss_fires_after_patience_increases() {
+
+    let trainer = ElmoTrainer::new();
+    let dev_losses = [3.0, 2.5, 2.6, 2.4, 2.5, 2.7, 2.9, 3.2];
+    let fired_at = (1..=dev_losses.len()).find(|n| trainer.diverging(&dev_progress(&dev_losses[..*n]), 3)).map(|n| n - 1);
+
+    // increases at evaluations 2, 4, 5, 6 and 7, the third in a row is at evaluation 6
+    assert_eq!(fired_at, Some(6));
+    assert!(!trainer.diverging(&dev_progress(&[]), 1));
+
+}
+
+#[cfg(feature = "char_encoder_export")]
+#[test]
+fn char_encoder_export_keeps_the_traced_shapes() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_char_encoder");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("char_encoder.pt").to_str().unwrap().to_string();
+    model.export_char_encoder(&output_file, 1, 3).unwrap();
+
+    let encoder = tch::CModule::load(&output_file).unwrap();
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let xs = xs.narrow(0, 0, 3).unsqueeze(0).to_device(params.device);
+    let encoded = encoder.forward_ts(&[&xs]).unwrap();
+    assert_eq!(encoded.size(), vec![1, 3, params.in_dim]);
+    let expected = model.layer_outputs(&xs).get(0);
+    assert!(encoded.allclose(&expected, 1e-5, 1e-6, false));
+
+}
+
+#[test]
+fn labels_keep_token_ids_above_255() {
+
+    let mut sentences = vec!["a b c".to_string()];
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (_, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    // ids far above the u8 range, as in a large vocabulary
+    let token2int = HashMap::from([
+        ("a".to_string(), 256), ("b".to_string(), 300), ("c".to_string(), 65_537), (params.str_unk.to_string(), 299_999)
+    ]);
+    let elmo_text_loader = ELMoText::new(vec!["a b c d".to_string()], token2int, char2int, &params);
+    let (_, labels) = elmo_text_loader.get_example(0).unwrap();
+    assert_eq!(labels.kind(), Kind::Int64);
+    assert_eq!(Vec::<i64>::try_from(&labels).unwrap(), vec![300, 65_537, 299_999]);
+
+}
+
+#[test]
+fn scheduled_sampling_at_zero_is_teacher_forcing() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let token_encodings = elmo_text_loader.token_encodings();
+    assert_eq!(token_encodings.size(), vec![params.token_vocab_size, params.max_len_token]);
+    let decoded = elmo_text_loader.decode_label(3);
+    assert!(token_encodings.get(3).equal(&elmo_text_loader.encode_tokens(&[decoded]).get(0)));
+
+    // the schedule starts with teacher forcing and ramps up to the configured probability
+    assert_eq!(sampling_schedule(0.4, 4, 0), 0.0);
+    assert!((sampling_schedule(0.4, 4, 2) - 0.2).abs() < 1e-12);
+    assert_eq!(sampling_schedule(0.4, 4, 9), 0.4);
+
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let xs = xs.unsqueeze(0);
+    let seq_length = xs.size()[1];
+    let logits = Tensor::randn(&[seq_length, params.token_vocab_size], (Kind::Float, Device::Cpu));
+    assert!(scheduled_sampling(&xs, &logits, &token_encodings, 0.0).equal(&xs));
+
+    // at 1 every input after the first is the previous prediction
+    let sampled = scheduled_sampling(&xs, &logits, &token_encodings, 1.0);
+    let predicted = logits.argmax(-1, false);
+    assert!(sampled.get(0).get(0).equal(&xs.get(0).get(0)));
+    for t in 1..seq_length {
+        assert!(sampled.get(0).get(t).equal(&token_encodings.get(predicted.int64_value(&[t - 1]))));
+    }
+
+}
+
+#[test]
+fn scheduled_sampling_is_rejected_with_the_backward_loss_and_char_backoff() {
+
+    let output_dir = std::env::temp_dir().join("elmo_scheduled_sampling_config");
+    fs::create_dir_all(&output_dir).unwrap();
+    let config_file = output_dir.join("config.json").to_str().unwrap().to_string();
+    let config_with = |fields: &str| {
+        let output_file = output_dir.join("model.ot");
+        fs::write(&config_file, format!("{{\"corpus_file\": \"corpus.txt\", \"output_file\": {:?}, {}}}", output_file, fields)).unwrap();
+        ConfigElmo::new(&["main".to_string(), config_file.clone()])
+    };
+
+    // the backward lm would read its targets from the sampled inputs
+    let error = config_with("\"scheduled_sampling_prob\": 0.2").err().unwrap();
+    assert!(error.to_string().contains("backward_loss_weight 0"), "{}", error);
+    let params = config_with("\"scheduled_sampling_prob\": 0.2, \"backward_loss_weight\": 0.0").unwrap().get_params();
+    assert_eq!(params.scheduled_sampling_prob, 0.2);
+
+    // the char backoff targets would be sampled, whatever the order of the fields
+    let error = config_with("\"char_backoff_weight\": 0.5, \"scheduled_sampling_prob\": 0.2, \"backward_loss_weight\": 0.0").err().unwrap();
+    assert!(error.to_string().contains("can't be used with scheduled_sampling_prob"), "{}", error);
+
+}
+
+#[test]
+fn lstm_states_follow_the_kind_and_device_of_the_model() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.device = Device::cuda_if_available();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let xs = xs.unsqueeze(0).to_device(params.device);
+
+    for kind in [Kind::Float, Kind::Double] {
+        let mut vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        vars.set_kind(kind);
+        let logits = tch::no_grad(|| model.forward_t(&xs, false));
+        assert_eq!(logits.kind(), kind);
+        assert_eq!(logits.device(), params.device);
+        let (h, c) = tch::no_grad(|| model.lstm_states(&xs));
+        assert_eq!((h.kind(), c.kind()), (kind, kind));
+        assert_eq!((h.device(), c.device()), (params.device, params.device));
+    }
+
+}
+
+#[test]
+fn backward_lm_reads_the_sequence_right_to_left() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let seq_length = xs.size()[0];
+    assert!(seq_length >= 3);
+    let xs = xs.unsqueeze(0);
+    let (forward, backward) = model.directional_layers(&xs);
+    let backward = backward.unwrap();
+    let top = params.n_lstm_layers;
+
+    // a different last input changes no forward output before it, and every backward output
+    let changed_last = xs.copy();
+    changed_last.get(0).get(seq_length - 1).copy_(&xs.get(0).get(0));
+    let (changed_forward, changed_backward) = model.directional_layers(&changed_last);
+    let changed_backward = changed_backward.unwrap();
+    for t in 0..seq_length - 1 {
+        assert!(changed_forward.get(top).get(0).get(t).allclose(&forward.get(top).get(0).get(t), 1e-6, 1e-6, false));
+        assert!(!changed_backward.get(top).get(0).get(t).allclose(&backward.get(top).get(0).get(t), 1e-6, 1e-6, false));
+    }
+
+    // a different first input changes no backward output after it
+    let changed_first = xs.copy();
+    changed_first.get(0).get(0).copy_(&xs.get(0).get(seq_length - 1));
+    let changed_backward = model.directional_layers(&changed_first).1.unwrap();
+    for t in 1..seq_length {
+        assert!(changed_backward.get(top).get(0).get(t).allclose(&backward.get(top).get(0).get(t), 1e-6, 1e-6, false));
+    }
+
+    // examples don't see each other's backward pass
+    let batch = Tensor::stack(&[xs.get(0), changed_first.get(0)], 0);
+    let batch_backward = model.directional_layers(&batch).1.unwrap();
+    assert!(batch_backward.get(top).get(0).allclose(&backward.get(top).get(0), 1e-5, 1e-6, false));
+
+    // both directions per layer, and the previous token loss is trained along the next token loss
+    assert_eq!(model.bidirectional_layers(&xs).size(), vec![top + 1, 1, seq_length, 2 * params.in_dim]);
+    let (logits, auxiliary_loss) = model.forward_with_auxiliary(&xs, &ys.unsqueeze(0), false);
+    assert_eq!(logits.size(), vec![seq_length, params.token_vocab_size]);
+    assert!(auxiliary_loss.unwrap().double_value(&[]) > 0.0);
+
+}
+
+#[test]
+fn windowed_embeddings_average_the_overlapping_windows() {
+
+    let mut params = example_params();
+    let mut sentences = vec!["a b c d e f g h i j".to_string()];
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    // 12 tokens with SOS and EOS, windows of 6 starting at 0, 4 and 6
+    let xs = elmo_text_loader.encode_tokens(&elmo_text_loader.get_tokens(0).unwrap());
+    assert_eq!(xs.size()[0], 12);
+    let embeddings = embedding::windowed_embed(&model, &xs, 6, 2);
+    assert_eq!(embeddings.size(), vec![12, params.in_dim]);
+
+    let window_embed = |start: i64| model.embed(&xs.narrow(0, start, 6).unsqueeze(0)).squeeze_dim(0);
+    let (first, second) = (window_embed(0), window_embed(4));
+    // the last token of the first window gets the context after it from the second window
+    let boundary = (first.get(5) + second.get(1)) / 2.0;
+    assert!(embeddings.get(5).allclose(&boundary, 1e-5, 1e-6, false));
+    assert!(!embeddings.get(5).allclose(&first.get(5), 1e-5, 1e-6, false));
+    assert!(embeddings.get(0).allclose(&first.get(0), 1e-5, 1e-6, false));
+
+    // without a window, the whole sentence is embedded at once
+    assert!(embedding::contextual_embeddings(&model, &xs, &params).allclose(&model.embed(&xs.unsqueeze(0)).squeeze_dim(0), 1e-6, 1e-6, false));
+    params.embedding_window = Some(6);
+    params.embedding_window_overlap = 2;
+    assert!(embedding::contextual_embeddings(&model, &xs, &params).allclose(&embeddings, 1e-6, 1e-6, false));
+
+}
+
+#[test]
+fn scalar_mix_combines_the_layers_into_one_representation() {
+
+    let vars = nn::VarStore::new(Device::Cpu);
+    let scalar_mix = ScalarMix::new(&(vars.root() / "scalar_mix"), 3);
+    assert_eq!(vars.trainable_variables().len(), 2);
+    let layers = (0..3).map(|j| Tensor::full(&[2, 4, 5], j as f64, (Kind::Float, Device::Cpu))).collect::<Vec<Tensor>>();
+
+    // uniform weights and gamma 1 at init, the mean of the layers
+    let mixed = scalar_mix.forward(&layers);
+    assert_eq!(mixed.size(), vec![2, 4, 5]);
+    assert!(mixed.allclose(&Tensor::full(&[2, 4, 5], 1.0, (Kind::Float, Device::Cpu)), 1e-6, 1e-6, false));
+
+    // softmax normalized weights, scaled by gamma
+    let mut variables = vars.variables();
+    tch::no_grad(|| {
+        variables.get_mut("scalar_mix.weights").unwrap().copy_(&Tensor::from_slice(&[0.0f32, 0.0, 2.0f32.ln()]));
+        variables.get_mut("scalar_mix.gamma").unwrap().copy_(&Tensor::from_slice(&[2.0f32]));
+    });
+    let expected = 2.0 * (0.25 * 0.0 + 0.25 * 1.0 + 0.5 * 2.0);
+    assert!((scalar_mix.forward(&layers).double_value(&[0, 0, 0]) - expected).abs() < 1e-5);
+
+    // the model's embeddings are the scalar mix of its layers, with both directions averaged
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (xs, _) = elmo_text_loader.get_example(0).unwrap();
+    let xs = xs.unsqueeze(0);
+    let (forward, backward) = model.directional_layers(&xs);
+    let expected = ((forward + backward.unwrap()) / 2.0).mean_dim(0, false, Kind::Float);
+    let embeddings = model.embed(&xs);
+    assert_eq!(embeddings.size(), vec![1, xs.size()[1], params.in_dim]);
+    assert!(embeddings.allclose(&expected, 1e-5, 1e-6, false));
+
+    // the lm predictions are made from the mix, so a training step moves its weights and gamma
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let elmo_train = ElmoTrainer::new();
+    let mut opt = elmo_train.init_optimizer(&vars, params.learning_rate, &params.optimizer, params.momentum).unwrap();
+    let before = ["scalar_mix.weights", "scalar_mix.gamma"].map(|name| vars.variables()[name].copy());
+    let (mut loss, mut accuracy) = (0.0, 0.0);
+    elmo_train.step(xs.unsqueeze(0).to_device(params.device), ys.unsqueeze(0).to_device(params.device), &model, &mut loss, &mut accuracy, Some((&mut opt, params.grad_clip_norm, params.loss_reduction.as_str(), params.unk_loss_weight, Some(elmo_text_loader.unk_id()))));
+    for (name, before) in ["scalar_mix.weights", "scalar_mix.gamma"].iter().zip(before.iter()) {
+        assert!(!vars.variables()[*name].equal(before), "{}", name);
+    }
+
+}
+
+#[test]
+fn scalar_mix_export_has_normalized_weights_per_layer() {
+
+    let output_dir = std::env::temp_dir().join("elmo_scalar_mix");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("scalar_mix.json").to_str().unwrap().to_string();
+    assert!(export_scalar_mix(&output_file, &nn::VarStore::new(Device::Cpu)).is_err());
+
+    // the scalar mix of a model, over the char level layer and its lstm layer
+    let params = example_params();
+    let vars = nn::VarStore::new(params.device);
+    let _model = ELMo::new(&vars.root(), &params);
+    let mut variables = vars.variables();
+    tch::no_grad(|| {
+        variables.get_mut("scalar_mix.weights").unwrap().copy_(&Tensor::from_slice(&[0.5f32, -1.0]));
+        variables.get_mut("scalar_mix.gamma").unwrap().copy_(&Tensor::from_slice(&[1.5f32]));
+    });
+    export_scalar_mix(&output_file, &vars).unwrap();
+
+    let exported: serde_json::Value = serde_json::from_str(&fs::read_to_string(&output_file).unwrap()).unwrap();
+    let layers = exported["layers"].as_array().unwrap();
+    assert_eq!(layers.len(), params.n_lstm_layers as usize + 1);
+    let total: f64 = layers.iter().map(|layer| layer["weight"].as_f64().unwrap()).sum();
+    assert!((total - 1.0).abs() < 1e-6);
+    assert!(layers[0]["weight"].as_f64().unwrap() > layers[1]["weight"].as_f64().unwrap());
+    assert_eq!(layers[1]["raw_weight"].as_f64(), Some(-1.0));
+    assert_eq!(exported["gamma"].as_f64(), Some(1.5));
+
+}
+
+#[test]
+fn blacklisted_chars_are_removed_in_training_and_inference() {
+
+    let mut sentences = example_sentences().into_iter().map(|s| s.replace("sky", "s\u{200b}ky")).collect::<Vec<String>>();
+    let mut params = example_params();
+    params.char_blacklist = Some("\u{200b}".to_string());
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert!(!char2int.contains_key(&'\u{200b}'));
+    assert!(sentences.iter().all(|s| !s.contains('\u{200b}')));
+    assert!(token2int.contains_key("sky"));
+
+    // at inference a raw token with the blacklisted char is encoded as the clean token
+    let elmo_text_loader = ELMoText::new(vec!["blue s\u{200b}ky".to_string()], token2int, char2int.clone(), &params);
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap(), vec!["blue".to_string(), "sky".to_string()]);
+    let raw = elmo_text_loader.encode_tokens(&["s\u{200b}ky".to_string()]);
+    let clean = elmo_text_loader.encode_tokens(&["sky".to_string()]);
+    assert!(raw.equal(&clean));
+
+    // with both set, the blacklist wins over the whitelist
+    let mut sentences = vec!["abc cab".to_string()];
+    params.char_whitelist = Some("ab".to_string());
+    params.char_blacklist = Some("b".to_string());
+    preprocessor.prepare_sentences(&mut sentences, &params);
+    assert_eq!(sentences, vec!["a a".to_string()]);
+
+}
+
+#[test]
+fn grad_cosine_is_bounded_on_synthetic_gradients() {
+
+    let grad = Tensor::from_slice(&[1.0f32, -2.0, 3.0]).reshape([3, 1]);
+    assert!((grad_cosine(&grad, &grad) - 1.0).abs() < 1e-9);
+    assert!((grad_cosine(&grad, &(-&grad)) + 1.0).abs() < 1e-9);
+    assert_eq!(grad_cosine(&Tensor::from_slice(&[1.0f32, 0.0]), &Tensor::from_slice(&[0.0f32, 1.0])), 0.0);
+    assert_eq!(grad_cosine(&grad, &grad.zeros_like()), 0.0);
+    for _ in 0..10 {
+        let cosine = grad_cosine(&Tensor::randn([4, 6], (Kind::Float, Device::Cpu)), &Tensor::randn([24], (Kind::Float, Device::Cpu)));
+        assert!((-1.0..=1.0).contains(&cosine));
+    }
+
+    // after a backward pass, the consecutive lstm layers of each lm are compared on a sample of their gradients
+    let mut params = example_params();
+    params.n_lstm_layers = 2;
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    let xs = Tensor::randint(params.char_vocab_size, [2, 5, params.max_len_token], (Kind::Int64, Device::Cpu));
+    model.forward_t(&xs, true).sum(Kind::Float).backward();
+    let cosines = layer_grad_cosines(&vars, 8);
+    assert!(cosines.iter().any(|(first, second, _)| first == "forward_lm.lstm_0" && second == "forward_lm.lstm_1"));
+    assert!(cosines.iter().all(|(first, second, cosine)| first.split('.').next() == second.split('.').next() && (-1.0..=1.0).contains(cosine)));
+
+}
+
+#[test]
+fn embedder_matches_the_training_time_inputs() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences.clone(), token2int.clone(), char2int.clone(), &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+
+    // the raw first sentence is preprocessed as in training, so its embeddings are the ones of the training example
+    let raw = example_sentences()[0].clone();
+    let xs = elmo_text_loader.encode_tokens(&elmo_text_loader.get_tokens(0).unwrap());
+    let expected = model.embed(&xs.unsqueeze(0)).squeeze_dim(0).to_device(Device::Cpu);
+    let embedder = Embedder::new(model, token2int, char2int, &params).unwrap();
+    assert_eq!(embedder.tokens(&raw).unwrap(), elmo_text_loader.get_tokens(0).unwrap());
+    let embeddings = embedder.embed_sentence(&raw).unwrap();
+    assert_eq!(embeddings.size(), vec![xs.size()[0], params.in_dim]);
+    assert!(embeddings.allclose(&expected, 1e-6, 1e-6, false));
+
+    // unknown tokens and chars go through the unk handling as well
+    assert_eq!(embedder.embed_sentence("zzz qqq").unwrap().size()[0], embedder.tokens("zzz qqq").unwrap().len() as i64);
+
+}
+
+#[test]
+fn vocabularies_round_trip_through_json_files() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+
+    let output_dir = std::env::temp_dir().join("elmo_vocab_files");
+    fs::create_dir_all(&output_dir).unwrap();
+    let token_file = output_dir.join(files_handling::TOKEN2INT_FILE).to_str().unwrap().to_string();
+    let char_file = output_dir.join(files_handling::CHAR2INT_FILE).to_str().unwrap().to_string();
+    files_handling::save_vocab(&token_file, &token2int).unwrap();
+    files_handling::save_char_vocab(&char_file, &char2int).unwrap();
+    assert_eq!(files_handling::load_vocab(&token_file).unwrap(), token2int);
+    assert_eq!(files_handling::load_char_vocab(&char_file).unwrap(), char2int);
+
+    // missing and malformed files are reported with their path
+    let missing = output_dir.join("missing.json").to_str().unwrap().to_string();
+    let error = files_handling::load_vocab(&missing).unwrap_err();
+    assert!(error.to_string().contains("couldn't open the vocabulary file"), "{}", error);
+    let malformed = output_dir.join("malformed.json").to_str().unwrap().to_string();
+    for content in ["{\"a\": 0, \"b\": ", "{\"a\": 0, \"b\": 0}", "{\"ab\": 0}"] {
+        fs::write(&malformed, content).unwrap();
+        let error = files_handling::load_char_vocab(&malformed).unwrap_err();
+        assert!(error.to_string().contains("malformed vocabulary file"), "{}", error);
+    }
+
+}
+
+#[test]
+fn a_long_token_is_truncated_to_max_len_token_in_its_batch() {
+
+    // tokens are always padded or truncated to the max_len_token of the config, there is no per batch
+    // token length, so one outlier token doesn't widen the batch
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let outlier = "a".repeat(10 * params.max_len_token as usize);
+    let batch_sentences = vec![format!("This is {} sentence", outlier), "This is a sentence".to_string()];
+    let elmo_text_loader = ELMoText::new(batch_sentences, token2int, char2int, &params);
+
+    let examples = (0..2).map(|i| elmo_text_loader.get_example(i).unwrap()).collect::<Vec<(Tensor, Tensor)>>();
+    let (xs, ys): (Vec<Tensor>, Vec<Tensor>) = examples.into_iter().unzip();
+    let loader = Loader::new(xs, ys, Device::Cpu, 2, 2);
+    let (xs, _, _) = loader.to_padded().next().unwrap();
+    assert_eq!(xs.size()[0], 2);
+    assert_eq!(xs.size()[2], params.max_len_token);
+
+}
+
+#[test]
+fn a_saved_splits_file_reproduces_the_splits() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+
+    let output_dir = std::env::temp_dir().join("elmo_splits_file");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_dir = output_dir.to_str().unwrap().to_string();
+    let splits_file = format!("{}/splits.json", output_dir);
+    let _ = fs::remove_file(&splits_file);
+    params.output_file = Some(format!("{}/model.ot", output_dir));
+    params.write_splits = true;
+    let _ = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    assert!(std::path::Path::new(&splits_file).exists());
+
+    // the splits of the file are those of the run that wrote it, not a new permutation
+    let n_samples = elmo_text_loader.get_len();
+    let saved = Splitter::from_splits_file(&splits_file).unwrap().get_split_train_dev_test_indices(n_samples);
+    let written = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(&splits_file).unwrap()).unwrap();
+    for (name, split) in ["train", "dev", "test"].iter().zip(&saved) {
+        let expected = written[name].as_array().unwrap().iter().map(|i| i.as_i64().unwrap()).collect::<Vec<i64>>();
+        assert_eq!(Vec::<i64>::try_from(split).unwrap(), expected);
+    }
+    let reloaded = Splitter::from_splits_file(&splits_file).unwrap().get_split_train_dev_test_indices(n_samples);
+    assert!(saved.iter().zip(&reloaded).all(|(a, b)| a.equal(b)));
+    params.write_splits = false;
+    assert!(build_loaders(&elmo_text_loader, None, None, &Splitter::from_splits_file(&splits_file).unwrap(), &params).is_ok());
+
+    // a missing file and a file of another corpus are rejected, a corpus of another size, or indices that repeat
+    // or are out of the corpus
+    assert!(Splitter::from_splits_file(&format!("{}/missing.json", output_dir)).is_err());
+    let bad_splits_file = format!("{}/bad_splits.json", output_dir);
+    let all_indices = (0..n_samples).collect::<Vec<i64>>();
+    let repeated = [&all_indices[..n_samples as usize - 1], &[0]].concat();
+    let out_of_range = [&all_indices[..n_samples as usize - 1], &[n_samples]].concat();
+    for train in [all_indices[1..].to_vec(), repeated, out_of_range] {
+        fs::write(&bad_splits_file, serde_json::json!({"train": train, "dev": [], "test": []}).to_string()).unwrap();
+        assert!(build_loaders(&elmo_text_loader, None, None, &Splitter::from_splits_file(&bad_splits_file).unwrap(), &params).is_err());
+    }
+
+}
+
+#[test]
+fn byte_fallback_encodes_unknown_chars_as_their_utf8_bytes() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences.clone(), &mut params.clone()).unwrap();
+    let elmo_text_loader = ELMoText::new(Vec::new(), token2int, char2int.clone(), &params);
+    let unk_id = char2int[&params.char_unk] as i64;
+    let encoding = Vec::<i64>::try_from(&elmo_text_loader.encode_tokens(&["café".to_string()]).reshape([-1])).unwrap();
+    assert!(encoding.contains(&unk_id));
+
+    // the 256 byte chars are reserved in the char vocabulary, é is encoded as its two utf-8 bytes
+    params.byte_fallback = true;
+    let (token2int, byte_char2int) = Preprocessor::new().preprocess(&mut sentences, &mut params).unwrap();
+    assert_eq!(byte_char2int.len(), char2int.len() + 256);
+    assert_eq!(params.char_vocab_size, byte_char2int.len() as i64);
+    let elmo_text_loader = ELMoText::new(Vec::new(), token2int, byte_char2int.clone(), &params);
+    let unk_id = byte_char2int[&params.char_unk] as i64;
+    let encoding = Vec::<i64>::try_from(&elmo_text_loader.encode_tokens(&["café".to_string()]).reshape([-1])).unwrap();
+    assert!(!encoding.contains(&unk_id));
+    let pad_id = byte_char2int[&' '] as i64;
+    // start, c, a, f, two bytes, end
+    assert_eq!(encoding.iter().filter(|id| **id != pad_id).count(), 7);
+    assert_eq!(elmo_text_loader.decode_chars(&encoding), "café");
+
+}
+
+#[test]
+fn tab_delimited_and_irregular_spacing_inputs_have_no_empty_tokens() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let expected = ["This", "is", "a", "first", "sentence"].map(|t| t.to_string()).to_vec();
+
+    // tab delimited, with leading, trailing and repeated delimiters
+    params.delimiter = "\t".to_string();
+    let raw = vec!["\tThis\tis\t\ta first\tsentence\t ".to_string()];
+    let elmo_text_loader = ELMoText::new(raw.clone(), token2int.clone(), char2int.clone(), &params);
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap(), expected);
+
+    // the preprocessed sentence splits the same, and its labels have no spurious unk
+    let mut prepared = raw.clone();
+    preprocessor.prepare_sentences(&mut prepared, &params);
+    let elmo_text_loader = ELMoText::new(prepared, token2int.clone(), char2int.clone(), &params);
+    let mut with_boundaries = vec!["SOS".to_string()];
+    with_boundaries.extend(expected.clone());
+    with_boundaries.push("EOS".to_string());
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap(), with_boundaries);
+    let (_, ys) = elmo_text_loader.get_example(0).unwrap();
+    let unk_id = token2int[&params.str_unk] as i64;
+    assert!(!Vec::<i64>::try_from(&ys).unwrap().contains(&unk_id));
+
+    // without collapsing, the ends still make no empty tokens, and a set of delimiter chars is accepted
+    params.delimiter = "|;".to_string();
+    params.collapse_whitespace = false;
+    let elmo_text_loader = ELMoText::new(vec!["|This;is|a first;sentence|".to_string()], token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.get_tokens(0).unwrap(), expected);
+
+}
+
+#[test]
+fn bilm_loss_is_the_weighted_sum_of_the_directional_losses() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let (xs, ys) = (xs.unsqueeze(0), ys.unsqueeze(0));
+
+    params.forward_loss_weight = 0.6;
+    params.backward_loss_weight = 0.4;
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (forward_loss, backward_loss) = model.directional_losses(&xs, &ys, false);
+    let (forward_loss, backward_loss) = (forward_loss.double_value(&[]), backward_loss.unwrap().double_value(&[]));
+    let bilm_loss = model.bilm_loss(&xs, &ys, false).double_value(&[]);
+    assert!((bilm_loss - (0.6 * forward_loss + 0.4 * backward_loss)).abs() < 1e-6);
+
+    // the trained loss of the language model is weighted the same way
+    let (logits, auxiliary_loss) = model.forward_with_auxiliary(&xs, &ys, false);
+    let trained = logits.cross_entropy_for_logits(&ys.reshape([-1])).double_value(&[]) * model.forward_loss_weight() + auxiliary_loss.unwrap().double_value(&[]);
+    assert!((trained - bilm_loss).abs() < 1e-6);
+
+}
+
+#[test]
+fn leaking_labels_are_detected_by_the_self_check() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    assert!(elmo_text_loader.check_label_leakage(10).is_ok());
+
+    // inputs shifted one token too far are the encodings of their labels
+    let tokens = elmo_text_loader.get_tokens(0).unwrap();
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    assert!(elmo_text_loader.leaking_positions(&tokens, &xs, &ys).is_empty());
+    let leaking_xs = elmo_text_loader.encode_tokens(&tokens[1..]);
+    let positions = elmo_text_loader.leaking_positions(&tokens, &leaking_xs, &ys);
+    assert_eq!(positions, (0..ys.size()[0]).collect::<Vec<i64>>());
+
+    // a single leaking position is flagged alone
+    let partly_leaking_xs = xs.copy();
+    partly_leaking_xs.get(2).copy_(&leaking_xs.get(2));
+    assert_eq!(elmo_text_loader.leaking_positions(&tokens, &partly_leaking_xs, &ys), vec![2]);
+
+}
+
+#[test]
+fn splits_of_a_large_corpus_cover_every_index_once() {
+
+    // indices are int64, a corpus of more than 127 sentences doesn't wrap around
+    let sentences = (0..1000).map(|i| format!("sentence number {}", i)).collect::<Vec<String>>();
+    let splits = Splitter::new().get_split_train_dev_test_indices(sentences.len() as i64);
+    assert!(splits.iter().all(|split| split.kind() == Kind::Int64));
+    assert_eq!(splits.iter().map(|split| split.size()[0]).collect::<Vec<i64>>(), vec![800, 100, 100]);
+
+    let mut indices = splits.iter().flat_map(|split| Vec::<i64>::try_from(split).unwrap()).collect::<Vec<i64>>();
+    indices.sort();
+    assert_eq!(indices, (0..1000).collect::<Vec<i64>>());
+    assert!(indices.iter().all(|i| sentences.get(*i as usize).is_some()));
+
+}
+
+#[test]
+fn splitter_with_ratios_validates_and_follows_them() {
+
+    let splits = Splitter::with_ratios([0.5, 0.2, 0.3]).unwrap().get_split_train_dev_test_indices(100);
+    assert_eq!(splits.iter().map(|split| split.size()[0]).collect::<Vec<i64>>(), vec![50, 20, 30]);
+    let splits = Splitter::with_ratios([1.0, 0.0, 0.0]).unwrap().get_split_train_dev_test_indices(10);
+    assert_eq!(splits.iter().map(|split| split.size()[0]).collect::<Vec<i64>>(), vec![10, 0, 0]);
+    assert_eq!(Splitter::new().get_split_train_dev_test_indices(10).iter().map(|split| split.size()[0]).collect::<Vec<i64>>(), vec![8, 1, 1]);
+
+    // ratios that don't sum to one, negative ratios or not 3 of them are errors, not panics
+    assert!(Splitter::with_ratios([0.7, 0.2, 0.2]).err().unwrap().to_string().contains("should sum to 1"));
+    assert!(Splitter::with_ratios([1.2, -0.1, -0.1]).is_err());
+    assert!(Splitter::check_ratios(&[0.5, 0.5]).is_err());
+    assert_eq!(Splitter::check_ratios(&[0.7, 0.2, 0.1]).unwrap(), [0.7, 0.2, 0.1]);
+
+}
+
+#[test]
+fn max_len_token_is_resolved_to_a_percentile_of_the_token_lengths() {
+
+    // token lengths of 1, 2, 3, 4, 5, 6, 8 and 10 chars, 3 to 12 with the start and end chars
+    let mut sentences = vec!["a bb ccc dddd".to_string(), "eeeee ffffff gggggggg hhhhhhhhhh".to_string()];
+    let mut params = example_params();
+    params.add_sentence_boundaries = false;
+    assert_eq!(token_length_percentile(&sentences, 50.0, false), 6);
+    assert_eq!(token_length_percentile(&sentences, 90.0, false), 12);
+    assert_eq!(token_length_percentile(&sentences, 100.0, false), 12);
+    assert_eq!(token_length_percentile(&sentences, 1.0, false), 3);
+
+    // the sentence boundaries aren't counted with add_sentence_boundaries, without it they are corpus tokens
+    let bounded = sentences.iter().map(|s| format!("SOS {} EOS", s)).collect::<Vec<String>>();
+    assert_eq!(token_length_percentile(&bounded, 50.0, true), 6);
+    assert_eq!(token_length_percentile(&bounded, 1.0, true), 3);
+    assert_eq!(token_length_percentile(&bounded, 50.0, false), 5);
+
+    // preprocessing resolves max_len_token, the encodings follow it
+    params.max_len_token_percentile = Some(75.0);
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    assert_eq!(params.max_len_token, 8);
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.encode_tokens(&elmo_text_loader.get_tokens(0).unwrap()).size(), vec![4, 8]);
+
+    // and is at least the widest conv kernel
+    let mut sentences = vec!["a bb ccc dddd".to_string()];
+    params.max_len_token_percentile = Some(1.0);
+    params.kernel_size = vec![1, 7];
+    params.out_channels = vec![20, 20];
+    Preprocessor::new().preprocess(&mut sentences, &mut params).unwrap();
+    assert_eq!(params.max_len_token, 7);
+
+}
+
+#[test]
+fn debug_shapes_logs_the_module_boundaries_of_a_forward_pass() {
+
+    let mut params = example_params();
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    let xs = Tensor::randint(params.char_vocab_size, [2, 5, params.max_len_token], (Kind::Int64, Device::Cpu));
+    let _ = model.forward_t(&xs, false);
+    assert!(model.shape_log().records().is_empty());
+
+    // shapes are logged in release builds too
+    params.debug_shapes = true;
+    let vars = nn::VarStore::new(Device::Cpu);
+    let model = ELMo::new(&vars.root(), &params);
+    let _ = model.forward_t(&xs, false);
+    let records = model.shape_log().records();
+
+    // each token goes through the conv block, (batch_size, 1, token_length, embedding_dim) => (batch_size, n_filters)
+    let conv_records = records.iter().filter(|(module, _, _)| module == "char_level.conv_0").collect::<Vec<_>>();
+    assert_eq!(conv_records.len(), 5);
+    assert!(conv_records.iter().all(|(_, input, output)| *input == vec![2, 1, params.max_len_token, params.char_embedding_dim] && *output == vec![2, params.out_channels[0]]));
+
+    // (batch_size, seq_length, token_length) => (batch_size, seq_length, out_linear) for the char level net, then
+    // (batch_size, seq_length, out_linear) => (n_lstm_layers + 1, batch_size, seq_length, out_linear) for each lm
+    let find = |name: &str| records.iter().find(|(module, _, _)| module == name).cloned().unwrap();
+    assert_eq!(find("char_level"), ("char_level".to_string(), vec![2, 5, params.max_len_token], vec![2, 5, params.in_dim]));
+    for lm in ["forward_lm", "backward_lm"] {
+        assert_eq!(find(lm), (lm.to_string(), vec![2, 5, params.in_dim], vec![params.n_lstm_layers + 1, 2, 5, params.in_dim]));
+    }
+    model.shape_log().clear();
+    assert!(model.shape_log().records().is_empty());
+
+    // the records are capped, the oldest are dropped and the last forward pass is kept
+    let n_passes = SHAPE_LOG_CAPACITY / records.len() + 1;
+    for _ in 0..n_passes {
+        let _ = model.forward_t(&xs, false);
+    }
+    let capped = model.shape_log().records();
+    assert_eq!(capped.len(), SHAPE_LOG_CAPACITY);
+    assert_eq!(capped[SHAPE_LOG_CAPACITY - records.len()..], records[..]);
+
+}
+
+#[test]
+fn seeded_splits_and_shuffles_are_reproducible() {
+
+    let split = |seed: i64| Splitter::new().with_seed(seed).get_split_train_dev_test_indices(1000);
+    let (first, second, other) = (split(3), split(3), split(4));
+    assert!(first.iter().zip(&second).all(|(a, b)| a.equal(b)));
+    assert!(!first[0].equal(&other[0]));
+
+    // equal seeds give the same batch order whatever the global generator did in between
+    let batch_order = |seed: u64| {
+        let xs = (0..50).map(|i| Tensor::full(&[3, 4], i, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+        let ys = (0..50).map(|i| Tensor::full(&[3], i, (Kind::Int64, Device::Cpu))).collect::<Vec<Tensor>>();
+        let mut loader = Loader::new(xs, ys, Device::Cpu, 1, 3);
+        let _ = Tensor::randperm(10, (Kind::Int64, Device::Cpu));
+        loader.shuffle_seeded(seed).batches().map(|(_, ys)| ys.int64_value(&[0, 0])).collect::<Vec<i64>>()
+    };
+    assert_eq!(batch_order(11), batch_order(11));
+    assert_ne!(batch_order(11), batch_order(12));
+    let mut order = batch_order(11);
+    order.sort();
+    assert_eq!(order, (0..50).collect::<Vec<i64>>());
+
+}
+
+#[test]
+fn k_fold_uses_each_index_as_validation_exactly_once() {
+
+    // 23 samples in 5 folds, of 4 validation samples and the last of 7
+    let folds = Splitter::new().with_seed(1).k_fold_indices(23, 5);
+    assert_eq!(folds.len(), 5);
+    assert_eq!(folds.iter().map(|(_, validation)| validation.size()[0]).collect::<Vec<i64>>(), vec![4, 4, 4, 4, 7]);
+
+    let mut validation_indices = Vec::new();
+    for (train, validation) in &folds {
+        let train = Vec::<i64>::try_from(train).unwrap();
+        let validation = Vec::<i64>::try_from(validation).unwrap();
+        assert_eq!(train.len() + validation.len(), 23);
+        assert!(train.iter().all(|i| !validation.contains(i)));
+        let mut all = [train, validation.clone()].concat();
+        all.sort();
+        assert_eq!(all, (0..23).collect::<Vec<i64>>());
+        validation_indices.extend(validation);
+    }
+    validation_indices.sort();
+    assert_eq!(validation_indices, (0..23).collect::<Vec<i64>>());
+
+    // sequential folds are consecutive parts of the corpus
+    let folds = Splitter::with_mode(SplitMode::Sequential).k_fold_indices(10, 2);
+    assert_eq!(Vec::<i64>::try_from(&folds[1].1).unwrap(), (5..10).collect::<Vec<i64>>());
+
+}
+
+#[test]
+fn unk_loss_weight_scales_the_unk_positions_loss() {
+
+    // a fixed batch of logits over a vocabulary of 5 tokens, UNK is the last id
+    let logits = Tensor::arange(24, (Kind::Float, Device::Cpu)).reshape([6, 4]).sin();
+    let logits = Tensor::cat(&[logits, Tensor::zeros([6, 1], (Kind::Float, Device::Cpu))], 1);
+    let targets = Tensor::from_slice(&[0i64, 4, 1, 4, 2, 3]);
+    let token_losses = logits.log_softmax(-1, Kind::Float).nll_loss_nd::<Tensor>(&targets, None, tch::Reduction::None, -100);
+    let is_unk = targets.eq(4).to_kind(Kind::Float);
+    let is_other: Tensor = 1.0 - &is_unk;
+    let unk_loss = (&token_losses * &is_unk).sum(Kind::Float).double_value(&[]);
+    let other_loss = (&token_losses * &is_other).sum(Kind::Float).double_value(&[]);
+
+    for unk_loss_weight in [0.0, 0.25, 1.0] {
+        let weighted = unk_weighted_losses(&token_losses, &targets, 4, unk_loss_weight);
+        let total = reduce_loss(&weighted, &targets, 2, "sum").double_value(&[]);
+        assert!((total - (other_loss + unk_loss_weight * unk_loss)).abs() < 1e-5);
+        // the other positions are untouched
+        assert!((&weighted * &is_other).allclose(&(&token_losses * &is_other), 1e-6, 1e-6, false));
+    }
+    let half = unk_weighted_losses(&token_losses, &targets, 4, 0.5);
+    assert!((reduce_loss(&half, &targets, 2, "token_mean").double_value(&[]) - (other_loss + 0.5 * unk_loss) / 6.0).abs() < 1e-5);
+
+    // the loaders carry the id of str_unk, a train loader without it can't weigh the unk targets
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let unk_id = token2int[&params.str_unk] as i64;
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    assert_eq!(elmo_text_loader.unk_id(), unk_id);
+    let (trainset_iter, _, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    assert_eq!(trainset_iter.unk_id, Some(unk_id));
+    params.unk_loss_weight = 0.5;
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let mut loader = Loader::new(vec![xs], vec![ys], params.device, 1, 1);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    assert!(ElmoTrainer::new().run_training(&mut loader, &mut None, &model, &mut vars, &params).is_err());
+
+}
+
+#[test]
+fn coverage_report_of_a_second_corpus() {
+
+    // vocabularies of the corpus fixture, reported against the dev and test fixtures
+    let fixtures = format!("{}/tests/fixtures", env!("CARGO_MANIFEST_DIR"));
+    let mut params = example_params();
+    let mut sentences = files_handling::load_sentences(&format!("{}/corpus.txt", fixtures), params.skip_blank_lines).unwrap();
+    let (token2int, char2int) = Preprocessor::new().preprocess(&mut sentences, &mut params).unwrap();
+
+    // dev: 3 of 8 tokens (This, is, is) and 25 of 29 chars (no d, k, y, y) are in the vocabularies
+    let report = coverage_report(&token2int, &char2int, &format!("{}/dev.txt", fixtures), &params, 10).unwrap();
+    assert_eq!((report.n_tokens, report.n_chars), (8, 29));
+    assert!((report.token_coverage - 3.0 / 8.0).abs() < 1e-9);
+    assert!((report.char_coverage - 25.0 / 29.0).abs() < 1e-9);
+    let oov_tokens = report.oov_tokens.iter().map(|(t, c)| (t.as_str(), *c)).collect::<Vec<(&str, usize)>>();
+    assert_eq!(oov_tokens, vec![("a", 1), ("dev", 1), ("sentence", 1), ("sky", 1), ("yellow", 1)]);
+
+    // test: 2 of 10 tokens and 26 of 29 chars (no A, B, C), only the top 3 oov tokens are listed
+    let report = coverage_report(&token2int, &char2int, &format!("{}/test.txt", fixtures), &params, 3).unwrap();
+    assert!((report.token_coverage - 2.0 / 10.0).abs() < 1e-9);
+    assert!((report.char_coverage - 26.0 / 29.0).abs() < 1e-9);
+    let oov_tokens = report.oov_tokens.iter().map(|(t, c)| (t.as_str(), *c)).collect::<Vec<(&str, usize)>>();
+    assert_eq!(oov_tokens, vec![("A", 1), ("B", 1), ("C", 1)]);
+
+    let output_dir = std::env::temp_dir().join("elmo_coverage_report");
+    fs::create_dir_all(&output_dir).unwrap();
+    let output_file = output_dir.join("coverage_report.json");
+    write_coverage_report(&report, output_file.to_str().unwrap()).unwrap();
+    let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&output_file).unwrap()).unwrap();
+    assert_eq!(written["oov_tokens"][0], serde_json::json!(["A", 1]));
+    assert_eq!(written["n_tokens"], 10);
+
+}
+
+#[test]
+fn patience_stops_on_a_dev_loss_plateau_and_restores_the_best_model() {
+
+    // the best dev loss is at epoch 1, patience 2 runs out two evaluations later
+    let dev_losses = [3.0, 2.0, 2.5, 2.0, 1.0];
+    let elmo_train = ElmoTrainer::new();
+    let stopped_at = (1..=dev_losses.len()).find(|n| elmo_train.patience_exhausted(&dev_progress(&dev_losses[..*n]), 2)).map(|n| n - 1);
+    assert_eq!(stopped_at, Some(3));
+    assert_eq!(dev_progress(&dev_losses[..4]).best_dev_index(), Some(1));
+    assert_eq!(dev_progress(&[]).best_dev_index(), None);
+
+    // without a learning rate the dev loss never improves on the first epoch
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_iter = 10;
+    params.patience = 2;
+    params.learning_rate = 0.0;
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let initial = snapshot_vars(&vars);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    assert_eq!(train_progress.epochs(), 3);
+    assert_eq!(train_progress.best_dev_index(), Some(0));
+    for (name, var) in snapshot_vars(&vars) {
+        assert!(var.equal(&initial[&name]));
+    }
+
+    // without a dev set early stopping is off, training runs for max_iter
+    let mut devset_iter = None;
+    params.max_iter = 4;
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    assert_eq!(train_progress.epochs(), 4);
+
+}
+
+#[test]
+fn periodic_checkpoints_resume_training_at_their_iteration() {
+
+    let output_dir = std::env::temp_dir().join("elmo_resume");
+    fs::create_dir_all(&output_dir).unwrap();
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    params.max_iter = 2;
+    params.checkpoint_every = Some(1);
+    params.patience = 10;
+    params.output_file = Some(output_dir.join("model.ot").to_str().unwrap().to_string());
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (mut trainset_iter, mut devset_iter, _) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let steps_per_epoch = trainset_iter.n_batches() as i64;
+    ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+
+    // a checkpoint after every epoch, with the iteration to continue from, the progress so far and the best
+    // perplexity and variables
+    let checkpoint_file = output_dir.join("checkpoint_1.ot").to_str().unwrap().to_string();
+    assert!(output_dir.join("checkpoint_2.ot").exists());
+    let state = TrainingCheckpoint::load(&checkpoint_file).unwrap();
+    assert_eq!((state.iteration, state.step, state.optimizer.as_str()), (1, steps_per_epoch, "adam"));
+    let checkpoint_progress = state.train_progress.unwrap();
+    assert_eq!(checkpoint_progress.epochs(), 1);
+    assert_eq!(state.best_perplexity, checkpoint_progress.last_perplexity());
+    let best_snapshot = TrainingCheckpoint::load_best_snapshot(&checkpoint_file).unwrap().unwrap();
+    assert_eq!(best_snapshot.keys().collect::<Vec<_>>(), snapshot_vars(&vars).keys().collect::<Vec<_>>());
+
+    // resuming from the first checkpoint runs the remaining epochs only, the steps and the progress count on
+    params.max_iter = 3;
+    params.checkpoint_every = None;
+    params.resume_from = Some(checkpoint_file);
+    let mut vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let train_progress = ElmoTrainer::new().run_training(&mut trainset_iter, &mut devset_iter, &model, &mut vars, &params).unwrap();
+    assert_eq!(train_progress.epochs(), 3);
+    assert_eq!(train_progress.last_step(), Some(3 * steps_per_epoch));
+
+    // a run with a dev set doesn't continue without one
+    assert!(ElmoTrainer::new().run_training(&mut trainset_iter, &mut None, &model, &mut vars, &params).is_err());
+
+}
+
+#[test]
+fn sentence_pooling_excludes_the_masked_padding() {
+
+    // two sentences of 3 and 2 tokens, the second is padded to 3
+    let hs = Tensor::arange(12, (Kind::Float, Device::Cpu)).reshape([2, 3, 2]);
+    let mask = Tensor::from_slice(&[true, true, true, true, true, false]).reshape([2, 3]);
+    let pooled = |pooling: &str| Vec::<f32>::try_from(&pool_sentence(&hs, &mask, pooling).reshape([-1])).unwrap();
+    assert_eq!(pooled("mean"), vec![2.0, 3.0, 7.0, 8.0]);
+    assert_eq!(pooled("max"), vec![4.0, 5.0, 8.0, 9.0]);
+    assert_eq!(pooled("last"), vec![4.0, 5.0, 8.0, 9.0]);
+    assert_eq!(pooled("cls"), vec![0.0, 1.0, 6.0, 7.0]);
+
+    // one vector per sentence of a padded batch, of the top layer dim, for every pooling
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let examples = (0..3).map(|i| elmo_text_loader.get_example(i).unwrap()).collect::<Vec<(Tensor, Tensor)>>();
+    let (xs, ys): (Vec<Tensor>, Vec<Tensor>) = examples.into_iter().unzip();
+    let (xs, _, mask) = Loader::new(xs, ys, Device::Cpu, 3, 2).to_padded().next().unwrap();
+
+    // a sentence cut to 3 tokens, alone and padded in a batch after a longer one
+    let (long_xs, long_ys) = elmo_text_loader.get_example(0).unwrap();
+    let (short_xs, short_ys) = elmo_text_loader.get_example(1).unwrap();
+    let (short_xs, short_ys) = (short_xs.narrow(0, 0, 3), short_ys.narrow(0, 0, 3));
+    let (batch_xs, _, batch_mask) = Loader::new(vec![long_xs, short_xs.shallow_clone()], vec![long_ys, short_ys.shallow_clone()], Device::Cpu, 2, 2).to_padded().next().unwrap();
+    let (alone_xs, _, alone_mask) = Loader::new(vec![short_xs], vec![short_ys], Device::Cpu, 1, 2).to_padded().next().unwrap();
+    assert_eq!(batch_mask.get(1).sum(Kind::Int64).int64_value(&[]), 3);
+
+    for pooling in ["mean", "max", "last", "cls"] {
+        params.sentence_pooling = pooling.to_string();
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let top_layer = model.bidirectional_layers(&xs).get(params.n_lstm_layers);
+        let embedded = model.sentence_embed(&xs, &mask);
+        assert_eq!(embedded.size(), vec![3, top_layer.size()[2]]);
+        assert!(embedded.allclose(&pool_sentence(&top_layer, &mask, pooling), 1e-5, 1e-5, false));
+
+        // the pads don't reach the backward half of the padded sentence
+        let in_batch = model.sentence_embed(&batch_xs, &batch_mask).get(1);
+        let alone = model.sentence_embed(&alone_xs, &alone_mask).get(0);
+        assert!(in_batch.allclose(&alone, 1e-5, 1e-5, false), "{}", pooling);
+    }
+
+}
+
+#[test]
+fn test_perplexity_is_the_exp_of_the_token_mean_cross_entropy() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let vars = nn::VarStore::new(params.device);
+    let model = ELMo::new(&vars.root(), &params);
+    let (_, _, testset_iter) = build_loaders(&elmo_text_loader, None, None, &Splitter::new(), &params).unwrap();
+    let mut testset_iter = testset_iter.unwrap();
+
+    // every position of the stream batches is a token, batches of different sizes weigh by their tokens
+    let (mut token_loss, mut n_tokens) = (0.0, 0.0);
+    for (xs, ys) in testset_iter.batches() {
+        let logits = tch::no_grad(|| model.forward_t(&xs, false));
+        let losses = logits.log_softmax(-1, Kind::Float).nll_loss_nd::<Tensor>(&ys.reshape([-1]), None, tch::Reduction::None, -100);
+        token_loss += losses.sum(Kind::Double).double_value(&[]);
+        n_tokens += ys.numel() as f64;
+    }
+    let (_, acc, perplexity) = ElmoTrainer::new().run_testing(&mut testset_iter, &model).unwrap();
+    assert!((perplexity - (token_loss / n_tokens).exp()).abs() < 1e-3 * perplexity);
+    assert!((0.0..=1.0).contains(&acc));
+    // an untrained model is far from certain of the next token
+    assert!(perplexity > 1.0);
+    assert_eq!(token_perplexity(0.0, 0.0), f64::INFINITY);
+
+    // the progress over epochs records the train and dev perplexities, the dev one is monitored
+    let train_progress = dev_progress(&[1.0]);
+    assert_eq!(train_progress.last_perplexity(), Some(1.0f64.exp()));
+    assert!(format!("{}", train_progress).contains(&format!("dev perplexity: {}", 1.0f64.exp())));
+
+}
+
+#[test]
+fn grad_clip_norm_bounds_the_total_gradient_norm_of_a_step() {
+
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    assert!(params.to_string().contains("grad_clip_norm: None (no clipping)"));
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let elmo_train = ElmoTrainer::new();
+
+    // the gradients are kept after the step, their total norm is measured over the trainable variables
+    let total_grad_norm = |grad_clip_norm: Option<f64>| {
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let mut opt = elmo_train.init_optimizer(&vars, params.learning_rate, &params.optimizer, params.momentum).unwrap();
+        let (mut loss, mut accuracy) = (0.0, 0.0);
+        elmo_train.step(xs.unsqueeze(0).to_device(params.device), ys.unsqueeze(0).to_device(params.device), &model, &mut loss, &mut accuracy, Some((&mut opt, grad_clip_norm, params.loss_reduction.as_str(), params.unk_loss_weight, Some(elmo_text_loader.unk_id()))));
+        vars.trainable_variables().iter()
+        .filter(|var| var.grad().defined())
+        .map(|var| var.grad().norm().double_value(&[]).powi(2))
+        .sum::<f64>()
+        .sqrt()
+    };
+
+    let unclipped = total_grad_norm(None);
+    assert!(unclipped > 1e-3);
+    let clipped = total_grad_norm(Some(1e-3));
+    assert!(clipped <= 1e-3 * (1.0 + 1e-4), "{}", clipped);
+
+    params.grad_clip_norm = Some(1e-3);
+    assert!(params.to_string().contains("grad_clip_norm: 0.001 (clipping)"));
+
+    // the deprecated clip_norm is read as grad_clip_norm, not with it
+    let output_dir = std::env::temp_dir().join("elmo_clip_norm");
+    fs::create_dir_all(&output_dir).unwrap();
+    let config_file = output_dir.join("config.json").to_str().unwrap().to_string();
+    let config_with = |fields: &str| {
+        fs::write(&config_file, format!("{{\"corpus_file\": \"corpus.txt\", \"output_file\": {:?}, {}}}", output_dir.join("model.ot"), fields)).unwrap();
+        ConfigElmo::new(&["main".to_string(), config_file.clone()])
+    };
+    assert_eq!(config_with("\"clip_norm\": 2.0").unwrap().get_params().grad_clip_norm, Some(2.0));
+    assert!(config_with("\"clip_norm\": 2.0, \"grad_clip_norm\": 1.0").is_err());
+
+}
+
+#[test]
+fn optimizer_is_selected_by_config() {
+
+    let output_dir = std::env::temp_dir().join("elmo_optimizer");
+    fs::create_dir_all(&output_dir).unwrap();
+    let config_file = output_dir.join("config.json").to_str().unwrap().to_string();
+    let config_with = |fields: &str| {
+        let output_file = output_dir.join("model.ot");
+        fs::write(&config_file, format!("{{\"corpus_file\": \"corpus.txt\", \"output_file\": {:?}, {}}}", output_file, fields)).unwrap();
+        ConfigElmo::new(&["main".to_string(), config_file.clone()])
+    };
+
+    // adam by default, sgd reads its momentum, unknown names list the supported ones
+    assert_eq!(config_with("\"seed\": 1").unwrap().get_params().optimizer, "adam");
+    let params = config_with("\"optimizer\": \"sgd\", \"momentum\": 0.9").unwrap().get_params();
+    assert_eq!((params.optimizer.as_str(), params.momentum), ("sgd", Some(0.9)));
+    let error = config_with("\"optimizer\": \"adagrad\"").err().unwrap();
+    assert!(error.to_string().contains("adam, sgd, rmsprop"), "{}", error);
+
+    // momentum is only read by sgd, it is rejected with the other optimizers (adam by default)
+    for fields in ["\"momentum\": 0.9", "\"optimizer\": \"adam\", \"momentum\": 0.9", "\"optimizer\": \"rmsprop\", \"momentum\": 0.9"] {
+        let error = config_with(fields).err().unwrap();
+        assert!(error.to_string().contains("sgd optimizer only"), "{}", error);
+    }
+
+    // each optimizer trains the variables
+    let mut sentences = example_sentences();
+    let mut params = example_params();
+    let mut preprocessor = Preprocessor::new();
+    let (token2int, char2int) = preprocessor.preprocess(&mut sentences, &mut params).unwrap();
+    let elmo_text_loader = ELMoText::new(sentences, token2int, char2int, &params);
+    let (xs, ys) = elmo_text_loader.get_example(0).unwrap();
+    let elmo_train = ElmoTrainer::new();
+    for (optimizer, momentum) in [("adam", None), ("sgd", Some(0.9)), ("rmsprop", None)] {
+        let vars = nn::VarStore::new(params.device);
+        let model = ELMo::new(&vars.root(), &params);
+        let before = vars.variables()["to_vocab.weight"].copy();
+        let mut opt = elmo_train.init_optimizer(&vars, params.learning_rate, optimizer, momentum).unwrap();
+        let (mut loss, mut accuracy) = (0.0, 0.0);
+        elmo_train.step(xs.unsqueeze(0).to_device(params.device), ys.unsqueeze(0).to_device(params.device), &model, &mut loss, &mut accuracy, Some((&mut opt, params.grad_clip_norm, params.loss_reduction.as_str(), params.unk_loss_weight, Some(elmo_text_loader.unk_id()))));
+        assert!(!vars.variables()["to_vocab.weight"].equal(&before), "{}", optimizer);
+    }
+    let vars = nn::VarStore::new(params.device);
+    assert!(elmo_train.init_optimizer(&vars, params.learning_rate, "adagrad", None).is_err());
+
+}